@@ -0,0 +1,23 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use wluma::config::Lightness;
+use wluma::frame::{compute_perceived_lightness_percent, synthetic_frame};
+
+fn perceived_lightness(c: &mut Criterion) {
+    let (width, height) = (3840, 2160);
+    let frame = synthetic_frame(width, height, true);
+
+    c.bench_function("compute_perceived_lightness_percent 4K", |b| {
+        b.iter(|| {
+            compute_perceived_lightness_percent(
+                black_box(&frame),
+                true,
+                width * height,
+                Lightness::Hsp,
+            )
+        })
+    });
+}
+
+criterion_group!(benches, perceived_lightness);
+criterion_main!(benches);
@@ -0,0 +1,23 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::hint::black_box;
+use wluma::predictor::{Controller, Entry};
+
+struct Interpolator;
+
+impl Controller for Interpolator {
+    fn adjust(&mut self, _luma: u8) {}
+}
+
+fn interpolate(c: &mut Criterion) {
+    let profiles = ["dark", "dim", "normal", "bright"];
+    let entries = (0..400)
+        .map(|i| Entry::new(profiles[i % profiles.len()], (i * 2 % 100) as u8, i as u64))
+        .collect::<Vec<_>>();
+
+    c.bench_function("interpolate 400 entries", |b| {
+        b.iter(|| Interpolator.interpolate(black_box(&entries), "normal", black_box(41)))
+    });
+}
+
+criterion_group!(benches, interpolate);
+criterion_main!(benches);
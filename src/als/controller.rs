@@ -1,18 +1,28 @@
 use super::Als;
+use crate::config::AlsPolling;
+use crate::record::{self, Event};
 use std::sync::mpsc::Sender;
-use std::thread;
 use std::time::Duration;
 
-const WAITING_SLEEP_MS: u64 = 100;
-
 pub struct Controller {
     als: Box<dyn Als>,
     value_txs: Vec<Sender<String>>,
+    last_value: Option<String>,
+    /// Readings in a row that kept the profile, the ALS is read less often the more there are.
+    unchanged: u32,
+    /// The ALS can't be read, predictions keep using the last known profile meanwhile.
+    degraded: bool,
 }
 
 impl Controller {
     pub fn new(als: Box<dyn Als>, value_txs: Vec<Sender<String>>) -> Self {
-        Self { als, value_txs }
+        Self {
+            als,
+            value_txs,
+            last_value: None,
+            unchanged: 0,
+            degraded: false,
+        }
     }
 
     pub fn run(&mut self) {
@@ -24,14 +34,76 @@ impl Controller {
     fn step(&mut self) {
         match self.als.get() {
             Ok(value) => {
+                if std::mem::take(&mut self.degraded) {
+                    log::info!("ALS can be read again, using profile '{}'", value);
+                }
+                if self.last_value.as_ref() != Some(&value) {
+                    record::add(Event::Profile {
+                        profile: value.clone(),
+                    });
+                    self.last_value = Some(value.clone());
+                    self.unchanged = 0;
+                } else {
+                    self.unchanged = self.unchanged.saturating_add(1);
+                }
                 self.value_txs.iter().for_each(|chan| {
                     chan.send(value.clone())
                         .expect("Unable to send new ALS value, channel is dead")
                 });
             }
-            Err(err) => log::error!("Unable to get ALS value: {:?}", err),
+            // Warn once rather than on every reading, until it recovers
+            Err(err) if !self.degraded => {
+                self.degraded = true;
+                self.unchanged = 0;
+                log::warn!(
+                    "Unable to get ALS value, keeping the last known profile {:?} until it recovers: {}",
+                    self.last_value,
+                    err
+                );
+            }
+            Err(err) => {
+                self.unchanged = 0;
+                log::debug!("Unable to get ALS value: {}", err);
+            }
+        };
+
+        self.als.wait(interval(self.als.polling(), self.unchanged));
+    }
+}
+
+/// Doubles the interval for every reading in a row that kept the profile, up to the longest one.
+fn interval(polling: AlsPolling, unchanged: u32) -> Duration {
+    let slowdown = 1_u64.checked_shl(unchanged).unwrap_or(u64::MAX);
+    Duration::from_millis(
+        polling
+            .poll_ms
+            .saturating_mul(slowdown)
+            .min(polling.max_poll_ms.max(polling.poll_ms)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval() {
+        let polling = AlsPolling {
+            poll_ms: 100,
+            max_poll_ms: 1000,
         };
 
-        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+        assert_eq!(Duration::from_millis(100), interval(polling, 0));
+        assert_eq!(Duration::from_millis(200), interval(polling, 1));
+        assert_eq!(Duration::from_millis(800), interval(polling, 3));
+        assert_eq!(Duration::from_millis(1000), interval(polling, 4));
+        assert_eq!(Duration::from_millis(1000), interval(polling, u32::MAX));
+
+        // Without slowing down
+        let polling = AlsPolling {
+            poll_ms: 10_000,
+            max_poll_ms: 10_000,
+        };
+        assert_eq!(Duration::from_secs(10), interval(polling, 5));
     }
 }
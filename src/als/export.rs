@@ -0,0 +1,68 @@
+use crate::error::WlumaError;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+const FILE_NAME: &str = "als.json";
+
+lazy_static! {
+    static ref EXPORTER: Mutex<Option<Exporter>> = Mutex::new(None);
+}
+
+/// What other programs find in the file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct Reading {
+    source: String,
+    raw: u64,
+    /// What the profile is decided by, the same as `raw` without smoothing.
+    smoothed: u64,
+    profile: String,
+}
+
+struct Exporter {
+    path: PathBuf,
+    last: Option<Reading>,
+}
+
+/// Starts writing ALS readings to `$XDG_RUNTIME_DIR/wluma/als.json`, returns its path.
+pub fn enable() -> Result<PathBuf, WlumaError> {
+    let path = xdg::BaseDirectories::with_prefix("wluma")
+        .map_err(WlumaError::als)?
+        .place_runtime_file(FILE_NAME)
+        .map_err(WlumaError::als)?;
+    *EXPORTER.lock().unwrap() = Some(Exporter {
+        path: path.clone(),
+        last: None,
+    });
+    Ok(path)
+}
+
+/// Replaces the file with the reading if it changed, if exporting is enabled.
+pub fn write(source: &str, raw: u64, smoothed: u64, profile: &str) {
+    if let Some(exporter) = EXPORTER.lock().unwrap().as_mut() {
+        let reading = Reading {
+            source: source.to_string(),
+            raw,
+            smoothed,
+            profile: profile.to_string(),
+        };
+        if exporter.last.as_ref() == Some(&reading) {
+            return;
+        }
+        match exporter.replace(&reading) {
+            Ok(()) => exporter.last = Some(reading),
+            Err(err) => log::debug!("Unable to export ALS reading: {}", err),
+        }
+    }
+}
+
+impl Exporter {
+    /// Readers never see a partially written file, as it's renamed into place.
+    fn replace(&self, reading: &Reading) -> std::io::Result<()> {
+        let tmp_path = self.path.with_extension("json.tmp");
+        fs::write(&tmp_path, serde_json::to_vec(reading)?)?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
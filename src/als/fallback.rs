@@ -0,0 +1,233 @@
+use crate::config::AlsPolling;
+use crate::error::WlumaError;
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Source {
+    name: String,
+    als: Box<dyn super::Als>,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Source {
+    fn is_available(&self, now: Instant) -> bool {
+        self.retry_at.is_none_or(|retry_at| now >= retry_at)
+    }
+}
+
+/// Combines several ALS sources ordered by preference.
+///
+/// The first source that can be read is used, so that the next one takes over whenever a source
+/// fails. A source that errors repeatedly is considered unhealthy and is only retried
+/// periodically, so that the preferred source is used again as soon as it recovers.
+pub struct Als {
+    sources: RefCell<Vec<Source>>,
+    active: RefCell<Option<usize>>,
+}
+
+impl Als {
+    pub fn new(sources: Vec<(String, Box<dyn super::Als>)>) -> Self {
+        Self {
+            sources: RefCell::new(
+                sources
+                    .into_iter()
+                    .map(|(name, als)| Source {
+                        name,
+                        als,
+                        failures: 0,
+                        retry_at: None,
+                    })
+                    .collect(),
+            ),
+            active: RefCell::new(None),
+        }
+    }
+
+    fn activate(&self, index: usize, name: &str) {
+        let mut active = self.active.borrow_mut();
+        if *active != Some(index) {
+            match *active {
+                None => log::debug!("Using ALS source '{}'", name),
+                Some(previous) if previous > index => {
+                    log::info!("Preferred ALS source '{}' has recovered", name)
+                }
+                Some(_) => log::warn!("Falling back to ALS source '{}'", name),
+            }
+            *active = Some(index);
+        }
+    }
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<String, WlumaError> {
+        let now = Instant::now();
+        let mut last_err = None;
+
+        for (index, source) in self.sources.borrow_mut().iter_mut().enumerate() {
+            if !source.is_available(now) {
+                continue;
+            }
+
+            match source.als.get() {
+                Ok(profile) => {
+                    source.failures = 0;
+                    source.retry_at = None;
+                    self.activate(index, &source.name);
+                    return Ok(profile);
+                }
+                Err(err) => {
+                    source.failures += 1;
+                    // Not yet considered unhealthy, it's tried first again on the next reading
+                    if source.failures >= MAX_CONSECUTIVE_FAILURES {
+                        if source.retry_at.is_none() {
+                            log::warn!(
+                                "ALS source '{}' failed {} times in a row, last error: {}",
+                                source.name,
+                                source.failures,
+                                err
+                            );
+                        }
+                        source.retry_at = Some(now + RETRY_INTERVAL);
+                    }
+                    last_err = Some(err);
+                }
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| WlumaError::als("No healthy ALS source available")))
+    }
+
+    /// Follows the source in use, the preferred one until any is.
+    fn polling(&self) -> AlsPolling {
+        let active = self.active.borrow().unwrap_or(0);
+        self.sources.borrow()[active].als.polling()
+    }
+
+    fn wait(&self, interval: Duration) {
+        let active = self.active.borrow().unwrap_or(0);
+        self.sources.borrow()[active].als.wait(interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Als as _;
+    use super::*;
+    use std::collections::VecDeque;
+    use std::error::Error;
+
+    struct Scripted {
+        values: RefCell<VecDeque<Result<String, String>>>,
+    }
+
+    impl Scripted {
+        fn boxed(values: Vec<Result<&str, &str>>) -> Box<dyn super::super::Als> {
+            Box::new(Self {
+                values: RefCell::new(
+                    values
+                        .into_iter()
+                        .map(|v| v.map(str::to_string).map_err(str::to_string))
+                        .collect(),
+                ),
+            })
+        }
+    }
+
+    impl super::super::Als for Scripted {
+        fn get(&self) -> Result<String, WlumaError> {
+            self.values
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| Err("exhausted".to_string()))
+                .map_err(WlumaError::als)
+        }
+    }
+
+    #[test]
+    fn test_get_uses_preferred_source_when_healthy() -> Result<(), Box<dyn Error>> {
+        let als = Als::new(vec![
+            ("iio".to_string(), Scripted::boxed(vec![Ok("dim")])),
+            ("time".to_string(), Scripted::boxed(vec![Ok("night")])),
+        ]);
+
+        assert_eq!("dim", als.get()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_falls_back_on_every_failure() -> Result<(), Box<dyn Error>> {
+        let als = Als::new(vec![
+            (
+                "iio".to_string(),
+                Scripted::boxed(vec![Err("busy"), Ok("dim")]),
+            ),
+            ("time".to_string(), Scripted::boxed(vec![Ok("night")])),
+        ]);
+
+        assert_eq!("night", als.get()?);
+        // A single failure doesn't keep the preferred source from being tried first
+        assert_eq!("dim", als.get()?);
+        assert_eq!(Some(0), *als.active.borrow());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_skips_source_after_repeated_failures() -> Result<(), Box<dyn Error>> {
+        let mut preferred = vec![Err("unplugged"); MAX_CONSECUTIVE_FAILURES as usize];
+        preferred.push(Ok("bright"));
+        let als = Als::new(vec![
+            ("iio".to_string(), Scripted::boxed(preferred)),
+            (
+                "time".to_string(),
+                Scripted::boxed(vec![Ok("night"); MAX_CONSECUTIVE_FAILURES as usize + 1]),
+            ),
+        ]);
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            assert_eq!("night", als.get()?);
+        }
+
+        // Unhealthy source is not retried before the retry interval passes
+        assert_eq!("night", als.get()?);
+        assert_eq!(Some(1), *als.active.borrow());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_recovers_preferred_source() -> Result<(), Box<dyn Error>> {
+        let mut preferred = vec![Err("unplugged"); MAX_CONSECUTIVE_FAILURES as usize];
+        preferred.push(Ok("bright"));
+
+        let als = Als::new(vec![
+            ("iio".to_string(), Scripted::boxed(preferred)),
+            (
+                "time".to_string(),
+                Scripted::boxed(vec![Ok("night"); MAX_CONSECUTIVE_FAILURES as usize]),
+            ),
+        ]);
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            assert_eq!("night", als.get()?);
+        }
+
+        // Pretend that the retry interval has passed
+        als.sources.borrow_mut()[0].retry_at = Some(Instant::now());
+
+        assert_eq!("bright", als.get()?);
+        assert_eq!(Some(0), *als.active.borrow());
+        Ok(())
+    }
+
+    #[test]
+    fn test_get_fails_when_all_sources_are_unhealthy() {
+        let als = Als::new(vec![("iio".to_string(), Scripted::boxed(vec![]))]);
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES * 2 {
+            assert!(als.get().is_err());
+        }
+    }
+}
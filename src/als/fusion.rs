@@ -0,0 +1,104 @@
+use super::{iio, webcam, Profiles};
+use crate::config::FusionMode;
+use crate::error::WlumaError;
+
+/// Combines readings of an IIO sensor and a webcam into one, e.g. when the sensor is easily
+/// shadowed and the webcam has a better view but is noisy.
+pub struct Als {
+    iio: iio::Als,
+    webcam: webcam::Als,
+    mode: FusionMode,
+    /// Webcam readings are multiplied by this to be comparable to the lux of the sensor.
+    webcam_scale: f64,
+    profiles: Profiles,
+}
+
+impl Als {
+    pub fn new(
+        iio: iio::Als,
+        webcam: webcam::Als,
+        mode: FusionMode,
+        webcam_scale: f64,
+        profiles: Profiles,
+    ) -> Self {
+        Self {
+            iio,
+            webcam,
+            mode,
+            webcam_scale,
+            profiles,
+        }
+    }
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<String, WlumaError> {
+        let webcam = self
+            .webcam
+            .get_lux()
+            .map(|lux| (lux as f64 * self.webcam_scale).round() as u64);
+        let raw = match (self.iio.get_raw(), webcam) {
+            (Ok(iio), Ok(webcam)) => {
+                let fused = fuse(iio, webcam, self.mode);
+                log::trace!(
+                    "ALS (fusion): iio {}, webcam {}, fused {}",
+                    iio,
+                    webcam,
+                    fused
+                );
+                fused
+            }
+            // The webcam still sees the light when the sensor can't be read
+            (Err(err), Ok(webcam)) => {
+                log::trace!("ALS (fusion): using only webcam {}: {}", webcam, err);
+                webcam
+            }
+            // ... and the sensor when the webcam stopped capturing
+            (Ok(iio), Err(err)) => {
+                log::trace!("ALS (fusion): using only iio {}: {}", iio, err);
+                iio
+            }
+            (Err(err), Err(_)) => return Err(WlumaError::als(err)),
+        };
+        Ok(self.profiles.find("fusion", raw))
+    }
+}
+
+fn fuse(iio: u64, webcam: u64, mode: FusionMode) -> u64 {
+    match mode {
+        FusionMode::Max => iio.max(webcam),
+        FusionMode::Average { webcam_weight } => {
+            ((1.0 - webcam_weight) * iio as f64 + webcam_weight * webcam as f64).round() as u64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fuse() {
+        // A shadowed sensor doesn't make it darker than the webcam sees
+        assert_eq!(300, fuse(20, 300, FusionMode::Max));
+        assert_eq!(300, fuse(300, 20, FusionMode::Max));
+        assert_eq!(
+            160,
+            fuse(20, 300, FusionMode::Average { webcam_weight: 0.5 })
+        );
+        assert_eq!(
+            20,
+            fuse(20, 300, FusionMode::Average { webcam_weight: 0.0 })
+        );
+        assert_eq!(
+            230,
+            fuse(
+                20,
+                300,
+                FusionMode::Average {
+                    webcam_weight: 0.75
+                }
+            )
+        );
+    }
+}
@@ -0,0 +1,104 @@
+use crate::brightness::BASIS_POINTS;
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Luma assumed for outputs that are not captured.
+const DEFAULT_LUMA: u8 = 50;
+
+lazy_static! {
+    static ref SCREENS: Mutex<HashMap<String, Screen>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Screen {
+    /// In basis points.
+    brightness: Option<u64>,
+    luma: Option<u8>,
+}
+
+/// Current brightness of the output, in basis points.
+pub fn set_brightness(output_name: &str, brightness: u64) {
+    let mut screens = SCREENS.lock().unwrap();
+    screens
+        .entry(output_name.to_string())
+        .or_default()
+        .brightness = Some(brightness);
+}
+
+/// Luma of the last frame of the output.
+pub fn set_luma(output_name: &str, luma: u8) {
+    let mut screens = SCREENS.lock().unwrap();
+    screens.entry(output_name.to_string()).or_default().luma = Some(luma);
+}
+
+/// The output is no longer captured, its luma is unknown.
+pub fn forget_luma(output_name: &str) {
+    let mut screens = SCREENS.lock().unwrap();
+    screens.entry(output_name.to_string()).or_default().luma = None;
+}
+
+/// Subtracts the light that the screens are estimated to add to the reading, where `glow` is
+/// what the brightest screen adds at full brightness showing a white picture. Otherwise the
+/// screen lighting up a dark room makes readings drop along with brightness, which lowers
+/// brightness further.
+pub fn compensate(raw: u64, glow: u64) -> u64 {
+    if glow == 0 {
+        return raw;
+    }
+    let screens = SCREENS.lock().unwrap();
+    raw.saturating_sub(contribution(glow, screens.values()))
+}
+
+fn contribution<'a>(glow: u64, screens: impl Iterator<Item = &'a Screen>) -> u64 {
+    let fraction = screens
+        .filter_map(|screen| {
+            let brightness = screen.brightness? as f64 / BASIS_POINTS as f64;
+            let luma = screen.luma.unwrap_or(DEFAULT_LUMA) as f64 / 100.0;
+            Some(brightness * luma)
+        })
+        .fold(0.0, f64::max);
+    (glow as f64 * fraction).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contribution() {
+        let screen = |brightness, luma| Screen {
+            brightness: Some(brightness),
+            luma,
+        };
+
+        assert_eq!(0, contribution(20, [].iter()));
+        assert_eq!(
+            20,
+            contribution(20, [screen(BASIS_POINTS, Some(100))].iter())
+        );
+        assert_eq!(
+            5,
+            contribution(20, [screen(BASIS_POINTS / 2, Some(50))].iter())
+        );
+        // The brightest screen counts, not captured ones are assumed to be average
+        assert_eq!(
+            10,
+            contribution(
+                20,
+                [
+                    screen(BASIS_POINTS, None),
+                    screen(BASIS_POINTS / 2, Some(80))
+                ]
+                .iter()
+            )
+        );
+        // Brightness is not known yet
+        assert_eq!(0, contribution(20, [Screen::default()].iter()));
+    }
+
+    #[test]
+    fn test_compensate_disabled() {
+        assert_eq!(42, compensate(42, 0));
+    }
+}
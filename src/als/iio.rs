@@ -1,15 +1,31 @@
+use super::Profiles;
 use crate::device_file::read;
-use std::collections::HashMap;
-use std::error::Error;
+use crate::error::WlumaError;
 use std::fs;
 use std::fs::File;
+use std::io::{self, Read};
+use std::os::fd::{AsRawFd, FromRawFd};
 use std::path::{Path, PathBuf};
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 use SensorType::*;
 
+/// How often to look for a device that went away, e.g. an unplugged USB sensor.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest wait for a threshold event, so that a missed one doesn't keep the profile for long.
+const EVENT_TIMEOUT: Duration = Duration::from_secs(60);
+/// `_IOR('i', 0x90, int)` of `linux/iio/events.h`, hands out the queue of events of a device.
+const IIO_GET_EVENT_FD_IOCTL: u32 = 0x80046990;
+/// Size of `struct iio_event_data`.
+const EVENT_SIZE: usize = 16;
+
 enum SensorType {
     Illuminance {
         value: Mutex<File>,
+        /// Channel of the attributes, e.g. `in_illuminance`, its threshold events are in the
+        /// same units.
+        channel: &'static str,
         scale: f64,
         offset: f64,
     },
@@ -20,75 +36,336 @@ enum SensorType {
     },
 }
 
+/// Whether the device tells when readings leave the band of the current profile.
+enum Events {
+    /// Readings are polled.
+    Off,
+    /// Set up once the device is read.
+    Pending,
+    Armed(Thresholds),
+}
+
+/// Threshold events of a device, configured in its `events` directory.
+struct Thresholds {
+    /// Queue of events of the device, shared with the waits for them.
+    queue: Arc<File>,
+    dir: PathBuf,
+    /// Channel of the attributes, e.g. `in_illuminance`.
+    channel: &'static str,
+    /// Some devices enable both directions at once.
+    either: bool,
+    /// Converts lux to the raw units of the thresholds.
+    scale: f64,
+    offset: f64,
+    band: Option<(Option<u64>, Option<u64>)>,
+}
+
 pub struct Als {
-    sensor: SensorType,
-    thresholds: HashMap<u64, String>,
+    base_path: String,
+    /// None while the device is gone, until it's found again.
+    sensor: Mutex<Option<(PathBuf, SensorType)>>,
+    /// When to look for the device again, after it wasn't found.
+    retry_at: Mutex<Option<Instant>>,
+    events: Mutex<Events>,
+    profiles: Profiles,
 }
 
 impl Als {
-    pub fn new(base_path: &str, thresholds: HashMap<u64, String>) -> Result<Self, Box<dyn Error>> {
-        Path::new(base_path)
-            .read_dir()
-            .ok()
-            .and_then(|dir| {
-                dir.filter_map(|e| e.ok())
-                    .find(|e| {
-                        ["als", "acpi-als"].contains(
-                            &fs::read_to_string(e.path().join("name"))
-                                .unwrap_or_default()
-                                .trim(),
-                        )
-                    })
-                    .and_then(|e| {
-                        // TODO should probably start from the `parse_illuminance_input` in the next major version
-                        parse_illuminance_raw(e.path())
-                            .or_else(|_| parse_illuminance_input(e.path()))
-                            .or_else(|_| parse_intensity_raw(e.path()))
-                            .or_else(|_| parse_intensity_rgb(e.path()))
-                            .ok()
-                    })
+    pub fn new(base_path: &str, profiles: Profiles, events: bool) -> Result<Self, WlumaError> {
+        discover(base_path)
+            .map(|sensor| Self {
+                base_path: base_path.to_string(),
+                sensor: Mutex::new(Some(sensor)),
+                retry_at: Mutex::new(None),
+                events: Mutex::new(match events {
+                    true => Events::Pending,
+                    false => Events::Off,
+                }),
+                profiles,
             })
-            .map(|sensor| Self { sensor, thresholds })
-            .ok_or_else(|| "No iio device found".into())
-    }
-
-    fn get_raw(&self) -> Result<u64, Box<dyn Error>> {
-        Ok(match self.sensor {
-            Illuminance {
-                ref value,
-                scale,
-                offset,
-            } => (read(&mut value.lock().unwrap())? + offset) * scale,
-
-            Intensity {
-                ref r,
-                ref g,
-                ref b,
-            } => {
-                -0.32466 * read(&mut r.lock().unwrap())?
-                    + 1.57837 * read(&mut g.lock().unwrap())?
-                    + -0.73191 * read(&mut b.lock().unwrap())?
+            .ok_or_else(|| WlumaError::als("No iio device found"))
+    }
+
+    /// Reading in lux, before it's mapped to a profile.
+    ///
+    /// A device that can't be read is looked for again, as it might have been unbound and come
+    /// back under another name, e.g. after a suspend.
+    pub fn get_raw(&self) -> io::Result<u64> {
+        let mut sensor = self.sensor.lock().unwrap();
+        if sensor.is_none() {
+            *sensor = Some(self.reconnect()?);
+        }
+        read_sensor(&sensor.as_ref().unwrap().1).inspect_err(|err| {
+            log::warn!(
+                "Unable to read iio device in {}, looking for it again: {}",
+                self.base_path,
+                err
+            );
+            *sensor = None;
+        })
+    }
+
+    fn reconnect(&self) -> io::Result<(PathBuf, SensorType)> {
+        let mut retry_at = self.retry_at.lock().unwrap();
+        if retry_at.is_some_and(|retry_at| Instant::now() < retry_at) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "iio device is gone",
+            ));
+        }
+        match discover(&self.base_path) {
+            Some(sensor) => {
+                log::info!("Found iio device in {} again", self.base_path);
+                *retry_at = None;
+                Ok(sensor)
             }
-        } as u64)
+            None => {
+                *retry_at = Some(Instant::now() + RECONNECT_INTERVAL);
+                Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "iio device is gone, still looking for it",
+                ))
+            }
+        }
+    }
+
+    /// Moves the thresholds of the device to the band of the current profile, setting them up
+    /// first if they aren't yet.
+    fn follow_band(&self) {
+        let mut events = self.events.lock().unwrap();
+        if let Events::Pending = *events {
+            let thresholds = match self.sensor.lock().unwrap().as_ref() {
+                Some((
+                    device,
+                    Illuminance {
+                        channel,
+                        scale,
+                        offset,
+                        ..
+                    },
+                )) => Thresholds::open(device, channel, *scale, *offset),
+                Some(_) => Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "only illuminance sensors have threshold events",
+                )),
+                None => return,
+            };
+            *events = match thresholds {
+                Ok(thresholds) => Events::Armed(thresholds),
+                Err(err) => {
+                    log::warn!(
+                        "Unable to use threshold events of the iio device in {}, polling it instead: {}",
+                        self.base_path,
+                        err
+                    );
+                    Events::Off
+                }
+            };
+        }
+
+        if let Events::Armed(thresholds) = &mut *events {
+            if let Err(err) = thresholds.follow(self.profiles.band()) {
+                log::warn!(
+                    "Unable to set thresholds of the iio device in {}, polling it instead: {}",
+                    self.base_path,
+                    err
+                );
+                *events = Events::Off;
+            }
+        }
     }
 }
 
-impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
-        let raw = self.get_raw()?;
-        let profile = super::find_profile(raw, &self.thresholds);
+impl Thresholds {
+    /// Only the channel that is read is followed, as thresholds are in its raw units.
+    fn open(device: &Path, channel: &'static str, scale: f64, offset: f64) -> io::Result<Self> {
+        let dir = device.join("events");
+        if !dir
+            .join(format!("{}_thresh_rising_value", channel))
+            .exists()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("the device has no threshold events for {}", channel),
+            ));
+        }
+        let either = dir.join(format!("{}_thresh_either_en", channel)).exists();
+
+        // Events come through the character device, e.g. /dev/iio:device0
+        let name = device.file_name().unwrap_or_default();
+        let device = File::open(Path::new("/dev").join(name))?;
+        let mut queue: libc::c_int = -1;
+        if unsafe { libc::ioctl(device.as_raw_fd(), IIO_GET_EVENT_FD_IOCTL as _, &mut queue) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            queue: Arc::new(unsafe { File::from_raw_fd(queue) }),
+            dir,
+            channel,
+            either,
+            scale,
+            offset,
+            band: None,
+        })
+    }
 
-        log::trace!("ALS (iio): {} ({})", profile, raw);
+    fn attribute(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}_thresh_{}", self.channel, name))
+    }
+
+    /// Lets the device tell when readings leave the band, readings never fall below 0 and a
+    /// band without an upper bound only has the falling threshold. Devices that enable both
+    /// thresholds at once get the highest rising one instead, as they would keep raising events
+    /// for the upper bound of the previous band otherwise.
+    fn follow(&mut self, band: (Option<u64>, Option<u64>)) -> io::Result<()> {
+        if self.band == Some(band) {
+            return Ok(());
+        }
+        let (lower, upper) = band;
+        let raw = |lux: u64| raw_threshold(lux, self.scale, self.offset).to_string();
+
+        fs::write(self.attribute("falling_value"), raw(lower.unwrap_or(0)))?;
+        match upper {
+            Some(upper) => fs::write(self.attribute("rising_value"), raw(upper))?,
+            None if self.either => fs::write(self.attribute("rising_value"), u32::MAX.to_string())?,
+            None => {}
+        }
+        if self.either {
+            fs::write(self.attribute("either_en"), "1")?;
+        } else {
+            fs::write(self.attribute("falling_en"), "1")?;
+            let rising = if upper.is_some() { "1" } else { "0" };
+            fs::write(self.attribute("rising_en"), rising)?;
+        }
+
+        log::debug!("Waiting for iio readings outside of {:?} lux", band);
+        self.band = Some(band);
+        Ok(())
+    }
+}
+
+/// Events that came to the queue before the timeout. Only the reading after them matters, so
+/// the queue is emptied.
+fn wait_for_events(queue: &File, timeout: Duration) -> io::Result<usize> {
+    let mut pollfd = libc::pollfd {
+        fd: queue.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) } {
+        ..0 => Err(io::Error::last_os_error()),
+        0 => Ok(0),
+        _ => {
+            let mut events = [0; EVENT_SIZE * 16];
+            Ok((&*queue).read(&mut events)? / EVENT_SIZE)
+        }
+    }
+}
+
+impl Drop for Thresholds {
+    /// The device would keep raising events that nobody reads otherwise.
+    fn drop(&mut self) {
+        let disabled = ["either_en", "falling_en", "rising_en"]
+            .into_iter()
+            .filter(|name| self.either == (*name == "either_en"))
+            .try_for_each(|name| fs::write(self.attribute(name), "0"));
+        if let Err(err) = disabled {
+            log::debug!("Unable to disable threshold events of iio device: {}", err);
+        }
+    }
+}
+
+/// Raw value of the device for the given lux, the opposite of how readings are converted.
+fn raw_threshold(lux: u64, scale: f64, offset: f64) -> u64 {
+    (lux as f64 / scale - offset).round().max(0.0) as u64
+}
+
+fn discover(base_path: &str) -> Option<(PathBuf, SensorType)> {
+    Path::new(base_path).read_dir().ok().and_then(|dir| {
+        dir.filter_map(|e| e.ok())
+            .find(|e| {
+                ["als", "acpi-als"].contains(
+                    &fs::read_to_string(e.path().join("name"))
+                        .unwrap_or_default()
+                        .trim(),
+                )
+            })
+            .and_then(|e| {
+                // TODO should probably start from the `parse_illuminance_input` in the next major version
+                parse_illuminance_raw(e.path())
+                    .or_else(|_| parse_illuminance_input(e.path()))
+                    .or_else(|_| parse_intensity_raw(e.path()))
+                    .or_else(|_| parse_intensity_rgb(e.path()))
+                    .ok()
+                    .map(|sensor| (e.path(), sensor))
+            })
+    })
+}
+
+fn read_sensor(sensor: &SensorType) -> io::Result<u64> {
+    Ok(match *sensor {
+        Illuminance {
+            ref value,
+            scale,
+            offset,
+            ..
+        } => (read(&mut value.lock().unwrap())? + offset) * scale,
+
+        Intensity {
+            ref r,
+            ref g,
+            ref b,
+        } => {
+            -0.32466 * read(&mut r.lock().unwrap())?
+                + 1.57837 * read(&mut g.lock().unwrap())?
+                + -0.73191 * read(&mut b.lock().unwrap())?
+        }
+    } as u64)
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<String, WlumaError> {
+        let raw = self.get_raw().map_err(|err| {
+            // A device that comes back might be another one, its events are set up again
+            let mut events = self.events.lock().unwrap();
+            if let Events::Armed(_) = *events {
+                *events = Events::Pending;
+            }
+            WlumaError::als(err)
+        })?;
+        let profile = self.profiles.find("iio", raw);
+        self.follow_band();
         Ok(profile)
     }
+
+    fn wait(&self, interval: Duration) {
+        // Not locked while waiting, so that readings and their bands don't wait for the events
+        let queue = match &*self.events.lock().unwrap() {
+            Events::Armed(thresholds) => Some(thresholds.queue.clone()),
+            _ => None,
+        };
+        if let Some(queue) = queue {
+            match wait_for_events(&queue, EVENT_TIMEOUT) {
+                Ok(0) => {}
+                Ok(events) => log::trace!(
+                    "iio device in {} crossed a threshold ({} events)",
+                    self.base_path,
+                    events
+                ),
+                Err(err) => log::debug!("Unable to wait for iio events: {}", err),
+            }
+        }
+        // Also lets a short flicker pass before readings after an event
+        thread::sleep(interval);
+    }
 }
 
-fn parse_illuminance_raw(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
+fn parse_illuminance_raw(path: PathBuf) -> io::Result<SensorType> {
+    let (channel, value) = open_channel(&path, "raw")?;
     Ok(Illuminance {
-        value: Mutex::new(
-            open_file(&path, "in_illuminance_raw")
-                .or_else(|_| open_file(&path, "in_illuminance0_raw"))?,
-        ),
+        value: Mutex::new(value),
+        channel,
         scale: open_file(&path, "in_illuminance_scale")
             .or_else(|_| open_file(&path, "in_illuminance0_scale"))
             .and_then(|mut f| read(&mut f))
@@ -100,9 +377,10 @@ fn parse_illuminance_raw(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
     })
 }
 
-fn parse_intensity_raw(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
+fn parse_intensity_raw(path: PathBuf) -> io::Result<SensorType> {
     Ok(Illuminance {
         value: Mutex::new(open_file(&path, "in_intensity_both_raw")?),
+        channel: "in_intensity_both",
         scale: open_file(&path, "in_intensity_scale")
             .and_then(|mut f| read(&mut f))
             .unwrap_or(1_f64),
@@ -112,18 +390,17 @@ fn parse_intensity_raw(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
     })
 }
 
-fn parse_illuminance_input(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
+fn parse_illuminance_input(path: PathBuf) -> io::Result<SensorType> {
+    let (channel, value) = open_channel(&path, "input")?;
     Ok(Illuminance {
-        value: Mutex::new(
-            open_file(&path, "in_illuminance_input")
-                .or_else(|_| open_file(&path, "in_illuminance0_input"))?,
-        ),
+        value: Mutex::new(value),
+        channel,
         scale: 1_f64,
         offset: 0_f64,
     })
 }
 
-fn parse_intensity_rgb(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
+fn parse_intensity_rgb(path: PathBuf) -> io::Result<SensorType> {
     Ok(Intensity {
         r: Mutex::new(open_file(&path, "in_intensity_red_raw")?),
         g: Mutex::new(open_file(&path, "in_intensity_green_raw")?),
@@ -131,6 +408,144 @@ fn parse_intensity_rgb(path: PathBuf) -> Result<SensorType, Box<dyn Error>> {
     })
 }
 
-fn open_file(path: &Path, name: &str) -> Result<File, Box<dyn Error>> {
-    File::open(path.join(name)).map_err(Box::<dyn Error>::from)
+fn open_file(path: &Path, name: &str) -> io::Result<File> {
+    File::open(path.join(name))
+}
+
+/// Opens the given attribute of the illuminance channel, which some devices number.
+fn open_channel(path: &Path, attribute: &str) -> io::Result<(&'static str, File)> {
+    open_file(path, &format!("in_illuminance_{}", attribute))
+        .map(|file| ("in_illuminance", file))
+        .or_else(|_| {
+            open_file(path, &format!("in_illuminance0_{}", attribute))
+                .map(|file| ("in_illuminance0", file))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_reconnects_to_device_that_came_back() {
+        let base_path = std::env::temp_dir().join(format!("wluma-test-iio-{}", std::process::id()));
+        let device = |name: &str, value: &str| {
+            let path = base_path.join(name);
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("name"), "als").unwrap();
+            fs::write(path.join("in_illuminance_raw"), value).unwrap();
+            path
+        };
+        let first = device("iio:device0", "42");
+        let als = Als::new(
+            base_path.to_str().unwrap(),
+            Profiles::new(
+                HashMap::from([(0, "dim".to_string())]),
+                0,
+                1.0,
+                Duration::ZERO,
+            ),
+            false,
+        )
+        .unwrap();
+        assert_eq!(42, als.get_raw().unwrap());
+
+        // The device stops answering, and comes back under another name
+        fs::write(first.join("in_illuminance_raw"), "").unwrap();
+        assert!(als.get_raw().is_err());
+        fs::remove_dir_all(first).unwrap();
+        device("iio:device1", "84");
+        assert_eq!(84, als.get_raw().unwrap());
+
+        fs::remove_dir_all(base_path).unwrap();
+    }
+
+    #[test]
+    fn test_thresholds_follow_band() {
+        let dir =
+            std::env::temp_dir().join(format!("wluma-test-iio-events-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        let mut thresholds = Thresholds {
+            queue: Arc::new(File::open("/dev/null").unwrap()),
+            dir: dir.clone(),
+            channel: "in_illuminance",
+            either: false,
+            scale: 0.5,
+            offset: 10.0,
+            band: None,
+        };
+
+        thresholds.follow((Some(100), Some(300))).unwrap();
+        assert_eq!("190", read("in_illuminance_thresh_falling_value"));
+        assert_eq!("590", read("in_illuminance_thresh_rising_value"));
+        assert_eq!("1", read("in_illuminance_thresh_rising_en"));
+
+        // The brightest profile has nothing to rise to
+        thresholds.follow((Some(300), None)).unwrap();
+        assert_eq!("590", read("in_illuminance_thresh_falling_value"));
+        assert_eq!("0", read("in_illuminance_thresh_rising_en"));
+
+        drop(thresholds);
+        assert_eq!("0", read("in_illuminance_thresh_falling_en"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_thresholds_follow_band_with_both_enabled_at_once() {
+        let dir = std::env::temp_dir().join(format!(
+            "wluma-test-iio-events-either-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        let mut thresholds = Thresholds {
+            queue: Arc::new(File::open("/dev/null").unwrap()),
+            dir: dir.clone(),
+            channel: "in_illuminance",
+            either: true,
+            scale: 1.0,
+            offset: 0.0,
+            band: None,
+        };
+
+        thresholds.follow((Some(100), Some(300))).unwrap();
+        assert_eq!("300", read("in_illuminance_thresh_rising_value"));
+        assert_eq!("1", read("in_illuminance_thresh_either_en"));
+
+        // Readings above the previous upper bound must not keep raising events
+        thresholds.follow((Some(300), None)).unwrap();
+        assert_eq!("300", read("in_illuminance_thresh_falling_value"));
+        assert_eq!(
+            u32::MAX.to_string(),
+            read("in_illuminance_thresh_rising_value")
+        );
+
+        drop(thresholds);
+        assert_eq!("0", read("in_illuminance_thresh_either_en"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_thresholds_only_follow_the_channel_read() {
+        let device =
+            std::env::temp_dir().join(format!("wluma-test-iio-channel-{}", std::process::id()));
+        fs::create_dir_all(device.join("events")).unwrap();
+        fs::write(
+            device.join("events/in_intensity_both_thresh_rising_value"),
+            "0",
+        )
+        .unwrap();
+
+        let thresholds = Thresholds::open(&device, "in_illuminance", 0.5, 0.0);
+        assert!(thresholds.is_err_and(|err| err.kind() == io::ErrorKind::Unsupported));
+        fs::remove_dir_all(device).unwrap();
+    }
+
+    #[test]
+    fn test_raw_threshold() {
+        assert_eq!(250, raw_threshold(25, 0.1, 0.0));
+        assert_eq!(0, raw_threshold(0, 1.0, 5.0));
+    }
 }
@@ -1,15 +1,142 @@
+use crate::config::AlsPolling;
+use crate::control::{self, Update};
+use crate::error::WlumaError;
+use crate::record::{self, Event};
 use itertools::Itertools;
 use std::collections::HashMap;
-use std::error::Error;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub mod controller;
+pub mod export;
+pub mod fallback;
+pub mod fusion;
+pub mod glow;
 pub mod iio;
 pub mod none;
+pub mod paced;
 pub mod time;
 pub mod webcam;
 
+/// How often sources without polling settings of their own are read, e.g. `none`.
+const DEFAULT_POLLING: AlsPolling = AlsPolling {
+    poll_ms: 100,
+    max_poll_ms: 1000,
+};
+
 pub trait Als {
-    fn get(&self) -> Result<String, Box<dyn Error>>;
+    fn get(&self) -> Result<String, WlumaError>;
+
+    fn polling(&self) -> AlsPolling {
+        DEFAULT_POLLING
+    }
+
+    /// Waits until the source is worth reading again, which is after `interval` unless the
+    /// source can tell when its readings change.
+    fn wait(&self, interval: Duration) {
+        thread::sleep(interval);
+    }
+}
+
+/// Lets D-Bus, the recording and the exported file know about a reading of the source, and the
+/// smoothed one that decided the profile.
+fn report(source: &str, raw: u64, smoothed: u64, profile: &str) {
+    log::trace!("ALS ({}): {} ({})", source, profile, raw);
+    control::report(Update::AlsValue(raw));
+    control::report(Update::AlsSmoothedValue(smoothed));
+    export::write(source, raw, smoothed, profile);
+    record::add(Event::Lux {
+        source: source.to_string(),
+        raw,
+        profile: profile.to_string(),
+    });
+}
+
+/// Maps readings of a sensor to profiles, smoothing out noise and flapping between them.
+pub struct Profiles {
+    thresholds: HashMap<u64, String>,
+    hysteresis: u8,
+    /// Weight of a reading that comes `poll_interval` after the previous one.
+    smoothing: f64,
+    poll_interval: Duration,
+    /// Smoothed reading with the time it was read at, and the profile it was mapped to.
+    last: Mutex<(Option<(f64, Instant)>, String)>,
+}
+
+impl Profiles {
+    pub fn new(
+        thresholds: HashMap<u64, String>,
+        hysteresis: u8,
+        smoothing: f64,
+        poll_interval: Duration,
+    ) -> Self {
+        Self {
+            thresholds,
+            hysteresis,
+            smoothing,
+            poll_interval,
+            last: Mutex::new((None, String::new())),
+        }
+    }
+
+    fn find(&self, source: &str, raw: u64) -> String {
+        self.find_at(source, raw, Instant::now())
+    }
+
+    fn find_at(&self, source: &str, raw: u64, now: Instant) -> String {
+        let mut last = self.last.lock().unwrap();
+        let smoothed = smooth(last.0, raw, now, self.smoothing, self.poll_interval);
+        if self.smoothing < 1.0 {
+            log::trace!("ALS ({}): {} smoothed to {:.1}", source, raw, smoothed);
+        }
+        let profile = find_profile_with_hysteresis(
+            smoothed.round() as u64,
+            &self.thresholds,
+            &last.1,
+            self.hysteresis,
+        );
+        *last = (Some((smoothed, now)), profile.clone());
+
+        report(source, raw, smoothed.round() as u64, &profile);
+        profile
+    }
+
+    /// Readings that keep the current profile, from the lowest one up to the first one that
+    /// doesn't, `None` for bounds that readings can't get past.
+    fn band(&self) -> (Option<u64>, Option<u64>) {
+        let last = self.last.lock().unwrap();
+        bands(&self.thresholds, self.hysteresis)
+            .into_iter()
+            .find(|(_, _, profile)| **profile == last.1)
+            .map_or((None, None), |(lower, upper, _)| {
+                (
+                    (lower > 0).then_some(lower),
+                    (upper < u64::MAX).then_some(upper),
+                )
+            })
+    }
+}
+
+/// Exponential moving average, where `smoothing` is the weight of a reading that comes `interval`
+/// after the previous one. Readings that come later weigh more, as if the ones in between were
+/// missed, so that the average settles just as fast when polling slows down.
+fn smooth(
+    previous: Option<(f64, Instant)>,
+    raw: u64,
+    now: Instant,
+    smoothing: f64,
+    interval: Duration,
+) -> f64 {
+    match previous {
+        Some((previous, at)) if smoothing < 1.0 => {
+            let intervals =
+                now.saturating_duration_since(at).as_secs_f64() / interval.as_secs_f64();
+            let weight = 1.0 - (1.0 - smoothing).powf(intervals);
+            weight * raw as f64 + (1.0 - weight) * previous
+        }
+        _ => raw as f64,
+    }
 }
 
 fn find_profile(raw: u64, thresholds: &HashMap<u64, String>) -> String {
@@ -22,6 +149,48 @@ fn find_profile(raw: u64, thresholds: &HashMap<u64, String>) -> String {
         .unwrap_or_else(|| panic!("Unable to find ALS profile for value '{}'", raw))
 }
 
+/// Keeps the previous profile until the reading gets `hysteresis` percent past the thresholds
+/// around it, so that a reading hovering at a threshold doesn't flip profiles back and forth.
+fn find_profile_with_hysteresis(
+    raw: u64,
+    thresholds: &HashMap<u64, String>,
+    previous: &str,
+    hysteresis: u8,
+) -> String {
+    let keep = bands(thresholds, hysteresis)
+        .into_iter()
+        .any(|(lower, upper, profile)| profile == previous && (lower..upper).contains(&raw));
+
+    match keep {
+        true => previous.to_string(),
+        false => find_profile(raw, thresholds),
+    }
+}
+
+/// Readings that keep each profile once it's in use, `hysteresis` percent past its thresholds.
+fn bands(thresholds: &HashMap<u64, String>, hysteresis: u8) -> Vec<(u64, u64, &String)> {
+    let hysteresis = hysteresis as u64;
+    let sorted = thresholds
+        .iter()
+        .sorted_by_key(|(lux, _)| *lux)
+        .collect_vec();
+    sorted
+        .iter()
+        .enumerate()
+        .map(|(i, (lux, profile))| {
+            // Readings below the first threshold belong to the first profile
+            let lower = match i {
+                0 => 0,
+                _ => **lux * (100 - hysteresis) / 100,
+            };
+            let upper = sorted.get(i + 1).map_or(u64::MAX, |(next, _)| {
+                next.saturating_mul(100 + hysteresis) / 100
+            });
+            (lower, upper, *profile)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -65,6 +234,119 @@ mod tests {
         assert_eq!("dark", find_profile(9, &thresholds));
     }
 
+    #[test]
+    fn test_find_profile_with_hysteresis_keeps_previous_profile_around_threshold() {
+        let thresholds = vec![(0, "dark"), (100, "dim"), (200, "bright")]
+            .into_iter()
+            .map(|(lux, profile)| (lux, profile.to_string()))
+            .collect();
+        let find = |raw, previous| find_profile_with_hysteresis(raw, &thresholds, previous, 10);
+
+        assert_eq!("dark", find(105, "dark"));
+        assert_eq!("dim", find(110, "dark"));
+        assert_eq!("dim", find(95, "dim"));
+        assert_eq!("dark", find(89, "dim"));
+        assert_eq!("dim", find(219, "dim"));
+        assert_eq!("bright", find(220, "dim"));
+        assert_eq!("bright", find(180, "bright"));
+
+        // Readings that skip a profile don't keep the previous one
+        assert_eq!("bright", find(205, "dark"));
+    }
+
+    #[test]
+    fn test_find_profile_with_hysteresis_without_previous_profile() {
+        let thresholds = vec![(0, "dark"), (100, "dim")]
+            .into_iter()
+            .map(|(lux, profile)| (lux, profile.to_string()))
+            .collect();
+
+        assert_eq!(
+            "dark",
+            find_profile_with_hysteresis(99, &thresholds, "", 10)
+        );
+        assert_eq!(
+            "dim",
+            find_profile_with_hysteresis(100, &thresholds, "", 10)
+        );
+        assert_eq!(
+            "dim",
+            find_profile_with_hysteresis(100, &thresholds, "dark", 0)
+        );
+    }
+
+    #[test]
+    fn test_profiles_band() {
+        let thresholds = vec![(0, "dark"), (100, "dim"), (200, "bright")]
+            .into_iter()
+            .map(|(lux, profile)| (lux, profile.to_string()))
+            .collect();
+        let profiles = Profiles::new(thresholds, 10, 1.0, Duration::ZERO);
+
+        assert_eq!("dark", profiles.find("test", 50));
+        assert_eq!((None, Some(110)), profiles.band());
+        assert_eq!("dim", profiles.find("test", 150));
+        assert_eq!((Some(90), Some(220)), profiles.band());
+        assert_eq!("bright", profiles.find("test", 500));
+        assert_eq!((Some(180), None), profiles.band());
+    }
+
+    #[test]
+    fn test_smooth() {
+        let at = Instant::now();
+        let interval = Duration::from_millis(100);
+        let smooth = |previous, after| smooth(previous, 200, at + after, 0.25, interval);
+
+        assert_eq!(200.0, smooth(None, interval));
+        assert_eq!(125.0, smooth(Some((100.0, at)), interval));
+        // twice as late weighs as much as two readings in a row
+        assert_eq!(
+            100.0 + 100.0 * (1.0 - 0.75 * 0.75),
+            smooth(Some((100.0, at)), 2 * interval)
+        );
+        assert_eq!(
+            200.0,
+            super::smooth(Some((100.0, at)), 200, at + interval, 1.0, interval)
+        );
+    }
+
+    #[test]
+    fn test_profiles_ignore_short_spikes_when_smoothing() {
+        let thresholds = vec![(0, "dark"), (100, "dim")]
+            .into_iter()
+            .map(|(lux, profile)| (lux, profile.to_string()))
+            .collect();
+        let interval = Duration::from_millis(100);
+        let at = Instant::now();
+        let profiles = Profiles::new(thresholds, 0, 0.25, interval);
+        let find = |raw, intervals| profiles.find_at("test", raw, at + intervals * interval);
+
+        assert_eq!("dark", find(50, 0));
+        assert_eq!("dark", find(200, 1));
+        assert_eq!("dark", find(50, 2));
+        assert_eq!("dim", find(500, 3));
+    }
+
+    #[test]
+    fn test_profiles_weigh_readings_by_time_since_the_previous_one() {
+        let thresholds = vec![(0, "dark"), (100, "dim")]
+            .into_iter()
+            .map(|(lux, profile)| (lux, profile.to_string()))
+            .collect();
+        let interval = Duration::from_millis(100);
+        let at = Instant::now();
+
+        // right after the previous reading it's mostly ignored...
+        let profiles = Profiles::new(thresholds, 0, 0.25, interval);
+        assert_eq!("dark", profiles.find_at("test", 50, at));
+        assert_eq!("dark", profiles.find_at("test", 200, at + interval));
+
+        // ... but when polling slowed down, it stands for the readings that were skipped
+        let profiles = Profiles::new(profiles.thresholds, 0, 0.25, interval);
+        assert_eq!("dark", profiles.find_at("test", 50, at));
+        assert_eq!("dim", profiles.find_at("test", 200, at + 10 * interval));
+    }
+
     #[test]
     #[should_panic]
     fn test_find_profile_panics_on_empty_thresholds() {
@@ -1,10 +1,10 @@
-use std::error::Error;
+use crate::error::WlumaError;
 
 #[derive(Default)]
 pub struct Als {}
 
 impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
+    fn get(&self) -> Result<String, WlumaError> {
         Ok("none".to_string())
     }
 }
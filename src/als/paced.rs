@@ -0,0 +1,29 @@
+use crate::config::AlsPolling;
+use crate::error::WlumaError;
+use std::time::Duration;
+
+/// Reads another source as often as it's configured to be read.
+pub struct Als {
+    als: Box<dyn super::Als>,
+    polling: AlsPolling,
+}
+
+impl Als {
+    pub fn new(als: Box<dyn super::Als>, polling: AlsPolling) -> Self {
+        Self { als, polling }
+    }
+}
+
+impl super::Als for Als {
+    fn get(&self) -> Result<String, WlumaError> {
+        self.als.get()
+    }
+
+    fn polling(&self) -> AlsPolling {
+        self.polling
+    }
+
+    fn wait(&self, interval: Duration) {
+        self.als.wait(interval);
+    }
+}
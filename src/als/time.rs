@@ -1,23 +1,108 @@
-use chrono::{Local, Timelike};
+use crate::config::{Location, TimeThreshold};
+use crate::error::WlumaError;
+use chrono::{Datelike, Local, Timelike};
 use std::collections::HashMap;
-use std::error::Error;
+use std::f64::consts::PI;
+
+/// Sunrise and sunset of an average day, for thresholds that don't depend on them.
+const AVERAGE_SUN: (u32, u32) = (6 * 60, 18 * 60);
 
 pub struct Als {
-    thresholds: HashMap<u64, String>,
+    thresholds: HashMap<TimeThreshold, String>,
+    location: Option<Location>,
 }
 
 impl Als {
-    pub fn new(thresholds: HashMap<u64, String>) -> Self {
-        Self { thresholds }
+    pub fn new(thresholds: HashMap<TimeThreshold, String>, location: Option<Location>) -> Self {
+        Self {
+            thresholds,
+            location,
+        }
     }
 }
 
 impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
-        let raw = Local::now().hour() as u64;
-        let profile = super::find_profile(raw, &self.thresholds);
+    fn get(&self) -> Result<String, WlumaError> {
+        let now = Local::now();
+        let (sunrise, sunset) = self.location.map_or(AVERAGE_SUN, |location| {
+            sun(location, now.ordinal(), now.offset().local_minus_utc() / 60)
+        });
+        // Thresholds follow sunrise and sunset as they move from day to day
+        let thresholds = self
+            .thresholds
+            .iter()
+            .map(|(time, profile)| (time.minutes(sunrise, sunset) as u64, profile.clone()))
+            .collect();
+
+        let raw = (now.hour() * 60 + now.minute()) as u64;
+        let profile = super::find_profile(raw, &thresholds);
 
-        log::trace!("ALS (time): {} ({})", profile, raw);
+        super::report("time", raw, raw, &profile);
         Ok(profile)
     }
 }
+
+/// Sunrise and sunset in minutes since the local midnight, on the given day of the year, using
+/// the approximations of NOAA. Close to the poles, when the sun doesn't set or rise, they are
+/// kept a couple of minutes apart, so that the day or the night still lasts for almost 24 hours.
+fn sun(location: Location, day_of_year: u32, utc_offset_minutes: i32) -> (u32, u32) {
+    let year = 2.0 * PI / 365.0 * (day_of_year as f64 - 1.0);
+    let equation_of_time = 229.18
+        * (0.000075 + 0.001868 * year.cos()
+            - 0.032077 * year.sin()
+            - 0.014615 * (2.0 * year).cos()
+            - 0.040849 * (2.0 * year).sin());
+    let declination = 0.006918 - 0.399912 * year.cos() + 0.070257 * year.sin()
+        - 0.006758 * (2.0 * year).cos()
+        + 0.000907 * (2.0 * year).sin()
+        - 0.002697 * (3.0 * year).cos()
+        + 0.00148 * (3.0 * year).sin();
+
+    let latitude = location.latitude.to_radians();
+    // The sun is 0.833° below the horizon at sunrise, as seen through the atmosphere
+    let hour_angle = (90.833_f64.to_radians().cos() / (latitude.cos() * declination.cos())
+        - latitude.tan() * declination.tan())
+    .clamp(-1.0, 1.0)
+    .acos()
+    .to_degrees()
+    .clamp(0.25, 179.75);
+
+    let noon = 720.0 - 4.0 * location.longitude - equation_of_time + utc_offset_minutes as f64;
+    let local = |minutes: f64| (minutes.round() as i32).rem_euclid(24 * 60) as u32;
+    (
+        local(noon - 4.0 * hour_angle),
+        local(noon + 4.0 * hour_angle),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sun() {
+        let greenwich = Location {
+            latitude: 51.48,
+            longitude: 0.0,
+        };
+        let near = |(sunrise, sunset): (u32, u32), expected: (u32, u32)| {
+            sunrise.abs_diff(expected.0) <= 3 && sunset.abs_diff(expected.1) <= 3
+        };
+
+        // 21 June, sunrise at 4:43 and sunset at 21:21 in British Summer Time
+        assert!(near(sun(greenwich, 172, 60), (4 * 60 + 43, 21 * 60 + 21)));
+        // 21 December, sunrise at 8:04 and sunset at 15:53 in UTC
+        assert!(near(sun(greenwich, 355, 0), (8 * 60 + 4, 15 * 60 + 53)));
+
+        // Polar day in Tromsø
+        let tromso = Location {
+            latitude: 69.65,
+            longitude: 18.96,
+        };
+        let (sunrise, sunset) = sun(tromso, 172, 120);
+        assert_eq!(2, sunrise - sunset);
+        // Polar night
+        let (sunrise, sunset) = sun(tromso, 355, 60);
+        assert_eq!(2, sunset - sunrise);
+    }
+}
@@ -1,9 +1,11 @@
+use super::{glow, Profiles};
+use crate::config::Lightness;
+use crate::error::WlumaError;
 use crate::frame::compute_perceived_lightness_percent;
 use itertools::Itertools;
 use std::cell::RefCell;
-use std::collections::HashMap;
-use std::error::Error;
-use std::sync::mpsc::{Receiver, Sender};
+use std::fs;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 use v4l::buffer::Type;
@@ -13,16 +15,105 @@ use v4l::video::Capture;
 use v4l::{Device, FourCC};
 
 const DEFAULT_LUX: u64 = 100;
-const WAITING_SLEEP_MS: u64 = 2000;
+const RETRY_SLEEP_MS: u64 = 2000;
+const V4L_PATH: &str = "/sys/class/video4linux";
+
+// Formats in the order of preference, the ones that are cheaper to convert come first
+const SUPPORTED_FORMATS: [PixelFormat; 5] = [
+    PixelFormat::Rgb3,
+    PixelFormat::Yuyv,
+    PixelFormat::Grey,
+    PixelFormat::Y16,
+    PixelFormat::Mjpg,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PixelFormat {
+    Rgb3,
+    Yuyv,
+    Grey,
+    /// 16-bit little-endian grey, common with infrared cameras.
+    Y16,
+    Mjpg,
+}
+
+impl PixelFormat {
+    fn fourcc(&self) -> FourCC {
+        FourCC::new(match self {
+            Self::Rgb3 => b"RGB3",
+            Self::Yuyv => b"YUYV",
+            Self::Grey => b"GREY",
+            Self::Y16 => b"Y16 ",
+            Self::Mjpg => b"MJPG",
+        })
+    }
+
+    fn from_fourcc(fourcc: FourCC) -> Option<Self> {
+        SUPPORTED_FORMATS
+            .into_iter()
+            .find(|format| format.fourcc() == fourcc)
+    }
+
+    /// Whether there is only the luma, e.g. from infrared cameras.
+    fn is_single_channel(self) -> bool {
+        matches!(self, Self::Grey | Self::Y16)
+    }
+
+    fn to_rgb(self, data: &[u8], pixels: usize) -> Result<Vec<u8>, WlumaError> {
+        Ok(match self {
+            Self::Rgb3 => data.to_vec(),
+            Self::Yuyv => yuyv_to_rgb(data, pixels),
+            Self::Grey => grey_to_rgb(data, pixels),
+            Self::Y16 => y16_to_rgb(data, pixels),
+            Self::Mjpg => mjpg_to_rgb(data)?,
+        })
+    }
+}
+
+struct Session {
+    stream: Stream<'static>,
+    format: PixelFormat,
+    width: usize,
+    pixels: usize,
+}
+
+/// Lux of a picture, or why none could be taken.
+pub type Reading = Result<u64, WlumaError>;
 
 pub struct Webcam {
-    webcam_tx: Sender<u64>,
+    webcam_tx: Sender<Reading>,
     video: usize,
+    sleep_ms: u64,
+    /// Percent of the width and height of pictures, around their center, to measure.
+    region: u8,
+    /// Maps channel values of pictures through the configured gamma.
+    gamma_table: [u8; 256],
+    lightness: Lightness,
+    /// Uses an infrared camera instead of `video`, if there is one.
+    prefer_infrared: bool,
+    session: Option<Session>,
 }
 
 impl Webcam {
-    pub fn new(webcam_tx: Sender<u64>, video: usize) -> Self {
-        Self { webcam_tx, video }
+    pub fn new(
+        webcam_tx: Sender<Reading>,
+        video: usize,
+        sleep_ms: u64,
+        region: u8,
+        gamma: f64,
+        lightness: Lightness,
+        prefer_infrared: bool,
+    ) -> Self {
+        Self {
+            webcam_tx,
+            video,
+            sleep_ms,
+            region,
+            gamma_table: gamma_table(gamma),
+            lightness,
+            prefer_infrared,
+            session: None,
+        }
     }
 
     pub fn run(&mut self) {
@@ -32,31 +123,74 @@ impl Webcam {
     }
 
     fn step(&mut self) {
-        if let Ok((rgbs, pixels)) = self.frame() {
-            let lux = compute_perceived_lightness_percent(&rgbs, false, pixels) as u64;
+        match self.frame() {
+            Ok((rgbs, width)) => {
+                let mut rgbs = crop_center(&rgbs, width, self.region);
+                for channel in &mut rgbs {
+                    *channel = self.gamma_table[*channel as usize];
+                }
+                let pixels = rgbs.len() / 3;
+                let lux = compute_perceived_lightness_percent(&rgbs, false, pixels, self.lightness)
+                    as u64;
 
-            self.webcam_tx
-                .send(lux)
-                .expect("Unable to send new webcam lux value, channel is dead");
-        };
+                self.webcam_tx
+                    .send(Ok(lux))
+                    .expect("Unable to send new webcam lux value, channel is dead");
 
-        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+                thread::sleep(Duration::from_millis(self.sleep_ms));
+            }
+            Err(err) => {
+                log::debug!("Unable to capture webcam frame: {}", err);
+                // Reopen the device on the next attempt, it might have been unplugged
+                self.session = None;
+                self.webcam_tx
+                    .send(Err(err))
+                    .expect("Unable to send webcam error, channel is dead");
+                thread::sleep(Duration::from_millis(RETRY_SLEEP_MS.max(self.sleep_ms)));
+            }
+        };
     }
 
-    fn frame(&mut self) -> Result<(Vec<u8>, usize), Box<dyn Error>> {
-        let (device, pixels) = Self::setup(self.video)?;
-        let mut stream = Stream::new(&device, Type::VideoCapture)?;
-        let (rgbs, _) = stream.next()?;
+    /// Returns the picture as RGB values, and its width.
+    fn frame(&mut self) -> Result<(Vec<u8>, usize), WlumaError> {
+        if self.session.is_none() {
+            let video = match self.prefer_infrared {
+                true => infrared_video().unwrap_or(self.video),
+                false => self.video,
+            };
+            self.session = Some(Self::setup(video)?);
+        }
+
+        let session = self.session.as_mut().unwrap();
+        let (data, _) = session.stream.next().map_err(WlumaError::als)?;
+        let rgbs = session.format.to_rgb(data, session.pixels)?;
 
-        Ok((rgbs.to_vec(), pixels))
+        Ok((rgbs, session.width))
     }
 
-    fn setup(video: usize) -> Result<(Device, usize), Box<dyn Error>> {
-        let device = Device::new(video)?;
-        let mut format = device.format()?;
-        format.fourcc = FourCC::new(b"RGB3");
+    fn setup(video: usize) -> Result<Session, WlumaError> {
+        let device = Device::new(video).map_err(WlumaError::als)?;
+
+        let available = device
+            .enum_formats()
+            .map_err(WlumaError::als)?
+            .into_iter()
+            .map(|description| description.fourcc)
+            .collect_vec();
+
+        let format = SUPPORTED_FORMATS
+            .into_iter()
+            .find(|format| available.contains(&format.fourcc()))
+            .ok_or_else(|| {
+                WlumaError::als(format!(
+                    "Webcam does not support any of the known formats, available: {}",
+                    available.iter().join(", ")
+                ))
+            })?;
+
         let (width, height) = device
-            .enum_framesizes(format.fourcc)?
+            .enum_framesizes(format.fourcc())
+            .map_err(WlumaError::als)?
             .into_iter()
             .flat_map(|f| {
                 f.size
@@ -66,63 +200,322 @@ impl Webcam {
                     .collect_vec()
             })
             .min_by(|&(w1, h1), &(w2, h2)| h1.cmp(&h2).then(w1.cmp(&w2)))
-            .ok_or("Unable to find minimum resolution")?;
+            .ok_or_else(|| WlumaError::als("Unable to find minimum resolution"))?;
+
+        let mut requested = device.format().map_err(WlumaError::als)?;
+        requested.fourcc = format.fourcc();
+        requested.height = height;
+        requested.width = width;
+
+        // Driver is allowed to adjust the format, so always use what it reports back
+        let actual = device.set_format(&requested).map_err(WlumaError::als)?;
+        let format = PixelFormat::from_fourcc(actual.fourcc).ok_or_else(|| {
+            WlumaError::als(format!(
+                "Webcam switched to unsupported format {}",
+                actual.fourcc
+            ))
+        })?;
+
+        log::debug!(
+            "Using webcam format {} at {}x{}",
+            actual.fourcc,
+            actual.width,
+            actual.height
+        );
+
+        Ok(Session {
+            stream: Stream::new(&device, Type::VideoCapture).map_err(WlumaError::als)?,
+            format,
+            width: actual.width as usize,
+            pixels: actual.width as usize * actual.height as usize,
+        })
+    }
+}
 
-        format.height = height;
-        format.width = width;
-        device.set_format(&format)?;
+/// Takes pictures on a separate thread, their readings come through the returned channel.
+pub fn start(
+    video: usize,
+    sleep_ms: u64,
+    region: u8,
+    gamma: f64,
+    lightness: Lightness,
+    prefer_infrared: bool,
+) -> Result<Receiver<Reading>, WlumaError> {
+    let (webcam_tx, webcam_rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("als-webcam".to_string())
+        .spawn(move || {
+            Webcam::new(
+                webcam_tx,
+                video,
+                sleep_ms,
+                region,
+                gamma,
+                lightness,
+                prefer_infrared,
+            )
+            .run();
+        })
+        .map_err(WlumaError::als)?;
+    Ok(webcam_rx)
+}
 
-        Ok((device, width as usize * height as usize))
+/// Index of the first video device that only captures single-channel pictures, which is how
+/// infrared cameras (e.g. the ones used for face recognition) usually show up. Their readings
+/// are less affected by the glow of the screen.
+fn infrared_video() -> Option<usize> {
+    let video = fs::read_dir(V4L_PATH)
+        .ok()?
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .file_name()
+                .to_str()?
+                .strip_prefix("video")?
+                .parse()
+                .ok()
+        })
+        .sorted()
+        .find(|&video| {
+            Device::new(video)
+                .and_then(|device| device.enum_formats())
+                .is_ok_and(|formats| is_infrared(&formats.iter().map(|f| f.fourcc).collect_vec()))
+        });
+    match video {
+        Some(video) => log::debug!("Using infrared camera /dev/video{}", video),
+        None => log::debug!("No infrared camera found, using the configured one"),
     }
+    video
+}
+
+fn is_infrared(formats: &[FourCC]) -> bool {
+    !formats.is_empty()
+        && formats.iter().all(|&fourcc| {
+            PixelFormat::from_fourcc(fourcc).is_some_and(PixelFormat::is_single_channel)
+        })
+}
+
+/// Keeps the given percent of the width and height of an RGB picture, around its center.
+fn crop_center(rgbs: &[u8], width: usize, percent: u8) -> Vec<u8> {
+    let height = rgbs.len() / 3 / width.max(1);
+    let keep = |size: usize| {
+        (size * percent as usize)
+            .div_ceil(100)
+            .clamp(1, size.max(1))
+    };
+    let (crop_width, crop_height) = (keep(width), keep(height));
+    let (x, y) = ((width - crop_width) / 2, (height - crop_height) / 2);
+
+    rgbs.chunks_exact(width * 3)
+        .skip(y)
+        .take(crop_height)
+        .flat_map(|row| &row[x * 3..(x + crop_width) * 3])
+        .copied()
+        .collect()
+}
+
+/// Values of 8-bit channels raised to the power of gamma, as if they were between 0 and 1.
+fn gamma_table(gamma: f64) -> [u8; 256] {
+    std::array::from_fn(|value| ((value as f64 / 255.0).powf(gamma) * 255.0).round() as u8)
+}
+
+fn yuyv_to_rgb(data: &[u8], pixels: usize) -> Vec<u8> {
+    // Every 4 bytes (Y0 U Y1 V) describe 2 pixels that share the same chroma
+    data.chunks_exact(4)
+        .take(pixels / 2)
+        .flat_map(|chunk| {
+            let (u, v) = (chunk[1], chunk[3]);
+            [yuv_to_rgb(chunk[0], u, v), yuv_to_rgb(chunk[2], u, v)]
+        })
+        .flatten()
+        .collect()
+}
+
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    // ITU-R BT.601
+    let (y, u, v) = (y as f64, u as f64 - 128.0, v as f64 - 128.0);
+    [
+        (y + 1.402 * v).round().clamp(0.0, 255.0) as u8,
+        (y - 0.344136 * u - 0.714136 * v).round().clamp(0.0, 255.0) as u8,
+        (y + 1.772 * u).round().clamp(0.0, 255.0) as u8,
+    ]
+}
+
+fn grey_to_rgb(data: &[u8], pixels: usize) -> Vec<u8> {
+    data.iter().take(pixels).flat_map(|&y| [y, y, y]).collect()
+}
+
+fn y16_to_rgb(data: &[u8], pixels: usize) -> Vec<u8> {
+    // The most significant byte is enough to measure lightness
+    data.chunks_exact(2)
+        .take(pixels)
+        .flat_map(|y| [y[1], y[1], y[1]])
+        .collect()
+}
+
+fn mjpg_to_rgb(data: &[u8]) -> Result<Vec<u8>, WlumaError> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    let pixels = decoder.decode().map_err(WlumaError::als)?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| WlumaError::als("Unable to read MJPG frame info"))?;
+
+    Ok(match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => pixels,
+        jpeg_decoder::PixelFormat::L8 => grey_to_rgb(&pixels, pixels.len()),
+        format => Err(WlumaError::als(format!(
+            "Unsupported MJPG pixel format {:?}",
+            format
+        )))?,
+    })
 }
 
 pub struct Als {
-    webcam_rx: Receiver<u64>,
-    thresholds: HashMap<u64, String>,
-    lux: RefCell<u64>,
+    webcam_rx: Receiver<Reading>,
+    profiles: Profiles,
+    /// Lux added by the screens at full brightness showing a white picture.
+    screen_glow: u64,
+    /// Latest reading, or why the webcam stopped capturing until it takes pictures again.
+    lux: RefCell<Result<u64, String>>,
 }
 
 impl Als {
-    pub fn new(webcam_rx: Receiver<u64>, thresholds: HashMap<u64, String>) -> Self {
+    pub fn new(webcam_rx: Receiver<Reading>, profiles: Profiles, screen_glow: u64) -> Self {
         Self {
             webcam_rx,
-            thresholds,
-            lux: RefCell::new(DEFAULT_LUX),
+            profiles,
+            screen_glow,
+            lux: RefCell::new(Ok(DEFAULT_LUX)),
         }
     }
 
-    fn get_raw(&self) -> Result<u64, Box<dyn Error>> {
-        let new_value = self
-            .webcam_rx
-            .try_iter()
-            .last()
-            .unwrap_or(*self.lux.borrow());
-        *self.lux.borrow_mut() = new_value;
-        Ok(new_value)
+    fn get_raw(&self) -> Result<u64, WlumaError> {
+        if let Some(reading) = self.webcam_rx.try_iter().last() {
+            *self.lux.borrow_mut() = reading.map_err(|err| err.to_string());
+        }
+        self.lux
+            .borrow()
+            .clone()
+            .map_err(|err| WlumaError::als(format!("Webcam is not capturing, last error: {}", err)))
     }
-}
 
-impl super::Als for Als {
-    fn get(&self) -> Result<String, Box<dyn Error>> {
+    /// Latest reading without the screen glow, before it's mapped to a profile.
+    pub fn get_lux(&self) -> Result<u64, WlumaError> {
         let raw = self.get_raw()?;
-        let profile = super::find_profile(raw, &self.thresholds);
+        let compensated = glow::compensate(raw, self.screen_glow);
+        if compensated != raw {
+            log::trace!(
+                "Webcam reading {} without screen glow: {}",
+                raw,
+                compensated
+            );
+        }
+        Ok(compensated)
+    }
+}
 
-        log::trace!("ALS (webcam): {} ({})", profile, raw);
-        Ok(profile)
+impl super::Als for Als {
+    fn get(&self) -> Result<String, WlumaError> {
+        Ok(self.profiles.find("webcam", self.get_lux()?))
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::collections::HashMap;
+    use std::error::Error;
     use std::sync::mpsc;
 
-    fn setup() -> (Als, Sender<u64>) {
+    fn setup() -> (Als, Sender<Reading>) {
         let (webcam_tx, webcam_rx) = mpsc::channel();
-        let als = Als::new(webcam_rx, HashMap::default());
+        let als = Als::new(
+            webcam_rx,
+            Profiles::new(HashMap::default(), 0, 1.0, Duration::ZERO),
+            0,
+        );
         (als, webcam_tx)
     }
 
+    #[test]
+    fn test_crop_center() {
+        // 4x2 picture, every pixel has the value of its index
+        let rgbs = (0..8).flat_map(|i| [i, i, i]).collect_vec();
+
+        assert_eq!(rgbs, crop_center(&rgbs, 4, 100));
+        assert_eq!(vec![1, 1, 1, 2, 2, 2], crop_center(&rgbs, 4, 50));
+        // Some pixel is always left
+        assert_eq!(vec![1, 1, 1], crop_center(&rgbs, 4, 1));
+    }
+
+    #[test]
+    fn test_gamma_table() {
+        assert_eq!((0..=255).collect_vec(), gamma_table(1.0).to_vec());
+
+        let table = gamma_table(2.0);
+        assert_eq!([0, 64, 255], [table[0], table[128], table[255]]);
+    }
+
+    #[test]
+    fn test_yuyv_to_rgb() {
+        // Black and white pixels without chroma, then a pure red-ish pair
+        let data = [0, 128, 255, 128, 76, 85, 76, 255];
+
+        assert_eq!(
+            vec![0, 0, 0, 255, 255, 255, 255, 0, 0, 255, 0, 0],
+            yuyv_to_rgb(&data, 4)
+                .into_iter()
+                .map(|c| if c > 250 {
+                    255
+                } else if c < 5 {
+                    0
+                } else {
+                    c
+                })
+                .collect_vec()
+        );
+    }
+
+    #[test]
+    fn test_yuyv_to_rgb_ignores_trailing_data() {
+        assert_eq!(6, yuyv_to_rgb(&[0, 128, 0, 128, 1, 2, 3, 4], 2).len());
+    }
+
+    #[test]
+    fn test_grey_to_rgb() {
+        assert_eq!(
+            vec![0, 0, 0, 42, 42, 42, 255, 255, 255],
+            grey_to_rgb(&[0, 42, 255, 7], 3)
+        );
+    }
+
+    #[test]
+    fn test_y16_to_rgb() {
+        assert_eq!(
+            vec![0, 0, 0, 42, 42, 42, 255, 255, 255],
+            y16_to_rgb(&[0xff, 0x00, 0x10, 0x2a, 0xff, 0xff, 1, 2], 3)
+        );
+    }
+
+    #[test]
+    fn test_is_infrared() {
+        assert!(is_infrared(&[FourCC::new(b"GREY")]));
+        assert!(is_infrared(&[FourCC::new(b"GREY"), FourCC::new(b"Y16 ")]));
+        assert!(!is_infrared(&[FourCC::new(b"GREY"), FourCC::new(b"YUYV")]));
+        assert!(!is_infrared(&[FourCC::new(b"GREY"), FourCC::new(b"NV12")]));
+        // Metadata nodes of cameras capture no pictures
+        assert!(!is_infrared(&[]));
+    }
+
+    #[test]
+    fn test_pixel_format_from_fourcc() {
+        assert_eq!(
+            Some(PixelFormat::Yuyv),
+            PixelFormat::from_fourcc(FourCC::new(b"YUYV"))
+        );
+        assert_eq!(None, PixelFormat::from_fourcc(FourCC::new(b"NV12")));
+    }
+
     #[test]
     fn test_get_raw_returns_default_value_when_no_data_from_webcam() -> Result<(), Box<dyn Error>> {
         let (als, _) = setup();
@@ -135,7 +528,7 @@ mod tests {
     fn test_get_raw_returns_value_from_webcam() -> Result<(), Box<dyn Error>> {
         let (als, webcam_tx) = setup();
 
-        webcam_tx.send(42)?;
+        webcam_tx.send(Ok(42))?;
 
         assert_eq!(42, als.get_raw()?);
         Ok(())
@@ -145,9 +538,9 @@ mod tests {
     fn test_get_raw_returns_most_recent_value_from_webcam() -> Result<(), Box<dyn Error>> {
         let (als, webcam_tx) = setup();
 
-        webcam_tx.send(42)?;
-        webcam_tx.send(43)?;
-        webcam_tx.send(44)?;
+        webcam_tx.send(Ok(42))?;
+        webcam_tx.send(Ok(43))?;
+        webcam_tx.send(Ok(44))?;
 
         assert_eq!(44, als.get_raw()?);
         Ok(())
@@ -158,12 +551,31 @@ mod tests {
     ) -> Result<(), Box<dyn Error>> {
         let (als, webcam_tx) = setup();
 
-        webcam_tx.send(42)?;
-        webcam_tx.send(43)?;
+        webcam_tx.send(Ok(42))?;
+        webcam_tx.send(Ok(43))?;
 
         assert_eq!(43, als.get_raw()?);
         assert_eq!(43, als.get_raw()?);
         assert_eq!(43, als.get_raw()?);
         Ok(())
     }
+
+    #[test]
+    fn test_get_raw_fails_until_webcam_captures_again() -> Result<(), Box<dyn Error>> {
+        let (als, webcam_tx) = setup();
+
+        webcam_tx.send(Ok(42))?;
+        webcam_tx.send(Err(WlumaError::als("No such device")))?;
+
+        assert_eq!(
+            "ALS error: Webcam is not capturing, last error: ALS error: No such device",
+            als.get_raw().unwrap_err().to_string()
+        );
+        assert!(als.get_raw().is_err());
+
+        webcam_tx.send(Ok(43))?;
+
+        assert_eq!(43, als.get_raw()?);
+        Ok(())
+    }
 }
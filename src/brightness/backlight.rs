@@ -1,12 +1,32 @@
+use crate::config::WriteMode;
 use crate::device_file::{read, write};
+use crate::error::WlumaError;
 use dbus::channel::Sender;
 use dbus::{self, blocking::Connection, Message};
 use inotify::{Inotify, WatchMask};
-use std::error::Error;
+use itertools::Itertools;
 use std::fs;
 use std::fs::File;
 use std::io::ErrorKind;
 use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// logind lags behind when asked to change brightness on every step of a transition.
+const DBUS_WRITE_INTERVAL: Duration = Duration::from_millis(20);
+/// Writes of the ddcci driver go over the slow DDC bus, and block until the screen acknowledges
+/// them.
+const DDCCI_WRITE_INTERVAL: Duration = Duration::from_millis(200);
+/// sysfs doesn't notify about new devices, so they are looked for at this interval.
+const APPEARANCE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Configured path that picks the device by the output's connector.
+const AUTO_PATH: &str = "auto";
+const BACKLIGHT_CLASS: &str = "/sys/class/backlight";
+const DRM_CLASS: &str = "/sys/class/drm";
+/// Devices of the ddcci-backlight driver are named `ddcci<bus>`.
+const DDCCI_PREFIX: &str = "ddcci";
+/// VCP feature that the ddcci-backlight driver controls by default.
+pub const DDCCI_FEATURE: u8 = 0x10;
 
 struct Dbus {
     connection: Connection,
@@ -22,31 +42,47 @@ pub struct Backlight {
     dbus: Option<Dbus>,
     has_write_permission: bool,
     pending_dbus_write: bool,
+    /// External screen driven by ddcci-backlight.
+    ddcci: bool,
 }
 
 impl Backlight {
-    pub fn new(path: &str, min_brightness: u64) -> Result<Self, Box<dyn Error>> {
+    pub fn new(
+        path: &str,
+        min_brightness: u64,
+        max_brightness: Option<u64>,
+        write_mode: WriteMode,
+    ) -> Result<Self, WlumaError> {
         let brightness_path = Path::new(path).join("brightness");
 
-        let current_brightness = fs::read(&brightness_path)?;
-
-        let has_write_permission = fs::write(&brightness_path, current_brightness).is_ok();
-
-        let (file, dbus) = if has_write_permission {
-            let file = File::options()
+        // Opening for writing checks the permission without writing anything
+        let writable_file = match write_mode {
+            WriteMode::Dbus => None,
+            WriteMode::Auto => File::options()
                 .read(true)
                 .write(true)
-                .open(&brightness_path)?;
+                .open(&brightness_path)
+                .ok(),
+            WriteMode::Direct => Some(
+                File::options()
+                    .read(true)
+                    .write(true)
+                    .open(&brightness_path)
+                    .map_err(WlumaError::brightness)?,
+            ),
+        };
+        let has_write_permission = writable_file.is_some();
 
+        let (file, dbus) = if let Some(file) = writable_file {
             log::debug!("Using direct write on {} to change brightness value", path);
             (file, None)
         } else {
-            let file = File::open(&brightness_path)?;
+            let file = File::open(&brightness_path).map_err(WlumaError::brightness)?;
 
             let id = Path::new(path)
                 .file_name()
                 .and_then(|x| x.to_str())
-                .ok_or("Unable to identify backlight ID")?;
+                .ok_or_else(|| WlumaError::brightness("Unable to identify backlight ID"))?;
 
             let message = Message::new_method_call(
                 "org.freedesktop.login1",
@@ -64,22 +100,49 @@ impl Backlight {
                 })
             });
 
+            if connection.is_none() && write_mode == WriteMode::Dbus {
+                return Err(WlumaError::brightness(
+                    "Unable to connect to logind via system DBus",
+                ));
+            }
+
             log::debug!("Using DBUS for {} to change brightness value", path);
             (file, connection)
         };
 
-        let max_brightness = fs::read_to_string(Path::new(path).join("max_brightness"))?
-            .trim()
-            .parse()?;
+        let max_brightness = match max_brightness {
+            Some(max_brightness) => max_brightness,
+            None => match fs::read_to_string(Path::new(path).join("max_brightness")) {
+                Ok(max_brightness) => max_brightness
+                    .trim()
+                    .parse()
+                    .map_err(WlumaError::brightness)?,
+                Err(err) => Err(WlumaError::config(format!(
+                    "Unable to read max brightness ({}), set max_brightness in the config",
+                    err
+                )))?,
+            },
+        };
+        // ddcci reports the maximum of the screen as it was when the driver probed it, which is
+        // 0 for screens that were off or didn't answer then
+        if max_brightness == 0 {
+            return Err(WlumaError::config(
+                "Device reports max brightness of 0, set max_brightness in the config",
+            ));
+        }
 
-        let inotify = Inotify::init()?;
-        inotify.watches().add(&brightness_path, WatchMask::MODIFY)?;
+        let inotify = Inotify::init().map_err(WlumaError::brightness)?;
+        inotify
+            .watches()
+            .add(&brightness_path, WatchMask::MODIFY)
+            .map_err(WlumaError::brightness)?;
 
         let brightness_hw_changed_path = Path::new(path).join("brightness_hw_changed");
         if Path::new(&brightness_hw_changed_path).exists() {
             inotify
                 .watches()
-                .add(&brightness_hw_changed_path, WatchMask::MODIFY)?;
+                .add(&brightness_hw_changed_path, WatchMask::MODIFY)
+                .map_err(WlumaError::brightness)?;
         }
 
         Ok(Self {
@@ -91,14 +154,134 @@ impl Backlight {
             dbus,
             has_write_permission,
             pending_dbus_write: false,
+            ddcci: is_ddcci(Path::new(path)),
+        })
+    }
+
+    /// Finds the device at the configured path, which can be `auto` to pick the backlight of the
+    /// output's connector, or end with a `*` pattern, e.g. `amdgpu_bl*`. It might appear later
+    /// when its driver is loaded late.
+    pub fn find(path: &str, output_name: &str) -> Option<String> {
+        if path == AUTO_PATH {
+            return find_by_connector(output_name);
+        }
+
+        let path = Path::new(path);
+        match path.file_name().and_then(|name| name.to_str()) {
+            Some(pattern) if pattern.contains('*') => fs::read_dir(path.parent()?)
+                .ok()?
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| glob_matches(pattern, name))
+                })
+                .filter(|path| path.join("brightness").exists())
+                .sorted()
+                .next()
+                .map(|path| path.display().to_string()),
+            _ => path
+                .join("brightness")
+                .exists()
+                .then(|| path.display().to_string()),
+        }
+    }
+
+    /// Name of the connector of the screen the backlight device belongs to, if it tells.
+    pub fn connector(path: &Path) -> Option<String> {
+        let device = fs::canonicalize(path.join("device")).ok()?;
+        if is_ddcci(path) {
+            return ddc_connector(i2c_bus(&device)?);
+        }
+        connector_name(device.file_name()?.to_str()?).map(str::to_string)
+    }
+
+    /// Finds the ddcci-backlight device of the screen at the connector, which is preferred over
+    /// talking DDC from userspace, as the driver already owns the bus.
+    pub fn find_ddcci(connector: &str) -> Option<String> {
+        find_by_connector(connector).filter(|path| is_ddcci(Path::new(path)))
+    }
+
+    /// Blocks until the device appears, and returns its path.
+    pub fn wait_for(path: &str, output_name: &str) -> String {
+        loop {
+            if let Some(path) = Self::find(path, output_name) {
+                return path;
+            }
+            thread::sleep(APPEARANCE_POLL_INTERVAL);
+        }
+    }
+}
+
+/// Backlight devices are children of the connector of the screen they belong to, e.g.
+/// `/sys/class/backlight/amdgpu_bl1/device` links to `/sys/class/drm/card1-eDP-1`.
+fn find_by_connector(output_name: &str) -> Option<String> {
+    fs::read_dir(BACKLIGHT_CLASS)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| Backlight::connector(path).is_some_and(|connector| connector == output_name))
+        .sorted()
+        .next()
+        .map(|path| path.display().to_string())
+}
+
+fn is_ddcci(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with(DDCCI_PREFIX))
+}
+
+/// I2C bus a ddcci device sits on, e.g. `i2c-5` for `/sys/devices/.../i2c-5/5-0037`.
+fn i2c_bus(device: &Path) -> Option<&str> {
+    device
+        .parent()?
+        .file_name()?
+        .to_str()
+        .filter(|bus| bus.starts_with("i2c-"))
+}
+
+/// Connector whose DDC bus is the given one, which it either links to as `ddc`, or has as a
+/// child for DisplayPort AUX channels.
+fn ddc_connector(bus: &str) -> Option<String> {
+    fs::read_dir(DRM_CLASS)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            fs::canonicalize(path.join("ddc"))
+                .is_ok_and(|ddc| ddc.file_name().is_some_and(|name| name == bus))
+                || path.join(bus).exists()
         })
+        .find_map(|path| connector_name(path.file_name()?.to_str()?).map(str::to_string))
+}
+
+/// Connector name of a DRM connector device, e.g. `eDP-1` for `card1-eDP-1`.
+fn connector_name(device: &str) -> Option<&str> {
+    let (card, connector) = device.split_once('-')?;
+    card.strip_prefix("card")?
+        .chars()
+        .all(|c| c.is_ascii_digit())
+        .then_some(connector)
+}
+
+/// Matches names against patterns where `*` stands for any number of characters.
+fn glob_matches(pattern: &str, name: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == name,
+        Some((prefix, rest)) => name.strip_prefix(prefix).is_some_and(|name| {
+            (0..=name.len())
+                .filter(|&i| name.is_char_boundary(i))
+                .any(|i| glob_matches(rest, &name[i..]))
+        }),
     }
 }
 
 impl super::Brightness for Backlight {
-    fn get(&mut self) -> Result<u64, Box<dyn Error>> {
+    fn get(&mut self) -> Result<u64, WlumaError> {
         let update = |this: &mut Self| {
-            let value = read(&mut this.file)? as u64;
+            let value = read(&mut this.file).map_err(WlumaError::brightness)? as u64;
             this.current = Some(value);
             Ok(value)
         };
@@ -115,22 +298,31 @@ impl super::Brightness for Backlight {
                 }
             }
             (Err(err), Some(cached)) if err.kind() == ErrorKind::WouldBlock => Ok(cached),
-            (Err(err), _) => Err(err.into()),
+            (Err(err), _) => Err(WlumaError::brightness(err)),
         }
     }
 
-    fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>> {
-        let value = value.clamp(self.min_brightness, self.max_brightness);
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
+        let value = value.clamp(self.min(), self.max_brightness);
 
         if self.has_write_permission {
-            write(&mut self.file, value as f64)?;
+            write(&mut self.file, value as f64).map_err(WlumaError::brightness)?;
         } else if let Some(dbus) = &self.dbus {
             dbus.connection
-                .send(dbus.message.duplicate()?.append1(value as u32))
-                .map_err(|_| "Unable to send brightness change message via dbus")?;
+                .send(
+                    dbus.message
+                        .duplicate()
+                        .map_err(WlumaError::brightness)?
+                        .append1(value as u32),
+                )
+                .map_err(|_| {
+                    WlumaError::brightness("Unable to send brightness change message via dbus")
+                })?;
             self.pending_dbus_write = true;
         } else {
-            Err(std::io::Error::from(ErrorKind::PermissionDenied))?
+            Err(WlumaError::brightness(std::io::Error::from(
+                ErrorKind::PermissionDenied,
+            )))?
         }
 
         self.current = Some(value);
@@ -139,8 +331,63 @@ impl super::Brightness for Backlight {
         let mut buffer = [0u8; 1024];
         match self.inotify.read_events(&mut buffer) {
             Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(value),
-            Err(err) => Err(err.into()),
+            Err(err) => Err(WlumaError::brightness(err)),
             _ => Ok(value),
         }
     }
+
+    fn max(&self) -> u64 {
+        self.max_brightness
+    }
+
+    fn min(&self) -> u64 {
+        self.min_brightness.min(self.max_brightness)
+    }
+
+    fn min_write_interval(&self) -> Duration {
+        if self.ddcci {
+            DDCCI_WRITE_INTERVAL
+        } else if self.has_write_permission {
+            Duration::ZERO
+        } else {
+            DBUS_WRITE_INTERVAL
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("amdgpu_bl*", "amdgpu_bl0"));
+        assert!(glob_matches("amdgpu_bl*", "amdgpu_bl"));
+        assert!(glob_matches("*_backlight", "intel_backlight"));
+        assert!(glob_matches("a*b*c", "aXbYc"));
+        assert!(!glob_matches("amdgpu_bl*", "intel_backlight"));
+        assert!(!glob_matches("a*b*c", "aXbY"));
+        assert!(glob_matches("intel_backlight", "intel_backlight"));
+    }
+
+    #[test]
+    fn test_connector_name() {
+        assert_eq!(Some("eDP-1"), connector_name("card1-eDP-1"));
+        assert_eq!(Some("HDMI-A-1"), connector_name("card0-HDMI-A-1"));
+        assert_eq!(None, connector_name("0000:04:00.0"));
+        assert_eq!(None, connector_name("cardX-eDP-1"));
+    }
+
+    #[test]
+    fn test_ddcci_device() {
+        assert!(is_ddcci(Path::new("/sys/class/backlight/ddcci5")));
+        assert!(!is_ddcci(Path::new("/sys/class/backlight/amdgpu_bl1")));
+
+        let device = Path::new("/sys/devices/pci0000:00/0000:00:02.0/i2c-5/5-0037");
+        assert_eq!(Some("i2c-5"), i2c_bus(device));
+        assert_eq!(
+            None,
+            i2c_bus(Path::new("/sys/devices/pci0000:00/card1-eDP-1"))
+        );
+    }
 }
@@ -1,18 +1,66 @@
-use super::Brightness;
-use std::sync::mpsc::{Receiver, Sender};
+use super::{from_basis_points, to_basis_points, Brightness, Command, BASIS_POINTS};
+use crate::control::{self, Update};
+use crate::record::{self, Event};
+use crate::shutdown;
+use crate::stats::{self, Stat};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 const TRANSITION_MAX_MS: u64 = 200;
 const TRANSITION_STEP_MS: u64 = 1;
 const WAITING_SLEEP_MS: u64 = 100;
+/// After a brightness key is pressed, the change it makes is looked for more often for a while,
+/// so that quick presses are not merged into one.
+const KEY_SLEEP_MS: u64 = 10;
+const KEY_WATCH_MS: u64 = 1000;
+/// Coarse devices are driven in finer logical steps, so that transitions are spread in time
+/// instead of jumping through a few hardware levels at once.
+const MIN_LOGICAL_LEVELS: u64 = 1000;
 
 pub struct Controller {
+    output_name: String,
     brightness: Box<dyn Brightness>,
+    max: u64,
+    /// Predictions below it are raised to it, rather than written only to be clamped.
+    min: u64,
     user_tx: Sender<u64>,
     prediction_rx: Receiver<u64>,
+    command_rx: Receiver<Command>,
+    /// Tells the capturer of the output whether to hold off capturing, while it's disabled.
+    capture_tx: Sender<bool>,
+    /// Brightness to set while the session is locked, in basis points.
+    locked_brightness: Option<u64>,
+    locked: bool,
+    paused: bool,
+    inhibited: bool,
+    asleep: bool,
+    disabled: bool,
+    screen_off: bool,
+    /// Offset applied to predictions, in basis points.
+    exposure_bias: i64,
+    /// Last prediction before applying the exposure bias, in basis points.
+    prediction: Option<u64>,
+    restore_on_exit: bool,
+    /// Hardware level found at startup, to restore on exit.
+    initial: Option<u64>,
+    /// Logical brightness to restore once the session is unlocked.
+    unlocked: Option<u64>,
+    /// Logical brightness, in units of `1 / scale` of a hardware level.
     current: Option<u64>,
+    /// Last hardware level that was read or written.
+    hardware: Option<u64>,
+    scale: u64,
     target: Option<Target>,
+    write_interval: Duration,
+    last_write: Option<Instant>,
+    change_interval: Duration,
+    /// When the last transition ended.
+    last_change: Option<Instant>,
+    /// When a brightness key was last pressed.
+    last_key: Option<Instant>,
+    /// Percent of the way to the target to set right away, after luma jumped.
+    head_start: Option<u8>,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -28,62 +76,280 @@ impl Target {
 }
 
 impl Controller {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        output_name: &str,
         brightness: Box<dyn Brightness>,
         user_tx: Sender<u64>,
         prediction_rx: Receiver<u64>,
+        command_rx: Receiver<Command>,
+        capture_tx: Sender<bool>,
+        locked_brightness: Option<u64>,
+        exposure_bias: i64,
+        restore_on_exit: bool,
     ) -> Self {
+        let max = brightness.max();
+        let min = brightness.min();
+        let write_interval = brightness.min_write_interval();
+        let change_interval = brightness.min_change_interval();
         Self {
+            output_name: output_name.to_string(),
+            max,
+            min,
             brightness,
             user_tx,
             prediction_rx,
+            command_rx,
+            capture_tx,
+            locked_brightness,
+            locked: false,
+            paused: false,
+            inhibited: false,
+            asleep: false,
+            disabled: false,
+            screen_off: false,
+            exposure_bias,
+            prediction: None,
+            restore_on_exit,
+            initial: None,
+            unlocked: None,
             current: None,
+            hardware: None,
+            scale: MIN_LOGICAL_LEVELS.div_ceil(max.max(1)),
             target: None,
+            write_interval,
+            last_write: None,
+            change_interval,
+            last_change: None,
+            last_key: None,
+            head_start: None,
         }
     }
 
     pub fn run(&mut self) {
-        loop {
-            self.step();
+        let _done = shutdown::register();
+        while !shutdown::stopping() {
+            if !self.step() {
+                self.wait();
+            }
+        }
+
+        if self.restore_on_exit {
+            self.restore();
         }
     }
 
-    fn step(&mut self) {
+    /// Returns whether there is more to do right away, otherwise it's time to wait a bit.
+    pub(crate) fn step(&mut self) -> bool {
         match self.brightness.get() {
             Ok(new_brightness) => {
+                let commands = self.command_rx.try_iter().collect::<Vec<_>>();
+                commands
+                    .into_iter()
+                    .for_each(|command| self.execute(command));
+
+                // Predictions are sent before the jumps towards them, so read them after commands
                 let predicted_value = self.prediction_rx.try_iter().last();
+                let head_start = self.head_start.take();
 
                 // 1. check if user wants to learn a new value - this overrides any ongoing activity
-                if Some(new_brightness) != self.current {
-                    return self.update_current(new_brightness);
+                if Some(new_brightness) != self.hardware {
+                    self.update_current(new_brightness);
+                    return true;
                 }
 
-                // 2. check if predictor wants to set a new value
-                if let Some(desired) = predicted_value {
-                    self.update_target(desired);
+                // 2. check if predictor wants to set a new value, unless learning is suspended
+                if let Some(prediction) = predicted_value {
+                    if self.prediction != Some(prediction) {
+                        record::add(Event::Prediction {
+                            output_name: self.output_name.clone(),
+                            brightness: prediction,
+                        });
+                        stats::add(&self.output_name, Stat::Prediction);
+                    }
+                    self.prediction = Some(prediction);
+                    if !self.suspended() {
+                        self.update_target(self.biased(prediction));
+
+                        // 3. get a head start towards the new target if the screen contents
+                        // changed suddenly
+                        if let Some(head_start) = head_start {
+                            self.jump(head_start);
+                        }
+                    }
                 }
 
-                // 3. continue the transition if there is one in progress
-                if self.target.is_some() {
-                    return self.transition();
+                // 4. continue the transition if there is one in progress, unless the previous one
+                // ended too recently to start a new one
+                if self.target.is_some() && !self.settling() {
+                    self.transition();
+                    return true;
                 }
             }
             Err(err) => log::error!("Unable to get brightness value: {:?}", err),
         };
 
-        // 4. nothing to do, sleep and check again
-        thread::sleep(Duration::from_millis(WAITING_SLEEP_MS));
+        // 5. nothing to do, sleep and check again
+        false
+    }
+
+    /// Sleeps until it's time to check again, or until a command arrives.
+    fn wait(&mut self) {
+        let timeout = self.waiting_time();
+        match self.command_rx.recv_timeout(timeout) {
+            Ok(command) => self.execute(command),
+            Err(RecvTimeoutError::Timeout) => {}
+            // Nothing sends commands to this output
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(timeout),
+        }
+    }
+
+    fn waiting_time(&self) -> Duration {
+        if self
+            .last_key
+            .is_some_and(|last_key| last_key.elapsed() < Duration::from_millis(KEY_WATCH_MS))
+        {
+            Duration::from_millis(KEY_SLEEP_MS)
+        } else {
+            Duration::from_millis(WAITING_SLEEP_MS)
+        }
     }
 
     fn update_current(&mut self, new_brightness: u64) {
-        self.current = Some(new_brightness);
-        self.user_tx
-            .send(new_brightness)
-            .expect("Unable to send new brightness value set by user, channel is dead");
+        let initial = self.hardware.is_none();
+        if initial {
+            self.initial = Some(new_brightness);
+        }
+        self.current = Some(new_brightness * self.scale);
+        self.hardware = Some(new_brightness);
+        let brightness = to_basis_points(new_brightness, self.max);
+        // Changes made on the lock screen or while paused are not preferences to learn from,
+        // but the predictor always needs to know the initial value
+        if initial || !self.suspended() {
+            // Learn what the brightness would be without the bias, so that it can be changed
+            // without retraining
+            let unbiased = brightness
+                .saturating_add_signed(-self.exposure_bias)
+                .min(BASIS_POINTS);
+            record::add(Event::User {
+                output_name: self.output_name.clone(),
+                brightness: unbiased,
+            });
+            self.user_tx
+                .send(unbiased)
+                .expect("Unable to send new brightness value set by user, channel is dead");
+        }
         self.target = None;
+        control::report(Update::Brightness {
+            output_name: self.output_name.clone(),
+            brightness,
+        });
+    }
+
+    /// Sets the hardware level found at startup back, without a transition.
+    fn restore(&mut self) {
+        if let Some(initial) = self
+            .initial
+            .filter(|&initial| Some(initial) != self.hardware)
+        {
+            log::debug!(
+                "[{}] Restoring brightness level {}",
+                self.output_name,
+                initial
+            );
+            if let Err(err) = self.brightness.set(initial) {
+                log::error!("Unable to restore brightness value: {:?}", err);
+            }
+        }
+    }
+
+    fn settling(&self) -> bool {
+        self.last_change
+            .is_some_and(|last_change| last_change.elapsed() < self.change_interval)
+    }
+
+    fn suspended(&self) -> bool {
+        self.locked
+            || self.paused
+            || self.inhibited
+            || self.asleep
+            || self.disabled
+            || self.screen_off
+    }
+
+    /// Converts a prediction to a logical brightness, with the exposure bias applied.
+    fn biased(&self, prediction: u64) -> u64 {
+        let biased = prediction
+            .saturating_add_signed(self.exposure_bias)
+            .min(BASIS_POINTS);
+        from_basis_points(biased, self.max * self.scale)
+    }
+
+    fn execute(&mut self, command: Command) {
+        match command {
+            Command::Lock(locked) => self.update_locked(locked),
+            Command::Pause(paused) => self.paused = paused,
+            Command::Key => self.last_key = Some(Instant::now()),
+            Command::LumaJump(head_start) => self.head_start = Some(head_start),
+            Command::Sleep(asleep) => {
+                self.asleep = asleep;
+                self.catch_up();
+            }
+            Command::Inhibit(inhibited) => {
+                self.inhibited = inhibited;
+                self.catch_up();
+            }
+            Command::Disable(disabled) => {
+                self.disabled = disabled;
+                if disabled {
+                    // It was predicted before, brightness stays wherever it is now
+                    self.target = None;
+                }
+                self.capture_tx.send(disabled).ok();
+                self.catch_up();
+            }
+            Command::ScreenOff(screen_off) => {
+                self.screen_off = screen_off;
+                self.catch_up();
+            }
+            Command::ExposureBias(exposure_bias) => {
+                self.exposure_bias = exposure_bias;
+                self.catch_up();
+            }
+        }
+    }
+
+    /// Predictions might not change for a while after they are no longer suspended, catches up
+    /// with the last one.
+    fn catch_up(&mut self) {
+        if let (false, Some(prediction), Some(_)) =
+            (self.suspended(), self.prediction, self.current)
+        {
+            self.update_target(self.biased(prediction));
+        }
+    }
+
+    fn update_locked(&mut self, locked: bool) {
+        if locked == self.locked {
+            return;
+        }
+        self.locked = locked;
+
+        let desired = match (locked, self.locked_brightness, self.current) {
+            (true, Some(brightness), Some(current)) => {
+                self.unlocked = Some(current);
+                Some(from_basis_points(brightness, self.max * self.scale))
+            }
+            (false, _, Some(_)) => self.unlocked.take(),
+            _ => None,
+        };
+        if let Some(desired) = desired {
+            self.update_target(desired);
+        }
     }
 
     fn update_target(&mut self, desired: u64) {
+        let desired = desired.max(self.min * self.scale);
         match (&self.target, self.current) {
             (Some(old_target), _) if old_target.desired == desired => (),
             (_, Some(current)) if desired == current => (),
@@ -94,6 +360,10 @@ impl Controller {
                     -((current - desired).div_ceil(TRANSITION_MAX_MS) as i64)
                 };
                 self.target = Some(Target { desired, step });
+                control::report(Update::Brightness {
+                    output_name: self.output_name.clone(),
+                    brightness: to_basis_points(desired, self.max * self.scale),
+                });
             }
             _ => unreachable!("Current value cannot be None at this point"),
         };
@@ -104,28 +374,66 @@ impl Controller {
             (Some(target), Some(current)) => {
                 if target.reached(current) {
                     self.target = None;
+                    self.last_change = Some(Instant::now());
                 } else {
-                    let new_value = current.saturating_add_signed(target.step);
-                    match self.brightness.set(new_value) {
-                        Ok(new_value) => self.current = Some(new_value),
-                        Err(err) => log::error!(
-                            "Unable to set brightness to value '{}': {:?}",
-                            new_value,
-                            err
-                        ),
+                    let new_value = match current.saturating_add_signed(target.step) {
+                        value if target.step > 0 => value.min(target.desired),
+                        value => value.max(target.desired),
                     };
+
+                    // Only write when the hardware level actually changes, and coalesce writes
+                    // for slow devices, but always write the final value
+                    let new_level = (new_value + self.scale / 2) / self.scale;
+                    let throttled = new_value != target.desired
+                        && self
+                            .last_write
+                            .is_some_and(|last_write| last_write.elapsed() < self.write_interval);
+                    if Some(new_level) == self.hardware || throttled {
+                        self.current = Some(new_value);
+                    } else {
+                        self.write(new_value, new_level);
+                    }
                     thread::sleep(Duration::from_millis(TRANSITION_STEP_MS));
                 }
             }
             _ => unreachable!("Current and target values cannot be None at this point"),
         }
     }
+
+    /// Moves the given percent of the way to the target at once, ignoring the write and change
+    /// intervals, then the transition goes on as usual.
+    fn jump(&mut self, head_start: u8) {
+        if let (Some(target), Some(current)) = (self.target, self.current) {
+            let distance = target.desired as i64 - current as i64;
+            let new_value = current.saturating_add_signed(distance * head_start as i64 / 100);
+            let new_level = (new_value + self.scale / 2) / self.scale;
+            if Some(new_level) != self.hardware {
+                self.write(new_value, new_level);
+            }
+            self.last_change = None;
+        }
+    }
+
+    fn write(&mut self, new_value: u64, new_level: u64) {
+        match self.brightness.set(new_level) {
+            Ok(new_level) => {
+                self.current = Some(new_value);
+                self.hardware = Some(new_level);
+                self.last_write = Some(Instant::now());
+            }
+            Err(err) => log::error!(
+                "Unable to set brightness to value '{}': {:?}",
+                new_level,
+                err
+            ),
+        };
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::brightness::MockBrightness;
+    use crate::brightness::{MockBrightness, BASIS_POINTS};
     use mockall::predicate;
     use std::error::Error;
     use std::sync::mpsc;
@@ -136,10 +444,39 @@ mod tests {
     }
 
     fn setup(brightness_mock: MockBrightness) -> (Controller, Sender<u64>, Receiver<u64>) {
+        let (controller, prediction_tx, user_rx, _) = setup_with_lock(brightness_mock, None);
+        (controller, prediction_tx, user_rx)
+    }
+
+    fn setup_with_lock(
+        mut brightness_mock: MockBrightness,
+        locked_brightness: Option<u64>,
+    ) -> (Controller, Sender<u64>, Receiver<u64>, Sender<Command>) {
+        // Keep raw values equal to basis points, to focus on the controller logic
+        brightness_mock.expect_max().return_const(BASIS_POINTS);
+        brightness_mock.expect_min().return_const(0u64);
+        brightness_mock
+            .expect_min_write_interval()
+            .return_const(Duration::ZERO);
+        brightness_mock
+            .expect_min_change_interval()
+            .return_const(Duration::ZERO);
         let (user_tx, user_rx) = mpsc::channel();
         let (prediction_tx, prediction_rx) = mpsc::channel();
-        let controller = Controller::new(Box::new(brightness_mock), user_tx, prediction_rx);
-        (controller, prediction_tx, user_rx)
+        let (command_tx, command_rx) = mpsc::channel();
+        let (capture_tx, _) = mpsc::channel();
+        let controller = Controller::new(
+            "eDP-1",
+            Box::new(brightness_mock),
+            user_tx,
+            prediction_rx,
+            command_rx,
+            capture_tx,
+            locked_brightness,
+            0,
+            false,
+        );
+        (controller, prediction_tx, user_rx, command_tx)
     }
 
     #[test]
@@ -334,4 +671,420 @@ mod tests {
         assert_eq!(true, target(10, -1).reached(10));
         assert_eq!(false, target(10, -1).reached(11));
     }
+
+    #[test]
+    fn test_step_converts_basis_points() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_max().return_const(100u64);
+        brightness_mock.expect_min().return_const(0u64);
+        brightness_mock
+            .expect_min_write_interval()
+            .return_const(Duration::ZERO);
+        brightness_mock
+            .expect_min_change_interval()
+            .return_const(Duration::ZERO);
+        brightness_mock.expect_get().times(2).returning(|| Ok(42));
+        brightness_mock.expect_set().returning(Ok);
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (_, command_rx) = mpsc::channel();
+        let mut controller = Controller::new(
+            "eDP-1",
+            Box::new(brightness_mock),
+            user_tx,
+            prediction_rx,
+            command_rx,
+            mpsc::channel().0,
+            None,
+            0,
+            false,
+        );
+
+        // user brightness is reported in basis points...
+        controller.step();
+        assert_eq!(4200, user_rx.try_recv()?);
+
+        // ... and predictions are converted back to (logical) raw values
+        prediction_tx.send(3700)?;
+        controller.step();
+        assert_eq!(Some(target(370, -1)), controller.target);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_transition_coarse_brightness_writes_only_changed_levels() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_max().return_const(10u64);
+        brightness_mock.expect_min().return_const(0u64);
+        brightness_mock
+            .expect_min_write_interval()
+            .return_const(Duration::ZERO);
+        brightness_mock
+            .expect_min_change_interval()
+            .return_const(Duration::ZERO);
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(4))
+            .times(1)
+            .returning(Ok);
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(5))
+            .times(1)
+            .returning(Ok);
+        let (user_tx, _user_rx) = mpsc::channel();
+        let (_prediction_tx, prediction_rx) = mpsc::channel();
+        let (_, command_rx) = mpsc::channel();
+        let mut controller = Controller::new(
+            "eDP-1",
+            Box::new(brightness_mock),
+            user_tx,
+            prediction_rx,
+            command_rx,
+            mpsc::channel().0,
+            None,
+            0,
+            false,
+        );
+
+        // brightness level 3 of 10 is tracked in finer logical steps...
+        controller.update_current(3);
+        assert_eq!(Some(300), controller.current);
+
+        // ... so the transition to level 5 is smooth, but only writes levels that change
+        controller.update_target(500);
+        while controller.target.is_some() {
+            controller.transition();
+        }
+
+        assert_eq!(Some(500), controller.current);
+        assert_eq!(Some(5), controller.hardware);
+    }
+
+    #[test]
+    fn test_step_ignores_user_and_predictor_while_locked() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        let mut values = vec![42, 42, 80].into_iter();
+        brightness_mock
+            .expect_get()
+            .times(3)
+            .returning(move || Ok(values.next().unwrap()));
+        let (mut controller, prediction_tx, user_rx, command_tx) =
+            setup_with_lock(brightness_mock, None);
+
+        // the initial value is reported even if the session is locked...
+        command_tx.send(Command::Lock(true))?;
+        controller.step();
+        assert_eq!(42, user_rx.try_recv()?);
+
+        // ... but predictions are ignored while locked...
+        prediction_tx.send(37)?;
+        controller.step();
+        assert_eq!(None, controller.target);
+
+        // ... and so are brightness changes made on the lock screen
+        controller.step();
+        assert_eq!(Some(80), controller.current);
+        assert_eq!(true, user_rx.try_recv().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_locked_sets_locked_brightness_and_restores_it() {
+        let (mut controller, _, _, _) = setup_with_lock(MockBrightness::new(), Some(1000));
+        controller.current = Some(4200);
+
+        controller.update_locked(true);
+        assert_eq!(Some(target(1000, -16)), controller.target);
+
+        controller.current = Some(1000);
+        controller.target = None;
+
+        controller.update_locked(false);
+        assert_eq!(Some(target(4200, 16)), controller.target);
+    }
+
+    #[test]
+    fn test_transition_coalesces_writes_to_slow_devices() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(1))
+            .times(1)
+            .returning(Ok);
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(100))
+            .times(1)
+            .returning(Ok);
+        let (mut controller, _, _) = setup(brightness_mock);
+        controller.write_interval = Duration::from_secs(3600);
+        controller.current = Some(0);
+        controller.hardware = Some(0);
+
+        // the first step is written right away, the rest is skipped until the target is reached
+        controller.update_target(100);
+        while controller.target.is_some() {
+            controller.transition();
+        }
+
+        assert_eq!(Some(100), controller.current);
+        assert_eq!(Some(100), controller.hardware);
+    }
+
+    #[test]
+    fn test_step_waits_before_starting_next_transition() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().returning(|| Ok(50));
+        brightness_mock.expect_set().times(1).returning(Ok);
+        let (mut controller, prediction_tx, _user_rx) = setup(brightness_mock);
+        controller.change_interval = Duration::from_secs(3600);
+        controller.step();
+        controller.last_change = Some(Instant::now());
+
+        // the new target is kept, but not approached while the previous change is recent...
+        prediction_tx.send(80)?;
+        assert_eq!(false, controller.step());
+        assert_eq!(Some(target(80, 1)), controller.target);
+        assert_eq!(Some(50), controller.current);
+
+        // ... until enough time passes
+        controller.change_interval = Duration::ZERO;
+        assert_eq!(true, controller.step());
+        assert_eq!(Some(51), controller.current);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_jumps_ahead_after_luma_jump() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().returning(|| Ok(80));
+        brightness_mock.expect_set().returning(Ok);
+        let (mut controller, prediction_tx, _user_rx, command_tx) =
+            setup_with_lock(brightness_mock, None);
+        controller.step();
+        controller.write_interval = Duration::from_secs(3600);
+        controller.change_interval = Duration::from_secs(3600);
+        controller.last_change = Some(Instant::now());
+
+        // half of the way is set at once, despite the previous change being recent...
+        prediction_tx.send(20)?;
+        command_tx.send(Command::LumaJump(50))?;
+        controller.step();
+        assert_eq!(Some(50), controller.hardware);
+        assert_eq!(Some(target(20, -1)), controller.target);
+
+        // ... then the transition goes on as usual
+        assert_eq!(None, controller.head_start);
+        assert_eq!(None, controller.last_change);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_ignores_luma_jump_without_new_prediction() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().returning(|| Ok(80));
+        brightness_mock.expect_set().returning(Ok);
+        let (mut controller, prediction_tx, _user_rx, command_tx) =
+            setup_with_lock(brightness_mock, None);
+        controller.step();
+        controller.write_interval = Duration::from_secs(3600);
+        controller.change_interval = Duration::from_secs(3600);
+        controller.last_change = Some(Instant::now());
+        prediction_tx.send(20)?;
+        controller.step();
+
+        // no jump towards a target predicted earlier...
+        command_tx.send(Command::LumaJump(50))?;
+        controller.step();
+        assert_eq!(Some(80), controller.hardware);
+        assert_eq!(None, controller.head_start);
+
+        // ... nor while learning is suspended
+        command_tx.send(Command::Pause(true))?;
+        prediction_tx.send(30)?;
+        command_tx.send(Command::LumaJump(50))?;
+        controller.step();
+        assert_eq!(Some(80), controller.hardware);
+        assert_eq!(None, controller.head_start);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_holds_brightness_while_screen_off() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().returning(|| Ok(50));
+        brightness_mock.expect_set().returning(Ok);
+        let (mut controller, prediction_tx, user_rx, command_tx) =
+            setup_with_lock(brightness_mock, None);
+        controller.step();
+        user_rx.try_recv()?;
+
+        // predictions made for black frames are not applied...
+        command_tx.send(Command::ScreenOff(true))?;
+        prediction_tx.send(10)?;
+        controller.step();
+        assert_eq!(None, controller.target);
+
+        // ... until the screen is back on, with the last prediction
+        controller.execute(Command::ScreenOff(false));
+        assert_eq!(Some(target(10, -1)), controller.target);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_target_keeps_above_min() {
+        let (mut controller, _, _) = setup(MockBrightness::new());
+        controller.min = 20;
+        controller.current = Some(50);
+
+        controller.update_target(5);
+        assert_eq!(Some(target(20, -1)), controller.target);
+
+        // Nothing to do when already at the minimum
+        controller.target = None;
+        controller.current = Some(20);
+        controller.update_target(0);
+        assert_eq!(None, controller.target);
+    }
+
+    #[test]
+    fn test_waiting_time_is_shorter_after_brightness_key() {
+        let (mut controller, _, _) = setup(MockBrightness::new());
+        assert_eq!(
+            Duration::from_millis(WAITING_SLEEP_MS),
+            controller.waiting_time()
+        );
+
+        controller.execute(Command::Key);
+        assert_eq!(
+            Duration::from_millis(KEY_SLEEP_MS),
+            controller.waiting_time()
+        );
+
+        controller.last_key = Instant::now().checked_sub(Duration::from_millis(KEY_WATCH_MS));
+        assert_eq!(
+            Duration::from_millis(WAITING_SLEEP_MS),
+            controller.waiting_time()
+        );
+    }
+
+    #[test]
+    fn test_exposure_bias_offsets_predictions_within_range() {
+        let (mut controller, _, _) = setup(MockBrightness::new());
+        controller.current = Some(5000);
+        controller.prediction = Some(3000);
+
+        // the last prediction is offset as soon as the bias changes...
+        controller.execute(Command::ExposureBias(1000));
+        assert_eq!(4000, controller.target.unwrap().desired);
+        controller.execute(Command::ExposureBias(-1000));
+        assert_eq!(2000, controller.target.unwrap().desired);
+
+        // ... the result never goes out of range...
+        controller.execute(Command::ExposureBias(-5000));
+        assert_eq!(0, controller.target.unwrap().desired);
+        controller.execute(Command::ExposureBias(9000));
+        assert_eq!(BASIS_POINTS, controller.target.unwrap().desired);
+
+        // ... and nothing changes while paused
+        controller.execute(Command::Pause(true));
+        controller.execute(Command::ExposureBias(0));
+        assert_eq!(BASIS_POINTS, controller.target.unwrap().desired);
+    }
+
+    #[test]
+    fn test_disable_ignores_predictions_until_enabled() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().times(2).returning(|| Ok(5000));
+        let (mut controller, prediction_tx, _user_rx) = setup(brightness_mock);
+        let (capture_tx, capture_rx) = mpsc::channel();
+        controller.capture_tx = capture_tx;
+        controller.step();
+        controller.update_target(7000);
+
+        // the transition in progress stops, predictions are ignored and capturing is held off
+        // while disabled...
+        controller.execute(Command::Disable(true));
+        assert_eq!(None, controller.target);
+        prediction_tx.send(3000)?;
+        controller.step();
+        assert_eq!(None, controller.target);
+        assert_eq!(Ok(true), capture_rx.try_recv());
+
+        // ... and the last one is applied as soon as it is enabled again
+        controller.execute(Command::Disable(false));
+        assert_eq!(3000, controller.target.unwrap().desired);
+        assert_eq!(Ok(false), capture_rx.try_recv());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_inhibit_holds_brightness_until_released() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock.expect_get().times(2).returning(|| Ok(5000));
+        let (mut controller, prediction_tx, _user_rx) = setup(brightness_mock);
+        controller.step();
+
+        // brightness stays as is while inhibited, e.g. during a screencast...
+        controller.execute(Command::Inhibit(true));
+        prediction_tx.send(3000)?;
+        controller.step();
+        assert_eq!(None, controller.target);
+
+        // ... and follows the last prediction once released, regardless of a pause being lifted
+        controller.execute(Command::Pause(false));
+        assert_eq!(None, controller.target);
+        controller.execute(Command::Inhibit(false));
+        assert_eq!(3000, controller.target.unwrap().desired);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_learns_brightness_without_exposure_bias() -> Result<(), Box<dyn Error>> {
+        let mut brightness_mock = MockBrightness::new();
+        let mut values = vec![5000, 500].into_iter();
+        brightness_mock
+            .expect_get()
+            .times(2)
+            .returning(move || Ok(values.next().unwrap()));
+        let (mut controller, _, user_rx) = setup(brightness_mock);
+        controller.exposure_bias = 1000;
+
+        controller.step();
+        assert_eq!(4000, user_rx.try_recv()?);
+
+        // values below the bias are learned as the lowest brightness
+        controller.step();
+        assert_eq!(0, user_rx.try_recv()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_sets_initial_brightness() {
+        let mut brightness_mock = MockBrightness::new();
+        brightness_mock
+            .expect_set()
+            .with(predicate::eq(42))
+            .times(1)
+            .returning(Ok);
+        let (mut controller, _, _user_rx) = setup(brightness_mock);
+
+        // nothing to restore when the brightness didn't change...
+        controller.update_current(42);
+        controller.restore();
+
+        // ... otherwise the level found at startup is set back
+        controller.update_current(60);
+        controller.restore();
+    }
 }
@@ -0,0 +1,47 @@
+use super::Brightness;
+use crate::error::WlumaError;
+use crate::stats::{self, Stat};
+use std::time::Duration;
+
+/// Counts the values that reach the device, wrappers above it might skip some of the writes.
+pub struct Counted {
+    brightness: Box<dyn Brightness + Send>,
+    output_name: String,
+}
+
+impl Counted {
+    pub fn new(brightness: Box<dyn Brightness + Send>, output_name: &str) -> Self {
+        Self {
+            brightness,
+            output_name: output_name.to_string(),
+        }
+    }
+}
+
+impl Brightness for Counted {
+    fn get(&mut self) -> Result<u64, WlumaError> {
+        self.brightness.get()
+    }
+
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
+        let written = self.brightness.set(value)?;
+        stats::add(&self.output_name, Stat::Write);
+        Ok(written)
+    }
+
+    fn max(&self) -> u64 {
+        self.brightness.max()
+    }
+
+    fn min(&self) -> u64 {
+        self.brightness.min()
+    }
+
+    fn min_write_interval(&self) -> Duration {
+        self.brightness.min_write_interval()
+    }
+
+    fn min_change_interval(&self) -> Duration {
+        self.brightness.min_change_interval()
+    }
+}
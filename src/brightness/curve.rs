@@ -0,0 +1,167 @@
+use super::mapped::Mapped;
+use super::{from_basis_points, to_basis_points, Brightness};
+use crate::config::Curve;
+use crate::error::WlumaError;
+use std::time::Duration;
+
+/// Maps brightness to raw values along a response curve, for devices whose raw steps are not
+/// perceptually even, e.g. the lowest few already look bright.
+pub struct Curved {
+    mapped: Mapped,
+    curve: Curve,
+}
+
+impl Curved {
+    pub fn new(brightness: Box<dyn Brightness + Send>, curve: Curve) -> Self {
+        Self {
+            mapped: Mapped::new(brightness),
+            curve,
+        }
+    }
+}
+
+impl Brightness for Curved {
+    fn get(&mut self) -> Result<u64, WlumaError> {
+        let max = self.max();
+        self.mapped.get(|raw| invert(&self.curve, raw, max))
+    }
+
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
+        let max = self.max();
+        let raw = apply(&self.curve, value, max);
+        self.mapped
+            .set(value, raw, |written| invert(&self.curve, written, max))
+    }
+
+    fn max(&self) -> u64 {
+        self.mapped.brightness.max()
+    }
+
+    fn min(&self) -> u64 {
+        invert(&self.curve, self.mapped.brightness.min(), self.max())
+    }
+
+    fn min_write_interval(&self) -> Duration {
+        self.mapped.brightness.min_write_interval()
+    }
+
+    fn min_change_interval(&self) -> Duration {
+        self.mapped.brightness.min_change_interval()
+    }
+}
+
+/// Raw value for the brightness, both in the units of the device.
+fn apply(curve: &Curve, value: u64, max: u64) -> u64 {
+    match curve {
+        Curve::Linear => value.min(max),
+        Curve::Exponential(exponent) => power(value, max, *exponent),
+        Curve::Map(points) => from_basis_points(
+            interpolate(to_basis_points(value, max), points.iter().copied()),
+            max,
+        ),
+    }
+}
+
+/// Brightness for the raw value, both in the units of the device.
+fn invert(curve: &Curve, raw: u64, max: u64) -> u64 {
+    match curve {
+        Curve::Linear => raw.min(max),
+        Curve::Exponential(exponent) => power(raw, max, 1.0 / exponent),
+        Curve::Map(points) => from_basis_points(
+            interpolate(
+                to_basis_points(raw, max),
+                points.iter().map(|&(brightness, raw)| (raw, brightness)),
+            ),
+            max,
+        ),
+    }
+}
+
+pub(super) fn power(value: u64, max: u64, exponent: f64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    (max as f64 * (value.min(max) as f64 / max as f64).powf(exponent)).round() as u64
+}
+
+/// Follows straight lines between the points, which are sorted by `x`, and stays flat outside
+/// of them.
+fn interpolate(x: u64, points: impl Iterator<Item = (u64, u64)>) -> u64 {
+    let mut previous: Option<(u64, u64)> = None;
+    for (x1, y1) in points {
+        if x1 >= x {
+            return match previous {
+                Some((x0, y0)) if x1 > x0 => {
+                    let offset = (x - x0) as f64 * (y1 as f64 - y0 as f64) / (x1 - x0) as f64;
+                    (y0 as f64 + offset).round() as u64
+                }
+                _ => y1,
+            };
+        }
+        previous = Some((x1, y1));
+    }
+    previous.map_or(x, |(_, y)| y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::MockBrightness;
+    use mockall::predicate;
+
+    #[test]
+    fn test_power() {
+        assert_eq!(0, power(0, 255, 2.2));
+        assert_eq!(255, power(255, 255, 2.2));
+        assert_eq!(56, power(128, 255, 2.2));
+        assert_eq!(128, power(56, 255, 1.0 / 2.2));
+        assert_eq!(42, power(42, 255, 1.0));
+    }
+
+    #[test]
+    fn test_map() {
+        let curve = Curve::Map(vec![(1000, 0), (5000, 2000), (10000, 10000)]);
+
+        assert_eq!(0, apply(&curve, 5, 100));
+        assert_eq!(10, apply(&curve, 30, 100));
+        assert_eq!(20, apply(&curve, 50, 100));
+        assert_eq!(60, apply(&curve, 75, 100));
+
+        // Raw values below the curve map back to where it starts
+        assert_eq!(10, invert(&curve, 0, 100));
+        assert_eq!(30, invert(&curve, 10, 100));
+        assert_eq!(75, invert(&curve, 60, 100));
+    }
+
+    #[test]
+    fn test_interpolate_stays_flat_outside_of_points() {
+        let points = [(2000, 1000), (8000, 4000)];
+        assert_eq!(1000, interpolate(0, points.into_iter()));
+        assert_eq!(2500, interpolate(5000, points.into_iter()));
+        assert_eq!(4000, interpolate(10000, points.into_iter()));
+    }
+
+    #[test]
+    fn test_set_maps_clamped_values_back_through_the_curve() {
+        let mut brightness = MockBrightness::new();
+        brightness.expect_max().return_const(100u64);
+        brightness.expect_min().return_const(30u64);
+        // The device doesn't go below its minimum
+        brightness
+            .expect_set()
+            .with(predicate::eq(10))
+            .times(1)
+            .returning(|_| Ok(30));
+        brightness
+            .expect_set()
+            .with(predicate::eq(60))
+            .times(1)
+            .returning(Ok);
+        let curve = Curve::Map(vec![(1000, 0), (5000, 2000), (10000, 10000)]);
+        let mut curved = Curved::new(Box::new(brightness), curve);
+
+        assert_eq!(56, curved.min());
+        assert_eq!(56, curved.set(30).unwrap());
+        assert_eq!(75, curved.set(75).unwrap());
+    }
+}
@@ -1,68 +1,300 @@
-use ddc_hi::{Ddc, Display, FeatureCode};
+use crate::error::WlumaError;
+use ddc_hi::{Ddc, Display, FeatureCode, VcpValue};
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use std::cell::RefCell;
-use std::error::Error;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::path::PathBuf;
 use std::sync::Mutex;
 
 lazy_static! {
     static ref DDC_MUTEX: Mutex<()> = Mutex::new(());
 }
 
-const DDC_BRIGHTNESS_FEATURE: FeatureCode = 0x10;
+/// VCP feature code of contrast.
+pub const DDC_CONTRAST_FEATURE: FeatureCode = 0x12;
+const DRM_PATH: &str = "/sys/class/drm";
+const EDID_BASE_BLOCK_SIZE: usize = 128;
+const LOWERED_CONTRAST_FILE: &str = "lowered-contrast.yaml";
 
 pub struct DdcUtil {
+    name: String,
     display: RefCell<Display>,
+    feature: FeatureCode,
+    contrast_stage: Option<ContrastStage>,
+    /// Whether the normal contrast is saved, which it is while contrast is lowered.
+    contrast_saved: bool,
     min_brightness: u64,
     max_brightness: u64,
 }
 
+/// Keeps dimming the screen by lowering its contrast, once the brightness reaches its minimum.
+///
+/// The combined value has `levels` contrast steps at the bottom, followed by the brightness range.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ContrastStage {
+    /// Contrast the screen had at startup, which is kept while brightness is above its minimum.
+    normal: u64,
+    levels: u64,
+}
+
+impl ContrastStage {
+    fn new(normal: u64) -> Self {
+        // Lowering contrast all the way makes the screen unreadable
+        Self {
+            normal,
+            levels: normal / 2,
+        }
+    }
+
+    /// Converts combined value into brightness and contrast.
+    fn split(&self, value: u64, min_brightness: u64) -> (u64, u64) {
+        match value.checked_sub(self.levels) {
+            Some(brightness) => (brightness.max(min_brightness), self.normal),
+            None => (min_brightness, self.normal - (self.levels - value)),
+        }
+    }
+
+    /// Converts brightness and contrast into combined value.
+    fn combine(&self, brightness: u64, contrast: u64, min_brightness: u64) -> u64 {
+        if brightness > min_brightness || contrast >= self.normal {
+            self.levels + brightness
+        } else {
+            self.levels - (self.normal - contrast).min(self.levels)
+        }
+    }
+}
+
 impl DdcUtil {
-    pub fn new(name: &str, min_brightness: u64) -> Result<Self, Box<dyn Error>> {
-        let mut display = find_display_by_name(name, true)
+    pub fn new(
+        name: &str,
+        min_brightness: u64,
+        max_brightness: Option<u64>,
+        feature: FeatureCode,
+        contrast_dimming: bool,
+    ) -> Result<Self, WlumaError> {
+        // Displays are discovered in parallel, don't let them probe the same buses at once
+        let _lock = DDC_MUTEX
+            .lock()
+            .expect("Unable to acquire exclusive access to DDC API");
+        let mut display = find_display_by_edid(name)
+            .or_else(|| find_display_by_name(name, true))
             .or_else(|| find_display_by_name(name, false))
-            .ok_or("Unable to find display")?;
-        let max_brightness = get_max_brightness(&mut display)?;
+            .ok_or_else(|| WlumaError::brightness("Unable to find display"))?;
+        let max_brightness = match max_brightness {
+            Some(max_brightness) => max_brightness,
+            None => get_feature(&mut display, feature)?.maximum() as u64,
+        };
+        // Contrast left lowered, e.g. by a crash, is not the one to go back to
+        let saved = load_lowered_contrasts().remove(name);
+        let contrast_stage = if contrast_dimming {
+            let contrast = match saved {
+                Some(contrast) => contrast,
+                None => get_feature(&mut display, DDC_CONTRAST_FEATURE)?.value() as u64,
+            };
+            log::debug!(
+                "Dimming '{}' below minimum brightness from contrast {}",
+                name,
+                contrast
+            );
+            Some(ContrastStage::new(contrast))
+        } else {
+            None
+        };
 
-        Ok(Self {
+        let mut ddcutil = Self {
+            name: name.to_string(),
             display: RefCell::new(display),
+            feature,
+            contrast_stage,
+            contrast_saved: saved.is_some(),
             min_brightness,
             max_brightness,
-        })
+        };
+        // Dimming was turned off since, the contrast it left is restored once
+        if let (None, Some(contrast)) = (contrast_stage, saved) {
+            log::debug!("Restoring contrast {} of '{}'", contrast, name);
+            ddcutil.set_value(DDC_CONTRAST_FEATURE, contrast)?;
+            ddcutil.save_contrast(None);
+        }
+        Ok(ddcutil)
+    }
+
+    /// Saves the normal contrast while it's lowered, or forgets it once it's not.
+    fn save_contrast(&mut self, lowered: Option<u64>) {
+        if lowered.is_some() == self.contrast_saved {
+            return;
+        }
+        let mut contrasts = load_lowered_contrasts();
+        match lowered {
+            Some(contrast) => contrasts.insert(self.name.clone(), contrast),
+            None => contrasts.remove(&self.name),
+        };
+        match save_lowered_contrasts(&contrasts) {
+            Ok(()) => self.contrast_saved = lowered.is_some(),
+            Err(err) => log::warn!("Unable to save contrast of '{}': {}", self.name, err),
+        }
+    }
+
+    fn get_value(&self, feature: FeatureCode) -> Result<u64, WlumaError> {
+        Ok(get_feature(&mut self.display.borrow_mut(), feature)?.value() as u64)
+    }
+
+    fn set_value(&self, feature: FeatureCode, value: u64) -> Result<(), WlumaError> {
+        self.display
+            .borrow_mut()
+            .handle
+            .set_vcp_feature(feature, value as u16)
+            .map_err(WlumaError::brightness)
     }
 }
 
 impl super::Brightness for DdcUtil {
-    fn get(&mut self) -> Result<u64, Box<dyn Error>> {
+    fn get(&mut self) -> Result<u64, WlumaError> {
         let _lock = DDC_MUTEX
             .lock()
             .expect("Unable to acquire exclusive access to DDC API");
-        Ok(self
-            .display
-            .borrow_mut()
-            .handle
-            .get_vcp_feature(DDC_BRIGHTNESS_FEATURE)?
-            .value() as u64)
+        let brightness = self.get_value(self.feature)?;
+
+        match self.contrast_stage {
+            // Contrast is only lowered at minimum brightness, avoid reading it otherwise
+            Some(stage) if brightness <= self.min_brightness => {
+                let contrast = self.get_value(DDC_CONTRAST_FEATURE)?;
+                Ok(stage.combine(brightness, contrast, self.min_brightness))
+            }
+            Some(stage) => Ok(stage.combine(brightness, stage.normal, self.min_brightness)),
+            None => Ok(brightness),
+        }
     }
 
-    fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>> {
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
         let _lock = DDC_MUTEX
             .lock()
             .expect("Unable to acquire exclusive access to DDC API");
-        let value = value.clamp(self.min_brightness, self.max_brightness);
-        self.display
-            .borrow_mut()
-            .handle
-            .set_vcp_feature(DDC_BRIGHTNESS_FEATURE, value as u16)?;
-        Ok(value)
+
+        match self.contrast_stage {
+            Some(stage) => {
+                let (brightness, contrast) =
+                    stage.split(value.min(self.max()), self.min_brightness);
+                let brightness = brightness.min(self.max_brightness);
+                // Saved before it's lowered, so that it's known even if wluma exits right after
+                self.save_contrast((contrast < stage.normal).then_some(stage.normal));
+                self.set_value(self.feature, brightness)?;
+                self.set_value(DDC_CONTRAST_FEATURE, contrast)?;
+                if contrast >= stage.normal {
+                    self.save_contrast(None);
+                }
+                Ok(stage.combine(brightness, contrast, self.min_brightness))
+            }
+            None => {
+                let value = value.clamp(self.min(), self.max_brightness);
+                self.set_value(self.feature, value)?;
+                Ok(value)
+            }
+        }
+    }
+
+    fn max(&self) -> u64 {
+        match self.contrast_stage {
+            Some(stage) => stage.levels + self.max_brightness,
+            None => self.max_brightness,
+        }
+    }
+
+    fn min(&self) -> u64 {
+        match self.contrast_stage {
+            // Contrast keeps dimming below the minimum brightness
+            Some(_) => 0,
+            None => self.min_brightness.min(self.max_brightness),
+        }
     }
 }
 
-fn get_max_brightness(display: &mut Display) -> Result<u64, Box<dyn Error>> {
-    Ok(display
+/// Normal contrast of the displays whose contrast is lowered, by name.
+fn load_lowered_contrasts() -> BTreeMap<String, u64> {
+    lowered_contrasts_path()
+        .ok()
+        .and_then(|path| File::open(path).ok())
+        .and_then(|file| serde_yaml::from_reader(file).ok())
+        .unwrap_or_default()
+}
+
+fn save_lowered_contrasts(contrasts: &BTreeMap<String, u64>) -> Result<(), WlumaError> {
+    let file = File::create(lowered_contrasts_path()?).map_err(WlumaError::brightness)?;
+    serde_yaml::to_writer(file, contrasts).map_err(WlumaError::brightness)
+}
+
+fn lowered_contrasts_path() -> Result<PathBuf, WlumaError> {
+    Ok(xdg::BaseDirectories::with_prefix("wluma")
+        .map_err(WlumaError::brightness)?
+        .create_data_directory("")
+        .map_err(WlumaError::brightness)?
+        .join(LOWERED_CONTRAST_FILE))
+}
+
+fn get_feature(display: &mut Display, feature: FeatureCode) -> Result<VcpValue, WlumaError> {
+    display
         .handle
-        .get_vcp_feature(DDC_BRIGHTNESS_FEATURE)?
-        .maximum() as u64)
+        .get_vcp_feature(feature)
+        .map_err(WlumaError::brightness)
+}
+
+/// When the config name is a DRM connector (e.g. `DP-1`), binds to the display with exactly the same EDID,
+/// which allows telling apart identical monitors.
+fn find_display_by_edid(name: &str) -> Option<Display> {
+    let edid = read_connector_edid(name)?;
+
+    let mut displays = ddc_hi::Display::enumerate()
+        .into_iter()
+        .filter(|display| {
+            display
+                .info
+                .edid_data
+                .as_deref()
+                .is_some_and(|data| edid_matches(data, &edid))
+        })
+        .collect_vec();
+
+    match displays.len() {
+        0 => {
+            log::debug!("No display matches EDID of connector '{}'", name);
+            None
+        }
+        1 => {
+            log::debug!("Using display with EDID of connector '{}'", name);
+            displays.pop()
+        }
+        _ => {
+            log::error!(
+                "Several displays match EDID of connector '{}', falling back to matching by name",
+                name
+            );
+            None
+        }
+    }
+}
+
+fn read_connector_edid(connector: &str) -> Option<Vec<u8>> {
+    fs::read_dir(DRM_PATH)
+        .ok()?
+        .flatten()
+        .filter(|entry| connector_name(&entry.file_name().to_string_lossy()) == Some(connector))
+        .find_map(|entry| fs::read(entry.path().join("edid")).ok())
+        .filter(|edid| edid.len() >= EDID_BASE_BLOCK_SIZE)
+}
+
+fn connector_name(drm_entry: &str) -> Option<&str> {
+    drm_entry
+        .strip_prefix("card")?
+        .split_once('-')
+        .map(|(_, connector)| connector)
+}
+
+fn edid_matches(a: &[u8], b: &[u8]) -> bool {
+    a.len() >= EDID_BASE_BLOCK_SIZE
+        && b.len() >= EDID_BASE_BLOCK_SIZE
+        && a[..EDID_BASE_BLOCK_SIZE] == b[..EDID_BASE_BLOCK_SIZE]
 }
 
 fn find_display_by_name(name: &str, check_caps: bool) -> Option<Display> {
@@ -107,3 +339,57 @@ fn find_display_by_name(name: &str, check_caps: bool) -> Option<Display> {
             .map(|_| display)
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connector_name() {
+        assert_eq!(Some("DP-1"), connector_name("card0-DP-1"));
+        assert_eq!(Some("eDP-1"), connector_name("card1-eDP-1"));
+        assert_eq!(None, connector_name("card0"));
+        assert_eq!(None, connector_name("renderD128"));
+    }
+
+    #[test]
+    fn test_contrast_stage() {
+        let stage = ContrastStage::new(80);
+        assert_eq!(40, stage.levels);
+
+        // Above the contrast levels only brightness changes...
+        assert_eq!((60, 80), stage.split(100, 1));
+        assert_eq!(100, stage.combine(60, 80, 1));
+
+        // ... and below them, contrast is lowered at minimum brightness
+        assert_eq!((1, 80), stage.split(41, 1));
+        assert_eq!((1, 70), stage.split(30, 1));
+        assert_eq!(30, stage.combine(1, 70, 1));
+        assert_eq!((1, 40), stage.split(0, 1));
+        assert_eq!(0, stage.combine(1, 40, 1));
+
+        // Contrast lowered beyond the stage by someone else is the darkest value
+        assert_eq!(0, stage.combine(1, 10, 1));
+
+        // Brightness below its minimum is reported as is
+        assert_eq!(40, stage.combine(0, 80, 1));
+    }
+
+    #[test]
+    fn test_edid_matches_base_block_only() {
+        let mut edid = vec![0u8; 256];
+        edid[12] = 42;
+        let mut other = edid.clone();
+
+        // Extension blocks are ignored, as some backends only read the base block
+        other[200] = 1;
+        assert!(edid_matches(&edid, &other[..EDID_BASE_BLOCK_SIZE]));
+        assert!(edid_matches(&edid, &other));
+
+        // Serial number is part of the base block
+        other[12] = 43;
+        assert!(!edid_matches(&edid, &other));
+
+        assert!(!edid_matches(&edid, &[]));
+    }
+}
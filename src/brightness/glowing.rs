@@ -0,0 +1,50 @@
+use super::{to_basis_points, Brightness};
+use crate::als::glow;
+use crate::error::WlumaError;
+use std::time::Duration;
+
+/// Lets the webcam ALS know how bright the screen is, to tell its glow apart from ambient light.
+pub struct Glowing {
+    brightness: Box<dyn Brightness + Send>,
+    output_name: String,
+}
+
+impl Glowing {
+    pub fn new(brightness: Box<dyn Brightness + Send>, output_name: &str) -> Self {
+        Self {
+            brightness,
+            output_name: output_name.to_string(),
+        }
+    }
+
+    fn record(&self, value: u64) -> u64 {
+        glow::set_brightness(&self.output_name, to_basis_points(value, self.max()));
+        value
+    }
+}
+
+impl Brightness for Glowing {
+    fn get(&mut self) -> Result<u64, WlumaError> {
+        self.brightness.get().map(|value| self.record(value))
+    }
+
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
+        self.brightness.set(value).map(|value| self.record(value))
+    }
+
+    fn max(&self) -> u64 {
+        self.brightness.max()
+    }
+
+    fn min(&self) -> u64 {
+        self.brightness.min()
+    }
+
+    fn min_write_interval(&self) -> Duration {
+        self.brightness.min_write_interval()
+    }
+
+    fn min_change_interval(&self) -> Duration {
+        self.brightness.min_change_interval()
+    }
+}
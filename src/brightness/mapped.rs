@@ -0,0 +1,87 @@
+use super::Brightness;
+use crate::error::WlumaError;
+
+/// Device whose raw values are mapped to and from brightness by the wrapper that owns it.
+pub(super) struct Mapped {
+    pub(super) brightness: Box<dyn Brightness + Send>,
+    /// Last raw value written, and the brightness it was written for.
+    last: Option<(u64, u64)>,
+}
+
+impl Mapped {
+    pub(super) fn new(brightness: Box<dyn Brightness + Send>) -> Self {
+        Self {
+            brightness,
+            last: None,
+        }
+    }
+
+    /// Brightness the device is at, mapping back with `invert` might not round to the value
+    /// that was written, so that one is kept while the device still has it.
+    pub(super) fn get(&mut self, invert: impl FnOnce(u64) -> u64) -> Result<u64, WlumaError> {
+        let raw = self.brightness.get()?;
+        match self.last {
+            Some((written, value)) if written == raw => Ok(value),
+            _ => {
+                self.last = None;
+                Ok(invert(raw))
+            }
+        }
+    }
+
+    /// Writes the raw value for the brightness, returns the brightness the device ended up at.
+    pub(super) fn set(
+        &mut self,
+        value: u64,
+        raw: u64,
+        invert: impl FnOnce(u64) -> u64,
+    ) -> Result<u64, WlumaError> {
+        let written = self.brightness.set(raw)?;
+        // The device might have clamped it, e.g. to its minimum
+        let value = if written == raw {
+            value
+        } else {
+            invert(written)
+        };
+        self.last = Some((written, value));
+        Ok(value)
+    }
+
+    /// Brightness that was written last, unless the device was changed since.
+    pub(super) fn last(&self) -> Option<u64> {
+        self.last.map(|(_, value)| value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::MockBrightness;
+    use mockall::predicate;
+
+    #[test]
+    fn test_get_returns_what_was_set() {
+        let mut brightness = MockBrightness::new();
+        brightness
+            .expect_set()
+            .with(predicate::eq(1))
+            .times(1)
+            .returning(Ok);
+        let mut values = vec![1, 2].into_iter();
+        brightness
+            .expect_get()
+            .times(2)
+            .returning(move || Ok(values.next().unwrap()));
+        let mut mapped = Mapped::new(Box::new(brightness));
+        let invert = |raw: u64| raw * 10 + 1;
+
+        // raw 1 maps back to 11, not to the 10 it was written for...
+        assert_eq!(10, mapped.set(10, 1, invert).unwrap());
+        assert_eq!(10, mapped.get(invert).unwrap());
+        assert_eq!(Some(10), mapped.last());
+
+        // ... but values changed by someone else are mapped back
+        assert_eq!(21, mapped.get(invert).unwrap());
+        assert_eq!(None, mapped.last());
+    }
+}
@@ -1,18 +1,109 @@
-use std::error::Error;
+use crate::error::WlumaError;
+use std::time::Duration;
 
 #[cfg(test)]
 use mockall::*;
 
 mod backlight;
 mod controller;
+mod counted;
+mod curve;
 mod ddcutil;
+mod glowing;
+mod mapped;
+mod oled;
 
-pub use backlight::Backlight;
+pub use backlight::{Backlight, DDCCI_FEATURE};
 pub use controller::Controller;
-pub use ddcutil::DdcUtil;
+pub use counted::Counted;
+pub use curve::Curved;
+pub use ddcutil::{DdcUtil, DDC_CONTRAST_FEATURE};
+pub use glowing::Glowing;
+pub use oled::Oled;
+
+/// Brightness exchanged with predictors is in basis points of the device's maximum,
+/// so that learned data doesn't depend on the backend or its raw units.
+pub const BASIS_POINTS: u64 = 10_000;
+
+/// Requests to the brightness controller that don't come from the predictor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// The session was locked or unlocked.
+    Lock(bool),
+    /// The system is about to sleep, or has resumed a while ago.
+    Sleep(bool),
+    /// Learning and predictions were paused or resumed on request.
+    Pause(bool),
+    /// Something asked to hold brightness still, e.g. while the screen is shared, or all such
+    /// requests were withdrawn.
+    Inhibit(bool),
+    /// Learning and predictions were disabled or enabled for this output only, on request.
+    Disable(bool),
+    /// Offset applied to predictions, in basis points.
+    ExposureBias(i64),
+    /// A brightness key was pressed, a change made by user is about to show up.
+    Key,
+    /// The screen looks turned off, or back on, learning and predictions are held meanwhile.
+    ScreenOff(bool),
+    /// Luma of the screen contents jumped, the given percent of the way to the next prediction
+    /// is set right away.
+    LumaJump(u8),
+}
 
 #[cfg_attr(test, automock)]
 pub trait Brightness {
-    fn get(&mut self) -> Result<u64, Box<dyn Error>>;
-    fn set(&mut self, value: u64) -> Result<u64, Box<dyn Error>>;
+    fn get(&mut self) -> Result<u64, WlumaError>;
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError>;
+    fn max(&self) -> u64;
+
+    /// Lowest value the device is set to, values below it are clamped.
+    fn min(&self) -> u64 {
+        0
+    }
+
+    /// Transitions skip intermediate values that would be written sooner than this after the
+    /// previous write, for devices that can't keep up with fast writes.
+    fn min_write_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    /// Transitions to a new brightness don't start sooner than this after the previous one
+    /// ended, for panels that flicker when it changes often.
+    fn min_change_interval(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+pub fn to_basis_points(raw: u64, max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    ((raw.min(max) as f64 * BASIS_POINTS as f64) / max as f64).round() as u64
+}
+
+pub fn from_basis_points(basis_points: u64, max: u64) -> u64 {
+    ((basis_points.min(BASIS_POINTS) as f64 * max as f64) / BASIS_POINTS as f64).round() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basis_points_round_trip() {
+        // Raw values are only preserved while they are at least as precise as basis points
+        for max in [1, 7, 100, 255, 937, 10000] {
+            for raw in 0..=max {
+                assert_eq!(raw, from_basis_points(to_basis_points(raw, max), max));
+            }
+        }
+    }
+
+    #[test]
+    fn test_basis_points_conversion() {
+        assert_eq!(3700, to_basis_points(37, 100));
+        assert_eq!(37, from_basis_points(3700, 100));
+        assert_eq!(10000, to_basis_points(200, 100));
+        assert_eq!(0, to_basis_points(5, 0));
+    }
 }
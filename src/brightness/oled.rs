@@ -0,0 +1,134 @@
+use super::curve::power;
+use super::mapped::Mapped;
+use super::{from_basis_points, Brightness};
+use crate::config;
+use crate::error::WlumaError;
+use std::time::Duration;
+
+/// Drives an OLED panel in coarse steps, optionally along a gamma curve, so that the many small
+/// changes of a transition or a drifting prediction don't make it flicker.
+pub struct Oled {
+    mapped: Mapped,
+    /// Size of the steps, in the same units as the brightness.
+    step: u64,
+    min_interval: Duration,
+    gamma: f64,
+}
+
+impl Oled {
+    pub fn new(brightness: Box<dyn Brightness + Send>, oled: config::Oled) -> Self {
+        let step = from_basis_points(oled.step, brightness.max()).max(1);
+        Self {
+            mapped: Mapped::new(brightness),
+            step,
+            min_interval: oled.min_interval,
+            gamma: oled.gamma,
+        }
+    }
+}
+
+impl Brightness for Oled {
+    fn get(&mut self) -> Result<u64, WlumaError> {
+        let (max, gamma) = (self.max(), self.gamma);
+        self.mapped.get(|raw| power(raw, max, 1.0 / gamma))
+    }
+
+    fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
+        let (max, gamma) = (self.max(), self.gamma);
+        let value = quantize(value, self.step, max);
+        if self.mapped.last() == Some(value) {
+            return Ok(value);
+        }
+
+        let raw = power(value, max, gamma);
+        self.mapped
+            .set(value, raw, |written| power(written, max, 1.0 / gamma))
+    }
+
+    fn max(&self) -> u64 {
+        self.mapped.brightness.max()
+    }
+
+    fn min(&self) -> u64 {
+        power(self.mapped.brightness.min(), self.max(), 1.0 / self.gamma)
+    }
+
+    fn min_write_interval(&self) -> Duration {
+        self.mapped
+            .brightness
+            .min_write_interval()
+            .max(self.min_interval)
+    }
+
+    fn min_change_interval(&self) -> Duration {
+        self.min_interval
+    }
+}
+
+/// Rounds to the nearest multiple of the step, the maximum can always be reached.
+fn quantize(value: u64, step: u64, max: u64) -> u64 {
+    ((value + step / 2) / step * step).min(max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brightness::MockBrightness;
+    use mockall::predicate;
+
+    fn oled(brightness: MockBrightness, step: u64, gamma: f64) -> Oled {
+        Oled::new(
+            Box::new(brightness),
+            config::Oled {
+                step,
+                min_interval: Duration::from_secs(1),
+                gamma,
+            },
+        )
+    }
+
+    #[test]
+    fn test_quantize() {
+        assert_eq!(0, quantize(2, 5, 100));
+        assert_eq!(5, quantize(3, 5, 100));
+        assert_eq!(40, quantize(42, 5, 100));
+        assert_eq!(255, quantize(254, 13, 255));
+        assert_eq!(247, quantize(250, 13, 255));
+    }
+
+    #[test]
+    fn test_set_writes_only_changed_steps() {
+        let mut brightness = MockBrightness::new();
+        brightness.expect_max().return_const(100u64);
+        brightness
+            .expect_set()
+            .with(predicate::eq(40))
+            .times(1)
+            .returning(Ok);
+        brightness
+            .expect_set()
+            .with(predicate::eq(45))
+            .times(1)
+            .returning(Ok);
+        let mut oled = oled(brightness, 500, 1.0);
+
+        assert_eq!(40, oled.set(41).unwrap());
+        assert_eq!(40, oled.set(42).unwrap());
+        assert_eq!(45, oled.set(43).unwrap());
+    }
+
+    #[test]
+    fn test_min_write_interval_is_the_longer_one() {
+        let mut brightness = MockBrightness::new();
+        brightness.expect_max().return_const(100u64);
+        let mut intervals = vec![Duration::from_millis(500), Duration::from_secs(3)].into_iter();
+        brightness
+            .expect_min_write_interval()
+            .times(2)
+            .returning(move || intervals.next().unwrap());
+
+        let oled = oled(brightness, 5, 1.0);
+        assert_eq!(Duration::from_secs(1), oled.min_write_interval());
+        assert_eq!(Duration::from_secs(3), oled.min_write_interval());
+    }
+}
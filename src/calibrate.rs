@@ -0,0 +1,206 @@
+use crate::als::{iio, webcam, Profiles};
+use crate::config::{self, Als};
+use crate::error::WlumaError;
+use itertools::Itertools;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+/// Readings taken in each lighting condition, their median is used.
+const SAMPLES: usize = 10;
+const IIO_SAMPLE_INTERVAL: Duration = Duration::from_millis(300);
+
+enum Sensor {
+    Iio(Box<iio::Als>),
+    Webcam(Receiver<webcam::Reading>),
+}
+
+impl Sensor {
+    fn sample(&self) -> Result<u64, WlumaError> {
+        let mut readings = match self {
+            Sensor::Iio(als) => (0..SAMPLES)
+                .map(|_| {
+                    thread::sleep(IIO_SAMPLE_INTERVAL);
+                    als.get_raw().map_err(WlumaError::als)
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            Sensor::Webcam(webcam_rx) => {
+                // Pictures taken before the lighting was set up
+                webcam_rx.try_iter().for_each(drop);
+                webcam_rx
+                    .iter()
+                    .take(SAMPLES)
+                    .collect::<Result<Vec<_>, _>>()?
+            }
+        };
+        if readings.len() < SAMPLES {
+            return Err(WlumaError::als("Webcam stopped taking pictures"));
+        }
+        readings.sort_unstable();
+        Ok(readings[SAMPLES / 2])
+    }
+}
+
+/// Asks to set up the lighting of each profile of the first IIO or webcam ALS in the config,
+/// samples the sensor and prints thresholds that would separate the readings.
+pub fn run() -> Result<(), WlumaError> {
+    let config = config::load()?;
+    let lightness = config.lightness;
+    let (sensor, thresholds) = config
+        .als
+        .into_iter()
+        .find_map(|als| match als {
+            Als::Iio {
+                path, thresholds, ..
+            } => Some(
+                iio::Als::new(
+                    &path,
+                    Profiles::new(HashMap::new(), 0, 1.0, Duration::ZERO),
+                    false,
+                )
+                .map(|als| (Sensor::Iio(Box::new(als)), thresholds)),
+            ),
+            Als::Webcam {
+                video,
+                sleep_ms,
+                region,
+                gamma,
+                prefer_infrared,
+                thresholds,
+                ..
+            } => Some(
+                webcam::start(video, sleep_ms, region, gamma, lightness, prefer_infrared)
+                    .map(|rx| (Sensor::Webcam(rx), thresholds)),
+            ),
+            Als::Time { .. } | Als::None => None,
+        })
+        .ok_or_else(|| WlumaError::config("Only [als.iio] and [als.webcam] can be calibrated"))??;
+
+    let profiles = thresholds
+        .into_iter()
+        .sorted_by_key(|(threshold, _)| *threshold)
+        .map(|(_, profile)| profile)
+        .unique()
+        .collect_vec();
+
+    println!(
+        "Calibrating {} profiles, from the darkest to the brightest.",
+        profiles.len()
+    );
+    let mut readings = Vec::new();
+    for profile in profiles {
+        match hint(&profile) {
+            Some(hint) => print!("\nSet up the lighting for '{}' ({}), ", profile, hint),
+            None => print!("\nSet up the lighting for '{}', ", profile),
+        }
+        print!("then press Enter and keep still...");
+        io::stdout().flush().map_err(WlumaError::als)?;
+        io::stdin()
+            .lock()
+            .read_line(&mut String::new())
+            .map_err(WlumaError::als)?;
+
+        let reading = sensor.sample()?;
+        println!("Sensor reads {}", reading);
+        readings.push((profile, reading));
+    }
+
+    let thresholds = suggest_thresholds(&readings).map_err(WlumaError::als)?;
+    println!(
+        "\nSuggested thresholds, to replace the ones in your config:\n\nthresholds = {{ {} }}",
+        thresholds
+            .iter()
+            .map(|(threshold, profile)| format!("{} = \"{}\"", threshold, profile))
+            .join(", ")
+    );
+
+    Ok(())
+}
+
+/// How to set up the lighting for the profiles of the default config.
+fn hint(profile: &str) -> Option<&'static str> {
+    Some(match profile {
+        "night" => "cover the sensor",
+        "dark" => "a single dim lamp",
+        "dim" => "an evening room",
+        "normal" => "normal room light",
+        "bright" => "a sunny room",
+        "outdoors" => "point the sensor at a window",
+        _ => return None,
+    })
+}
+
+/// Puts each threshold between the readings of adjacent profiles. Sensors respond to light
+/// roughly exponentially, so the geometric mean is used rather than the arithmetic one.
+fn suggest_thresholds(readings: &[(String, u64)]) -> Result<Vec<(u64, String)>, String> {
+    let mut thresholds = Vec::new();
+    for (i, (profile, reading)) in readings.iter().enumerate() {
+        let threshold = match i {
+            0 => 0,
+            _ => {
+                let (previous_profile, previous) = &readings[i - 1];
+                if reading <= previous {
+                    return Err(format!(
+                        "Sensor read {} for '{}' and {} for '{}', the lighting must get brighter from one profile to the next",
+                        previous, previous_profile, reading, profile
+                    ));
+                }
+                let mean = ((*previous as f64 + 1.0) * (*reading as f64 + 1.0)).sqrt() - 1.0;
+                (mean.round() as u64).clamp(previous + 1, *reading)
+            }
+        };
+        thresholds.push((threshold, profile.clone()));
+    }
+    Ok(thresholds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn readings(values: &[(&str, u64)]) -> Vec<(String, u64)> {
+        values
+            .iter()
+            .map(|(profile, reading)| (profile.to_string(), *reading))
+            .collect()
+    }
+
+    #[test]
+    fn test_suggest_thresholds() {
+        let readings = readings(&[
+            ("night", 0),
+            ("dim", 15),
+            ("normal", 255),
+            ("outdoors", 2303),
+        ]);
+
+        assert_eq!(
+            Ok(vec![
+                (0, "night".to_string()),
+                (3, "dim".to_string()),
+                (63, "normal".to_string()),
+                (767, "outdoors".to_string()),
+            ]),
+            suggest_thresholds(&readings)
+        );
+    }
+
+    #[test]
+    fn test_suggest_thresholds_separates_close_readings() {
+        let readings = readings(&[("dark", 1), ("dim", 2)]);
+
+        assert_eq!(
+            Ok(vec![(0, "dark".to_string()), (2, "dim".to_string())]),
+            suggest_thresholds(&readings)
+        );
+    }
+
+    #[test]
+    fn test_suggest_thresholds_requires_increasing_readings() {
+        let readings = readings(&[("dark", 10), ("dim", 40), ("normal", 40)]);
+
+        assert!(suggest_thresholds(&readings).is_err());
+    }
+}
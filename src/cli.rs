@@ -0,0 +1,232 @@
+use crate::config::Overrides;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "Usage: wluma [COMMAND]
+
+Commands:
+  diagnose       Check the environment and print a report of what wluma can use
+  calibrate-als  Sample the ambient light sensor in different lighting and suggest thresholds
+  init-config    Look for screens and sensors, and write a config for them if there is none
+  tui            Show what the running instance is doing, needs the D-Bus service enabled
+  inhibit <REASON> <COMMAND>...
+                 Hold brightness still while the command runs, needs the D-Bus service enabled
+  data plot --output <NAME> [--csv]
+                 Show what was learned for an output or data group, as a heatmap or as CSV
+
+Options:
+  --record <FILE>            Run as usual, recording what happens to the file
+  --replay <FILE> --dry-run  Replay a recorded session and print what predictors do
+  --als=<ALS>                Use only this ALS (iio, webcam, time, none) [env: WLUMA_ALS]
+  --capturer=<CAPTURER>      Capture all outputs with this capturer [env: WLUMA_CAPTURER]
+  --output-backlight-path=<PATH>
+                             Path of all backlight outputs [env: WLUMA_OUTPUT_BACKLIGHT_PATH]
+  --replace                  Take over from wluma already running in this session
+  -h, --help                 Print help
+  -V, --version              Print version
+";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Record(PathBuf),
+    Replay(PathBuf),
+    Diagnose,
+    CalibrateAls,
+    InitConfig,
+    Tui,
+    Inhibit {
+        reason: String,
+        /// Program to run, followed by its arguments.
+        command: Vec<String>,
+    },
+    Plot {
+        /// Output or data group.
+        name: String,
+        csv: bool,
+    },
+    Help,
+    Version,
+}
+
+/// Options that can be given along with any command.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    pub overrides: Overrides,
+    /// Whether to ask the running instance to exit, rather than refusing to start.
+    pub replace: bool,
+}
+
+/// Parses the command, and the options that can be given along with any of them.
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<(Command, Options), String> {
+    let mut options = Options::default();
+    let overrides = &mut options.overrides;
+    let mut args = args
+        .into_iter()
+        .filter(|arg| {
+            if arg == "--replace" {
+                options.replace = true;
+                return false;
+            }
+            let (option, value) = match arg.split_once('=') {
+                Some((option, value)) => (option, Some(value.to_string())),
+                None => return true,
+            };
+            match option {
+                "--als" => overrides.als = value,
+                "--capturer" => overrides.capturer = value,
+                "--output-backlight-path" => overrides.backlight_path = value,
+                _ => return true,
+            }
+            false
+        })
+        .collect::<Vec<_>>()
+        .into_iter();
+
+    let command = match args.next().as_deref() {
+        None => Command::Run,
+        Some("diagnose") => Command::Diagnose,
+        Some("calibrate-als") => Command::CalibrateAls,
+        Some("init-config") => Command::InitConfig,
+        Some("tui") => Command::Tui,
+        Some("inhibit") => {
+            let reason = args
+                .next()
+                .ok_or_else(|| "Missing reason to inhibit for".to_string())?;
+            let command = args.by_ref().collect::<Vec<_>>();
+            if command.is_empty() {
+                return Err("Missing command to run while inhibited".to_string());
+            }
+            Command::Inhibit { reason, command }
+        }
+        Some("data") => {
+            if args.next().as_deref() != Some("plot") {
+                return Err("Expected 'data plot'".to_string());
+            }
+            let mut name = None;
+            let mut csv = false;
+            while let Some(arg) = args.next() {
+                match arg.as_str() {
+                    "--output" => name = args.next(),
+                    "--csv" => csv = true,
+                    _ => return Err(format!("Unexpected argument '{}'", arg)),
+                }
+            }
+            let name = name.ok_or_else(|| "Missing name of the output".to_string())?;
+            Command::Plot { name, csv }
+        }
+        Some("--record") => Command::Record(path(args.next())?),
+        Some("--replay") => {
+            let path = path(args.next())?;
+            // Nothing but a dry run is supported yet, ask for it explicitly to leave room for
+            // replaying onto real displays
+            if args.next().as_deref() != Some("--dry-run") {
+                return Err("Replaying is only supported with '--dry-run'".to_string());
+            }
+            Command::Replay(path)
+        }
+        Some("-h" | "--help" | "help") => Command::Help,
+        Some("-V" | "--version") => Command::Version,
+        Some(arg) => return Err(format!("Unexpected argument '{}'", arg)),
+    };
+
+    match args.next() {
+        Some(arg) => Err(format!("Unexpected argument '{}'", arg)),
+        None => Ok((command, options)),
+    }
+}
+
+fn path(arg: Option<String>) -> Result<PathBuf, String> {
+    arg.map(PathBuf::from)
+        .ok_or_else(|| "Missing path to the recording".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(args: &[&str]) -> Result<Command, String> {
+        parse(args.iter().map(|a| a.to_string())).map(|(command, _)| command)
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Ok(Command::Run), parse_str(&[]));
+        assert_eq!(Ok(Command::Diagnose), parse_str(&["diagnose"]));
+        assert_eq!(Ok(Command::CalibrateAls), parse_str(&["calibrate-als"]));
+        assert_eq!(Ok(Command::InitConfig), parse_str(&["init-config"]));
+        assert_eq!(Ok(Command::Tui), parse_str(&["tui"]));
+        assert_eq!(Ok(Command::Help), parse_str(&["--help"]));
+        assert_eq!(Ok(Command::Version), parse_str(&["-V"]));
+        assert_eq!(
+            Ok(Command::Record("session.json".into())),
+            parse_str(&["--record", "session.json"])
+        );
+        assert_eq!(
+            Ok(Command::Replay("session.json".into())),
+            parse_str(&["--replay", "session.json", "--dry-run"])
+        );
+        assert_eq!(
+            Ok(Command::Plot {
+                name: "eDP-1".to_string(),
+                csv: true
+            }),
+            parse_str(&["data", "plot", "--csv", "--output", "eDP-1"])
+        );
+        assert_eq!(
+            Ok(Command::Inhibit {
+                reason: "screencast".to_string(),
+                command: vec!["wf-recorder".to_string(), "-f".to_string()],
+            }),
+            parse_str(&["inhibit", "screencast", "wf-recorder", "-f"])
+        );
+        assert!(parse_str(&["inhibit", "screencast"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let args = ["--als=time", "--record", "session.json", "--capturer=none"];
+        assert_eq!(
+            Ok((
+                Command::Record("session.json".into()),
+                Options {
+                    overrides: Overrides {
+                        als: Some("time".to_string()),
+                        capturer: Some("none".to_string()),
+                        backlight_path: None,
+                    },
+                    replace: false,
+                }
+            )),
+            parse(args.iter().map(|a| a.to_string()))
+        );
+        assert_eq!(
+            Ok(Command::Run),
+            parse_str(&["--output-backlight-path=/sys/class/backlight/amdgpu_bl1"])
+        );
+        assert!(parse_str(&["--brightness=50"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_replace() {
+        assert_eq!(
+            Ok((
+                Command::Run,
+                Options {
+                    replace: true,
+                    ..Default::default()
+                }
+            )),
+            parse(["--replace".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_parse_unexpected_arguments() {
+        assert!(parse_str(&["diagnoze"]).is_err());
+        assert!(parse_str(&["diagnose", "--all"]).is_err());
+        assert!(parse_str(&["--record"]).is_err());
+        assert!(parse_str(&["--replay", "session.json"]).is_err());
+        assert!(parse_str(&["data", "plot"]).is_err());
+        assert!(parse_str(&["data", "--output", "eDP-1"]).is_err());
+    }
+}
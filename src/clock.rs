@@ -0,0 +1,72 @@
+use chrono::{DateTime, Local};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Source of the current time for cooldowns and times of day, so that cooldowns last as long no matter how often
+/// frames come, and can follow another time than the real one, e.g. of a recorded session.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Date and time of day, e.g. for the time buckets and the age of learned entries.
+    fn wall(&self) -> DateTime<Local>;
+
+    /// Current unix timestamp in seconds.
+    fn timestamp(&self) -> u64 {
+        self.wall().timestamp().max(0) as u64
+    }
+}
+
+/// The real time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct System;
+
+impl Clock for System {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> DateTime<Local> {
+        Local::now()
+    }
+}
+
+/// Time that only moves when it's told to, shared by its clones.
+#[derive(Debug, Clone)]
+pub struct Manual {
+    now: Arc<Mutex<(Instant, DateTime<Local>)>>,
+}
+
+impl Manual {
+    pub fn new() -> Self {
+        Self::at(Local::now())
+    }
+
+    /// Starts at the given date and time of day.
+    pub fn at(wall: DateTime<Local>) -> Self {
+        Self {
+            now: Arc::new(Mutex::new((Instant::now(), wall))),
+        }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        let mut now = self.now.lock().unwrap();
+        now.0 += by;
+        now.1 += by;
+    }
+}
+
+impl Default for Manual {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for Manual {
+    fn now(&self) -> Instant {
+        self.now.lock().unwrap().0
+    }
+
+    fn wall(&self) -> DateTime<Local> {
+        self.now.lock().unwrap().1
+    }
+}
@@ -1,4 +1,33 @@
-use std::{collections::HashMap, fmt};
+use itertools::Itertools;
+use std::{collections::HashMap, fmt, time::Duration};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferFunction {
+    /// Perceptual quantizer (SMPTE ST 2084), used by HDR10.
+    Pq,
+    /// Hybrid log-gamma (ITU-R BT.2100).
+    Hlg,
+}
+
+/// Formula that turns colors into perceived lightness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lightness {
+    /// Weighted root mean square of the channels (HSP color model), cheap but rough.
+    Hsp,
+    /// Relative luminance of the linear sRGB channels (ITU-R BT.709), in percents of white.
+    Rec709,
+    /// CIE 1976 L*, relative to the white of the screen or the webcam.
+    CieLab,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    /// Write directly to the device when permitted, otherwise via logind.
+    Auto,
+    Direct,
+    /// Ask logind to write, even when writing directly is permitted.
+    Dbus,
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum WaylandProtocol {
@@ -20,51 +49,317 @@ impl fmt::Display for WaylandProtocol {
     }
 }
 
+/// Tells apart outputs that have the same name or description, e.g. in nested or remote sessions.
+/// Outputs must match all the given fields exactly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OutputIdentity {
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// Looked up in the description, as Wayland doesn't report it separately.
+    pub serial: Option<String>,
+}
+
+/// Part of the screen to compute luma from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CaptureRegion {
+    /// Exact rectangle in pixels.
+    Rect {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// Margins to exclude, in percents of the screen size.
+    Margins {
+        top: f64,
+        right: f64,
+        bottom: f64,
+        left: f64,
+    },
+}
+
 #[derive(Debug, Clone)]
 pub enum Capturer {
     Wayland(WaylandProtocol),
     None,
+    /// Reuse screen contents captured for another output with the given name.
+    Mirror(String),
+    /// Read screen contents from images in the directory or named pipe with the given path.
+    Directory(String),
 }
 
 #[derive(Debug)]
 pub enum Als {
     Iio {
         path: String,
+        /// Percent past a threshold that readings need to get to leave the current profile.
+        hysteresis: u8,
+        /// Weight of a reading that comes `poll_ms` after the previous one in the moving average of
+        /// readings, 1 disables smoothing.
+        smoothing: f64,
+        polling: AlsPolling,
+        /// Waits for threshold events of the device instead of polling it, if it has them.
+        events: bool,
         thresholds: HashMap<u64, String>,
     },
     Time {
-        thresholds: HashMap<u64, String>,
+        thresholds: HashMap<TimeThreshold, String>,
+        /// Needed to resolve sunrise and sunset thresholds.
+        location: Option<Location>,
+        polling: AlsPolling,
     },
     Webcam {
         video: usize,
+        sleep_ms: u64,
+        /// Percent of the width and height of pictures, around their center, to measure.
+        region: u8,
+        /// Exponent applied to the camera output (as values between 0 and 1), 1 keeps it as is.
+        gamma: f64,
+        /// Percent past a threshold that readings need to get to leave the current profile.
+        hysteresis: u8,
+        /// Weight of a reading that comes `poll_ms` after the previous one in the moving average of
+        /// readings, 1 disables smoothing.
+        smoothing: f64,
+        /// Uses an infrared camera instead of `video`, if there is one.
+        prefer_infrared: bool,
+        /// Added to readings, in the units of the thresholds, by the screens at full brightness
+        /// showing a white picture, subtracted according to their current brightness and luma.
+        screen_glow: u64,
+        polling: AlsPolling,
         thresholds: HashMap<u64, String>,
     },
     None,
 }
 
+/// How often an ALS source is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AlsPolling {
+    pub poll_ms: u64,
+    /// Readings slow down to this interval while the profile stays the same.
+    pub max_poll_ms: u64,
+}
+
+impl Als {
+    pub fn polling(&self) -> Option<AlsPolling> {
+        match self {
+            Self::Iio { polling, .. }
+            | Self::Time { polling, .. }
+            | Self::Webcam { polling, .. } => Some(*polling),
+            Self::None => None,
+        }
+    }
+}
+
+/// Start of a profile of the time ALS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeThreshold {
+    /// Minutes since midnight.
+    At(u32),
+    /// Minutes after sunrise, before it if negative.
+    Sunrise(i32),
+    /// Minutes after sunset, before it if negative.
+    Sunset(i32),
+}
+
+impl TimeThreshold {
+    /// Minutes since midnight, given the ones of sunrise and sunset of the day.
+    pub fn minutes(&self, sunrise: u32, sunset: u32) -> u32 {
+        let (base, offset) = match *self {
+            Self::At(minutes) => return minutes,
+            Self::Sunrise(offset) => (sunrise, offset),
+            Self::Sunset(offset) => (sunset, offset),
+        };
+        (base as i32 + offset).rem_euclid(24 * 60) as u32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// In degrees, positive to the north.
+    pub latitude: f64,
+    /// In degrees, positive to the east.
+    pub longitude: f64,
+}
+
+impl fmt::Display for Als {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let output = match self {
+            Self::Iio { .. } => "iio",
+            Self::Time { .. } => "time",
+            Self::Webcam { .. } => "webcam",
+            Self::None => "none",
+        };
+        write!(f, "{}", output)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FusionMode {
+    /// The brighter of the two readings, so that a shadowed sensor doesn't make it darker.
+    Max,
+    /// Weighted average, where `webcam_weight` is between 0 (only the sensor) and 1.
+    Average { webcam_weight: f64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct AlsFusion {
+    pub mode: FusionMode,
+    /// Webcam readings are multiplied by this to be comparable to the lux of the sensor.
+    pub webcam_scale: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdaptivePredictor {
+    /// How much less a neighbouring ALS profile is trusted for every step away from the
+    /// current one, when the current profile has no learned data yet.
+    pub profile_fallback_discount: f64,
+    /// Never predict higher brightness for brighter screen contents.
+    pub enforce_monotonic: bool,
+    /// Entries older than this have less and less influence, until they are removed at twice the age.
+    pub decay_days: Option<u64>,
+    /// Learn separately for each workspace, as they might contain very different contents.
+    pub per_workspace: bool,
+    /// Hours at which each time of day bucket starts, sorted, none to learn the same way all day.
+    pub time_buckets: Vec<u8>,
+    /// Entries for screen contents within this luma of a later one are merged into it.
+    pub compact_luma_epsilon: u8,
+    /// Outputs in the same group learn together, into a file named after the group.
+    pub data_group: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Predictor {
-    Adaptive,
+    Adaptive(AdaptivePredictor),
     Manual {
         thresholds: HashMap<String, HashMap<u8, u64>>,
+        /// Whether profiles without thresholds blend the ones of the closest darker and
+        /// brighter profiles, instead of not reducing brightness at all.
+        blend_profiles: bool,
+    },
+    Schedule {
+        /// Minutes since midnight and brightness (in basis points) at that time, sorted.
+        points: Vec<(u32, u64)>,
     },
 }
 
+impl Predictor {
+    /// Name of the config section, which the predictor is registered by.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Predictor::Adaptive(_) => "adaptive",
+            Predictor::Manual { .. } => "manual",
+            Predictor::Schedule { .. } => "schedule",
+        }
+    }
+}
+
+/// Avoids flicker of OLED panels, which many of them show on every brightness change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Oled {
+    /// Brightness only changes in steps of this size, in basis points.
+    pub step: u64,
+    /// Brightness doesn't change again sooner than this after the previous change, and isn't
+    /// written more often than this during one.
+    pub min_interval: Duration,
+    /// Exponent of the curve that maps brightness to raw values, 1 keeps it linear.
+    pub gamma: f64,
+}
+
+/// Reacts faster to sudden changes of the screen contents, e.g. a white page in a dark room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LumaJump {
+    /// Smallest change of luma between frames, in percent, that counts as a jump.
+    pub delta: u8,
+    /// Percent of the way to the new prediction to set right away, the rest is a transition.
+    pub head_start: u8,
+}
+
+/// Weighs the screen contents around the focused window, or the center of the screen, more
+/// than the rest of the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LumaFocus {
+    /// Standard deviation of the weights of pixels, as a fraction of the window or screen size.
+    pub spread: f64,
+    /// Whether to weigh around the focused window, found with Sway or Hyprland IPC, rather than
+    /// around the center of the screen.
+    pub follow_window: bool,
+}
+
+/// Holds brightness while frames are black, as when the compositor blanks the screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScreenOff {
+    /// Frames with luma up to this are black.
+    pub max_luma: u8,
+    /// Black frames for this long mean the screen is off, shorter streaks are ignored.
+    pub after_secs: f64,
+}
+
+/// Maps brightness to raw values of devices whose raw steps are not perceptually even.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Curve {
+    Linear,
+    /// Raw values grow with brightness to the power of this exponent.
+    Exponential(f64),
+    /// Points of brightness and the raw value for it (both in basis points), sorted, with
+    /// straight lines in between.
+    Map(Vec<(u64, u64)>),
+}
+
+/// Replaces the predictor of an output while it's switched on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scene {
+    /// Keep the given brightness (in basis points).
+    Fixed { brightness: u64 },
+    /// Predict like the manual predictor does, with these thresholds.
+    Manual {
+        thresholds: HashMap<String, HashMap<u8, u64>>,
+    },
+}
+
+/// Settings that outputs have whatever controls their brightness.
 #[derive(Debug, Clone)]
-pub struct BacklightOutput {
+pub struct OutputSettings {
     pub name: String,
-    pub path: String,
     pub capturer: Capturer,
+    pub identity: OutputIdentity,
+    pub capture_region: Option<CaptureRegion>,
+    /// Percentile of the screen contents lightness to use as luma, instead of the mean.
+    pub luma_percentile: Option<u8>,
+    /// Luma is only recomputed once this percent of the screen has changed, when the compositor
+    /// reports which parts of the frames changed.
+    pub min_damage_percent: u8,
+    /// How HDR screen contents are encoded, SDR contents need none.
+    pub transfer_function: Option<TransferFunction>,
+    pub oled: Option<Oled>,
+    pub luma_jump: Option<LumaJump>,
+    pub luma_focus: Option<LumaFocus>,
+    pub screen_off: Option<ScreenOff>,
+    pub curve: Curve,
+    /// Scenes by name, sorted.
+    pub scenes: Vec<(String, Scene)>,
+    /// Lowest raw value written to the device.
     pub min_brightness: u64,
+    /// Overrides the maximum reported by the device, if any.
+    pub max_brightness: Option<u64>,
     pub predictor: Predictor,
 }
 
+#[derive(Debug, Clone)]
+pub struct BacklightOutput {
+    pub settings: OutputSettings,
+    pub path: String,
+    /// Keyboard backlight rather than a screen, it doesn't light up the room.
+    pub keyboard: bool,
+    pub write_mode: WriteMode,
+}
+
 #[derive(Debug, Clone)]
 pub struct DdcUtilOutput {
-    pub name: String,
-    pub capturer: Capturer,
-    pub min_brightness: u64,
-    pub predictor: Predictor,
+    pub settings: OutputSettings,
+    /// VCP feature code that controls the brightness.
+    pub feature: u8,
+    /// Keep dimming by lowering contrast after reaching minimum brightness.
+    pub contrast_dimming: bool,
 }
 
 #[derive(Debug, Clone)]
@@ -73,8 +368,138 @@ pub enum Output {
     DdcUtil(DdcUtilOutput),
 }
 
+impl Output {
+    pub fn settings(&self) -> &OutputSettings {
+        match self {
+            Self::Backlight(output) => &output.settings,
+            Self::DdcUtil(output) => &output.settings,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AppMode {
+    /// Adjust brightness as usual.
+    Normal,
+    /// Keep the current brightness untouched.
+    Freeze,
+    /// Set the given brightness (in basis points) and keep it.
+    Fixed { brightness: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct App {
+    /// Substring of the focused window's app_id.
+    pub app_id: String,
+    /// Only apply when the window is fullscreen.
+    pub fullscreen: bool,
+    pub mode: AppMode,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Notifications {
+    /// Events that happen sooner after the previous notification are delayed.
+    pub min_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Lock {
+    /// Brightness to set while the session is locked (in basis points), otherwise it's left as is.
+    pub brightness: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Sleep {
+    /// How long learning and predictions stay paused after resuming, while the ALS still reports
+    /// stale values and the screen wakes up.
+    pub grace_secs: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Battery {
+    /// Offset applied to predictions while on battery, in basis points.
+    pub brightness_offset: i64,
+    /// Highest brightness predicted while on battery (in basis points), otherwise it's not capped.
+    pub max_brightness: Option<u64>,
+    /// Frames are captured at most this often while on battery, otherwise as usual.
+    pub capture_interval: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PredictorTiming {
+    /// How long to wait after the last brightness change made by user, before learning it.
+    pub pending_cooldown_secs: f64,
+    /// How long a new ALS profile must last, before switching to it.
+    pub als_cooldown_secs: f64,
+}
+
 #[derive(Debug)]
 pub struct Config {
-    pub als: Als,
+    /// ALS sources in the order of preference, the first healthy one is used.
+    pub als: Vec<Als>,
+    /// Combining readings of the first IIO and webcam sources into one, mapped to profiles with
+    /// the thresholds of the IIO one, if enabled.
+    pub als_fusion: Option<AlsFusion>,
     pub output: Vec<Output>,
+    /// Per-application overrides, the first matching one is applied.
+    pub app: Vec<App>,
+    /// Desktop notifications about switched ALS profiles and learned data, if enabled.
+    pub notifications: Option<Notifications>,
+    /// Pausing learning while the session is locked, if enabled.
+    pub lock: Option<Lock>,
+    /// Pausing learning while the system sleeps and for a while after it resumes, if enabled.
+    pub sleep: Option<Sleep>,
+    /// Adjusting predictions while on battery, if enabled.
+    pub battery: Option<Battery>,
+    /// Exposing the current state and controls on D-Bus, if enabled.
+    pub control: bool,
+    /// Writing ALS readings to a file for other programs, if enabled.
+    pub als_export: bool,
+    /// Noticing brightness changes made with brightness keys right away, if enabled.
+    pub keys: bool,
+    /// Offset applied to predictions (in basis points), unless it was changed on D-Bus.
+    pub exposure_bias: i64,
+    /// Setting the brightness found at startup back when exiting.
+    pub restore_on_exit: bool,
+    /// Part of the name of the GPU to compute luma on, instead of the one that renders the frames.
+    pub vulkan_device: Option<String>,
+    /// Pixels that luma is computed from at most, frames are scaled down until they fit, instead
+    /// of computing it from every pixel. Ignored by outputs with a luma percentile or a transfer
+    /// function.
+    pub luma_samples: Option<u64>,
+    /// Used for both screen contents and webcam pictures.
+    pub lightness: Lightness,
+    pub predictor: PredictorTiming,
+}
+
+impl Config {
+    /// Names of all ALS profiles, ordered by their thresholds from the darkest to the brightest.
+    pub fn als_profiles(&self) -> Vec<String> {
+        let mut profiles = Vec::new();
+        for als in &self.als {
+            let names = match als {
+                Als::Iio { thresholds, .. } | Als::Webcam { thresholds, .. } => thresholds
+                    .iter()
+                    .sorted_by_key(|(threshold, _)| **threshold)
+                    .map(|(_, name)| name)
+                    .collect_vec(),
+                // Sunrise and sunset of an average day
+                Als::Time { thresholds, .. } => thresholds
+                    .iter()
+                    .sorted_by_key(|(threshold, _)| threshold.minutes(6 * 60, 18 * 60))
+                    .map(|(_, name)| name)
+                    .collect_vec(),
+                Als::None => {
+                    profiles.push("none".to_string());
+                    continue;
+                }
+            };
+            for name in names {
+                if !profiles.contains(name) {
+                    profiles.push(name.clone());
+                }
+            }
+        }
+        profiles
+    }
 }
@@ -1,4 +1,4 @@
-use serde::Deserialize;
+use serde::{de, Deserialize, Deserializer};
 use std::collections::HashMap;
 
 #[derive(Deserialize, Debug, Default)]
@@ -16,6 +16,10 @@ pub enum Capturer {
     ExtImageCopyCaptureV1,
     #[serde(rename = "none")]
     None,
+    #[serde(rename = "mirror")]
+    Mirror(String),
+    #[serde(rename = "directory")]
+    Directory(String),
 }
 
 #[derive(Deserialize, Debug)]
@@ -23,13 +27,33 @@ pub enum Capturer {
 pub enum Als {
     Iio {
         path: String,
+        hysteresis: Option<u8>,
+        smoothing: Option<f64>,
+        poll_ms: Option<u64>,
+        max_poll_ms: Option<u64>,
+        #[serde(default)]
+        events: bool,
         thresholds: HashMap<String, String>,
     },
     Time {
+        latitude: Option<f64>,
+        longitude: Option<f64>,
+        poll_ms: Option<u64>,
+        max_poll_ms: Option<u64>,
         thresholds: HashMap<String, String>,
     },
     Webcam {
         video: usize,
+        sleep_ms: Option<u64>,
+        region: Option<u8>,
+        gamma: Option<f64>,
+        hysteresis: Option<u8>,
+        smoothing: Option<f64>,
+        #[serde(default)]
+        prefer_infrared: bool,
+        screen_glow: Option<u64>,
+        poll_ms: Option<u64>,
+        max_poll_ms: Option<u64>,
         thresholds: HashMap<String, String>,
     },
     None,
@@ -43,41 +67,408 @@ pub struct OutputByType {
 }
 
 #[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdaptivePredictor {
+    pub profile_fallback_discount: Option<f64>,
+    pub enforce_monotonic: bool,
+    pub decay_days: Option<u64>,
+    pub per_workspace: bool,
+    pub time_buckets: Vec<u8>,
+    pub compact_luma_epsilon: u8,
+    pub data_group: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum Predictor {
-    #[default]
-    Adaptive,
+    Adaptive(AdaptivePredictor),
     Manual {
         thresholds: HashMap<String, HashMap<String, u64>>,
+        #[serde(default)]
+        blend_profiles: bool,
+    },
+    Schedule {
+        points: HashMap<TimeOfDay, u64>,
     },
 }
 
+/// Time of day written as `HH:MM`, in minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimeOfDay(pub u32);
+
+impl TimeOfDay {
+    pub fn parse(value: &str) -> Option<Self> {
+        value
+            .split_once(':')
+            .and_then(|(hours, minutes)| Some((hours.parse::<u32>().ok()?, minutes)))
+            .filter(|(hours, minutes)| *hours < 24 && minutes.len() == 2)
+            .and_then(|(hours, minutes)| Some((hours, minutes.parse::<u32>().ok()?)))
+            .filter(|(_, minutes)| *minutes < 60)
+            .map(|(hours, minutes)| Self(hours * 60 + minutes))
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeOfDay {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        Self::parse(&value).ok_or_else(|| {
+            de::Error::custom(format!("invalid time of day '{}', expected HH:MM", value))
+        })
+    }
+}
+
+impl Default for Predictor {
+    fn default() -> Self {
+        Self::Adaptive(AdaptivePredictor::default())
+    }
+}
+
+// Allows both `predictor = "adaptive"` and `[output.backlight.predictor.adaptive]` with options
+fn deserialize_predictor<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Predictor>, D::Error> {
+    match toml::Value::deserialize(deserializer)? {
+        toml::Value::String(name) if name == "adaptive" => Ok(Some(Predictor::default())),
+        value => value.try_into().map(Some).map_err(de::Error::custom),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RectRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MarginsRegion {
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub left: f64,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum CaptureRegion {
+    Rect(RectRegion),
+    Margins(MarginsRegion),
+}
+
 #[derive(Deserialize, Debug)]
 pub struct BacklightOutput {
     pub name: String,
     pub path: String,
+    pub min_brightness: Option<u64>,
+    pub max_brightness: Option<u64>,
+    pub write_mode: Option<WriteMode>,
     pub capturer: Option<Capturer>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub capture_region: Option<CaptureRegion>,
+    pub luma_percentile: Option<u8>,
+    pub min_damage_percent: Option<u8>,
+    pub transfer_function: Option<TransferFunction>,
+    pub oled: Option<Oled>,
+    pub luma_jump: Option<LumaJump>,
+    pub luma_focus: Option<LumaFocus>,
+    pub screen_off: Option<ScreenOff>,
+    pub curve: Option<Curve>,
+    #[serde(default)]
+    pub scenes: HashMap<String, Scene>,
+    #[serde(default, deserialize_with = "deserialize_predictor")]
     pub predictor: Option<Predictor>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct DdcUtilOutput {
     pub name: String,
+    pub feature: Option<u8>,
+    pub min_brightness: Option<u64>,
+    pub max_brightness: Option<u64>,
+    #[serde(default)]
+    pub contrast_dimming: bool,
     pub capturer: Option<Capturer>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub capture_region: Option<CaptureRegion>,
+    pub luma_percentile: Option<u8>,
+    pub min_damage_percent: Option<u8>,
+    pub transfer_function: Option<TransferFunction>,
+    pub oled: Option<Oled>,
+    pub luma_jump: Option<LumaJump>,
+    pub luma_focus: Option<LumaFocus>,
+    pub screen_off: Option<ScreenOff>,
+    pub curve: Option<Curve>,
+    #[serde(default)]
+    pub scenes: HashMap<String, Scene>,
+    #[serde(default, deserialize_with = "deserialize_predictor")]
     pub predictor: Option<Predictor>,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(untagged, deny_unknown_fields)]
+pub enum Curve {
+    Named(CurveName),
+    Exponential { exponent: f64 },
+    Map { map: HashMap<String, u64> },
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum CurveName {
+    Linear,
+}
+
+#[derive(Debug)]
+pub enum Scene {
+    Fixed {
+        brightness: u64,
+    },
+    Manual {
+        thresholds: HashMap<String, HashMap<String, u64>>,
+    },
+}
+
+/// Fields of all kinds of scenes, which are told apart by the one that is set.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneFields {
+    brightness: Option<u64>,
+    thresholds: Option<HashMap<String, HashMap<String, u64>>>,
+}
+
+impl<'de> Deserialize<'de> for Scene {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Not using `#[serde(untagged)]`, as its errors don't tell what is wrong with the scene
+        match SceneFields::deserialize(deserializer)? {
+            SceneFields {
+                brightness: Some(brightness),
+                thresholds: None,
+            } => Ok(Self::Fixed { brightness }),
+            SceneFields {
+                brightness: None,
+                thresholds: Some(thresholds),
+            } => Ok(Self::Manual { thresholds }),
+            _ => Err(de::Error::custom(
+                "a scene needs either `brightness` or `thresholds`",
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Oled {
+    pub step: Option<u64>,
+    pub min_interval_ms: Option<u64>,
+    pub gamma: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LumaJump {
+    pub delta: Option<u8>,
+    pub head_start: Option<u8>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct LumaFocus {
+    pub spread: Option<f64>,
+    pub follow_window: Option<bool>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScreenOff {
+    pub max_luma: Option<u8>,
+    pub after_secs: Option<f64>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct Keyboard {
     pub name: String,
     pub path: String,
+    pub min_brightness: Option<u64>,
+    pub max_brightness: Option<u64>,
+    pub write_mode: Option<WriteMode>,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    #[default]
+    Auto,
+    Direct,
+    Dbus,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferFunction {
+    Pq,
+    Hlg,
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Lightness {
+    #[default]
+    Hsp,
+    Rec709,
+    CieLab,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum AppMode {
+    #[default]
+    Normal,
+    Freeze,
+    Fixed(u64),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct App {
+    pub app_id: String,
+    #[serde(default)]
+    pub fullscreen: bool,
+    #[serde(default)]
+    pub mode: AppMode,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FusionMode {
+    #[default]
+    Max,
+    Average,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AlsFusion {
+    pub enabled: bool,
+    pub mode: FusionMode,
+    pub webcam_scale: Option<f64>,
+    pub webcam_weight: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Notifications {
+    pub enabled: bool,
+    pub min_interval_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Lock {
+    pub enabled: bool,
+    pub brightness: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Sleep {
+    pub enabled: bool,
+    pub grace_secs: Option<f64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Battery {
+    pub enabled: bool,
+    pub brightness_offset: i64,
+    pub max_brightness: Option<u64>,
+    pub capture_interval_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Control {
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct AlsExport {
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default)]
+pub struct Keys {
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct PredictorTiming {
+    pub pending_cooldown_secs: Option<f64>,
+    pub als_cooldown_secs: Option<f64>,
+}
+
+#[derive(Debug)]
+pub enum AlsSources {
+    One(Als),
+    Many(Vec<Als>),
+}
+
+impl<'de> Deserialize<'de> for AlsSources {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        // Not using `#[serde(untagged)]`, as it doesn't support table-style unit variants like `[als.none]`
+        match toml::Value::deserialize(deserializer)? {
+            toml::Value::Array(items) => items
+                .into_iter()
+                .map(|item| item.try_into())
+                .collect::<Result<_, _>>()
+                .map(Self::Many),
+            value => value.try_into().map(Self::One),
+        }
+        .map_err(de::Error::custom)
+    }
 }
 
 #[derive(Deserialize, Debug)]
 pub struct Config {
-    pub als: Als,
+    #[serde(default)]
+    pub exposure_bias: i64,
+    #[serde(default)]
+    pub restore_on_exit: bool,
+    pub vulkan_device: Option<String>,
+    pub luma_samples: Option<u64>,
+    #[serde(default)]
+    pub lightness: Lightness,
+    pub als: AlsSources,
+    #[serde(default)]
+    pub als_fusion: AlsFusion,
     #[serde(default)]
     pub output: OutputByType,
     #[serde(default)]
     pub keyboard: Vec<Keyboard>,
+    #[serde(default)]
+    pub app: Vec<App>,
+    #[serde(default)]
+    pub notifications: Notifications,
+    #[serde(default)]
+    pub lock: Lock,
+    #[serde(default)]
+    pub sleep: Sleep,
+    #[serde(default)]
+    pub battery: Battery,
+    #[serde(default)]
+    pub control: Control,
+    #[serde(default)]
+    pub als_export: AlsExport,
+    #[serde(default)]
+    pub keys: Keys,
+    #[serde(default)]
+    pub predictor: PredictorTiming,
 }
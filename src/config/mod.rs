@@ -1,29 +1,94 @@
+use crate::error::WlumaError;
+use itertools::Itertools;
+use problems::Problems;
 use std::collections::HashMap;
 use std::collections::HashSet;
-use std::error::Error;
 use std::fs;
+use std::path::Path;
+use std::time::Duration;
 mod app;
 mod file;
+mod overrides;
+mod problems;
 pub use app::*;
+pub use overrides::{set as set_overrides, Overrides};
 
-pub fn load() -> Result<app::Config, Box<dyn Error>> {
-    validate(parse()?)
+/// Thresholds of the ALS sources that new configs and `--als` start with, as TOML inline tables.
+pub const DEFAULT_IIO_THRESHOLDS: &str =
+    r#"{ 0 = "night", 20 = "dark", 80 = "dim", 250 = "normal", 500 = "bright", 800 = "outdoors" }"#;
+pub const DEFAULT_WEBCAM_THRESHOLDS: &str =
+    r#"{ 0 = "night", 15 = "dark", 30 = "dim", 45 = "normal", 60 = "bright", 75 = "outdoors" }"#;
+pub const DEFAULT_TIME_THRESHOLDS: &str = r#"{ 0 = "night", 7 = "dark", 9 = "dim", 11 = "normal", 13 = "bright", 16 = "normal", 18 = "dark", 20 = "night" }"#;
+
+const DEFAULT_WEBCAM_SLEEP_MS: u64 = 2000;
+const DEFAULT_WEBCAM_REGION: u8 = 100;
+const DEFAULT_WEBCAM_GAMMA: f64 = 1.0;
+const DEFAULT_WEBCAM_SCREEN_GLOW: u64 = 0;
+const DEFAULT_ALS_HYSTERESIS: u8 = 0;
+const DEFAULT_ALS_SMOOTHING: f64 = 1.0;
+const DEFAULT_IIO_POLL_MS: u64 = 100;
+const DEFAULT_WEBCAM_POLL_MS: u64 = 500;
+/// Thresholds of the time ALS are whole minutes.
+const DEFAULT_TIME_POLL_MS: u64 = 10_000;
+/// Sensors are read up to this many times less often while the profile stays the same.
+const DEFAULT_ALS_SLOWDOWN: u64 = 10;
+const DEFAULT_FUSION_WEBCAM_SCALE: f64 = 10.0;
+const DEFAULT_FUSION_WEBCAM_WEIGHT: f64 = 0.5;
+const DEFAULT_PROFILE_FALLBACK_DISCOUNT: f64 = 0.5;
+const DEFAULT_NOTIFICATIONS_MIN_INTERVAL_SECS: u64 = 30;
+const DEFAULT_PENDING_COOLDOWN_SECS: f64 = 1.5;
+const DEFAULT_ALS_COOLDOWN_SECS: f64 = 1.5;
+const MAX_COOLDOWN_SECS: f64 = 3600.0;
+const DEFAULT_MIN_DAMAGE_PERCENT: u8 = 1;
+const DEFAULT_OLED_STEP: u64 = 5;
+const DEFAULT_OLED_MIN_INTERVAL_MS: u64 = 1000;
+const DEFAULT_OLED_GAMMA: f64 = 1.0;
+const DEFAULT_LUMA_JUMP_DELTA: u8 = 30;
+const DEFAULT_LUMA_JUMP_HEAD_START: u8 = 50;
+const DEFAULT_LUMA_FOCUS_SPREAD: f64 = 0.5;
+const MAX_LUMA_FOCUS_SPREAD: f64 = 10.0;
+const DEFAULT_SCREEN_OFF_MAX_LUMA: u8 = 0;
+const DEFAULT_SCREEN_OFF_AFTER_SECS: f64 = 3.0;
+const DEFAULT_SLEEP_GRACE_SECS: f64 = 5.0;
+const DEFAULT_DDC_FEATURE: u8 = 0x10;
+/// Screens are never turned off entirely, unlike keyboard backlights.
+const DEFAULT_SCREEN_MIN_BRIGHTNESS: u64 = 1;
+const DEFAULT_KEYBOARD_MIN_BRIGHTNESS: u64 = 0;
+
+pub fn load() -> Result<app::Config, WlumaError> {
+    let content = read();
+    let config = parse(&content, &overrides::current())
+        .and_then(|config| validate(config, &content))
+        .map_err(WlumaError::config)?;
+    warn_missing_devices(&config);
+    Ok(config)
 }
 
 fn match_predictor(predictor: file::Predictor) -> app::Predictor {
     match predictor {
-        file::Predictor::Adaptive => app::Predictor::Adaptive,
-        file::Predictor::Manual { thresholds } => app::Predictor::Manual {
-            thresholds: thresholds
+        file::Predictor::Adaptive(adaptive) => app::Predictor::Adaptive(app::AdaptivePredictor {
+            profile_fallback_discount: adaptive
+                .profile_fallback_discount
+                .unwrap_or(DEFAULT_PROFILE_FALLBACK_DISCOUNT),
+            enforce_monotonic: adaptive.enforce_monotonic,
+            decay_days: adaptive.decay_days,
+            per_workspace: adaptive.per_workspace,
+            time_buckets: adaptive.time_buckets.into_iter().sorted().dedup().collect(),
+            compact_luma_epsilon: adaptive.compact_luma_epsilon,
+            data_group: adaptive.data_group,
+        }),
+        file::Predictor::Manual {
+            thresholds,
+            blend_profiles,
+        } => app::Predictor::Manual {
+            thresholds: match_manual_thresholds(thresholds),
+            blend_profiles,
+        },
+        file::Predictor::Schedule { points } => app::Predictor::Schedule {
+            points: points
                 .into_iter()
-                .map(|(k, v)| {
-                    (
-                        k,
-                        v.into_iter()
-                            .map(|(k, v)| (k.parse::<u8>().unwrap(), v))
-                            .collect(),
-                    )
-                })
+                .map(|(time, percent)| (time.0, percent.saturating_mul(100)))
+                .sorted()
                 .collect(),
         },
     }
@@ -32,6 +97,8 @@ fn match_predictor(predictor: file::Predictor) -> app::Predictor {
 fn match_capturer(capturer: file::Capturer) -> app::Capturer {
     match capturer {
         file::Capturer::None => app::Capturer::None,
+        file::Capturer::Mirror(name) => app::Capturer::Mirror(name),
+        file::Capturer::Directory(path) => app::Capturer::Directory(path),
         file::Capturer::Wlroots => {
             log::warn!(
                 "Config value capturer=\"wlroots\" is deprecated, use capturer=\"wayland\" instead"
@@ -51,12 +118,309 @@ fn match_capturer(capturer: file::Capturer) -> app::Capturer {
     }
 }
 
-fn parse() -> Result<app::Config, toml::de::Error> {
-    let file_config = xdg::BaseDirectories::with_prefix("wluma")
+fn match_manual_thresholds(
+    thresholds: HashMap<String, HashMap<String, u64>>,
+) -> HashMap<String, HashMap<u8, u64>> {
+    thresholds
+        .into_iter()
+        .map(|(k, v)| {
+            (
+                k,
+                v.into_iter()
+                    .map(|(k, v)| (k.parse::<u8>().unwrap(), v))
+                    .collect(),
+            )
+        })
+        .collect()
+}
+
+fn match_curve(curve: file::Curve) -> app::Curve {
+    match curve {
+        file::Curve::Named(file::CurveName::Linear) => app::Curve::Linear,
+        file::Curve::Exponential { exponent } => app::Curve::Exponential(exponent),
+        file::Curve::Map { map } => app::Curve::Map(
+            map.into_iter()
+                .map(|(k, v)| {
+                    (
+                        k.parse::<u64>().unwrap().saturating_mul(100),
+                        v.saturating_mul(100),
+                    )
+                })
+                .sorted()
+                .collect(),
+        ),
+    }
+}
+
+fn match_scenes(scenes: HashMap<String, file::Scene>) -> Vec<(String, app::Scene)> {
+    scenes
+        .into_iter()
+        .map(|(name, scene)| {
+            let scene = match scene {
+                file::Scene::Fixed { brightness } => app::Scene::Fixed {
+                    brightness: brightness.saturating_mul(100),
+                },
+                file::Scene::Manual { thresholds } => app::Scene::Manual {
+                    thresholds: match_manual_thresholds(thresholds),
+                },
+            };
+            (name, scene)
+        })
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .collect()
+}
+
+fn match_transfer_function(transfer_function: file::TransferFunction) -> app::TransferFunction {
+    match transfer_function {
+        file::TransferFunction::Pq => app::TransferFunction::Pq,
+        file::TransferFunction::Hlg => app::TransferFunction::Hlg,
+    }
+}
+
+fn match_lightness(lightness: file::Lightness) -> app::Lightness {
+    match lightness {
+        file::Lightness::Hsp => app::Lightness::Hsp,
+        file::Lightness::Rec709 => app::Lightness::Rec709,
+        file::Lightness::CieLab => app::Lightness::CieLab,
+    }
+}
+
+fn match_oled(oled: file::Oled) -> app::Oled {
+    app::Oled {
+        step: oled.step.unwrap_or(DEFAULT_OLED_STEP).saturating_mul(100),
+        min_interval: Duration::from_millis(
+            oled.min_interval_ms.unwrap_or(DEFAULT_OLED_MIN_INTERVAL_MS),
+        ),
+        gamma: oled.gamma.unwrap_or(DEFAULT_OLED_GAMMA),
+    }
+}
+
+fn match_luma_jump(luma_jump: file::LumaJump) -> app::LumaJump {
+    app::LumaJump {
+        delta: luma_jump.delta.unwrap_or(DEFAULT_LUMA_JUMP_DELTA),
+        head_start: luma_jump.head_start.unwrap_or(DEFAULT_LUMA_JUMP_HEAD_START),
+    }
+}
+
+/// The longest interval defaults to a multiple of the configured one.
+fn match_als_polling(
+    poll_ms: Option<u64>,
+    max_poll_ms: Option<u64>,
+    default_poll_ms: u64,
+    slowdown: u64,
+) -> app::AlsPolling {
+    let poll_ms = poll_ms.unwrap_or(default_poll_ms);
+    app::AlsPolling {
+        poll_ms,
+        max_poll_ms: max_poll_ms.unwrap_or(poll_ms.saturating_mul(slowdown)),
+    }
+}
+
+fn match_luma_focus(luma_focus: file::LumaFocus) -> app::LumaFocus {
+    app::LumaFocus {
+        spread: luma_focus.spread.unwrap_or(DEFAULT_LUMA_FOCUS_SPREAD),
+        follow_window: luma_focus.follow_window.unwrap_or(true),
+    }
+}
+
+fn match_screen_off(screen_off: file::ScreenOff) -> app::ScreenOff {
+    app::ScreenOff {
+        max_luma: screen_off.max_luma.unwrap_or(DEFAULT_SCREEN_OFF_MAX_LUMA),
+        after_secs: screen_off
+            .after_secs
+            .unwrap_or(DEFAULT_SCREEN_OFF_AFTER_SECS),
+    }
+}
+
+fn match_write_mode(write_mode: file::WriteMode) -> app::WriteMode {
+    match write_mode {
+        file::WriteMode::Auto => app::WriteMode::Auto,
+        file::WriteMode::Direct => app::WriteMode::Direct,
+        file::WriteMode::Dbus => app::WriteMode::Dbus,
+    }
+}
+
+fn match_capture_region(region: file::CaptureRegion) -> app::CaptureRegion {
+    match region {
+        file::CaptureRegion::Rect(r) => app::CaptureRegion::Rect {
+            x: r.x,
+            y: r.y,
+            width: r.width,
+            height: r.height,
+        },
+        file::CaptureRegion::Margins(m) => app::CaptureRegion::Margins {
+            top: m.top,
+            right: m.right,
+            bottom: m.bottom,
+            left: m.left,
+        },
+    }
+}
+
+fn read() -> String {
+    xdg::BaseDirectories::with_prefix("wluma")
         .ok()
         .and_then(|xdg| xdg.find_config_file("config.toml"))
         .and_then(|cfg_path| fs::read_to_string(cfg_path).ok())
-        .unwrap_or_else(|| include_str!("../../config.toml").to_string());
+        .unwrap_or_else(|| include_str!("../../config.toml").to_string())
+}
+
+/// Hour (e.g. `7`), `HH:MM`, or `sunrise` or `sunset` with an optional offset in minutes
+/// (e.g. `sunset-30`).
+fn parse_time_threshold(time: &str) -> Option<app::TimeThreshold> {
+    let offset = |offset: &str| match offset {
+        "" => Some(0),
+        _ if offset.starts_with(['+', '-']) => offset
+            .parse::<i32>()
+            .ok()
+            .filter(|offset| offset.abs() < 24 * 60),
+        _ => None,
+    };
+    if let Some(rest) = time.strip_prefix("sunrise") {
+        return offset(rest).map(app::TimeThreshold::Sunrise);
+    }
+    if let Some(rest) = time.strip_prefix("sunset") {
+        return offset(rest).map(app::TimeThreshold::Sunset);
+    }
+    match time.parse::<u32>() {
+        Ok(hours) => (hours < 24).then_some(app::TimeThreshold::At(hours * 60)),
+        Err(_) => file::TimeOfDay::parse(time).map(|time| app::TimeThreshold::At(time.0)),
+    }
+}
+
+/// Problems with numbers written as keys of tables, which TOML can't check by itself.
+fn check_keys(config: &file::Config) -> Problems {
+    let mut problems = Problems::default();
+
+    let als = match &config.als {
+        file::AlsSources::One(als) => std::slice::from_ref(als),
+        file::AlsSources::Many(als) => als.as_slice(),
+    };
+    for als in als {
+        let (section, thresholds) = match als {
+            file::Als::Iio { thresholds, .. } => ("iio", thresholds),
+            file::Als::Webcam { thresholds, .. } => ("webcam", thresholds),
+            file::Als::Time { thresholds, .. } => {
+                for time in thresholds
+                    .keys()
+                    .filter(|time| parse_time_threshold(time).is_none())
+                    .sorted()
+                {
+                    problems.als(
+                        "time",
+                        &format!("thresholds.{}", time),
+                        format!(
+                        "Threshold '{}' of [als.time] must be an hour, HH:MM, or sunrise or sunset with an optional offset in minutes, e.g. sunset-30",
+                        time
+                    ),
+                    );
+                }
+                continue;
+            }
+            file::Als::None => continue,
+        };
+        for lux in thresholds
+            .keys()
+            .filter(|lux| lux.parse::<u64>().is_err())
+            .sorted()
+        {
+            problems.als(
+                section,
+                &format!("thresholds.{}", lux),
+                format!(
+                    "Threshold '{}' of [als.{}] must be a whole number",
+                    lux, section
+                ),
+            );
+        }
+    }
+
+    let outputs = config
+        .output
+        .backlight
+        .iter()
+        .map(|o| (&o.name, &o.predictor, &o.scenes, &o.curve))
+        .chain(
+            config
+                .output
+                .ddcutil
+                .iter()
+                .map(|o| (&o.name, &o.predictor, &o.scenes, &o.curve)),
+        );
+    for (name, predictor, scenes, curve) in outputs {
+        if let Some(file::Predictor::Manual { thresholds, .. }) = predictor {
+            check_manual_thresholds(
+                &mut problems,
+                name,
+                "predictor.manual.thresholds",
+                &format!("manual predictor of '{}'", name),
+                thresholds,
+            );
+        }
+        for (scene, scene_config) in scenes.iter().sorted_by_key(|(scene, _)| *scene) {
+            if let file::Scene::Manual { thresholds } = scene_config {
+                check_manual_thresholds(
+                    &mut problems,
+                    name,
+                    &format!("scenes.{}.thresholds", scene),
+                    &format!("scene '{}' of '{}'", scene, name),
+                    thresholds,
+                );
+            }
+        }
+        if let Some(file::Curve::Map { map }) = curve {
+            for brightness in map
+                .keys()
+                .filter(|brightness| brightness.parse::<u64>().is_err())
+                .sorted()
+            {
+                problems.output(
+                    name,
+                    &format!("curve.map.{}", brightness),
+                    format!(
+                        "Brightness '{}' in the curve map of '{}' must be a whole percentage",
+                        brightness, name
+                    ),
+                );
+            }
+        }
+    }
+
+    problems
+}
+
+fn check_manual_thresholds(
+    problems: &mut Problems,
+    name: &str,
+    key: &str,
+    context: &str,
+    thresholds: &HashMap<String, HashMap<String, u64>>,
+) {
+    for (profile, points) in thresholds.iter().sorted_by_key(|(profile, _)| *profile) {
+        for luma in points
+            .keys()
+            .filter(|luma| !luma.parse::<u8>().is_ok_and(|luma| luma <= 100))
+            .sorted()
+        {
+            problems.output(
+                name,
+                &format!("{}.{}.{}", key, profile, luma),
+                format!(
+                    "Luma '{}' in the thresholds of '{}' of the {} must be a percentage between 0 and 100",
+                    luma, profile, context
+                ),
+            );
+        }
+    }
+}
+
+fn parse(content: &str, overrides: &Overrides) -> Result<app::Config, String> {
+    let mut file_config: file::Config = toml::from_str(content).map_err(|err| err.to_string())?;
+    overrides.apply(&mut file_config)?;
+    let problems = check_keys(&file_config);
+    if !problems.is_empty() {
+        return Err(problems.describe(content));
+    }
 
     let parse_als_thresholds = |t: HashMap<String, String>| -> HashMap<u64, String> {
         t.into_iter()
@@ -64,69 +428,1226 @@ fn parse() -> Result<app::Config, toml::de::Error> {
             .collect()
     };
 
-    toml::from_str(&file_config).map(|file_config: file::Config| app::Config {
+    Ok(app::Config {
         output: file_config
             .output
             .backlight
             .into_iter()
             .map(|o| {
                 app::Output::Backlight(app::BacklightOutput {
-                    name: o.name,
+                    settings: app::OutputSettings {
+                        name: o.name,
+                        min_brightness: o.min_brightness.unwrap_or(DEFAULT_SCREEN_MIN_BRIGHTNESS),
+                        max_brightness: o.max_brightness,
+                        capturer: match_capturer(o.capturer.unwrap_or_default()),
+                        identity: app::OutputIdentity {
+                            make: o.make,
+                            model: o.model,
+                            serial: o.serial,
+                        },
+                        capture_region: o.capture_region.map(match_capture_region),
+                        luma_percentile: o.luma_percentile,
+                        min_damage_percent: o
+                            .min_damage_percent
+                            .unwrap_or(DEFAULT_MIN_DAMAGE_PERCENT),
+                        transfer_function: o.transfer_function.map(match_transfer_function),
+                        oled: o.oled.map(match_oled),
+                        luma_jump: o.luma_jump.map(match_luma_jump),
+                        luma_focus: o.luma_focus.map(match_luma_focus),
+                        screen_off: o.screen_off.map(match_screen_off),
+                        curve: o.curve.map_or(app::Curve::Linear, match_curve),
+                        scenes: match_scenes(o.scenes),
+                        predictor: match_predictor(o.predictor.unwrap_or_default()),
+                    },
                     path: o.path,
-                    min_brightness: 1,
-                    capturer: match_capturer(o.capturer.unwrap_or_default()),
-                    predictor: match_predictor(o.predictor.unwrap_or_default()),
+                    keyboard: false,
+                    write_mode: match_write_mode(o.write_mode.unwrap_or_default()),
                 })
             })
             .chain(file_config.output.ddcutil.into_iter().map(|o| {
                 app::Output::DdcUtil(app::DdcUtilOutput {
-                    name: o.name,
-                    min_brightness: 1,
-                    capturer: match_capturer(o.capturer.unwrap_or_default()),
-                    predictor: match_predictor(o.predictor.unwrap_or_default()),
+                    settings: app::OutputSettings {
+                        name: o.name,
+                        min_brightness: o.min_brightness.unwrap_or(DEFAULT_SCREEN_MIN_BRIGHTNESS),
+                        max_brightness: o.max_brightness,
+                        capturer: match_capturer(o.capturer.unwrap_or_default()),
+                        identity: app::OutputIdentity {
+                            make: o.make,
+                            model: o.model,
+                            serial: o.serial,
+                        },
+                        capture_region: o.capture_region.map(match_capture_region),
+                        luma_percentile: o.luma_percentile,
+                        min_damage_percent: o
+                            .min_damage_percent
+                            .unwrap_or(DEFAULT_MIN_DAMAGE_PERCENT),
+                        transfer_function: o.transfer_function.map(match_transfer_function),
+                        oled: o.oled.map(match_oled),
+                        luma_jump: o.luma_jump.map(match_luma_jump),
+                        luma_focus: o.luma_focus.map(match_luma_focus),
+                        screen_off: o.screen_off.map(match_screen_off),
+                        curve: o.curve.map_or(app::Curve::Linear, match_curve),
+                        scenes: match_scenes(o.scenes),
+                        predictor: match_predictor(o.predictor.unwrap_or_default()),
+                    },
+                    feature: o.feature.unwrap_or(DEFAULT_DDC_FEATURE),
+                    contrast_dimming: o.contrast_dimming,
                 })
             }))
             .chain(file_config.keyboard.into_iter().map(|k| {
                 app::Output::Backlight(app::BacklightOutput {
-                    name: k.name,
+                    settings: app::OutputSettings {
+                        name: k.name,
+                        min_brightness: k.min_brightness.unwrap_or(DEFAULT_KEYBOARD_MIN_BRIGHTNESS),
+                        max_brightness: k.max_brightness,
+                        capturer: Capturer::None,
+                        identity: app::OutputIdentity::default(),
+                        capture_region: None,
+                        luma_percentile: None,
+                        min_damage_percent: DEFAULT_MIN_DAMAGE_PERCENT,
+                        transfer_function: None,
+                        oled: None,
+                        luma_jump: None,
+                        luma_focus: None,
+                        screen_off: None,
+                        curve: app::Curve::Linear,
+                        scenes: Vec::new(),
+                        predictor: app::Predictor::Adaptive(app::AdaptivePredictor {
+                            profile_fallback_discount: DEFAULT_PROFILE_FALLBACK_DISCOUNT,
+                            enforce_monotonic: false,
+                            decay_days: None,
+                            per_workspace: false,
+                            time_buckets: Vec::new(),
+                            compact_luma_epsilon: 0,
+                            data_group: None,
+                        }),
+                    },
                     path: k.path,
-                    min_brightness: 0,
-                    capturer: Capturer::None,
-                    predictor: app::Predictor::Adaptive,
+                    keyboard: true,
+                    write_mode: match_write_mode(k.write_mode.unwrap_or_default()),
                 })
             }))
             .collect(),
 
         als: match file_config.als {
-            file::Als::Iio { path, thresholds } => app::Als::Iio {
+            file::AlsSources::One(als) => vec![als],
+            file::AlsSources::Many(als) => als,
+        }
+        .into_iter()
+        .map(|als| match als {
+            file::Als::Iio {
+                path,
+                hysteresis,
+                smoothing,
+                poll_ms,
+                max_poll_ms,
+                events,
+                thresholds,
+            } => app::Als::Iio {
                 path,
+                hysteresis: hysteresis.unwrap_or(DEFAULT_ALS_HYSTERESIS),
+                smoothing: smoothing.unwrap_or(DEFAULT_ALS_SMOOTHING),
+                polling: match_als_polling(
+                    poll_ms,
+                    max_poll_ms,
+                    DEFAULT_IIO_POLL_MS,
+                    DEFAULT_ALS_SLOWDOWN,
+                ),
+                events,
                 thresholds: parse_als_thresholds(thresholds),
             },
-            file::Als::Webcam { video, thresholds } => app::Als::Webcam {
+            file::Als::Webcam {
+                video,
+                sleep_ms,
+                region,
+                gamma,
+                hysteresis,
+                smoothing,
+                prefer_infrared,
+                screen_glow,
+                poll_ms,
+                max_poll_ms,
+                thresholds,
+            } => app::Als::Webcam {
                 video,
+                sleep_ms: sleep_ms.unwrap_or(DEFAULT_WEBCAM_SLEEP_MS),
+                region: region.unwrap_or(DEFAULT_WEBCAM_REGION),
+                gamma: gamma.unwrap_or(DEFAULT_WEBCAM_GAMMA),
+                hysteresis: hysteresis.unwrap_or(DEFAULT_ALS_HYSTERESIS),
+                smoothing: smoothing.unwrap_or(DEFAULT_ALS_SMOOTHING),
+                prefer_infrared,
+                screen_glow: screen_glow.unwrap_or(DEFAULT_WEBCAM_SCREEN_GLOW),
+                polling: match_als_polling(
+                    poll_ms,
+                    max_poll_ms,
+                    DEFAULT_WEBCAM_POLL_MS,
+                    DEFAULT_ALS_SLOWDOWN,
+                ),
                 thresholds: parse_als_thresholds(thresholds),
             },
-            file::Als::Time { thresholds } => app::Als::Time {
-                thresholds: parse_als_thresholds(thresholds),
+            file::Als::Time {
+                latitude,
+                longitude,
+                poll_ms,
+                max_poll_ms,
+                thresholds,
+            } => app::Als::Time {
+                thresholds: thresholds
+                    .into_iter()
+                    .map(|(time, profile)| (parse_time_threshold(&time).unwrap(), profile))
+                    .collect(),
+                location: latitude
+                    .zip(longitude)
+                    .map(|(latitude, longitude)| app::Location {
+                        latitude,
+                        longitude,
+                    }),
+                // The profile only changes at thresholds, which come at known times
+                polling: match_als_polling(poll_ms, max_poll_ms, DEFAULT_TIME_POLL_MS, 1),
             },
             file::Als::None => app::Als::None,
+        })
+        .collect(),
+        app: file_config
+            .app
+            .into_iter()
+            .map(|a| app::App {
+                app_id: a.app_id,
+                fullscreen: a.fullscreen,
+                mode: match a.mode {
+                    file::AppMode::Normal => app::AppMode::Normal,
+                    file::AppMode::Freeze => app::AppMode::Freeze,
+                    file::AppMode::Fixed(percent) => app::AppMode::Fixed {
+                        brightness: percent.saturating_mul(100),
+                    },
+                },
+            })
+            .collect(),
+        als_fusion: file_config.als_fusion.enabled.then(|| app::AlsFusion {
+            mode: match file_config.als_fusion.mode {
+                file::FusionMode::Max => app::FusionMode::Max,
+                file::FusionMode::Average => app::FusionMode::Average {
+                    webcam_weight: file_config
+                        .als_fusion
+                        .webcam_weight
+                        .unwrap_or(DEFAULT_FUSION_WEBCAM_WEIGHT),
+                },
+            },
+            webcam_scale: file_config
+                .als_fusion
+                .webcam_scale
+                .unwrap_or(DEFAULT_FUSION_WEBCAM_SCALE),
+        }),
+        notifications: file_config
+            .notifications
+            .enabled
+            .then(|| app::Notifications {
+                min_interval_secs: file_config
+                    .notifications
+                    .min_interval_secs
+                    .unwrap_or(DEFAULT_NOTIFICATIONS_MIN_INTERVAL_SECS),
+            }),
+        lock: file_config.lock.enabled.then(|| app::Lock {
+            brightness: file_config
+                .lock
+                .brightness
+                .map(|percent| percent.saturating_mul(100)),
+        }),
+        sleep: file_config.sleep.enabled.then(|| app::Sleep {
+            grace_secs: file_config
+                .sleep
+                .grace_secs
+                .unwrap_or(DEFAULT_SLEEP_GRACE_SECS),
+        }),
+        battery: file_config.battery.enabled.then(|| app::Battery {
+            brightness_offset: file_config.battery.brightness_offset.saturating_mul(100),
+            max_brightness: file_config
+                .battery
+                .max_brightness
+                .map(|percent| percent.saturating_mul(100)),
+            capture_interval: file_config
+                .battery
+                .capture_interval_ms
+                .map(Duration::from_millis),
+        }),
+        control: file_config.control.enabled,
+        als_export: file_config.als_export.enabled,
+        keys: file_config.keys.enabled,
+        exposure_bias: file_config.exposure_bias.saturating_mul(100),
+        restore_on_exit: file_config.restore_on_exit,
+        vulkan_device: file_config.vulkan_device,
+        luma_samples: file_config.luma_samples,
+        lightness: match_lightness(file_config.lightness),
+        predictor: app::PredictorTiming {
+            pending_cooldown_secs: file_config
+                .predictor
+                .pending_cooldown_secs
+                .unwrap_or(DEFAULT_PENDING_COOLDOWN_SECS),
+            als_cooldown_secs: file_config
+                .predictor
+                .als_cooldown_secs
+                .unwrap_or(DEFAULT_ALS_COOLDOWN_SECS),
         },
     })
 }
 
-fn validate(config: app::Config) -> Result<app::Config, Box<dyn Error>> {
+/// Problems with the values of the config, `content` is the file it was parsed from, to tell
+/// where they are.
+fn validate(config: app::Config, content: &str) -> Result<app::Config, String> {
+    let mut problems = Problems::default();
+
+    let als_profiles = config.als_profiles();
+    for output in &config.output {
+        check_output(&mut problems, output, &config.output, &als_profiles);
+    }
+    for als in &config.als {
+        check_als(&mut problems, als);
+    }
+    if let Some(fusion) = config.als_fusion {
+        check_als_fusion(&mut problems, fusion, &config.als);
+    }
+    check_predictor_timing(&mut problems, config.predictor);
+    for app in &config.app {
+        check_app(&mut problems, app);
+    }
+    check_sections(&mut problems, &config);
+
+    if config.als.is_empty() {
+        problems.other("No ALS configured");
+    }
+
     let names = config
         .output
         .iter()
-        .map(|output| match output {
-            app::Output::Backlight(app::BacklightOutput { name, .. }) => name,
-            app::Output::DdcUtil(DdcUtilOutput { name, .. }) => name,
-        })
+        .map(|output| &output.settings().name)
         .collect::<HashSet<_>>();
-
     match (names.len(), names.len() == config.output.len()) {
-        (0, _) => Err("No output or keyboard configured".into()),
-        (_, false) => Err("Names of all outputs and keyboards are not unique".into()),
-        _ => Ok(config),
+        (0, _) => problems.other("No output or keyboard configured"),
+        (_, false) => problems.other("Names of all outputs and keyboards are not unique"),
+        _ => {}
+    }
+
+    match problems.is_empty() {
+        true => Ok(config),
+        false => Err(problems.describe(content)),
+    }
+}
+
+fn check_output(
+    problems: &mut Problems,
+    output: &app::Output,
+    outputs: &[app::Output],
+    als_profiles: &[String],
+) {
+    let settings = output.settings();
+    let name = &settings.name;
+    match settings.max_brightness {
+        Some(0) => problems.output(
+            name,
+            "max_brightness",
+            format!("Max brightness of '{}' must be positive", name),
+        ),
+        Some(max_brightness) if settings.min_brightness > max_brightness => problems.output(
+            name,
+            "min_brightness",
+            format!(
+                "Min brightness of '{}' must not be above its max brightness",
+                name
+            ),
+        ),
+        _ => {}
+    }
+    if let app::Output::DdcUtil(cfg) = output {
+        if cfg.contrast_dimming && cfg.feature == crate::brightness::DDC_CONTRAST_FEATURE {
+            problems.output(
+                name,
+                "contrast_dimming",
+                format!(
+                    "Contrast dimming of '{}' requires a feature other than contrast",
+                    name
+                ),
+            );
+        }
+    }
+
+    check_capture(problems, settings, outputs);
+    check_luma(problems, settings);
+    check_curve(problems, name, &settings.curve);
+    check_scenes(problems, name, &settings.scenes);
+    check_predictor(problems, name, &settings.predictor, als_profiles);
+}
+
+/// What part of the screen is captured, and which output it's captured from.
+fn check_capture(problems: &mut Problems, settings: &app::OutputSettings, outputs: &[app::Output]) {
+    let name = &settings.name;
+    if settings.min_damage_percent > 100 {
+        problems.output(
+            name,
+            "min_damage_percent",
+            format!("Minimal damage percent of '{}' must be at most 100", name),
+        );
+    }
+    match settings.capture_region {
+        Some(app::CaptureRegion::Rect { width, height, .. }) if width == 0 || height == 0 => {
+            problems.output(
+                name,
+                "capture_region",
+                format!("Capture region of '{}' must not be empty", name),
+            )
+        }
+        Some(app::CaptureRegion::Margins {
+            top,
+            right,
+            bottom,
+            left,
+        }) if [top, right, bottom, left].iter().any(|m| *m < 0.0)
+            || top + bottom >= 100.0
+            || left + right >= 100.0 =>
+        {
+            problems.output(
+                name,
+                "capture_region",
+                format!(
+                    "Capture region margins of '{}' must be positive and leave some part of the screen",
+                    name
+                ),
+            )
+        }
+        _ => {}
+    }
+    if let Capturer::Mirror(source) = &settings.capturer {
+        let source_capturer = outputs
+            .iter()
+            .map(app::Output::settings)
+            .find(|output| &output.name == source)
+            .map(|output| &output.capturer);
+        match source_capturer {
+            None => problems.output(
+                name,
+                "capturer",
+                format!("Output '{}' mirrors unknown output '{}'", name, source),
+            ),
+            Some(Capturer::Mirror(_)) => problems.output(
+                name,
+                "capturer",
+                format!(
+                    "Output '{}' mirrors output '{}', which is a mirror itself",
+                    name, source
+                ),
+            ),
+            _ => {}
+        }
+    }
+}
+
+/// How luma is computed from the screen contents and what changes of it mean.
+fn check_luma(problems: &mut Problems, settings: &app::OutputSettings) {
+    let name = &settings.name;
+    if settings.luma_percentile.is_some_and(|p| p > 100) {
+        problems.output(
+            name,
+            "luma_percentile",
+            format!("Luma percentile of '{}' must be at most 100", name),
+        );
+    }
+    if let Some(oled) = settings.oled {
+        if !(1..=crate::brightness::BASIS_POINTS).contains(&oled.step) {
+            problems.output(
+                name,
+                "oled.step",
+                format!(
+                    "OLED step of '{}' must be a percentage between 1 and 100",
+                    name
+                ),
+            );
+        }
+        if !(oled.gamma.is_finite() && oled.gamma > 0.0) {
+            problems.output(
+                name,
+                "oled.gamma",
+                format!("OLED gamma of '{}' must be positive", name),
+            );
+        }
+    }
+    if let Some(luma_jump) = settings.luma_jump {
+        if !(1..=100).contains(&luma_jump.delta) {
+            problems.output(
+                name,
+                "luma_jump.delta",
+                format!(
+                    "Luma jump delta of '{}' must be a percentage between 1 and 100",
+                    name
+                ),
+            );
+        }
+        if !(1..=100).contains(&luma_jump.head_start) {
+            problems.output(
+                name,
+                "luma_jump.head_start",
+                format!(
+                    "Luma jump head start of '{}' must be a percentage between 1 and 100",
+                    name
+                ),
+            );
+        }
+    }
+    if settings
+        .luma_focus
+        .is_some_and(|focus| !(focus.spread > 0.0 && focus.spread <= MAX_LUMA_FOCUS_SPREAD))
+    {
+        problems.output(
+            name,
+            "luma_focus.spread",
+            format!(
+                "Luma focus spread of '{}' must be positive and at most {}",
+                name, MAX_LUMA_FOCUS_SPREAD
+            ),
+        );
+    }
+    if let Some(screen_off) = settings.screen_off {
+        if screen_off.max_luma > 100 {
+            problems.output(
+                name,
+                "screen_off.max_luma",
+                format!(
+                    "Screen off max luma of '{}' must be a percentage of at most 100",
+                    name
+                ),
+            );
+        }
+        if !(0.0..=MAX_COOLDOWN_SECS).contains(&screen_off.after_secs) {
+            problems.output(
+                name,
+                "screen_off.after_secs",
+                format!(
+                    "Screen off seconds of '{}' must be between 0 and {}",
+                    name, MAX_COOLDOWN_SECS
+                ),
+            );
+        }
+    }
+}
+
+fn check_curve(problems: &mut Problems, name: &str, curve: &app::Curve) {
+    match curve {
+        app::Curve::Exponential(exponent) if !(exponent.is_finite() && *exponent > 0.0) => {
+            problems.output(
+                name,
+                "curve.exponent",
+                format!("Curve exponent of '{}' must be positive", name),
+            );
+        }
+        app::Curve::Map(points)
+            if points.len() < 2
+                || points.iter().any(|&(brightness, raw)| {
+                    brightness > crate::brightness::BASIS_POINTS
+                        || raw > crate::brightness::BASIS_POINTS
+                }) =>
+        {
+            problems.output(
+                name,
+                "curve.map",
+                format!(
+                    "Curve map of '{}' must have at least two points, with percentages between 0 and 100",
+                    name
+                ),
+            );
+        }
+        app::Curve::Map(points) if points.windows(2).any(|pair| pair[0].1 > pair[1].1) => {
+            problems.output(
+                name,
+                "curve.map",
+                format!(
+                    "Curve map of '{}' must not go down as brightness goes up",
+                    name
+                ),
+            );
+        }
+        _ => {}
+    }
+}
+
+fn check_scenes(problems: &mut Problems, name: &str, scenes: &[(String, app::Scene)]) {
+    if scenes.iter().any(|(scene, _)| scene.is_empty()) {
+        problems.output(
+            name,
+            "scenes",
+            format!("Scenes of '{}' must have non-empty names", name),
+        );
+    }
+    if let Some((scene, _)) = scenes.iter().find(|(_, scene)| {
+        matches!(scene, app::Scene::Fixed { brightness } if *brightness > crate::brightness::BASIS_POINTS)
+    }) {
+        problems.output(
+            name,
+            &format!("scenes.{}.brightness", scene),
+            format!(
+                "Brightness of scene '{}' of '{}' must be a percentage between 0 and 100",
+                scene, name
+            ),
+        );
+    }
+}
+
+fn check_predictor(
+    problems: &mut Problems,
+    name: &str,
+    predictor: &app::Predictor,
+    als_profiles: &[String],
+) {
+    match predictor {
+        app::Predictor::Manual {
+            thresholds,
+            blend_profiles,
+        } => {
+            let missing = als_profiles
+                .iter()
+                .filter(|profile| !thresholds.contains_key(*profile))
+                .collect_vec();
+            if !missing.is_empty() {
+                log::warn!(
+                    "Manual predictor of '{}' has no thresholds for ALS profiles {}, {}",
+                    name,
+                    missing.iter().join(", "),
+                    match blend_profiles {
+                        true => "they are blended from the closest profiles that have them",
+                        false => "brightness is not reduced in them",
+                    }
+                );
+            }
+            let unknown = thresholds
+                .keys()
+                .filter(|profile| !als_profiles.contains(profile))
+                .sorted()
+                .collect_vec();
+            if !unknown.is_empty() {
+                log::warn!(
+                    "Manual predictor of '{}' has thresholds for ALS profiles {}, which no ALS has",
+                    name,
+                    unknown.iter().join(", ")
+                );
+            }
+        }
+        app::Predictor::Adaptive(adaptive) => {
+            if !(0.0..=1.0).contains(&adaptive.profile_fallback_discount) {
+                problems.output(
+                    name,
+                    "predictor.adaptive.profile_fallback_discount",
+                    format!(
+                        "Profile fallback discount of '{}' must be between 0 and 1",
+                        name
+                    ),
+                );
+            }
+            if adaptive.decay_days == Some(0) {
+                problems.output(
+                    name,
+                    "predictor.adaptive.decay_days",
+                    format!("Decay days of '{}' must be positive", name),
+                );
+            }
+            if adaptive.time_buckets.len() == 1 || adaptive.time_buckets.iter().any(|h| *h > 23) {
+                problems.output(
+                    name,
+                    "predictor.adaptive.time_buckets",
+                    format!(
+                        "Time buckets of '{}' must be at least two different hours between 0 and 23",
+                        name
+                    ),
+                );
+            }
+            if adaptive.compact_luma_epsilon > 100 {
+                problems.output(
+                    name,
+                    "predictor.adaptive.compact_luma_epsilon",
+                    format!(
+                        "Luma epsilon to compact learned data of '{}' must be at most 100",
+                        name
+                    ),
+                );
+            }
+            if adaptive
+                .data_group
+                .as_ref()
+                .is_some_and(|group| group.is_empty())
+            {
+                problems.output(
+                    name,
+                    "predictor.adaptive.data_group",
+                    format!("Data group of '{}' must have a name", name),
+                );
+            }
+        }
+        app::Predictor::Schedule { points } => {
+            if points.is_empty() {
+                problems.output(
+                    name,
+                    "predictor.schedule.points",
+                    format!("Schedule of '{}' must have at least one point", name),
+                );
+            }
+            if points
+                .iter()
+                .any(|(_, brightness)| *brightness > crate::brightness::BASIS_POINTS)
+            {
+                problems.output(
+                    name,
+                    "predictor.schedule.points",
+                    format!(
+                        "Brightness in the schedule of '{}' must be a percentage between 0 and 100",
+                        name
+                    ),
+                );
+            }
+        }
+    }
+}
+
+fn check_als(problems: &mut Problems, als: &app::Als) {
+    if let app::Als::Iio { thresholds, .. } | app::Als::Webcam { thresholds, .. } = als {
+        if thresholds.is_empty() {
+            problems.als(
+                &als.to_string(),
+                "thresholds",
+                format!("Thresholds of [als.{}] must not be empty", als),
+            );
+        }
+    }
+    if let app::Als::Time {
+        thresholds,
+        location,
+        ..
+    } = als
+    {
+        if thresholds.is_empty() {
+            problems.als(
+                &als.to_string(),
+                "thresholds",
+                format!("Thresholds of [als.{}] must not be empty", als),
+            );
+        }
+        let sun = thresholds
+            .keys()
+            .any(|time| !matches!(time, app::TimeThreshold::At(_)));
+        match location {
+            None if sun => problems.als(
+                "time",
+                "thresholds",
+                "Sunrise and sunset thresholds of [als.time] need latitude and longitude",
+            ),
+            Some(location)
+                if !(-90.0..=90.0).contains(&location.latitude)
+                    || !(-180.0..=180.0).contains(&location.longitude) =>
+            {
+                problems.als("time", "latitude", "Latitude of [als.time] must be between -90 and 90, and longitude between -180 and 180")
+            }
+            _ => {}
+        }
+    }
+    if let Some(polling) = als.polling() {
+        if polling.poll_ms == 0 {
+            problems.als(
+                &als.to_string(),
+                "poll_ms",
+                format!("Polling interval of [als.{}] must be positive", als),
+            );
+        } else if polling.max_poll_ms < polling.poll_ms {
+            problems.als(
+                &als.to_string(),
+                "max_poll_ms",
+                format!(
+                    "Longest polling interval of [als.{}] must be at least its poll_ms",
+                    als
+                ),
+            );
+        }
+    }
+    if let app::Als::Webcam {
+        region,
+        gamma,
+        screen_glow,
+        ..
+    } = als
+    {
+        if !(1..=100).contains(region) {
+            problems.als(
+                "webcam",
+                "region",
+                "Webcam region must be a percentage between 1 and 100",
+            );
+        }
+        if *screen_glow > 100 {
+            problems.als(
+                "webcam",
+                "screen_glow",
+                "Webcam screen glow must be at most 100, the highest webcam reading",
+            );
+        }
+        if !(gamma.is_finite() && *gamma > 0.0) {
+            problems.als("webcam", "gamma", "Webcam gamma must be positive");
+        }
+    }
+    if let app::Als::Iio {
+        hysteresis,
+        smoothing,
+        ..
+    }
+    | app::Als::Webcam {
+        hysteresis,
+        smoothing,
+        ..
+    } = als
+    {
+        if *hysteresis >= 100 {
+            problems.als(
+                &als.to_string(),
+                "hysteresis",
+                "ALS hysteresis must be a percentage below 100",
+            );
+        }
+        if !(*smoothing > 0.0 && *smoothing <= 1.0) {
+            problems.als(
+                &als.to_string(),
+                "smoothing",
+                "ALS smoothing must be above 0 and at most 1",
+            );
+        }
+    }
+    if let app::Als::Iio {
+        smoothing,
+        events: true,
+        ..
+    } = als
+    {
+        if *smoothing < 1.0 {
+            problems.als(
+                "iio",
+                "smoothing",
+                "ALS smoothing needs regular readings, it can't be used with events of [als.iio]",
+            );
+        }
+    }
+}
+
+fn check_als_fusion(problems: &mut Problems, fusion: app::AlsFusion, als: &[app::Als]) {
+    let has = |kind| als.iter().any(|als| als.to_string() == kind);
+    if !has("iio") || !has("webcam") {
+        problems.key(
+            "als_fusion",
+            "ALS fusion needs both [als.iio] and [als.webcam]",
+        );
+    }
+    if !(fusion.webcam_scale.is_finite() && fusion.webcam_scale > 0.0) {
+        problems.key(
+            "als_fusion.webcam_scale",
+            "ALS fusion webcam scale must be positive",
+        );
+    }
+    if let app::FusionMode::Average { webcam_weight } = fusion.mode {
+        if !(0.0..=1.0).contains(&webcam_weight) {
+            problems.key(
+                "als_fusion.webcam_weight",
+                "ALS fusion webcam weight must be between 0 and 1",
+            );
+        }
+    }
+}
+
+fn check_predictor_timing(problems: &mut Problems, timing: app::PredictorTiming) {
+    if [timing.pending_cooldown_secs, timing.als_cooldown_secs]
+        .iter()
+        .any(|secs| !(0.0..=MAX_COOLDOWN_SECS).contains(secs))
+    {
+        problems.key(
+            "predictor",
+            format!(
+                "Predictor cooldowns must be between 0 and {} seconds",
+                MAX_COOLDOWN_SECS
+            ),
+        );
+    }
+}
+
+fn check_app(problems: &mut Problems, app: &app::App) {
+    if app.app_id.is_empty() {
+        problems.key("app", "Application rules must have a non-empty 'app_id'");
+    }
+    if let app::AppMode::Fixed { brightness } = app.mode {
+        if brightness > crate::brightness::BASIS_POINTS {
+            problems.key(
+                "app",
+                format!(
+                    "Fixed brightness for '{}' must be a percentage between 0 and 100",
+                    app.app_id
+                ),
+            );
+        }
+    }
+}
+
+/// Sections and keys at the top of the config, other than outputs and ALS sources.
+fn check_sections(problems: &mut Problems, config: &app::Config) {
+    if let Some(app::Lock {
+        brightness: Some(brightness),
+    }) = config.lock
+    {
+        if brightness > crate::brightness::BASIS_POINTS {
+            problems.key(
+                "lock.brightness",
+                "Brightness while locked must be a percentage between 0 and 100",
+            );
+        }
+    }
+
+    if let Some(sleep) = config.sleep {
+        if !(0.0..=MAX_COOLDOWN_SECS).contains(&sleep.grace_secs) {
+            problems.key(
+                "sleep.grace_secs",
+                format!(
+                    "Grace period after resuming must be between 0 and {} seconds",
+                    MAX_COOLDOWN_SECS
+                ),
+            );
+        }
+    }
+
+    if let Some(battery) = config.battery {
+        if battery.brightness_offset.unsigned_abs() > crate::brightness::BASIS_POINTS {
+            problems.key(
+                "battery.brightness_offset",
+                "Brightness offset on battery must be a percentage between -100 and 100",
+            );
+        }
+        if battery
+            .max_brightness
+            .is_some_and(|brightness| brightness > crate::brightness::BASIS_POINTS)
+        {
+            problems.key(
+                "battery.max_brightness",
+                "Maximum brightness on battery must be a percentage between 0 and 100",
+            );
+        }
+    }
+
+    if config.luma_samples == Some(0) {
+        problems.key("luma_samples", "Luma samples must be positive");
+    }
+
+    if config.exposure_bias.unsigned_abs() > crate::brightness::BASIS_POINTS {
+        problems.key(
+            "exposure_bias",
+            "Exposure bias must be a percentage between -100 and 100",
+        );
+    }
+}
+
+/// Devices that are not there are not errors, they might show up later, e.g. when drivers are
+/// loaded late, so they are only checked once the config is valid.
+fn warn_missing_devices(config: &app::Config) {
+    for output in &config.output {
+        if let app::Output::Backlight(cfg) = output {
+            if crate::brightness::Backlight::find(&cfg.path, &cfg.settings.name).is_none() {
+                log::warn!(
+                    "Backlight of '{}' not found at '{}' yet",
+                    cfg.settings.name,
+                    cfg.path
+                );
+            }
+        }
+    }
+
+    for als in &config.als {
+        if let app::Als::Iio { path, .. } = als {
+            if !Path::new(path).exists() {
+                log::warn!("IIO devices not found at '{}'", path);
+            }
+        }
+        if let app::Als::Webcam {
+            video,
+            prefer_infrared: false,
+            ..
+        } = als
+        {
+            if !Path::new(&format!("/dev/video{}", video)).exists() {
+                log::warn!("Webcam not found at /dev/video{}", video);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_lists_all_invalid_keys() {
+        let err = parse(
+            r#"
+            [als.iio]
+            path = "/sys/bus/iio/devices"
+            thresholds = { 0 = "night", dark = "dark", 20 = "dim" }
+
+            [[output.backlight]]
+            name = "eDP-1"
+            path = "/sys/class/backlight/intel_backlight"
+            [output.backlight.predictor.manual]
+            thresholds.night = { 0 = 0, 150 = 10 }
+            "#,
+            &Overrides::default(),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            "found 2 problems:\n  - line 4: Threshold 'dark' of [als.iio] must be a whole number\n  - line 10: Luma '150' in the thresholds of 'night' of the manual predictor of 'eDP-1' must be a percentage between 0 and 100",
+            err
+        );
+    }
+
+    #[test]
+    fn test_parse_tells_what_is_wrong_with_scenes() {
+        let parse_scene = |scene: &str| {
+            let content = format!(
+                "[als.none]\n[[output.ddcutil]]\nname = \"DELL\"\nscenes.movie = {}",
+                scene
+            );
+            parse(&content, &Overrides::default()).map(|_| ())
+        };
+
+        assert_eq!(Ok(()), parse_scene("{ brightness = 30 }"));
+        assert!(parse_scene("{ thresholds.dim = { 0 = 30 } }").is_ok());
+        assert!(parse_scene("{}")
+            .unwrap_err()
+            .contains("a scene needs either `brightness` or `thresholds`"));
+        assert!(
+            parse_scene("{ brightness = 30, thresholds.dim = { 0 = 30 } }")
+                .unwrap_err()
+                .contains("a scene needs either `brightness` or `thresholds`")
+        );
+        assert!(parse_scene("{ brigthness = 30 }")
+            .unwrap_err()
+            .contains("unknown field `brigthness`, expected `brightness` or `thresholds`"));
+        assert!(parse_scene("{ brightness = \"dim\" }")
+            .unwrap_err()
+            .contains("invalid type: string \"dim\", expected u64"));
+    }
+
+    #[test]
+    fn test_parse_applies_overrides() {
+        let config = parse(
+            r#"
+            [als.iio]
+            path = "/sys/bus/iio/devices"
+            thresholds = { 0 = "dark", 100 = "bright" }
+
+            [[output.backlight]]
+            name = "eDP-1"
+            path = "/sys/class/backlight/intel_backlight"
+            "#,
+            &Overrides {
+                als: Some("none".to_string()),
+                backlight_path: Some("/sys/class/backlight/amdgpu_bl1".to_string()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        assert!(matches!(config.als.as_slice(), [app::Als::None]));
+        assert!(matches!(
+            &config.output[0],
+            app::Output::Backlight(output) if output.path == "/sys/class/backlight/amdgpu_bl1"
+        ));
+    }
+
+    #[test]
+    fn test_validate_lists_all_problems() {
+        let content = r#"
+            exposure_bias = 200
+
+            [als.time]
+            thresholds = {}
+
+            [[output.ddcutil]]
+            name = "DELL"
+            luma_percentile = 101
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            "found 3 problems:\n  - line 9: Luma percentile of 'DELL' must be at most 100\n  - line 5: Thresholds of [als.time] must not be empty\n  - line 2: Exposure bias must be a percentage between -100 and 100",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_time_threshold() {
+        use app::TimeThreshold::*;
+
+        assert_eq!(Some(At(7 * 60)), parse_time_threshold("7"));
+        assert_eq!(Some(At(6 * 60 + 30)), parse_time_threshold("06:30"));
+        assert_eq!(Some(Sunrise(0)), parse_time_threshold("sunrise"));
+        assert_eq!(Some(Sunset(-30)), parse_time_threshold("sunset-30"));
+        assert_eq!(Some(Sunrise(45)), parse_time_threshold("sunrise+45"));
+        assert_eq!(None, parse_time_threshold("24"));
+        assert_eq!(None, parse_time_threshold("6:3"));
+        assert_eq!(None, parse_time_threshold("sunset30"));
+        assert_eq!(None, parse_time_threshold("noon"));
+    }
+
+    #[test]
+    fn test_validate_sun_thresholds_need_location() {
+        let content = r#"
+            [als.time]
+            thresholds = { 0 = "night", "sunrise-30" = "dim", "07:30" = "day", sunset = "dark" }
+
+            [[output.ddcutil]]
+            name = "DELL"
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            "line 3: Sunrise and sunset thresholds of [als.time] need latitude and longitude",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_als_fusion() {
+        let content = r#"
+            [als.iio]
+            path = "/sys/bus/iio/devices"
+            thresholds = { 0 = "dark", 100 = "bright" }
+
+            [als_fusion]
+            enabled = true
+            mode = "average"
+            webcam_weight = 2.0
+
+            [[output.ddcutil]]
+            name = "DELL"
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            "found 2 problems:\n  - line 6: ALS fusion needs both [als.iio] and [als.webcam]\n  - line 9: ALS fusion webcam weight must be between 0 and 1",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_als_polling_and_events() {
+        let content = r#"
+            [[als]]
+            [als.iio]
+            path = "/sys/bus/iio/devices"
+            thresholds = { 0 = "dark", 100 = "bright" }
+            poll_ms = 200
+            events = true
+            smoothing = 0.5
+
+            [[als]]
+            [als.time]
+            thresholds = { 0 = "night", 7 = "day" }
+            max_poll_ms = 1000
+
+            [[output.ddcutil]]
+            name = "DELL"
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            Some(app::AlsPolling {
+                poll_ms: 200,
+                max_poll_ms: 2000,
+            }),
+            config.als[0].polling()
+        );
+        assert_eq!(
+            "found 2 problems:\n  - line 8: ALS smoothing needs regular readings, it can't be used with events of [als.iio]\n  - line 13: Longest polling interval of [als.time] must be at least its poll_ms",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_sleep_grace() {
+        let content = r#"
+            [als.none]
+
+            [sleep]
+            enabled = true
+            grace_secs = -1
+
+            [[output.ddcutil]]
+            name = "DELL"
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            "line 6: Grace period after resuming must be between 0 and 3600 seconds",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_luma_focus() {
+        let content = r#"
+            [als.none]
+
+            [[output.ddcutil]]
+            name = "DELL"
+            luma_focus = { spread = 0 }
+
+            [[output.backlight]]
+            name = "eDP-1"
+            path = "/sys/class/backlight/intel_backlight"
+            [output.backlight.luma_focus]
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert!(matches!(
+            &config.output[0],
+            app::Output::Backlight(output) if output.settings.luma_focus == Some(app::LumaFocus {
+                spread: 0.5,
+                follow_window: true,
+            })
+        ));
+        assert_eq!(
+            "line 6: Luma focus spread of 'DELL' must be positive and at most 10",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_luma_samples() {
+        let content = r#"
+            luma_samples = 0
+
+            [als.none]
+
+            [[output.ddcutil]]
+            name = "DELL"
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            "line 2: Luma samples must be positive",
+            validate(config, content).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_validate_reports_single_problem_as_is() {
+        let content = r#"
+            [als.none]
+
+            [[output.ddcutil]]
+            name = "DELL"
+            luma_percentile = 101
+            "#;
+        let config = parse(content, &Overrides::default()).unwrap();
+
+        assert_eq!(
+            "line 6: Luma percentile of 'DELL' must be at most 100",
+            validate(config, content).unwrap_err()
+        );
     }
 }
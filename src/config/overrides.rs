@@ -0,0 +1,235 @@
+use super::{file, DEFAULT_IIO_THRESHOLDS, DEFAULT_TIME_THRESHOLDS, DEFAULT_WEBCAM_THRESHOLDS};
+use crate::diagnose::IIO_PATH;
+use lazy_static::lazy_static;
+use std::sync::Mutex;
+
+lazy_static! {
+    static ref OVERRIDES: Mutex<Overrides> = Mutex::new(Overrides::default());
+}
+
+/// Config values changed for this run only, from command line options or environment variables.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Overrides {
+    /// Kind of the only ALS source to use, e.g. `time`.
+    pub als: Option<String>,
+    /// Capturer of all outputs.
+    pub capturer: Option<String>,
+    /// Path of all backlight outputs.
+    pub backlight_path: Option<String>,
+}
+
+impl Overrides {
+    /// Environment variables, e.g. `WLUMA_ALS=none`.
+    fn from_env() -> Self {
+        let var = |name| std::env::var(name).ok();
+        Self {
+            als: var("WLUMA_ALS"),
+            capturer: var("WLUMA_CAPTURER"),
+            backlight_path: var("WLUMA_OUTPUT_BACKLIGHT_PATH"),
+        }
+    }
+
+    /// Values set here win over the other ones.
+    fn or(self, other: Self) -> Self {
+        Self {
+            als: self.als.or(other.als),
+            capturer: self.capturer.or(other.capturer),
+            backlight_path: self.backlight_path.or(other.backlight_path),
+        }
+    }
+
+    pub(super) fn apply(&self, config: &mut file::Config) -> Result<(), String> {
+        if let Some(kind) = &self.als {
+            let sources = match std::mem::replace(&mut config.als, file::AlsSources::Many(vec![])) {
+                file::AlsSources::One(als) => vec![als],
+                file::AlsSources::Many(als) => als,
+            };
+            let als = match sources.into_iter().find(|als| kind_of(als) == kind) {
+                Some(als) => als,
+                None => default_als(kind)?,
+            };
+            config.als = file::AlsSources::One(als);
+            // Only one source is left to read
+            config.als_fusion.enabled = false;
+        }
+
+        if let Some(capturer) = &self.capturer {
+            let capturer = || {
+                toml::Value::from(capturer.as_str())
+                    .try_into::<file::Capturer>()
+                    .map_err(|_| format!("Unknown capturer '{}'", capturer))
+            };
+            for output in &mut config.output.backlight {
+                output.capturer = Some(capturer()?);
+            }
+            for output in &mut config.output.ddcutil {
+                output.capturer = Some(capturer()?);
+            }
+        }
+
+        if let Some(path) = &self.backlight_path {
+            for output in &mut config.output.backlight {
+                output.path = path.clone();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Sets the overrides given on the command line, which win over the environment variables.
+pub fn set(overrides: Overrides) {
+    *OVERRIDES.lock().unwrap() = overrides;
+}
+
+/// Overrides of this run, from the command line or else from the environment.
+pub(super) fn current() -> Overrides {
+    OVERRIDES.lock().unwrap().clone().or(Overrides::from_env())
+}
+
+fn kind_of(als: &file::Als) -> &'static str {
+    match als {
+        file::Als::Iio { .. } => "iio",
+        file::Als::Time { .. } => "time",
+        file::Als::Webcam { .. } => "webcam",
+        file::Als::None => "none",
+    }
+}
+
+/// Source used by `--als`, when there is none of the kind in the config.
+fn default_als(kind: &str) -> Result<file::Als, String> {
+    let defaults = format!(
+        "iio = {{ path = {}, thresholds = {} }}\n\
+         webcam = {{ video = 0, thresholds = {} }}\n\
+         time = {{ thresholds = {} }}\n\
+         none = {{}}",
+        toml::Value::from(IIO_PATH),
+        DEFAULT_IIO_THRESHOLDS,
+        DEFAULT_WEBCAM_THRESHOLDS,
+        DEFAULT_TIME_THRESHOLDS
+    );
+    let mut defaults: toml::Table = toml::from_str(&defaults).expect("Invalid default ALS");
+    let als = defaults.remove(kind).ok_or_else(|| {
+        format!(
+            "Unknown ALS '{}', expected one of iio, webcam, time, none",
+            kind
+        )
+    })?;
+    toml::Value::Table(toml::Table::from_iter([(kind.to_string(), als)]))
+        .try_into()
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> file::Config {
+        toml::from_str(
+            r#"
+            [[als]]
+            [als.iio]
+            path = "/sys/bus/iio/devices"
+            thresholds = { 0 = "dark", 100 = "bright" }
+
+            [[als]]
+            [als.time]
+            thresholds = { 0 = "night", 8 = "day" }
+
+            [[output.backlight]]
+            name = "eDP-1"
+            path = "/sys/class/backlight/intel_backlight"
+
+            [[output.ddcutil]]
+            name = "DP-1"
+            capturer = "wayland"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut config = config();
+        let overrides = Overrides {
+            als: Some("time".to_string()),
+            capturer: Some("none".to_string()),
+            backlight_path: Some("/sys/class/backlight/amdgpu_bl1".to_string()),
+        };
+        overrides.apply(&mut config).unwrap();
+
+        assert!(matches!(
+            config.als,
+            file::AlsSources::One(file::Als::Time { ref thresholds, .. }) if thresholds.len() == 2
+        ));
+        assert!(matches!(
+            config.output.ddcutil[0].capturer,
+            Some(file::Capturer::None)
+        ));
+        assert!(matches!(
+            config.output.backlight[0].capturer,
+            Some(file::Capturer::None)
+        ));
+        assert_eq!(
+            "/sys/class/backlight/amdgpu_bl1",
+            config.output.backlight[0].path
+        );
+    }
+
+    #[test]
+    fn test_apply_als_not_in_config() {
+        let mut config = config();
+        let overrides = Overrides {
+            als: Some("none".to_string()),
+            ..Default::default()
+        };
+        overrides.apply(&mut config).unwrap();
+        assert!(matches!(config.als, file::AlsSources::One(file::Als::None)));
+
+        let overrides = Overrides {
+            als: Some("webcam".to_string()),
+            ..Default::default()
+        };
+        overrides.apply(&mut config).unwrap();
+        assert!(matches!(
+            config.als,
+            file::AlsSources::One(file::Als::Webcam { video: 0, .. })
+        ));
+    }
+
+    #[test]
+    fn test_apply_unknown_values() {
+        let overrides = Overrides {
+            als: Some("sun".to_string()),
+            ..Default::default()
+        };
+        assert!(overrides.apply(&mut config()).is_err());
+
+        let overrides = Overrides {
+            capturer: Some("pipewire".to_string()),
+            ..Default::default()
+        };
+        assert!(overrides.apply(&mut config()).is_err());
+    }
+
+    #[test]
+    fn test_command_line_wins_over_environment() {
+        let command_line = Overrides {
+            als: Some("time".to_string()),
+            ..Default::default()
+        };
+        let environment = Overrides {
+            als: Some("none".to_string()),
+            capturer: Some("none".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            Overrides {
+                als: Some("time".to_string()),
+                capturer: Some("none".to_string()),
+                backlight_path: None,
+            },
+            command_line.or(environment)
+        );
+    }
+}
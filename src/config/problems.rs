@@ -0,0 +1,151 @@
+use itertools::Itertools;
+use toml_edit::{ImDocument, Item, Table, TableLike};
+
+/// Part of the config that a problem is about, looked up in the file once it's reported.
+enum At {
+    /// Key of the output or keyboard with the given name, e.g. `oled.step`.
+    Output(String, String),
+    /// Key of the ALS source of the given kind, e.g. `region` of `webcam`.
+    Als(String, String),
+    /// Key from the top of the config, e.g. `lock.brightness`.
+    Key(String),
+    /// Nothing in particular, e.g. something that is missing.
+    Nowhere,
+}
+
+/// Problems found in the config, reported all at once so that they can be fixed at once.
+#[derive(Default)]
+pub(super) struct Problems(Vec<(At, String)>);
+
+impl Problems {
+    /// Problem with a key (dotted for nested ones) of the output or keyboard with the given name.
+    pub(super) fn output(&mut self, name: &str, key: &str, message: impl Into<String>) {
+        self.0.push((
+            At::Output(name.to_string(), key.to_string()),
+            message.into(),
+        ));
+    }
+
+    /// Problem with a key of the ALS source of the given kind, e.g. `iio`.
+    pub(super) fn als(&mut self, kind: &str, key: &str, message: impl Into<String>) {
+        self.0
+            .push((At::Als(kind.to_string(), key.to_string()), message.into()));
+    }
+
+    pub(super) fn key(&mut self, key: &str, message: impl Into<String>) {
+        self.0.push((At::Key(key.to_string()), message.into()));
+    }
+
+    pub(super) fn other(&mut self, message: impl Into<String>) {
+        self.0.push((At::Nowhere, message.into()));
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Lists the problems, each with the line of the config it's on, if it's in there at all,
+    /// e.g. not when it comes from a default or an override.
+    pub(super) fn describe(&self, content: &str) -> String {
+        let document = ImDocument::parse(content).ok();
+        let errors = self
+            .0
+            .iter()
+            .map(
+                |(at, message)| match document.as_ref().and_then(|document| line(document, at)) {
+                    Some(line) => format!("line {}: {}", line, message),
+                    None => message.clone(),
+                },
+            )
+            .collect_vec();
+
+        match errors.as_slice() {
+            [error] => error.clone(),
+            _ => format!(
+                "found {} problems:\n{}",
+                errors.len(),
+                errors
+                    .iter()
+                    .map(|error| format!("  - {}", error))
+                    .join("\n")
+            ),
+        }
+    }
+}
+
+/// Line of the most specific part of the document that the problem is found at.
+fn line(document: &ImDocument<&str>, at: &At) -> Option<usize> {
+    let root = document.as_table();
+    let (table, mut span, key): (&dyn TableLike, _, _) = match at {
+        At::Output(name, key) => {
+            let output = root.get("output");
+            let table = named(output.and_then(|output| output.get("backlight")), name)
+                .or_else(|| named(output.and_then(|output| output.get("ddcutil")), name))
+                .or_else(|| named(root.get("keyboard"), name))?;
+            (table, table.get("name")?.span(), key)
+        }
+        At::Als(kind, key) => {
+            let als = root.get("als")?;
+            let source = match als.as_array_of_tables() {
+                Some(sources) => sources.iter().find_map(|source| source.get(kind)),
+                None => als.get(kind),
+            }?;
+            (source.as_table_like()?, source.span(), key)
+        }
+        At::Key(key) => (root, None, key),
+        At::Nowhere => return None,
+    };
+
+    // Keys that are not in the file, e.g. defaults, point at the closest part that is
+    let mut parent: Option<&Item> = None;
+    for segment in key.split('.') {
+        let item = match parent {
+            Some(parent) => parent.get(segment),
+            None => table.get(segment),
+        };
+        match item {
+            Some(item) => {
+                span = item.span().or(span);
+                parent = Some(item);
+            }
+            None => break,
+        }
+    }
+    span.map(|span| document.raw()[..span.start].matches('\n').count() + 1)
+}
+
+/// Table of the array that has the given name, e.g. one of `[[output.backlight]]`.
+fn named<'a>(tables: Option<&'a Item>, name: &str) -> Option<&'a Table> {
+    tables?
+        .as_array_of_tables()?
+        .iter()
+        .find(|table| table.get("name").and_then(Item::as_str) == Some(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe_points_at_closest_part_in_the_file() {
+        let content = r#"
+            [[als]]
+            [als.webcam]
+            video = 0
+
+            [[output.ddcutil]]
+            name = "DELL"
+            oled = { gamma = 0.0 }
+            "#;
+        let mut problems = Problems::default();
+        problems.output("DELL", "oled.gamma", "gamma");
+        problems.output("DELL", "luma_jump.delta", "delta");
+        problems.als("webcam", "region", "region");
+        problems.other("other");
+
+        assert_eq!(
+            "found 4 problems:\n  - line 8: gamma\n  - line 7: delta\n  - line 3: region\n  - other",
+            problems.describe(content)
+        );
+    }
+}
@@ -0,0 +1,687 @@
+use crate::brightness::Command;
+use crate::error::WlumaError;
+use crate::logging;
+use crate::scene::{Switch, Switcher};
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    PropertiesPropertiesChanged, RequestNameReply,
+};
+use dbus::blocking::Connection;
+use dbus::message::{MatchRule, MessageType, SignalArgs};
+use dbus::{Message, MethodErr, Path};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub const NAME: &str = "org.wluma.Control";
+pub const PATH: &str = "/org/wluma/Control";
+pub const INTERFACE: &str = "org.wluma.Control";
+
+const BUS_NAME: &str = "org.freedesktop.DBus";
+const BUS_INTERFACE: &str = "org.freedesktop.DBus";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const INTROSPECTABLE_INTERFACE: &str = "org.freedesktop.DBus.Introspectable";
+const PROPERTIES: [&str; 10] = [
+    "CurrentProfile",
+    "AlsValue",
+    "AlsSmoothedValue",
+    "Brightness",
+    "Luma",
+    "Paused",
+    "Inhibitors",
+    "ExposureBias",
+    "DisabledOutputs",
+    "Scenes",
+];
+/// Exposure bias accepted over D-Bus, in percent of the brightness range.
+const MAX_EXPOSURE_BIAS: i32 = 100;
+/// How long to wait for the running instance to answer method calls.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait for method calls, before announcing the changes reported meanwhile.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+const INTROSPECTION: &str = r#"<!DOCTYPE node PUBLIC "-//freedesktop//DTD D-BUS Object Introspection 1.0//EN"
+ "http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd">
+<node>
+  <interface name="org.wluma.Control">
+    <method name="Pause"/>
+    <method name="Resume"/>
+    <method name="Inhibit">
+      <arg name="reason" type="s" direction="in"/>
+    </method>
+    <method name="Uninhibit">
+      <arg name="reason" type="s" direction="in"/>
+    </method>
+    <method name="SetExposureBias">
+      <arg name="bias" type="i" direction="in"/>
+    </method>
+    <method name="Disable">
+      <arg name="output" type="s" direction="in"/>
+    </method>
+    <method name="Enable">
+      <arg name="output" type="s" direction="in"/>
+    </method>
+    <method name="SetScene">
+      <arg name="output" type="s" direction="in"/>
+      <arg name="scene" type="s" direction="in"/>
+    </method>
+    <method name="NextScene">
+      <arg name="output" type="s" direction="in"/>
+    </method>
+    <method name="SetLogFilter">
+      <arg name="filter" type="s" direction="in"/>
+    </method>
+    <property name="CurrentProfile" type="s" access="read"/>
+    <property name="AlsValue" type="t" access="read"/>
+    <property name="AlsSmoothedValue" type="t" access="read"/>
+    <property name="Brightness" type="a{su}" access="read"/>
+    <property name="Luma" type="a{sy}" access="read"/>
+    <property name="Paused" type="b" access="read"/>
+    <property name="Inhibitors" type="as" access="read"/>
+    <property name="ExposureBias" type="i" access="read"/>
+    <property name="DisabledOutputs" type="as" access="read"/>
+    <property name="Scenes" type="a{ss}" access="read"/>
+  </interface>
+  <interface name="org.freedesktop.DBus.Properties">
+    <method name="Get">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="property_name" type="s" direction="in"/>
+      <arg name="value" type="v" direction="out"/>
+    </method>
+    <method name="GetAll">
+      <arg name="interface_name" type="s" direction="in"/>
+      <arg name="properties" type="a{sv}" direction="out"/>
+    </method>
+    <signal name="PropertiesChanged">
+      <arg name="interface_name" type="s"/>
+      <arg name="changed_properties" type="a{sv}"/>
+      <arg name="invalidated_properties" type="as"/>
+    </signal>
+  </interface>
+  <interface name="org.freedesktop.DBus.Introspectable">
+    <method name="Introspect">
+      <arg name="xml_data" type="s" direction="out"/>
+    </method>
+  </interface>
+</node>
+"#;
+
+lazy_static! {
+    static ref UPDATE_TX: Mutex<Option<Sender<Update>>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Update {
+    Profile(String),
+    /// Raw value of the ALS, in its own units.
+    AlsValue(u64),
+    /// Value of the ALS that decides the profile, after smoothing.
+    AlsSmoothedValue(u64),
+    Brightness {
+        output_name: String,
+        /// In basis points.
+        brightness: u64,
+    },
+    Luma {
+        output_name: String,
+        luma: u8,
+    },
+    Scene {
+        output_name: String,
+        /// Empty when no scene is switched on.
+        scene: String,
+    },
+}
+
+/// Holds brightness still for the reason while the returned connection is open, wluma drops
+/// the reason once it's closed, e.g. when the caller exits.
+pub fn inhibit(reason: &str) -> Result<Connection, dbus::Error> {
+    let connection = Connection::new_session()?;
+    connection
+        .with_proxy(NAME, PATH, CALL_TIMEOUT)
+        .method_call::<(), _, _, _>(INTERFACE, "Inhibit", (reason,))?;
+    Ok(connection)
+}
+
+/// Publishes the update on D-Bus, if the D-Bus service is enabled.
+pub fn report(update: Update) {
+    if let Some(tx) = UPDATE_TX.lock().unwrap().as_ref() {
+        tx.send(update).ok();
+    }
+}
+
+/// Exposure bias that can be changed on D-Bus, which is remembered across restarts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExposureBias {
+    /// Configured bias this one was changed from, in basis points.
+    configured: i64,
+    /// In basis points.
+    pub current: i64,
+}
+
+impl ExposureBias {
+    /// Restores the bias changed on D-Bus, unless the configured one has changed since then.
+    pub fn load(configured: i64) -> Self {
+        Self::path()
+            .ok()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_yaml::from_reader::<_, Self>(file).ok())
+            .filter(|saved| saved.configured == configured)
+            .unwrap_or(Self {
+                configured,
+                current: configured,
+            })
+    }
+
+    fn save(&self) -> Result<(), WlumaError> {
+        let file = File::create(Self::path()?).map_err(WlumaError::brightness)?;
+        serde_yaml::to_writer(file, self).map_err(WlumaError::brightness)
+    }
+
+    fn path() -> Result<PathBuf, WlumaError> {
+        data_path("exposure-bias.yaml")
+    }
+}
+
+/// Outputs disabled on D-Bus, which stay disabled across restarts until enabled again.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DisabledOutputs(BTreeSet<String>);
+
+impl DisabledOutputs {
+    pub fn load() -> Self {
+        Self::path()
+            .ok()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_yaml::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self) -> Result<(), WlumaError> {
+        let file = File::create(Self::path()?).map_err(WlumaError::brightness)?;
+        serde_yaml::to_writer(file, self).map_err(WlumaError::brightness)
+    }
+
+    fn path() -> Result<PathBuf, WlumaError> {
+        data_path("disabled-outputs.yaml")
+    }
+}
+
+fn data_path(file_name: &str) -> Result<PathBuf, WlumaError> {
+    Ok(xdg::BaseDirectories::with_prefix("wluma")
+        .map_err(WlumaError::brightness)?
+        .create_data_directory("")
+        .map_err(WlumaError::brightness)?
+        .join(file_name))
+}
+
+/// State exposed as properties of the D-Bus service.
+#[derive(Debug, Default)]
+struct Status {
+    profile: String,
+    als_value: u64,
+    als_smoothed_value: u64,
+    /// In percent, per output.
+    brightness: BTreeMap<String, u32>,
+    /// In percent, per output.
+    luma: BTreeMap<String, u8>,
+    paused: bool,
+    /// Reasons brightness is held still for, e.g. "screencast", by any caller.
+    inhibitors: BTreeSet<String>,
+    /// In percent of the brightness range.
+    exposure_bias: i32,
+    disabled_outputs: Vec<String>,
+    /// Switched on scenes, per output.
+    scenes: BTreeMap<String, String>,
+}
+
+impl Status {
+    /// Returns the name of the property that changed, if any.
+    fn apply(&mut self, update: Update) -> Option<&'static str> {
+        match update {
+            Update::Profile(profile) if profile != self.profile => {
+                self.profile = profile;
+                Some("CurrentProfile")
+            }
+            Update::Profile(_) => None,
+            Update::AlsValue(value) => {
+                (std::mem::replace(&mut self.als_value, value) != value).then_some("AlsValue")
+            }
+            Update::AlsSmoothedValue(value) => {
+                (std::mem::replace(&mut self.als_smoothed_value, value) != value)
+                    .then_some("AlsSmoothedValue")
+            }
+            Update::Brightness {
+                output_name,
+                brightness,
+            } => {
+                let percent = (brightness / 100) as u32;
+                (self.brightness.insert(output_name, percent) != Some(percent))
+                    .then_some("Brightness")
+            }
+            Update::Luma { output_name, luma } => {
+                (self.luma.insert(output_name, luma) != Some(luma)).then_some("Luma")
+            }
+            Update::Scene { output_name, scene } if scene.is_empty() => {
+                self.scenes.remove(&output_name).map(|_| "Scenes")
+            }
+            Update::Scene { output_name, scene } => (self.scenes.get(&output_name) != Some(&scene))
+                .then(|| {
+                    self.scenes.insert(output_name, scene);
+                    "Scenes"
+                }),
+        }
+    }
+
+    fn property(&self, name: &str) -> Option<Variant<Box<dyn RefArg>>> {
+        let value: Box<dyn RefArg> = match name {
+            "CurrentProfile" => Box::new(self.profile.clone()),
+            "AlsValue" => Box::new(self.als_value),
+            "AlsSmoothedValue" => Box::new(self.als_smoothed_value),
+            "Brightness" => Box::new(self.brightness.clone()),
+            "Luma" => Box::new(self.luma.clone()),
+            "Paused" => Box::new(self.paused),
+            "Inhibitors" => Box::new(self.inhibitors.iter().cloned().collect::<Vec<_>>()),
+            "ExposureBias" => Box::new(self.exposure_bias),
+            "DisabledOutputs" => Box::new(self.disabled_outputs.clone()),
+            "Scenes" => Box::new(self.scenes.clone()),
+            _ => return None,
+        };
+        Some(Variant(value))
+    }
+
+    fn properties<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> PropMap {
+        names
+            .into_iter()
+            .filter_map(|name| Some((name.to_string(), self.property(name)?)))
+            .collect()
+    }
+}
+
+/// Exposes the current state of wluma on the session bus, and lets other programs control it.
+pub struct Service {
+    update_rx: Receiver<Update>,
+    /// Per output.
+    command_txs: HashMap<String, Sender<Command>>,
+    /// Per output that has scenes.
+    switchers: HashMap<String, Switcher>,
+    exposure_bias: ExposureBias,
+    disabled_outputs: DisabledOutputs,
+    /// Bus names of the callers holding each reason, so that the reasons of callers that
+    /// disconnect without withdrawing them, e.g. crashed ones, are dropped too.
+    inhibitor_holders: BTreeMap<String, BTreeSet<String>>,
+    status: Status,
+}
+
+impl Service {
+    pub fn new(exposure_bias: ExposureBias, disabled_outputs: DisabledOutputs) -> Self {
+        let (update_tx, update_rx) = mpsc::channel();
+        *UPDATE_TX.lock().unwrap() = Some(update_tx);
+
+        Self {
+            update_rx,
+            command_txs: HashMap::new(),
+            switchers: HashMap::new(),
+            exposure_bias,
+            status: Status {
+                exposure_bias: (exposure_bias.current / 100) as i32,
+                disabled_outputs: disabled_outputs.0.iter().cloned().collect(),
+                ..Status::default()
+            },
+            disabled_outputs,
+            inhibitor_holders: BTreeMap::new(),
+        }
+    }
+
+    pub fn run(
+        &mut self,
+        command_txs: HashMap<String, Sender<Command>>,
+        switchers: HashMap<String, Switcher>,
+    ) {
+        self.command_txs = command_txs;
+        self.switchers = switchers;
+        self.send_disabled(true);
+        if let Err(err) = self.serve() {
+            log::warn!(
+                "Unable to provide D-Bus service '{}', it is disabled: {}",
+                NAME,
+                err
+            );
+            // Otherwise there would be no way to enable them again
+            self.send_disabled(false);
+        }
+        *UPDATE_TX.lock().unwrap() = None;
+    }
+
+    fn serve(&mut self) -> Result<(), dbus::Error> {
+        let connection = Connection::new_session()?;
+        if connection.request_name(NAME, false, false, true)? != RequestNameReply::PrimaryOwner {
+            return Err(dbus::Error::new_failed(
+                "the name is already taken, is another instance running?",
+            ));
+        }
+        log::debug!("Providing D-Bus service '{}'", NAME);
+        let rule = MatchRule::new_signal(BUS_INTERFACE, "NameOwnerChanged").with_sender(BUS_NAME);
+        connection.add_match_no_cb(&rule.match_str())?;
+
+        loop {
+            let mut changed = BTreeSet::new();
+
+            if let Some(message) = connection.channel().blocking_pop_message(POLL_INTERVAL)? {
+                match message.msg_type() {
+                    MessageType::MethodCall => {
+                        let reply = self
+                            .handle(&message, &mut changed)
+                            .unwrap_or_else(|err| err.to_message(&message));
+                        connection.channel().send(reply).ok();
+                    }
+                    MessageType::Signal => {
+                        if let Ok((name, _, "")) = message.read3::<&str, &str, &str>() {
+                            self.drop_inhibitors(name, &mut changed);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            for update in self.update_rx.try_iter() {
+                changed.extend(self.status.apply(update));
+            }
+
+            if !changed.is_empty() {
+                let signal = PropertiesPropertiesChanged {
+                    interface_name: INTERFACE.to_string(),
+                    changed_properties: self.status.properties(changed),
+                    invalidated_properties: Vec::new(),
+                };
+                connection
+                    .channel()
+                    .send(signal.to_emit_message(&Path::from(PATH)))
+                    .ok();
+            }
+        }
+    }
+
+    fn handle(
+        &mut self,
+        message: &Message,
+        changed: &mut BTreeSet<&'static str>,
+    ) -> Result<Message, MethodErr> {
+        let path = message.path().map(|path| path.to_string());
+        if path.as_deref() != Some(PATH) {
+            return Err(MethodErr::no_path(&path.unwrap_or_default()));
+        }
+
+        let member = message.member().map(|member| member.to_string());
+        let member = member.unwrap_or_default();
+        match (message.interface().as_deref(), member.as_str()) {
+            (Some(INTROSPECTABLE_INTERFACE), "Introspect") => {
+                Ok(message.method_return().append1(INTROSPECTION))
+            }
+            (Some(PROPERTIES_INTERFACE), "Get") => {
+                let (interface, name): (&str, &str) = message.read2()?;
+                if interface != INTERFACE {
+                    return Err(MethodErr::no_interface(interface));
+                }
+                let value = self
+                    .status
+                    .property(name)
+                    .ok_or_else(|| MethodErr::no_property(name))?;
+                Ok(message.method_return().append1(value))
+            }
+            (Some(PROPERTIES_INTERFACE), "GetAll") => {
+                let interface: &str = message.read1()?;
+                if interface != INTERFACE {
+                    return Err(MethodErr::no_interface(interface));
+                }
+                Ok(message
+                    .method_return()
+                    .append1(self.status.properties(PROPERTIES)))
+            }
+            (Some(PROPERTIES_INTERFACE), "Set") => {
+                let (_, name): (&str, &str) = message.read2()?;
+                Err(MethodErr::ro_property(name))
+            }
+            (Some(INTERFACE) | None, "Pause") => {
+                self.update_paused(true, changed);
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, "Resume") => {
+                self.update_paused(false, changed);
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, method @ ("Inhibit" | "Uninhibit")) => {
+                let reason: String = message.read1()?;
+                if reason.is_empty() {
+                    return Err(MethodErr::invalid_arg(&reason));
+                }
+                let sender = message.sender().map(|sender| sender.to_string());
+                let sender = sender.unwrap_or_default();
+                self.update_inhibitors(reason, sender, method == "Inhibit", changed);
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, "SetExposureBias") => {
+                let exposure_bias: i32 = message.read1()?;
+                if !(-MAX_EXPOSURE_BIAS..=MAX_EXPOSURE_BIAS).contains(&exposure_bias) {
+                    return Err(MethodErr::invalid_arg(&exposure_bias));
+                }
+                if exposure_bias != self.status.exposure_bias {
+                    log::debug!("Exposure bias changed to {}%", exposure_bias);
+                    self.status.exposure_bias = exposure_bias;
+                    self.exposure_bias.current = exposure_bias as i64 * 100;
+                    self.send(Command::ExposureBias(self.exposure_bias.current));
+                    changed.insert("ExposureBias");
+                    if let Err(err) = self.exposure_bias.save() {
+                        log::error!("Unable to save exposure bias: {}", err);
+                    }
+                }
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, method @ ("Disable" | "Enable")) => {
+                let output_name: String = message.read1()?;
+                if !self.command_txs.contains_key(&output_name) {
+                    return Err(MethodErr::invalid_arg(&output_name));
+                }
+                self.update_disabled(output_name, method == "Disable", changed);
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, "SetScene") => {
+                let (output_name, scene): (String, String) = message.read2()?;
+                let switcher = self
+                    .switchers
+                    .get(&output_name)
+                    .ok_or_else(|| MethodErr::invalid_arg(&output_name))?;
+                if !scene.is_empty() && !switcher.names.contains(&scene) {
+                    return Err(MethodErr::invalid_arg(&scene));
+                }
+                switcher.send(Switch::To((!scene.is_empty()).then_some(scene)));
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, "NextScene") => {
+                let output_name: String = message.read1()?;
+                self.switchers
+                    .get(&output_name)
+                    .ok_or_else(|| MethodErr::invalid_arg(&output_name))?
+                    .send(Switch::Next);
+                Ok(message.method_return())
+            }
+            (Some(INTERFACE) | None, "SetLogFilter") => {
+                let filter: String = message.read1()?;
+                logging::set_filter(&filter);
+                Ok(message.method_return())
+            }
+            _ => Err(MethodErr::no_method(&member)),
+        }
+    }
+
+    fn update_paused(&mut self, paused: bool, changed: &mut BTreeSet<&'static str>) {
+        if paused != self.status.paused {
+            log::debug!("Learning and predictions paused on request: {}", paused);
+            self.status.paused = paused;
+            self.send(Command::Pause(paused));
+            changed.insert("Paused");
+        }
+    }
+
+    fn update_inhibitors(
+        &mut self,
+        reason: String,
+        sender: String,
+        inhibit: bool,
+        changed: &mut BTreeSet<&'static str>,
+    ) {
+        if inhibit {
+            self.inhibitor_holders
+                .entry(reason)
+                .or_default()
+                .insert(sender);
+        } else if let Some(holders) = self.inhibitor_holders.get_mut(&reason) {
+            holders.remove(&sender);
+        }
+        self.refresh_inhibitors(changed);
+    }
+
+    /// Withdraws the reasons of a caller that left the bus.
+    fn drop_inhibitors(&mut self, sender: &str, changed: &mut BTreeSet<&'static str>) {
+        for holders in self.inhibitor_holders.values_mut() {
+            holders.remove(sender);
+        }
+        self.refresh_inhibitors(changed);
+    }
+
+    fn refresh_inhibitors(&mut self, changed: &mut BTreeSet<&'static str>) {
+        self.inhibitor_holders
+            .retain(|_, holders| !holders.is_empty());
+        let inhibitors = self.inhibitor_holders.keys().cloned().collect();
+        if inhibitors != self.status.inhibitors {
+            log::debug!("Adjustments inhibited for {:?}", inhibitors);
+            let was_inhibited = !self.status.inhibitors.is_empty();
+            self.status.inhibitors = inhibitors;
+            changed.insert("Inhibitors");
+            let inhibited = !self.status.inhibitors.is_empty();
+            if inhibited != was_inhibited {
+                self.send(Command::Inhibit(inhibited));
+            }
+        }
+    }
+
+    fn update_disabled(
+        &mut self,
+        output_name: String,
+        disabled: bool,
+        changed: &mut BTreeSet<&'static str>,
+    ) {
+        let updated = if disabled {
+            self.disabled_outputs.0.insert(output_name.clone())
+        } else {
+            self.disabled_outputs.0.remove(&output_name)
+        };
+        if updated {
+            log::debug!("Output '{}' disabled on request: {}", output_name, disabled);
+            if let Some(tx) = self.command_txs.get(&output_name) {
+                tx.send(Command::Disable(disabled)).ok();
+            }
+            self.status.disabled_outputs = self.disabled_outputs.0.iter().cloned().collect();
+            changed.insert("DisabledOutputs");
+            if let Err(err) = self.disabled_outputs.save() {
+                log::error!("Unable to save disabled outputs: {}", err);
+            }
+        }
+    }
+
+    /// Disables or enables again the outputs that were disabled on request.
+    fn send_disabled(&self, disabled: bool) {
+        for output_name in &self.disabled_outputs.0 {
+            if let Some(tx) = self.command_txs.get(output_name) {
+                tx.send(Command::Disable(disabled)).ok();
+            }
+        }
+    }
+
+    fn send(&mut self, command: Command) {
+        // Brightness controllers only go away if they fail to start
+        self.command_txs.retain(|_, tx| tx.send(command).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_apply_reports_only_changes() {
+        let mut status = Status::default();
+        let brightness = |brightness| Update::Brightness {
+            output_name: "eDP-1".to_string(),
+            brightness,
+        };
+
+        assert_eq!(
+            Some("CurrentProfile"),
+            status.apply(Update::Profile("dim".to_string()))
+        );
+        assert_eq!(None, status.apply(Update::Profile("dim".to_string())));
+        assert_eq!(Some("AlsValue"), status.apply(Update::AlsValue(120)));
+        assert_eq!(None, status.apply(Update::AlsValue(120)));
+        assert_eq!(
+            Some("AlsSmoothedValue"),
+            status.apply(Update::AlsSmoothedValue(110))
+        );
+        assert_eq!(None, status.apply(Update::AlsSmoothedValue(110)));
+
+        let scene = |scene: &str| Update::Scene {
+            output_name: "eDP-1".to_string(),
+            scene: scene.to_string(),
+        };
+        assert_eq!(Some("Scenes"), status.apply(scene("movie")));
+        assert_eq!(None, status.apply(scene("movie")));
+        assert_eq!(Some("Scenes"), status.apply(scene("")));
+        assert_eq!(None, status.apply(scene("")));
+
+        // brightness is exposed in percent, so small changes are not announced
+        assert_eq!(Some("Brightness"), status.apply(brightness(4210)));
+        assert_eq!(None, status.apply(brightness(4290)));
+        assert_eq!(Some(&42), status.brightness.get("eDP-1"));
+
+        let properties = status.properties(PROPERTIES);
+        assert_eq!(PROPERTIES.len(), properties.len());
+        assert_eq!(Some("dim"), properties["CurrentProfile"].as_str());
+        assert_eq!(
+            0,
+            properties["DisabledOutputs"].0.as_iter().unwrap().count()
+        );
+        assert_eq!(None, status.property("Unknown").map(|_| ()));
+    }
+
+    #[test]
+    fn test_inhibitors_of_callers_that_leave_the_bus_are_dropped() {
+        let exposure_bias = ExposureBias {
+            configured: 0,
+            current: 0,
+        };
+        let mut service = Service::new(exposure_bias, DisabledOutputs::default());
+        let (tx, rx) = mpsc::channel();
+        service.command_txs.insert("eDP-1".to_string(), tx);
+        let mut changed = BTreeSet::new();
+
+        service.update_inhibitors("screencast".into(), ":1.1".into(), true, &mut changed);
+        service.update_inhibitors("screencast".into(), ":1.2".into(), true, &mut changed);
+        service.update_inhibitors("movie".into(), ":1.2".into(), true, &mut changed);
+        assert!(matches!(rx.try_recv(), Ok(Command::Inhibit(true))));
+        assert!(changed.contains("Inhibitors"));
+
+        // other callers still hold the reason
+        service.update_inhibitors("screencast".into(), ":1.1".into(), false, &mut changed);
+        assert_eq!(2, service.status.inhibitors.len());
+
+        service.drop_inhibitors(":1.2", &mut changed);
+        assert!(service.status.inhibitors.is_empty());
+        assert!(matches!(rx.try_recv(), Ok(Command::Inhibit(false))));
+        assert!(rx.try_recv().is_err());
+    }
+}
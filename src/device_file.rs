@@ -1,15 +1,17 @@
-use std::error::Error;
 use std::fs::File;
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
 
-pub fn read(file: &mut File) -> Result<f64, Box<dyn Error>> {
+pub fn read(file: &mut File) -> io::Result<f64> {
     let mut content = String::new();
     file.read_to_string(&mut content)?;
     file.seek(SeekFrom::Start(0))?;
-    Ok(content.trim().parse()?)
+    content
+        .trim()
+        .parse()
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
 }
 
-pub fn write(file: &mut File, value: f64) -> Result<(), Box<dyn Error>> {
+pub fn write(file: &mut File, value: f64) -> io::Result<()> {
     file.write_all(value.to_string().as_bytes())?;
     file.seek(SeekFrom::Start(0))?;
     Ok(())
@@ -0,0 +1,388 @@
+use crate::config::Lightness;
+use crate::frame::vulkan::{self, Vulkan};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use wayland_client::protocol::wl_output::WlOutput;
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::{Connection, Dispatch, Proxy, QueueHandle};
+
+pub(crate) const BACKLIGHT_PATH: &str = "/sys/class/backlight";
+pub(crate) const LEDS_PATH: &str = "/sys/class/leds";
+pub(crate) const IIO_PATH: &str = "/sys/bus/iio/devices";
+pub(crate) const V4L_PATH: &str = "/sys/class/video4linux";
+
+/// Wayland protocols relevant to wluma, and what they are needed for.
+const PROTOCOLS: &[(&str, &str)] = &[
+    (
+        "ext_image_copy_capture_manager_v1",
+        "capturer=\"ext-image-copy-capture-v1\"",
+    ),
+    (
+        "ext_output_image_capture_source_manager_v1",
+        "capturer=\"ext-image-copy-capture-v1\"",
+    ),
+    (
+        "zwlr_screencopy_manager_v1",
+        "capturer=\"wlr-screencopy-unstable-v1\"",
+    ),
+    (
+        "zwlr_export_dmabuf_manager_v1",
+        "capturer=\"wlr-export-dmabuf-unstable-v1\"",
+    ),
+    (
+        "zwp_linux_dmabuf_v1",
+        "ext-image-copy-capture-v1 and wlr-screencopy-unstable-v1",
+    ),
+    ("zwlr_foreign_toplevel_manager_v1", "[[app]] rules"),
+];
+
+struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn section(&self, title: &str) {
+        println!("\n{}", title);
+    }
+
+    fn ok(&self, message: impl AsRef<str>) {
+        println!("  [ok] {}", message.as_ref());
+    }
+
+    fn info(&self, message: impl AsRef<str>) {
+        println!("       {}", message.as_ref());
+    }
+
+    fn problem(&mut self, message: impl AsRef<str>, hint: impl AsRef<str>) {
+        self.problems += 1;
+        println!("  [!!] {}", message.as_ref());
+        println!("       hint: {}", hint.as_ref());
+    }
+}
+
+pub fn run() {
+    println!("wluma v{} diagnostics", crate::VERSION);
+
+    let mut report = Report { problems: 0 };
+
+    check_wayland(&mut report);
+    check_vulkan(&mut report);
+    check_backlight(&mut report);
+    check_ddc(&mut report);
+    check_iio(&mut report);
+    check_v4l(&mut report);
+
+    match report.problems {
+        0 => println!("\nNo problems found."),
+        n => println!("\nFound {} potential problem(s), see hints above.", n),
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Globals {
+    pub interfaces: HashMap<String, u32>,
+    pub outputs: Vec<Output>,
+}
+
+#[derive(Default)]
+pub(crate) struct Output {
+    /// Connector name, e.g. `eDP-1`, sent by compositors since wl_output version 4.
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+impl Globals {
+    /// Lists globals of the compositor, and the outputs it has.
+    pub(crate) fn probe() -> Result<Self, (String, &'static str)> {
+        let connection = Connection::connect_to_env().map_err(|err| {
+            (
+                format!("Unable to connect to Wayland display: {}", err),
+                "run wluma inside of a Wayland session, or set capturer=\"none\" for all outputs",
+            )
+        })?;
+
+        let mut globals = Self::default();
+        let mut event_queue = connection.new_event_queue();
+        connection.display().get_registry(&event_queue.handle(), ());
+
+        // First roundtrip lists globals, second one receives wl_output events
+        for _ in 0..2 {
+            event_queue.roundtrip(&mut globals).map_err(|err| {
+                (
+                    format!("Unable to talk to Wayland compositor: {}", err),
+                    "check that the compositor is running properly",
+                )
+            })?;
+        }
+        Ok(globals)
+    }
+
+    pub(crate) fn can_capture(&self) -> bool {
+        let has = |interface: &str| self.interfaces.contains_key(interface);
+        (has("zwp_linux_dmabuf_v1")
+            && (has("zwlr_screencopy_manager_v1")
+                || (has("ext_image_copy_capture_manager_v1")
+                    && has("ext_output_image_capture_source_manager_v1"))))
+            || has("zwlr_export_dmabuf_manager_v1")
+    }
+}
+
+impl Dispatch<WlRegistry, ()> for Globals {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: <WlRegistry as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use wayland_client::protocol::wl_registry::Event;
+
+        if let Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            if interface == WlOutput::interface().name {
+                registry.bind::<WlOutput, _, _>(name, version, qh, state.outputs.len());
+                state.outputs.push(Output::default());
+            }
+            state.interfaces.insert(interface, version);
+        }
+    }
+}
+
+impl Dispatch<WlOutput, usize> for Globals {
+    fn event(
+        state: &mut Self,
+        _: &WlOutput,
+        event: <WlOutput as Proxy>::Event,
+        index: &usize,
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use wayland_client::protocol::wl_output::Event;
+
+        match event {
+            Event::Name { name } => state.outputs[*index].name = Some(name),
+            Event::Description { description } => {
+                state.outputs[*index].description = Some(description)
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_wayland(report: &mut Report) {
+    report.section("Wayland");
+
+    let globals = match Globals::probe() {
+        Ok(globals) => globals,
+        Err((problem, hint)) => return report.problem(problem, hint),
+    };
+
+    for (interface, needed_for) in PROTOCOLS {
+        match globals.interfaces.get(*interface) {
+            Some(version) => report.ok(format!("{} (version {})", interface, version)),
+            None => report.info(format!(
+                "{} is not supported, needed for {}",
+                interface, needed_for
+            )),
+        }
+    }
+
+    let kwin = globals
+        .interfaces
+        .keys()
+        .any(|interface| interface.starts_with("org_kde_kwin_"));
+    if kwin {
+        report.info("Compositor looks like KWin");
+    }
+
+    let can_capture = globals.can_capture();
+    if !can_capture && kwin {
+        report.problem(
+            "None of the screen capture protocols are supported",
+            "KWin only shares the screen over PipeWire, which wluma doesn't support, use a KWin version that supports ext-image-copy-capture-v1 or set capturer=\"none\" in the config",
+        );
+    } else if !can_capture {
+        report.problem(
+            "None of the screen capture protocols are supported",
+            "set capturer=\"none\" in the config, so that only ALS is used to predict brightness",
+        );
+    }
+
+    if globals.outputs.is_empty() {
+        report.problem(
+            "No outputs found",
+            "make sure that the screens are connected and enabled",
+        );
+    }
+    for output in &globals.outputs {
+        let description = output.description.as_deref().unwrap_or_default();
+        match &output.name {
+            Some(name) => report.ok(format!("Output '{}' ({})", description, name)),
+            None => report.ok(format!("Output '{}'", description)),
+        }
+    }
+}
+
+fn check_vulkan(report: &mut Report) {
+    report.section("Vulkan");
+
+    match Vulkan::new(None, None, None, Lightness::Hsp, None, None, None, None) {
+        Ok(_) => {
+            report.ok("Vulkan device is available");
+            for name in vulkan::device_names().unwrap_or_default() {
+                report.info(format!("GPU '{}', can be set as vulkan_device", name));
+            }
+        }
+        Err(err) => report.problem(
+            format!("Unable to initialize Vulkan: {}", err),
+            "install Vulkan drivers for your GPU (e.g. vulkan-intel, vulkan-radeon or nvidia-utils)",
+        ),
+    }
+}
+
+fn check_backlight(report: &mut Report) {
+    report.section("Backlight");
+
+    let logind = dbus::blocking::Connection::new_system().is_ok();
+    if logind {
+        report.ok("System DBus is available for unprivileged brightness changes via logind");
+    }
+
+    let mut devices = list_dir(BACKLIGHT_PATH);
+    if devices.is_empty() {
+        report.info(format!(
+            "No devices found in {}, this is normal for desktops with external screens only",
+            BACKLIGHT_PATH
+        ));
+    }
+    devices.extend(
+        list_dir(LEDS_PATH)
+            .into_iter()
+            .filter(|path| is_kbd_backlight(path)),
+    );
+
+    for path in devices {
+        let brightness = path.join("brightness");
+        let writable = fs::File::options().write(true).open(&brightness).is_ok();
+        match (writable, logind) {
+            (true, _) => report.ok(format!("{} is writable", path.display())),
+            (false, true) => report.ok(format!(
+                "{} is not writable, logind will be used instead (transitions might be slower)",
+                path.display()
+            )),
+            (false, false) => report.problem(
+                format!(
+                    "{} is not writable and logind is not available",
+                    path.display()
+                ),
+                "install 90-wluma-backlight.rules udev rule and add your user to the video group",
+            ),
+        }
+    }
+}
+
+fn check_ddc(report: &mut Report) {
+    report.section("DDC");
+
+    if !has_i2c_devices() {
+        return report.info(
+            "No /dev/i2c-* devices found, load the i2c-dev kernel module to control external screens",
+        );
+    }
+
+    let displays = ddc_hi::Display::enumerate();
+    if displays.is_empty() {
+        report
+            .info("No DDC displays found, make sure that your user can access /dev/i2c-* devices");
+    }
+    for display in displays {
+        let empty = "".to_string();
+        report.ok(format!(
+            "Display '{} {} {}'",
+            display.info.model_name.as_ref().unwrap_or(&empty),
+            display.info.serial_number.as_ref().unwrap_or(&empty),
+            display.info.manufacturer_id.as_ref().unwrap_or(&empty),
+        ));
+    }
+}
+
+fn check_iio(report: &mut Report) {
+    report.section("Ambient light sensor");
+
+    let sensors = light_sensors()
+        .into_iter()
+        .map(|(path, name)| format!("{} ({})", path.display(), name))
+        .collect::<Vec<_>>();
+
+    if sensors.is_empty() {
+        report.info("No iio light sensors found, use [als.webcam] or [als.time] instead");
+    }
+
+    for sensor in sensors {
+        report.ok(format!("Sensor {}", sensor));
+    }
+}
+
+fn check_v4l(report: &mut Report) {
+    report.section("Webcam");
+
+    let devices = list_dir(V4L_PATH);
+    if devices.is_empty() {
+        report.info("No video devices found, [als.webcam] is not available");
+    }
+
+    for path in devices {
+        let name = fs::read_to_string(path.join("name")).unwrap_or_default();
+        let device = path
+            .file_name()
+            .map(|f| f.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let index = device.trim_start_matches("video");
+        report.ok(format!(
+            "{} '{}', use video = {}",
+            device,
+            name.trim(),
+            index
+        ));
+    }
+}
+
+/// IIO devices that are ambient light sensors, and their names.
+pub(crate) fn light_sensors() -> Vec<(PathBuf, String)> {
+    list_dir(IIO_PATH)
+        .into_iter()
+        .filter_map(|path| {
+            let name = fs::read_to_string(path.join("name")).ok()?;
+            let name = name.trim().to_string();
+            ["als", "acpi-als"]
+                .contains(&name.as_str())
+                .then_some((path, name))
+        })
+        .collect()
+}
+
+/// Whether DDC displays could be found at all, which needs the i2c-dev kernel module.
+pub(crate) fn has_i2c_devices() -> bool {
+    Path::new("/dev").read_dir().is_ok_and(|mut dir| {
+        dir.any(|e| e.is_ok_and(|e| e.file_name().to_string_lossy().starts_with("i2c-")))
+    })
+}
+
+pub(crate) fn is_kbd_backlight(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|f| f.to_string_lossy().ends_with("kbd_backlight"))
+}
+
+pub(crate) fn list_dir(path: &str) -> Vec<PathBuf> {
+    let mut entries = fs::read_dir(path)
+        .map(|dir| dir.flatten().map(|e| e.path()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    entries.sort();
+    entries
+}
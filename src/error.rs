@@ -0,0 +1,75 @@
+use std::error::Error;
+
+type Source = Box<dyn Error + Send + Sync>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum WlumaError {
+    #[error("Config error: {0}")]
+    Config(Source),
+    #[error("Wayland error: {0}")]
+    Wayland(Source),
+    #[error("Vulkan error: {0}")]
+    Vulkan(Source),
+    #[error("Capture error: {0}")]
+    Capture(Source),
+    #[error("ALS error: {0}")]
+    Als(Source),
+    #[error("Brightness error: {0}")]
+    Brightness(Source),
+    #[error("Predictor error: {0}")]
+    Predictor(Source),
+}
+
+impl WlumaError {
+    pub fn config(err: impl Into<Source>) -> Self {
+        Self::Config(err.into())
+    }
+
+    pub fn wayland(err: impl Into<Source>) -> Self {
+        Self::Wayland(err.into())
+    }
+
+    pub fn vulkan(err: impl Into<Source>) -> Self {
+        Self::Vulkan(err.into())
+    }
+
+    pub fn capture(err: impl Into<Source>) -> Self {
+        Self::Capture(err.into())
+    }
+
+    pub fn als(err: impl Into<Source>) -> Self {
+        Self::Als(err.into())
+    }
+
+    pub fn brightness(err: impl Into<Source>) -> Self {
+        Self::Brightness(err.into())
+    }
+
+    pub fn predictor(err: impl Into<Source>) -> Self {
+        Self::Predictor(err.into())
+    }
+
+    /// Config errors need user's attention, retrying will not help.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Self::Config(_))
+    }
+}
+
+impl From<ash::vk::Result> for WlumaError {
+    fn from(err: ash::vk::Result) -> Self {
+        Self::Vulkan(err.to_string().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_includes_source() {
+        let err = WlumaError::als(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!("ALS error: entity not found", err.to_string());
+        assert!(err.is_recoverable());
+        assert!(!WlumaError::config("Missing output").is_recoverable());
+    }
+}
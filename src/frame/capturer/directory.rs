@@ -0,0 +1,517 @@
+use crate::config::{CaptureRegion, Lightness};
+use crate::error::WlumaError;
+use crate::frame::{crop, lightness_percentile, perceived_lightness_percent, Rect};
+use crate::predictor::Controller;
+use inotify::{Inotify, WatchMask};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread;
+
+/// Images in a directory are only read with one of these extensions, so that files that are
+/// still being written under a temporary name are skipped.
+const EXTENSIONS: [&str; 5] = ["ppm", "pgm", "jpg", "jpeg", "png"];
+/// Widest and tallest image that is decoded, so that a bogus header can't make us allocate
+/// gigabytes.
+const MAX_DIMENSION: u32 = 16384;
+
+/// Reads screen contents from images written by another program, either as files in a directory
+/// (the newest one is used) or as a stream of PPM images in a named pipe.
+pub struct Capturer {
+    path: PathBuf,
+    measure: Measure,
+    controller: Box<dyn Controller>,
+}
+
+impl Capturer {
+    pub fn new(
+        path: &str,
+        capture_region: Option<CaptureRegion>,
+        luma_percentile: Option<u8>,
+        lightness: Lightness,
+        controller: Box<dyn Controller>,
+    ) -> Self {
+        Self {
+            path: PathBuf::from(path),
+            measure: Measure {
+                capture_region,
+                luma_percentile,
+                lightness,
+            },
+            controller,
+        }
+    }
+}
+
+impl super::Capturer for Capturer {
+    fn run(&mut self, _output_name: &str) -> Result<(), WlumaError> {
+        let file_type = fs::metadata(&self.path)
+            .map_err(|err| {
+                WlumaError::config(format!(
+                    "Unable to read frames from '{}': {}",
+                    self.path.display(),
+                    err
+                ))
+            })?
+            .file_type();
+
+        let (luma_tx, luma_rx) = mpsc::channel();
+        let path = self.path.clone();
+        let measure = self.measure;
+        if file_type.is_dir() {
+            thread::spawn(move || send_error(&luma_tx, watch_directory(&path, measure, &luma_tx)));
+        } else if file_type.is_fifo() {
+            thread::spawn(move || send_error(&luma_tx, read_pipe(&path, measure, &luma_tx)));
+        } else {
+            return Err(WlumaError::config(format!(
+                "Frames are read from a directory or a named pipe, '{}' is neither",
+                self.path.display()
+            )));
+        }
+
+        // The controller keeps getting the latest luma between frames, as the ALS may change
+        let mut luma = None;
+        loop {
+            match luma_rx.recv_timeout(super::none::INTERVAL) {
+                Ok(Ok(new_luma)) => luma = Some(new_luma),
+                Ok(Err(err)) => return Err(err),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(WlumaError::capture("Reading frames stopped unexpectedly"))
+                }
+            }
+            if let Some(luma) = luma {
+                self.controller.adjust(luma);
+            }
+        }
+    }
+}
+
+fn send_error(luma_tx: &Sender<Result<u8, WlumaError>>, result: Result<(), WlumaError>) {
+    if let Err(err) = result {
+        let _ = luma_tx.send(Err(err));
+    }
+}
+
+/// Measures the newest image in the directory, then every new one that shows up. Returns once
+/// nobody needs the luma anymore.
+fn watch_directory(
+    dir: &Path,
+    measure: Measure,
+    luma_tx: &Sender<Result<u8, WlumaError>>,
+) -> Result<(), WlumaError> {
+    let mut inotify = Inotify::init().map_err(WlumaError::capture)?;
+    inotify
+        .watches()
+        .add(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO)
+        .map_err(WlumaError::capture)?;
+
+    let mut latest = newest_image(dir).map_err(WlumaError::capture)?;
+    let mut buffer = [0u8; 4096];
+    loop {
+        if let Some(path) = latest {
+            match fs::read(&path)
+                .map_err(WlumaError::capture)
+                .and_then(|data| decode(&data))
+            {
+                Ok(image) => {
+                    if luma_tx.send(Ok(measure.luma(&image))).is_err() {
+                        return Ok(());
+                    }
+                }
+                Err(err) => log::warn!("Skipping frame '{}': {}", path.display(), err),
+            }
+        }
+
+        // Only the last of the images written meanwhile matters
+        latest = inotify
+            .read_events_blocking(&mut buffer)
+            .map_err(WlumaError::capture)?
+            .filter_map(|event| event.name.map(|name| dir.join(name)))
+            .filter(|path| is_image(path))
+            .last();
+    }
+}
+
+/// Measures every image written to the pipe, until nobody needs the luma anymore. Programs can
+/// come and go, the pipe is opened again once the last one closes it.
+fn read_pipe(
+    path: &Path,
+    measure: Measure,
+    luma_tx: &Sender<Result<u8, WlumaError>>,
+) -> Result<(), WlumaError> {
+    loop {
+        // Blocks until a program opens the pipe for writing
+        let mut reader = BufReader::new(File::open(path).map_err(WlumaError::capture)?);
+        while let Some(image) = read_ppm(&mut reader)? {
+            if luma_tx.send(Ok(measure.luma(&image))).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+fn newest_image(dir: &Path) -> Result<Option<PathBuf>, std::io::Error> {
+    let mut newest = None;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !is_image(&path) {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        if newest.as_ref().is_none_or(|(newest, _)| modified > *newest) {
+            newest = Some((modified, path));
+        }
+    }
+    Ok(newest.map(|(_, path)| path))
+}
+
+/// Pixels of a decoded image, as RGB.
+#[derive(Debug, PartialEq)]
+struct Image {
+    width: u32,
+    height: u32,
+    rgbs: Vec<u8>,
+}
+
+fn decode(data: &[u8]) -> Result<Image, WlumaError> {
+    match data {
+        [b'P', b'5' | b'6', ..] => {
+            read_ppm(&mut &data[..])?.ok_or_else(|| WlumaError::capture("Empty PPM image"))
+        }
+        [0xff, 0xd8, ..] => decode_jpeg(data),
+        [0x89, b'P', b'N', b'G', ..] => decode_png(data),
+        _ => Err(WlumaError::capture("Unknown image format")),
+    }
+}
+
+fn check_dimensions(width: u32, height: u32) -> Result<(), WlumaError> {
+    if width > MAX_DIMENSION || height > MAX_DIMENSION {
+        return Err(WlumaError::capture(format!(
+            "Images larger than {}x{} are not supported, this one is {}x{}",
+            MAX_DIMENSION, MAX_DIMENSION, width, height
+        )));
+    }
+    Ok(())
+}
+
+fn decode_jpeg(data: &[u8]) -> Result<Image, WlumaError> {
+    let mut decoder = jpeg_decoder::Decoder::new(data);
+    decoder.read_info().map_err(WlumaError::capture)?;
+    let info = decoder
+        .info()
+        .ok_or_else(|| WlumaError::capture("Unable to read JPEG image info"))?;
+    check_dimensions(info.width as u32, info.height as u32)?;
+    let pixels = decoder.decode().map_err(WlumaError::capture)?;
+
+    let rgbs = match info.pixel_format {
+        jpeg_decoder::PixelFormat::RGB24 => pixels,
+        jpeg_decoder::PixelFormat::L8 => pixels.iter().flat_map(|&y| [y, y, y]).collect(),
+        format => Err(WlumaError::capture(format!(
+            "Unsupported JPEG pixel format {:?}",
+            format
+        )))?,
+    };
+    Ok(Image {
+        width: info.width as u32,
+        height: info.height as u32,
+        rgbs,
+    })
+}
+
+fn decode_png(data: &[u8]) -> Result<Image, WlumaError> {
+    let mut decoder = png::Decoder::new(data);
+    // Palettes are expanded and 16-bit samples reduced, so that every sample is a byte
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(WlumaError::capture)?;
+    let (width, height) = reader.info().size();
+    check_dimensions(width, height)?;
+
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader
+        .next_frame(&mut pixels)
+        .map_err(WlumaError::capture)?;
+    let pixels = &pixels[..info.buffer_size()];
+
+    // Transparency is ignored, like in captured frames
+    let rgbs = match info.color_type {
+        png::ColorType::Rgb => pixels.to_vec(),
+        png::ColorType::Rgba => pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[0], pixel[1], pixel[2]])
+            .collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&y| [y, y, y]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels
+            .chunks_exact(2)
+            .flat_map(|pixel| [pixel[0]; 3])
+            .collect(),
+        format => Err(WlumaError::capture(format!(
+            "Unsupported PNG color type {:?}",
+            format
+        )))?,
+    };
+    Ok(Image {
+        width,
+        height,
+        rgbs,
+    })
+}
+
+/// Reads the next binary PPM (or PGM) image, nothing if the stream ended before it.
+fn read_ppm(reader: &mut impl BufRead) -> Result<Option<Image>, WlumaError> {
+    let Some(magic) = header_token(reader)? else {
+        return Ok(None);
+    };
+    let channels = match magic.as_str() {
+        "P5" => 1,
+        "P6" => 3,
+        _ => {
+            return Err(WlumaError::capture(format!(
+                "Unsupported PPM type {}",
+                magic
+            )))
+        }
+    };
+
+    let mut number = || -> Result<u32, WlumaError> {
+        header_token(reader)?
+            .and_then(|token| token.parse().ok())
+            .ok_or_else(|| WlumaError::capture("Malformed PPM header"))
+    };
+    let (width, height, max_value) = (number()?, number()?, number()?);
+    if width == 0 || height == 0 || !(1..=u16::MAX as u32).contains(&max_value) {
+        return Err(WlumaError::capture("Malformed PPM header"));
+    }
+    check_dimensions(width, height)?;
+
+    // Samples above 255 take two bytes, the most significant one first
+    let sample_len = if max_value > 255 { 2 } else { 1 };
+    let mut data = vec![0; width as usize * height as usize * channels * sample_len];
+    reader.read_exact(&mut data).map_err(WlumaError::capture)?;
+
+    let samples = data.chunks_exact(sample_len).map(|sample| {
+        let value = sample
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as u32);
+        ((value.min(max_value) * 255 + max_value / 2) / max_value) as u8
+    });
+    let rgbs = match channels {
+        1 => samples.flat_map(|y| [y, y, y]).collect(),
+        _ => samples.collect(),
+    };
+
+    Ok(Some(Image {
+        width,
+        height,
+        rgbs,
+    }))
+}
+
+/// Next whitespace separated value of a PPM header, skipping comments, nothing at the end of the
+/// stream. The single whitespace that ends the header is consumed with its last value.
+fn header_token(reader: &mut impl BufRead) -> Result<Option<String>, WlumaError> {
+    let mut token = String::new();
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) if token.is_empty() => return Ok(None),
+            Ok(0) => return Ok(Some(token)),
+            Ok(_) => {}
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(WlumaError::capture(err)),
+        }
+        match byte[0] {
+            b'#' if token.is_empty() => {
+                reader
+                    .read_until(b'\n', &mut Vec::new())
+                    .map_err(WlumaError::capture)?;
+            }
+            b if b.is_ascii_whitespace() => {
+                if !token.is_empty() {
+                    return Ok(Some(token));
+                }
+            }
+            b => token.push(b as char),
+        }
+    }
+}
+
+/// Computes luma of an image on the CPU, the same way the GPU does for captured frames.
+#[derive(Debug, Clone, Copy)]
+struct Measure {
+    capture_region: Option<CaptureRegion>,
+    luma_percentile: Option<u8>,
+    lightness: Lightness,
+}
+
+impl Measure {
+    fn luma(&self, image: &Image) -> u8 {
+        let rect = match &self.capture_region {
+            Some(region) => crop(region, image.width, image.height),
+            None => Rect::full(image.width, image.height),
+        };
+        let pixels = (rect.y..rect.y + rect.height).flat_map(|y| {
+            let start = (y as usize * image.width as usize + rect.x as usize) * 3;
+            image.rgbs[start..start + rect.width as usize * 3].chunks_exact(3)
+        });
+        let lightness = |pixel: &[u8]| {
+            perceived_lightness_percent(
+                self.lightness,
+                pixel[0] as f64,
+                pixel[1] as f64,
+                pixel[2] as f64,
+            )
+        };
+
+        let luma = match self.luma_percentile {
+            Some(percentile) => {
+                let mut histogram = [0u32; 101];
+                for pixel in pixels {
+                    histogram[lightness(pixel).min(100) as usize] += 1;
+                }
+                lightness_percentile(&histogram, percentile)
+            }
+            None => {
+                let (rs, gs, bs) = pixels.fold((0u64, 0u64, 0u64), |(rs, gs, bs), pixel| {
+                    (
+                        rs + pixel[0] as u64,
+                        gs + pixel[1] as u64,
+                        bs + pixel[2] as u64,
+                    )
+                });
+                let count = rect.width as f64 * rect.height as f64;
+                perceived_lightness_percent(
+                    self.lightness,
+                    rs as f64 / count,
+                    gs as f64 / count,
+                    bs as f64 / count,
+                )
+            }
+        };
+
+        log::trace!("Computed luma {}%", luma);
+        luma
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(capture_region: Option<CaptureRegion>, luma_percentile: Option<u8>) -> Measure {
+        Measure {
+            capture_region,
+            luma_percentile,
+            lightness: Lightness::Hsp,
+        }
+    }
+
+    #[test]
+    fn test_read_ppm_stream() {
+        let mut stream = b"P6\n# written by a test\n2 1\n255\n\x00\x00\x00\xff\xff\xff".to_vec();
+        stream.extend(b"P5 1 2 65535 \xff\xff\x00\x00");
+        let mut reader = &stream[..];
+
+        assert_eq!(
+            Some(Image {
+                width: 2,
+                height: 1,
+                rgbs: vec![0, 0, 0, 255, 255, 255],
+            }),
+            read_ppm(&mut reader).unwrap()
+        );
+        assert_eq!(
+            Some(Image {
+                width: 1,
+                height: 2,
+                rgbs: vec![255, 255, 255, 0, 0, 0],
+            }),
+            read_ppm(&mut reader).unwrap()
+        );
+        assert_eq!(None, read_ppm(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn test_read_ppm_rejects_truncated_image() {
+        assert!(read_ppm(&mut &b"P6 2 2 255\n\x00\x00\x00"[..]).is_err());
+        assert!(read_ppm(&mut &b"P3 1 1 255\n0 0 0"[..]).is_err());
+        assert!(read_ppm(&mut &b"P6 0 1 255\n"[..]).is_err());
+
+        // Nothing is allocated for images that are too large
+        let err = read_ppm(&mut &b"P6 100000 100000 255\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("not supported"), "{}", err);
+    }
+
+    #[test]
+    fn test_decode_by_contents() {
+        assert!(decode(b"P5 1 1 255\n\x80").is_ok());
+        assert!(decode(b"\x89PNG\r\n").is_err());
+        assert!(decode(b"hello").is_err());
+    }
+
+    #[test]
+    fn test_decode_png() {
+        let mut data = Vec::new();
+        let mut encoder = png::Encoder::new(&mut data, 2, 1);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder
+            .write_header()
+            .unwrap()
+            .write_image_data(&[0, 0, 0, 255, 255, 128, 0, 0])
+            .unwrap();
+
+        assert_eq!(
+            Image {
+                width: 2,
+                height: 1,
+                rgbs: vec![0, 0, 0, 255, 128, 0],
+            },
+            decode(&data).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_luma() {
+        // Left half black, right half white
+        let image = Image {
+            width: 4,
+            height: 2,
+            rgbs: [[0; 6], [255; 6]].concat().repeat(2),
+        };
+
+        assert_eq!(50, measure(None, None).luma(&image));
+        assert_eq!(0, measure(None, Some(50)).luma(&image));
+        assert_eq!(100, measure(None, Some(51)).luma(&image));
+        let right_half = CaptureRegion::Rect {
+            x: 2,
+            y: 0,
+            width: 2,
+            height: 2,
+        };
+        assert_eq!(100, measure(Some(right_half), None).luma(&image));
+    }
+
+    #[test]
+    fn test_newest_image() {
+        let dir = std::env::temp_dir().join(format!("wluma-test-frames-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        assert_eq!(None, newest_image(&dir).unwrap());
+
+        fs::write(dir.join("old.ppm"), b"").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        fs::write(dir.join("new.JPG"), b"").unwrap();
+        fs::write(dir.join(".new.ppm.tmp"), b"").unwrap();
+
+        assert_eq!(Some(dir.join("new.JPG")), newest_image(&dir).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
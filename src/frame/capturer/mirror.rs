@@ -0,0 +1,33 @@
+use crate::error::WlumaError;
+use crate::predictor::Controller;
+use std::sync::mpsc::Receiver;
+
+/// Receives luma values captured for another output, see [`crate::predictor::controller::fanout`].
+pub struct Capturer {
+    luma_rx: Receiver<u8>,
+    controller: Box<dyn Controller>,
+}
+
+impl Capturer {
+    pub fn new(luma_rx: Receiver<u8>, controller: Box<dyn Controller>) -> Self {
+        Self {
+            luma_rx,
+            controller,
+        }
+    }
+}
+
+impl super::Capturer for Capturer {
+    fn run(&mut self, output_name: &str) -> Result<(), WlumaError> {
+        for luma in self.luma_rx.iter() {
+            self.controller.adjust(luma);
+        }
+
+        // Restarting would not help, the mirrored output is gone for good
+        log::error!(
+            "Output '{}' no longer receives screen contents, as the mirrored output has stopped",
+            output_name
+        );
+        Ok(())
+    }
+}
@@ -1,6 +1,15 @@
+pub mod directory;
+pub mod mirror;
 pub mod none;
+#[cfg(test)]
+pub mod scripted;
 pub mod wayland;
 
+use crate::error::WlumaError;
+
 pub trait Capturer {
-    fn run(&mut self, output_name: &str, controller: Box<dyn crate::predictor::Controller>);
+    /// Feeds luma of the captured screen contents to the controller, until an error happens.
+    ///
+    /// Capturing can be restarted by calling this method again.
+    fn run(&mut self, output_name: &str) -> Result<(), WlumaError>;
 }
@@ -1,13 +1,24 @@
+use crate::error::WlumaError;
+use crate::predictor::Controller;
 use std::{thread, time::Duration};
 
-#[derive(Default)]
-pub struct Capturer {}
+pub const INTERVAL: Duration = Duration::from_millis(200);
+
+pub struct Capturer {
+    controller: Box<dyn Controller>,
+}
+
+impl Capturer {
+    pub fn new(controller: Box<dyn Controller>) -> Self {
+        Self { controller }
+    }
+}
 
 impl super::Capturer for Capturer {
-    fn run(&mut self, _output_name: &str, mut controller: Box<dyn crate::predictor::Controller>) {
+    fn run(&mut self, _output_name: &str) -> Result<(), WlumaError> {
         loop {
-            controller.adjust(0);
-            thread::sleep(Duration::from_millis(200));
+            self.controller.adjust(0);
+            thread::sleep(INTERVAL);
         }
     }
 }
@@ -0,0 +1,219 @@
+use crate::error::WlumaError;
+use crate::predictor::Controller;
+use std::{thread, time::Duration};
+
+/// Plays back a script of luma values instead of capturing the screen, to test predictors and
+/// brightness controllers together without a compositor.
+pub struct Capturer {
+    /// Luma values, and for how many frames each of them lasts.
+    script: Vec<(u8, u32)>,
+    interval: Duration,
+    controller: Box<dyn Controller>,
+}
+
+impl Capturer {
+    pub fn new(
+        script: Vec<(u8, u32)>,
+        interval: Duration,
+        controller: Box<dyn Controller>,
+    ) -> Self {
+        Self {
+            script,
+            interval,
+            controller,
+        }
+    }
+}
+
+impl super::Capturer for Capturer {
+    fn run(&mut self, output_name: &str) -> Result<(), WlumaError> {
+        for &(luma, frames) in &self.script {
+            for _ in 0..frames {
+                self.controller.adjust(luma);
+                thread::sleep(self.interval);
+            }
+        }
+
+        log::debug!("Finished playing back the script of '{}'", output_name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Capturer as _;
+    use super::*;
+    use crate::brightness::{self, Brightness};
+    use crate::clock;
+    use crate::config::AdaptivePredictor;
+    use crate::predictor::controller::adaptive;
+    use crate::predictor::{Cooldowns, Profiles};
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use std::sync::mpsc::{self, Sender};
+    use std::sync::Arc;
+
+    const MAX_BRIGHTNESS: u64 = 1000;
+    const ALS_DIM: &str = "dim";
+    const ALS_BRIGHT: &str = "bright";
+    /// Time between frames, cooldowns are multiples of it.
+    const FRAME: Duration = Duration::from_millis(100);
+
+    /// Backlight that is shared with the test, which changes it the way a user would.
+    struct Device(Rc<Cell<u64>>);
+
+    impl Brightness for Device {
+        fn get(&mut self) -> Result<u64, WlumaError> {
+            Ok(self.0.get())
+        }
+
+        fn set(&mut self, value: u64) -> Result<u64, WlumaError> {
+            self.0.set(value);
+            Ok(value)
+        }
+
+        fn max(&self) -> u64 {
+            MAX_BRIGHTNESS
+        }
+    }
+
+    enum Event {
+        User(u64),
+        Als(&'static str),
+    }
+
+    /// Lets the brightness controller catch up after every frame, as its own thread would,
+    /// and plays the role of the user and the ALS at the given frames.
+    struct Harness {
+        clock: clock::Manual,
+        predictor: Box<dyn Controller>,
+        brightness: brightness::Controller,
+        device: Rc<Cell<u64>>,
+        als_tx: Sender<String>,
+        events: HashMap<u32, Event>,
+        frame: u32,
+        history: Rc<RefCell<Vec<u64>>>,
+    }
+
+    impl Controller for Harness {
+        fn adjust(&mut self, luma: u8) {
+            match self.events.remove(&self.frame) {
+                Some(Event::User(brightness)) => self.device.set(brightness),
+                Some(Event::Als(profile)) => self.als_tx.send(profile.to_string()).unwrap(),
+                None => {}
+            }
+
+            while self.brightness.step() {}
+            self.predictor.adjust(luma);
+            while self.brightness.step() {}
+
+            self.history.borrow_mut().push(self.device.get());
+            self.frame += 1;
+            self.clock.advance(FRAME);
+        }
+    }
+
+    /// Plays the script through the adaptive predictor and the brightness controller,
+    /// returns the brightness of the device after every frame.
+    fn play(script: Vec<(u8, u32)>, events: Vec<(u32, Event)>) -> Vec<u64> {
+        let device = Rc::new(Cell::new(500));
+        let history = Rc::new(RefCell::new(Vec::new()));
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (als_tx, als_rx) = mpsc::channel();
+        let (_, workspace_rx) = mpsc::channel();
+        let (_, command_rx) = mpsc::channel();
+        als_tx.send(ALS_DIM.to_string()).unwrap();
+        let clock = clock::Manual::new();
+
+        let predictor = adaptive::Controller::new(
+            prediction_tx,
+            user_rx,
+            als_rx,
+            workspace_rx,
+            false,
+            "eDP-1",
+            MAX_BRIGHTNESS,
+            Profiles::new(vec![ALS_DIM.to_string(), ALS_BRIGHT.to_string()]),
+            AdaptivePredictor {
+                profile_fallback_discount: 0.0,
+                enforce_monotonic: false,
+                decay_days: None,
+                per_workspace: false,
+                time_buckets: Vec::new(),
+                compact_luma_epsilon: 0,
+                data_group: None,
+            },
+            Cooldowns {
+                pending: 3 * FRAME,
+                next_als: 2 * FRAME,
+            },
+            Arc::new(clock.clone()),
+        );
+        let brightness = brightness::Controller::new(
+            "eDP-1",
+            Box::new(Device(device.clone())),
+            user_tx,
+            prediction_rx,
+            command_rx,
+            mpsc::channel().0,
+            None,
+            0,
+            false,
+        );
+        let harness = Harness {
+            clock,
+            predictor: Box::new(predictor),
+            brightness,
+            device,
+            als_tx,
+            events: events.into_iter().collect(),
+            frame: 0,
+            history: history.clone(),
+        };
+
+        Capturer::new(script, Duration::ZERO, Box::new(harness))
+            .run("eDP-1")
+            .unwrap();
+        history.take()
+    }
+
+    #[test]
+    fn test_learning_waits_for_user_to_settle() {
+        let history = play(
+            vec![(20, 3), (80, 9), (50, 2)],
+            vec![(1, Event::User(700)), (7, Event::User(300))],
+        );
+
+        // The first change is learned for the luma it was made at, even though the screen
+        // got brighter during the cooldown, then the second one doesn't get overridden by
+        // a prediction while the user might still be adjusting it...
+        let mut expected = vec![500, 700, 700, 700, 700, 700, 700];
+        expected.extend([300, 300, 300, 300, 300]);
+        // ... and both are used to predict for screen contents in between
+        expected.extend([500, 500]);
+        assert_eq!(expected, history);
+    }
+
+    #[test]
+    fn test_profile_switches_after_cooldown() {
+        let history = play(
+            vec![(50, 18)],
+            vec![
+                (1, Event::User(700)),
+                (6, Event::Als(ALS_BRIGHT)),
+                (9, Event::User(900)),
+                (14, Event::Als(ALS_DIM)),
+            ],
+        );
+
+        // Nothing is known about the bright profile yet, so brightness is left as is,
+        // until the user adjusts it...
+        let mut expected = vec![500, 700, 700, 700, 700, 700, 700, 700, 700];
+        expected.extend([900, 900, 900, 900, 900, 900]);
+        // ... and it's restored once the dim profile lasts long enough
+        expected.extend([900, 700, 700]);
+        assert_eq!(expected, history);
+    }
+}
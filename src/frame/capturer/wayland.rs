@@ -1,15 +1,27 @@
-use crate::config::WaylandProtocol;
+use crate::config::{CaptureRegion, Lightness, OutputIdentity, TransferFunction, WaylandProtocol};
+use crate::error::WlumaError;
 use crate::frame::object::Object;
-use crate::frame::vulkan::Vulkan;
+use crate::frame::vulkan::{Vulkan, DRM_FORMAT_MOD_LINEAR};
+use crate::window::FocusedWindow;
 use crate::predictor::Controller;
-use std::os::fd::BorrowedFd;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 use wayland_client::protocol::wl_buffer::WlBuffer;
 use wayland_client::protocol::wl_output::WlOutput;
 use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::backend::WaylandError;
 use wayland_client::Connection;
 use wayland_client::Dispatch;
+use wayland_client::EventQueue;
 use wayland_client::Proxy;
 use wayland_client::QueueHandle;
 use wayland_protocols::ext::image_copy_capture::v1::client::ext_image_copy_capture_session_v1::ExtImageCopyCaptureSessionV1;
@@ -26,17 +38,82 @@ use wayland_protocols_wlr::export_dmabuf::v1::client::zwlr_export_dmabuf_manager
 use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_frame_v1::ZwlrScreencopyFrameV1;
 use wayland_protocols_wlr::screencopy::v1::client::zwlr_screencopy_manager_v1::ZwlrScreencopyManagerV1;
 
-const DELAY_SUCCESS: Duration = Duration::from_millis(100);
+pub const DELAY_SUCCESS: Duration = Duration::from_millis(100);
 const DELAY_FAILURE: Duration = Duration::from_millis(1000);
 
+/// Globals that capturing depends on, worth reporting when it doesn't work.
+const CAPTURE_INTERFACES: &[&str] = &[
+    "ext_image_copy_capture_manager_v1",
+    "ext_output_image_capture_source_manager_v1",
+    "zwlr_export_dmabuf_manager_v1",
+    "zwlr_screencopy_manager_v1",
+    "zwp_linux_dmabuf_v1",
+];
+
+lazy_static! {
+    static ref CAPABILITIES: Mutex<Option<String>> = Mutex::new(None);
+}
+
 pub struct Capturer {
     protocol: WaylandProtocol,
+    identity: OutputIdentity,
+    capture_region: Option<CaptureRegion>,
+    luma_percentile: Option<u8>,
+    min_damage_percent: u8,
+    transfer_function: Option<TransferFunction>,
+    lightness: Lightness,
+    vulkan_device: Option<String>,
+    luma_samples: Option<u64>,
+    /// Spread of the weights of pixels around the focused window or the center of the screen.
+    luma_focus: Option<f64>,
+    /// Where the focused window is, when it's followed.
+    window_rx: Option<Receiver<Option<FocusedWindow>>>,
+    window: Option<FocusedWindow>,
+    /// Whether to hold off capturing, while the output is disabled.
+    paused_rx: Option<Receiver<bool>>,
+    /// Connector name of the captured output, once it's found.
+    connector: Arc<Mutex<Option<String>>>,
+    controller: Box<dyn Controller>,
+}
+
+/// Everything that belongs to a Wayland connection. Its events are handled on a separate thread,
+/// so that computing luma, the controller and pacing the capture never hold them back.
+struct State {
+    protocol: WaylandProtocol,
+    capture_region: Option<CaptureRegion>,
+    luma_percentile: Option<u8>,
+    /// Luma is only recomputed once this percent of the frame has changed since it was last
+    /// computed.
+    min_damage_percent: u8,
+    transfer_function: Option<TransferFunction>,
+    lightness: Lightness,
     is_processing_frame: bool,
-    vulkan: Option<Vulkan>,
+    /// Frames handed over to be processed, that are not done yet.
+    pending_jobs: usize,
+    /// Shared with the thread that processes frames, created once the output shows up.
+    vulkan: Arc<Mutex<Option<Vulkan>>>,
+    /// Configured GPU to compute luma on.
+    vulkan_device: Option<String>,
+    /// Pixels to compute luma from at most.
+    luma_samples: Option<u64>,
+    luma_focus: Option<f64>,
+    /// Shared with the thread that processes frames, to tell which output the focused window is on.
+    connector: Arc<Mutex<Option<String>>>,
+    /// DRM device number (`dev_t`) of the GPU the compositor copies frames with, if known.
+    dmabuf_device: Option<u64>,
     output: Option<WlOutput>,
     output_global_id: Option<u32>,
+    /// How the output was matched with the config.
+    output_match: Option<Match>,
+    /// What is known about every output so far, by their global ID.
+    outputs: HashMap<u32, OutputInfo>,
+    /// Versions of the capture related globals advertised by the compositor.
+    capabilities: BTreeMap<String, u32>,
     pending_frame: Option<Object>,
-    controller: Option<Box<dyn Controller>>,
+    /// Exported frames whose buffers the compositor must not reuse until their luma is computed.
+    exported_frames: Vec<ZwlrExportDmabufFrameV1>,
+    job_tx: Sender<Job>,
+    error: Option<WlumaError>,
     // linux-dmabuf-v1
     dmabuf: Option<ZwpLinuxDmabufV1>,
     wl_buffer: Option<WlBuffer>,
@@ -45,48 +122,261 @@ pub struct Capturer {
     // ext-image-copy-capture-v1
     img_copy_capture_manager: Option<ExtImageCopyCaptureManagerV1>,
     img_copy_capture_session: Option<ExtImageCopyCaptureSessionV1>,
+    /// Formats and modifiers the compositor can copy frames into, as announced by the session.
+    img_copy_dmabuf_formats: Vec<(u32, Vec<u64>)>,
+    /// Area of the frame that changed since the luma was last computed, in pixels, for the
+    /// protocols that report it.
+    damage: u64,
     // wlr-screencopy-unstable-v1
     screencopy_manager: Option<ZwlrScreencopyManagerV1>,
     // wlr-export-dmabuf-unstable-v1
     dmabuf_manager: Option<ZwlrExportDmabufManagerV1>,
 }
 
+/// Frames handed over by the event loop, which waits until they are processed before requesting
+/// the next one.
+enum Job {
+    /// The frame was copied into the exportable image, luma is only recomputed if it was damaged.
+    Copied { damaged: bool },
+    /// The frame was exported by the compositor.
+    Exported(Object),
+    /// The frame couldn't be captured, wait longer before trying again.
+    Failed,
+}
+
 #[derive(Clone)]
 struct GlobalsContext {
     global_id: Option<u32>,
     desired_output: String,
+    identity: OutputIdentity,
+}
+
+#[derive(Debug, Default)]
+struct OutputInfo {
+    name: Option<String>,
+    description: Option<String>,
+    make: String,
+    model: String,
+}
+
+/// Connector name (e.g. `DP-1`) is matched exactly, unlike the description, so it takes precedence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Match {
+    Description,
+    Name,
 }
 
 impl Capturer {
-    pub fn new(protocol: WaylandProtocol) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        protocol: WaylandProtocol,
+        identity: OutputIdentity,
+        capture_region: Option<CaptureRegion>,
+        luma_percentile: Option<u8>,
+        min_damage_percent: u8,
+        transfer_function: Option<TransferFunction>,
+        lightness: Lightness,
+        vulkan_device: Option<String>,
+        luma_samples: Option<u64>,
+        luma_focus: Option<f64>,
+        window_rx: Option<Receiver<Option<FocusedWindow>>>,
+        paused_rx: Option<Receiver<bool>>,
+        controller: Box<dyn Controller>,
+    ) -> Self {
         Self {
             protocol,
+            identity,
+            capture_region,
+            luma_percentile,
+            min_damage_percent,
+            transfer_function,
+            lightness,
+            vulkan_device,
+            luma_samples,
+            luma_focus,
+            window_rx,
+            window: None,
+            paused_rx,
+            connector: Arc::new(Mutex::new(None)),
+            controller,
+        }
+    }
+
+    /// Computes luma of the frames and feeds it to the controller, then waits before letting the
+    /// event loop know that the next frame can be requested.
+    fn process(
+        &mut self,
+        job_rx: Receiver<Job>,
+        vulkan: &Mutex<Option<Vulkan>>,
+        mut done: UnixStream,
+    ) -> Result<(), WlumaError> {
+        let mut last_luma = None;
+        loop {
+            // Compositors only complete copies once the screen changes, the controller still
+            // needs to follow ALS and learn meanwhile
+            let job = match job_rx.recv_timeout(DELAY_SUCCESS) {
+                Ok(job) => job,
+                Err(RecvTimeoutError::Timeout) => {
+                    if let Some(luma) = last_luma {
+                        self.controller.adjust(luma);
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            let delay = match job {
+                Job::Failed => DELAY_FAILURE,
+                _ => DELAY_SUCCESS,
+            };
+            let refocused = self.refocus(vulkan);
+            let luma = match job {
+                Job::Copied { damaged: false } if last_luma.is_some() && !refocused => last_luma,
+                Job::Copied { .. } => Some(compute_luma(vulkan, |vulkan| {
+                    vulkan.luma_percent_from_internal_fd()
+                })?),
+                Job::Exported(frame) => Some(compute_luma(vulkan, |vulkan| {
+                    vulkan.luma_percent_from_external_fd(&frame)
+                })?),
+                Job::Failed => None,
+            };
+
+            if let Some(luma) = luma {
+                last_luma = Some(luma);
+                self.controller.adjust(luma);
+            }
+            thread::sleep(delay);
+            self.wait_while_paused();
+
+            // The event loop only goes away when it fails, and then there is nothing to wait for
+            if done.write_all(&[0]).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Holds off requesting the next frame while the output is disabled, as nothing is predicted
+    /// for it meanwhile.
+    fn wait_while_paused(&self) {
+        let Some(paused_rx) = &self.paused_rx else {
+            return;
+        };
+        if paused_rx.try_iter().last() == Some(true) {
+            log::debug!("Capturing paused while the output is disabled");
+            // The brightness controller only goes away when it fails or wluma exits
+            while let Ok(true) = paused_rx.recv() {}
+        }
+    }
+
+    /// Weights luma around the focused window if it's on this output, and returns whether that
+    /// changed since the last frame.
+    fn refocus(&mut self, vulkan: &Mutex<Option<Vulkan>>) -> bool {
+        if let Some(window) = self.window_rx.as_ref().and_then(|rx| rx.try_iter().last()) {
+            self.window = window;
+        }
+        let connector = self.connector.lock().unwrap().clone();
+        let area = self
+            .window
+            .as_ref()
+            .filter(|window| connector.as_ref() == Some(&window.output))
+            .map(|window| window.area);
+
+        vulkan
+            .lock()
+            .unwrap()
+            .as_mut()
+            .is_some_and(|vulkan| vulkan.set_focus_area(area))
+    }
+}
+
+impl super::Capturer for Capturer {
+    fn run(&mut self, output_name: &str) -> Result<(), WlumaError> {
+        let vulkan = Arc::new(Mutex::new(None));
+        let (job_tx, job_rx) = mpsc::channel();
+        let (done_tx, done_rx) = UnixStream::pair().map_err(WlumaError::wayland)?;
+        done_rx.set_nonblocking(true).map_err(WlumaError::wayland)?;
+
+        let mut state = State {
+            protocol: self.protocol.clone(),
+            capture_region: self.capture_region,
+            luma_percentile: self.luma_percentile,
+            min_damage_percent: self.min_damage_percent,
+            transfer_function: self.transfer_function,
+            lightness: self.lightness,
             is_processing_frame: false,
-            vulkan: None,
+            pending_jobs: 0,
+            vulkan: vulkan.clone(),
+            vulkan_device: self.vulkan_device.clone(),
+            luma_samples: self.luma_samples,
+            luma_focus: self.luma_focus,
+            connector: self.connector.clone(),
+            dmabuf_device: None,
             output: None,
             output_global_id: None,
+            output_match: None,
+            outputs: HashMap::new(),
+            capabilities: BTreeMap::new(),
             pending_frame: None,
-            controller: None,
-            // linux-dmabuf-v1
+            exported_frames: vec![],
+            job_tx,
+            error: None,
             dmabuf: None,
             wl_buffer: None,
-            // ext-image-capture-source-v1
             img_capture_source_manager: None,
-            // ext-image-copy-capture-v1
             img_copy_capture_manager: None,
             img_copy_capture_session: None,
-            // wlr-screencopy-unstable-v1
+            img_copy_dmabuf_formats: vec![],
+            damage: 0,
             screencopy_manager: None,
-            // wlr-export-dmabuf-unstable-v1
             dmabuf_manager: None,
+        };
+
+        let identity = self.identity.clone();
+        let result = thread::scope(|scope| {
+            let events = thread::Builder::new()
+                .name(format!("wayland-{}", output_name))
+                .spawn_scoped(scope, move || state.run(output_name, identity, done_rx))
+                .map_err(WlumaError::wayland)?;
+
+            // When processing fails, the event loop stops as well, and the other way around
+            let processed = self.process(job_rx, &vulkan, done_tx);
+            let handled = events
+                .join()
+                .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+            processed.and(handled)
+        });
+
+        match result {
+            // Restarting would not help, e.g. the compositor doesn't support any capture protocol,
+            // but brightness can still follow ambient light
+            Err(err) if !err.is_recoverable() => {
+                log::warn!(
+                    "Unable to capture screen contents of '{}': {}. Only ALS is used to predict its brightness, as with capturer=\"none\"",
+                    output_name,
+                    err
+                );
+                self.controller.uncaptured();
+                // Paced like captured frames, so that brightness follows ALS just as quickly
+                loop {
+                    self.controller.adjust(0);
+                    thread::sleep(DELAY_SUCCESS);
+                }
+            }
+            result => result,
         }
     }
 }
 
-impl super::Capturer for Capturer {
-    fn run(&mut self, output_name: &str, controller: Box<dyn Controller>) {
-        let connection =
-            Connection::connect_to_env().expect("Unable to connect to Wayland display");
+impl State {
+    fn run(
+        &mut self,
+        output_name: &str,
+        identity: OutputIdentity,
+        mut done_rx: UnixStream,
+    ) -> Result<(), WlumaError> {
+        let connection = Connection::connect_to_env().map_err(|err| {
+            WlumaError::wayland(format!("Unable to connect to Wayland display: {}", err))
+        })?;
         let display = connection.display();
         let mut event_queue = connection.new_event_queue();
         let qh = event_queue.handle();
@@ -94,45 +384,49 @@ impl super::Capturer for Capturer {
         let ctx = GlobalsContext {
             global_id: None,
             desired_output: output_name.to_string(),
+            identity,
         };
 
         display.get_registry(&qh, ctx);
 
         // 1. process registry events
-        event_queue
-            .roundtrip(self)
-            .expect("Unable to perform initial roundtrip");
+        event_queue.roundtrip(self).map_err(|err| {
+            WlumaError::wayland(format!("Unable to perform initial roundtrip: {}", err))
+        })?;
 
         // 2. registry requested wl_output events, process those
-        event_queue
-            .roundtrip(self)
-            .expect("Unable to perform 2nd initial roundtrip");
+        event_queue.roundtrip(self).map_err(|err| {
+            WlumaError::wayland(format!("Unable to perform 2nd initial roundtrip: {}", err))
+        })?;
+
+        let capabilities = describe(&self.capabilities);
+        remember(&capabilities);
 
         let protocol_to_use = match self.protocol {
             WaylandProtocol::ExtImageCopyCaptureV1 => {
                 if self.img_copy_capture_manager.is_none() {
-                    panic!("Requested to use ext-image-copy-capture-v1 protocol, but it's not available");
+                    return Err(missing("Requested to use ext-image-copy-capture-v1 protocol, but it's not available", &capabilities));
                 }
                 if self.img_capture_source_manager.is_none() {
-                    panic!("Requested to use ext-image-copy-capture-v1 protocol, but a required ext-image-capture-source-v1 protocol it's not available");
+                    return Err(missing("Requested to use ext-image-copy-capture-v1 protocol, but a required ext-image-capture-source-v1 protocol it's not available", &capabilities));
                 }
                 if self.dmabuf.is_none() {
-                    panic!("Requested to use ext-image-copy-capture-v1 protocol, but a required linux-dmabuf-v1 protocol it's not available");
+                    return Err(missing("Requested to use ext-image-copy-capture-v1 protocol, but a required linux-dmabuf-v1 protocol it's not available", &capabilities));
                 }
                 WaylandProtocol::ExtImageCopyCaptureV1
             }
             WaylandProtocol::WlrScreencopyUnstableV1 => {
                 if self.screencopy_manager.is_none() {
-                    panic!("Requested to use wlr-screencopy-unstable-v1 protocol, but it's not available");
+                    return Err(missing("Requested to use wlr-screencopy-unstable-v1 protocol, but it's not available", &capabilities));
                 }
                 if self.dmabuf.is_none() {
-                    panic!("Requested to use wlr-screencopy-unstable-v1 protocol, but a required linux-dmabuf-v1 protocol it's not available");
+                    return Err(missing("Requested to use wlr-screencopy-unstable-v1 protocol, but a required linux-dmabuf-v1 protocol it's not available", &capabilities));
                 }
                 WaylandProtocol::WlrScreencopyUnstableV1
             }
             WaylandProtocol::WlrExportDmabufUnstableV1 => {
                 if self.dmabuf_manager.is_none() {
-                    panic!("Requested to use wlr-export-dmabuf-unstable-v1 protocol, but it's not available");
+                    return Err(missing("Requested to use wlr-export-dmabuf-unstable-v1 protocol, but it's not available", &capabilities));
                 }
                 WaylandProtocol::WlrExportDmabufUnstableV1
             }
@@ -147,18 +441,22 @@ impl super::Capturer for Capturer {
                 } else if self.dmabuf_manager.is_some() {
                     WaylandProtocol::WlrExportDmabufUnstableV1
                 } else {
-                    panic!("No supported Wayland protocols found to capture screen contents, set capturer=\"none\" in the config, or report an issue if you believe it's a mistake");
+                    return Err(missing("No supported Wayland protocols found to capture screen contents, run `wluma diagnose` for details, set capturer=\"none\" in the config, or report an issue if you believe it's a mistake", &capabilities));
                 }
             }
         };
         log::debug!("Using {protocol_to_use} protocol to request frames");
 
-        self.vulkan = Some(Vulkan::new().expect("Unable to initialize Vulkan"));
-        self.controller = Some(controller);
-
         loop {
             if !self.is_processing_frame {
                 if let Some(output) = self.output.as_ref() {
+                    // Creating a Vulkan device is slow and holds on to the GPU, only do it once
+                    // the output shows up and the first frame is about to be requested. The
+                    // image copy session first tells which GPU the frames come from
+                    if protocol_to_use != WaylandProtocol::ExtImageCopyCaptureV1 {
+                        self.init_vulkan()?;
+                    }
+
                     match protocol_to_use {
                         WaylandProtocol::ExtImageCopyCaptureV1 => {
                             if self.img_copy_capture_session.is_none() {
@@ -216,16 +514,213 @@ impl super::Capturer for Capturer {
                 }
             }
 
-            event_queue
-                .blocking_dispatch(self)
-                .expect("Error running wayland capturer main loop");
+            self.wait(&mut event_queue, &mut done_rx)?;
+
+            if let Some(err) = self.error.take() {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Dispatches Wayland events as they come, and notices when the frame has been processed.
+    fn wait(
+        &mut self,
+        event_queue: &mut EventQueue<Self>,
+        done_rx: &mut UnixStream,
+    ) -> Result<(), WlumaError> {
+        let failed = |err: &dyn Display| {
+            WlumaError::wayland(format!("Error running wayland capturer main loop: {}", err))
+        };
+
+        if event_queue.dispatch_pending(self).map_err(|e| failed(&e))? == 0 {
+            event_queue.flush().map_err(|e| failed(&e))?;
+            if let Some(guard) = event_queue.prepare_read() {
+                let [events, _] = poll_readable([guard.connection_fd(), done_rx.as_fd()])?;
+                if events {
+                    match guard.read() {
+                        Ok(_) => {}
+                        Err(WaylandError::Io(err)) if err.kind() == ErrorKind::WouldBlock => {}
+                        Err(err) => return Err(failed(&err)),
+                    }
+                }
+            }
+            event_queue.dispatch_pending(self).map_err(|e| failed(&e))?;
+        }
+
+        // One byte per processed frame
+        let mut buffer = [0; 16];
+        match done_rx.read(&mut buffer) {
+            Ok(0) => Err(WlumaError::wayland("Frame processing stopped")),
+            Ok(done) => {
+                self.pending_jobs = self.pending_jobs.saturating_sub(done);
+                self.is_processing_frame = self.pending_jobs > 0;
+                if !self.is_processing_frame {
+                    self.exported_frames
+                        .drain(..)
+                        .for_each(|frame| frame.destroy());
+                }
+                Ok(())
+            }
+            Err(err) if err.kind() == ErrorKind::WouldBlock => Ok(()),
+            Err(err) => Err(WlumaError::wayland(err)),
+        }
+    }
+
+    fn init_vulkan(&self) -> Result<(), WlumaError> {
+        let mut vulkan = self.vulkan.lock().unwrap();
+        if vulkan.is_none() {
+            *vulkan = Some(Vulkan::new(
+                self.capture_region,
+                self.luma_percentile,
+                self.transfer_function,
+                self.lightness,
+                self.vulkan_device.as_deref(),
+                self.dmabuf_device,
+                self.luma_samples,
+                self.luma_focus,
+            )?);
+        }
+        Ok(())
+    }
+
+    /// Hands the frame over to be processed, the next one is requested once it's done.
+    fn send(&mut self, job: Job) {
+        self.is_processing_frame = true;
+        self.pending_jobs += 1;
+        // The receiver only goes away when processing failed, which stops the event loop
+        self.job_tx.send(job).ok();
+    }
+
+    fn add_damage(&mut self, width: u32, height: u32) {
+        // Overlapping regions are counted twice, which only makes luma recomputed sooner
+        self.damage += width as u64 * height as u64;
+    }
+
+    /// Whether enough of the frame changed since the luma was last computed to compute it again.
+    fn take_damage(&mut self) -> bool {
+        let frame_area = self
+            .pending_frame
+            .as_ref()
+            .map_or(0, |f| f.width as u64 * f.height as u64);
+
+        let damaged = self.damage * 100 >= frame_area * self.min_damage_percent as u64;
+        if damaged {
+            self.damage = 0;
+        }
+        damaged
+    }
+
+    /// Event handlers can't return errors, remember the error to stop the main loop.
+    fn fail(&mut self, context: &str, err: WlumaError) {
+        self.error = Some(WlumaError::wayland(format!("{}: {}", context, err)));
+    }
+}
+
+/// Capture related globals with their versions, e.g. `zwp_linux_dmabuf_v1 v4`.
+fn describe(capabilities: &BTreeMap<String, u32>) -> String {
+    match capabilities.is_empty() {
+        true => "none".to_string(),
+        false => capabilities
+            .iter()
+            .map(|(interface, version)| format!("{} v{}", interface, version))
+            .join(", "),
+    }
+}
+
+/// Logs what the compositor supports when it is first known or changes, and keeps it to report
+/// in panics.
+fn remember(capabilities: &str) {
+    let mut current = CAPABILITIES.lock().unwrap();
+    if current.as_deref() != Some(capabilities) {
+        log::info!(
+            "Capture protocols advertised by the compositor: {}",
+            capabilities
+        );
+        *current = Some(capabilities.to_string());
+    }
+}
+
+/// Capture related globals advertised by the compositor, once a capturer connected to it.
+pub fn capabilities() -> Option<String> {
+    CAPABILITIES.lock().unwrap().clone()
+}
+
+fn missing(message: &str, capabilities: &str) -> WlumaError {
+    WlumaError::config(format!(
+        "{}. Capture protocols advertised by the compositor: {}",
+        message, capabilities
+    ))
+}
+
+fn compute_luma(
+    vulkan: &Mutex<Option<Vulkan>>,
+    compute: impl FnOnce(&mut Vulkan) -> Result<u8, WlumaError>,
+) -> Result<u8, WlumaError> {
+    let mut vulkan = vulkan.lock().unwrap();
+    let vulkan = vulkan
+        .as_mut()
+        .expect("Vulkan must be ready before frames are requested");
+    compute(vulkan)
+        .map_err(|err| WlumaError::wayland(format!("Unable to compute luma percent: {}", err)))
+}
+
+/// Waits until any of the file descriptors becomes readable, returns which ones are.
+fn poll_readable<const N: usize>(fds: [BorrowedFd; N]) -> Result<[bool; N], WlumaError> {
+    let mut pollfds = fds.map(|fd| libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    if unsafe { libc::poll(pollfds.as_mut_ptr(), N as libc::nfds_t, -1) } < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(WlumaError::wayland(err));
         }
     }
+    Ok(pollfds.map(|pollfd| pollfd.revents != 0))
+}
+
+/// How well the output matches the config, none if it doesn't.
+fn match_output(info: &OutputInfo, desired: &str, identity: &OutputIdentity) -> Option<Match> {
+    let serial_matches = |serial: &String| {
+        info.description.as_ref().is_some_and(|description| {
+            description
+                .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+                .any(|word| word == serial)
+        })
+    };
+    if identity
+        .make
+        .as_ref()
+        .is_some_and(|make| *make != info.make)
+        || identity
+            .model
+            .as_ref()
+            .is_some_and(|model| *model != info.model)
+        || identity
+            .serial
+            .as_ref()
+            .is_some_and(|serial| !serial_matches(serial))
+    {
+        return None;
+    }
+
+    if info.name.as_deref() == Some(desired) {
+        Some(Match::Name)
+    } else if info
+        .description
+        .as_ref()
+        .is_some_and(|description| description.contains(desired))
+    {
+        Some(Match::Description)
+    } else {
+        None
+    }
 }
 
 // ==== Globals ====
 
-impl Dispatch<WlOutput, GlobalsContext> for Capturer {
+impl Dispatch<WlOutput, GlobalsContext> for State {
     fn event(
         state: &mut Self,
         output: &WlOutput,
@@ -236,27 +731,51 @@ impl Dispatch<WlOutput, GlobalsContext> for Capturer {
     ) {
         use wayland_client::protocol::wl_output::Event;
 
+        let Some(global_id) = ctx.global_id else {
+            return;
+        };
+        let info = state.outputs.entry(global_id).or_default();
         match event {
-            Event::Description { description } if description.contains(&ctx.desired_output) => {
-                if state.output.is_none() {
+            Event::Geometry { make, model, .. } => {
+                info.make = make;
+                info.model = model;
+            }
+            Event::Name { name } => info.name = Some(name),
+            Event::Description { description } => info.description = Some(description),
+            // All the properties of the output have been sent
+            Event::Done => {
+                let Some(matched) = match_output(info, &ctx.desired_output, &ctx.identity) else {
+                    return;
+                };
+                let described = format!(
+                    "{} ({})",
+                    info.name.as_deref().unwrap_or_default(),
+                    info.description.as_deref().unwrap_or_default()
+                );
+                if state.output_global_id == Some(global_id)
+                    || state.output_match.is_some_and(|current| current > matched)
+                {
+                    // Already in use, or a better match is
+                } else if state.output_match == Some(matched) {
+                    log::error!("Cannot use output '{}' for config '{}' because another output was already matched with it, skipping this output. Set its make, model or serial in the config to tell them apart.", described, ctx.desired_output);
+                } else {
                     log::debug!(
                         "Using output '{}' for config '{}'",
-                        description,
-                        ctx.desired_output,
+                        described,
+                        ctx.desired_output
                     );
                     state.output = Some(output.clone());
-                    state.output_global_id = ctx.global_id;
-                } else {
-                    log::error!("Cannot use output '{}' for config '{}' because another output was already matched with it, skipping this output.", description, ctx.desired_output);
+                    *state.connector.lock().unwrap() = info.name.clone();
+                    state.output_global_id = Some(global_id);
+                    state.output_match = Some(matched);
                 }
             }
-
             _ => {}
         }
     }
 }
 
-impl Dispatch<WlRegistry, GlobalsContext> for Capturer {
+impl Dispatch<WlRegistry, GlobalsContext> for State {
     fn event(
         state: &mut Self,
         registry: &WlRegistry,
@@ -273,6 +792,9 @@ impl Dispatch<WlRegistry, GlobalsContext> for Capturer {
                 interface,
                 version,
             } => {
+                if CAPTURE_INTERFACES.contains(&&interface[..]) {
+                    state.capabilities.insert(interface.clone(), version);
+                }
                 match &interface[..] {
                     _ if interface == WlOutput::interface().name => {
                         registry.bind::<WlOutput, _, _>(
@@ -282,6 +804,7 @@ impl Dispatch<WlRegistry, GlobalsContext> for Capturer {
                             GlobalsContext {
                                 global_id: Some(name),
                                 desired_output: ctx.desired_output.clone(),
+                                identity: ctx.identity.clone(),
                             },
                         );
                     }
@@ -327,10 +850,12 @@ impl Dispatch<WlRegistry, GlobalsContext> for Capturer {
             }
 
             Event::GlobalRemove { name } => {
+                state.outputs.remove(&name);
                 if Some(name) == state.output_global_id {
                     log::debug!("Disconnected screen {}", ctx.desired_output);
                     state.output = None;
                     state.output_global_id = None;
+                    state.output_match = None;
                 }
             }
             _ => {}
@@ -340,7 +865,7 @@ impl Dispatch<WlRegistry, GlobalsContext> for Capturer {
 
 // ==== wlr-export-dmabuf-unstable-v1 protocol ====
 
-impl Dispatch<ZwlrExportDmabufManagerV1, ()> for Capturer {
+impl Dispatch<ZwlrExportDmabufManagerV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ZwlrExportDmabufManagerV1,
@@ -352,7 +877,7 @@ impl Dispatch<ZwlrExportDmabufManagerV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<ZwlrExportDmabufFrameV1, ()> for Capturer {
+impl Dispatch<ZwlrExportDmabufFrameV1, ()> for State {
     fn event(
         state: &mut Self,
         frame: &ZwlrExportDmabufFrameV1,
@@ -385,27 +910,16 @@ impl Dispatch<ZwlrExportDmabufFrameV1, ()> for Capturer {
             }
 
             Event::Ready { .. } => {
-                let luma = state
-                    .vulkan
-                    .as_mut()
-                    .unwrap()
-                    .luma_percent_from_external_fd(&state.pending_frame.take().unwrap())
-                    .expect("Unable to compute luma percent");
-
-                state.controller.as_mut().unwrap().adjust(luma);
-
-                frame.destroy();
-
-                thread::sleep(DELAY_SUCCESS);
-                state.is_processing_frame = false;
+                // Destroyed once processed, as that lets the compositor reuse the buffers
+                state.exported_frames.push(frame.clone());
+                let pending_frame = state.pending_frame.take().unwrap();
+                state.send(Job::Exported(pending_frame));
             }
 
             Event::Cancel { reason } => {
                 log::debug!("Frame was cancelled, reason: {reason:?}");
                 frame.destroy();
-
-                thread::sleep(DELAY_FAILURE);
-                state.is_processing_frame = false;
+                state.send(Job::Failed);
             }
 
             _ => unreachable!(),
@@ -415,7 +929,7 @@ impl Dispatch<ZwlrExportDmabufFrameV1, ()> for Capturer {
 
 // ==== linux-dmabuf-v1 protocol ====
 
-impl Dispatch<ZwpLinuxDmabufV1, ()> for Capturer {
+impl Dispatch<ZwpLinuxDmabufV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ZwpLinuxDmabufV1,
@@ -427,7 +941,7 @@ impl Dispatch<ZwpLinuxDmabufV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<ZwpLinuxBufferParamsV1, ()> for Capturer {
+impl Dispatch<ZwpLinuxBufferParamsV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ZwpLinuxBufferParamsV1,
@@ -439,7 +953,7 @@ impl Dispatch<ZwpLinuxBufferParamsV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<WlBuffer, ()> for Capturer {
+impl Dispatch<WlBuffer, ()> for State {
     fn event(
         _: &mut Self,
         _: &WlBuffer,
@@ -453,7 +967,7 @@ impl Dispatch<WlBuffer, ()> for Capturer {
 
 // ==== wlr-screencopy-unstable-v1 protocol ====
 
-impl Dispatch<ZwlrScreencopyManagerV1, ()> for Capturer {
+impl Dispatch<ZwlrScreencopyManagerV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ZwlrScreencopyManagerV1,
@@ -465,7 +979,7 @@ impl Dispatch<ZwlrScreencopyManagerV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<ZwlrScreencopyFrameV1, ()> for Capturer {
+impl Dispatch<ZwlrScreencopyFrameV1, ()> for State {
     fn event(
         state: &mut Self,
         frame: &ZwlrScreencopyFrameV1,
@@ -496,12 +1010,19 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for Capturer {
                 if state.wl_buffer.is_none() {
                     let pending_frame = Object::new(width, height, 1, format);
                     let dmabuf_params = state.dmabuf.as_ref().unwrap().create_params(qh, ());
-                    let (fd, offset, stride, modifier) = state
+                    let image = state
                         .vulkan
+                        .lock()
+                        .unwrap()
                         .as_mut()
                         .unwrap()
-                        .init_exportable_frame_image(&pending_frame)
-                        .expect("Unable to init exportable frame image");
+                        .init_exportable_frame_image(&pending_frame, &[DRM_FORMAT_MOD_LINEAR]);
+                    let (fd, offset, stride, modifier) = match image {
+                        Ok(image) => image,
+                        Err(err) => {
+                            return state.fail("Unable to init exportable frame image", err)
+                        }
+                    };
 
                     let fd = unsafe { BorrowedFd::borrow_raw(fd) };
 
@@ -525,26 +1046,27 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for Capturer {
 
                     dmabuf_params.destroy();
                     state.wl_buffer = Some(wl_buffer);
+                    // The new buffer gets the whole frame copied into it
+                    state.damage = width as u64 * height as u64;
                     state.pending_frame = Some(pending_frame);
                 }
 
-                frame.copy(state.wl_buffer.as_ref().unwrap());
+                // Older compositors can't tell which parts of the frame changed
+                if frame.version() >= 2 {
+                    frame.copy_with_damage(state.wl_buffer.as_ref().unwrap());
+                } else {
+                    frame.copy(state.wl_buffer.as_ref().unwrap());
+                }
             }
 
-            Event::Ready { .. } => {
-                let luma = state
-                    .vulkan
-                    .as_mut()
-                    .unwrap()
-                    .luma_percent_from_internal_fd()
-                    .expect("Unable to compute luma percent");
-
-                state.controller.as_mut().unwrap().adjust(luma);
+            Event::Damage { width, height, .. } => {
+                state.add_damage(width, height);
+            }
 
+            Event::Ready { .. } => {
+                let damaged = frame.version() < 2 || state.take_damage();
                 frame.destroy();
-
-                thread::sleep(DELAY_SUCCESS);
-                state.is_processing_frame = false;
+                state.send(Job::Copied { damaged });
             }
 
             Event::Failed {} => {
@@ -555,8 +1077,7 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for Capturer {
                     buffer.destroy()
                 }
 
-                thread::sleep(DELAY_FAILURE);
-                state.is_processing_frame = false;
+                state.send(Job::Failed);
             }
 
             _ => {}
@@ -566,7 +1087,7 @@ impl Dispatch<ZwlrScreencopyFrameV1, ()> for Capturer {
 
 // ==== ext-image-capture-source-v1 protocol ====
 
-impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for Capturer {
+impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ExtOutputImageCaptureSourceManagerV1,
@@ -578,7 +1099,7 @@ impl Dispatch<ExtOutputImageCaptureSourceManagerV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<ExtImageCaptureSourceV1, ()> for Capturer {
+impl Dispatch<ExtImageCaptureSourceV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ExtImageCaptureSourceV1,
@@ -592,7 +1113,7 @@ impl Dispatch<ExtImageCaptureSourceV1, ()> for Capturer {
 
 // ==== ext-image-copy-capture-v1 protocol ====
 
-impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for Capturer {
+impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for State {
     fn event(
         _: &mut Self,
         _: &ExtImageCopyCaptureManagerV1,
@@ -604,7 +1125,7 @@ impl Dispatch<ExtImageCopyCaptureManagerV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for Capturer {
+impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for State {
     fn event(
         state: &mut Self,
         _: &ExtImageCopyCaptureSessionV1,
@@ -617,13 +1138,22 @@ impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for Capturer {
 
         match event {
             Event::BufferSize { width, height } => {
-                // TODO format is actually not known at this stage, see below
-                let pending_frame = Object::new(width, height, 1, 875713112);
+                // Format is only known once all constraints are received
+                let pending_frame = Object::new(width, height, 1, 0);
                 state.pending_frame = Some(pending_frame);
             }
 
-            Event::DmabufFormat { .. } => {
-                // TODO figure out how to use modifiers from wl_screenrec, once I have a device that supports modifiers
+            Event::DmabufDevice { device } => {
+                log::debug!("Compositor copies frames using device {device:?}");
+                state.dmabuf_device = device.try_into().ok().map(u64::from_ne_bytes);
+            }
+
+            Event::DmabufFormat { format, modifiers } => {
+                let modifiers = modifiers
+                    .chunks_exact(8)
+                    .map(|m| u64::from_ne_bytes(m.try_into().unwrap()))
+                    .collect();
+                state.img_copy_dmabuf_formats.push((format, modifiers));
             }
 
             Event::Done => {
@@ -631,15 +1161,44 @@ impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for Capturer {
                     buffer.destroy()
                 }
 
-                let pending_frame = state.pending_frame.as_ref().unwrap();
+                let dmabuf_formats = std::mem::take(&mut state.img_copy_dmabuf_formats);
+                if let Err(err) = state.init_vulkan() {
+                    return state.fail("Unable to initialize Vulkan", err);
+                }
+                let vulkan = state.vulkan.clone();
+                let mut vulkan = vulkan.lock().unwrap();
+                let vulkan = vulkan.as_mut().unwrap();
+                let Some((format, modifiers)) =
+                    dmabuf_formats.into_iter().find_map(|(format, offered)| {
+                        let supported = vulkan.supported_modifiers(format);
+                        let modifiers = offered
+                            .into_iter()
+                            .filter(|m| supported.contains(m))
+                            .collect_vec();
+                        (!modifiers.is_empty()).then_some((format, modifiers))
+                    })
+                else {
+                    return state.fail(
+                        "Unable to init exportable frame image",
+                        WlumaError::vulkan(
+                            "Compositor offered no DMA-BUF format supported by your GPU",
+                        ),
+                    );
+                };
+                log::debug!("Using DMA-BUF format {format:#010x} with modifiers {modifiers:?}");
+
+                let pending_frame = state.pending_frame.as_mut().unwrap();
+                pending_frame.format = format;
+                // The new buffer gets the whole frame copied into it
+                state.damage = pending_frame.width as u64 * pending_frame.height as u64;
 
                 let dmabuf_params = state.dmabuf.as_ref().unwrap().create_params(qh, ());
-                let (fd, offset, stride, modifier) = state
-                    .vulkan
-                    .as_mut()
-                    .unwrap()
-                    .init_exportable_frame_image(pending_frame)
-                    .expect("Unable to init exportable frame image");
+                let (fd, offset, stride, modifier) = match vulkan
+                    .init_exportable_frame_image(pending_frame, &modifiers)
+                {
+                    Ok(image) => image,
+                    Err(err) => return state.fail("Unable to init exportable frame image", err),
+                };
 
                 let fd = unsafe { BorrowedFd::borrow_raw(fd) };
 
@@ -673,8 +1232,7 @@ impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for Capturer {
                     buffer.destroy()
                 }
 
-                thread::sleep(DELAY_FAILURE);
-                state.is_processing_frame = false;
+                state.send(Job::Failed);
             }
 
             _ => {}
@@ -682,7 +1240,7 @@ impl Dispatch<ExtImageCopyCaptureSessionV1, ()> for Capturer {
     }
 }
 
-impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for Capturer {
+impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for State {
     fn event(
         state: &mut Self,
         frame: &ExtImageCopyCaptureFrameV1,
@@ -694,31 +1252,77 @@ impl Dispatch<ExtImageCopyCaptureFrameV1, ()> for Capturer {
         use wayland_protocols::ext::image_copy_capture::v1::client::ext_image_copy_capture_frame_v1::Event;
 
         match event {
-            Event::Ready => {
-                let luma = state
-                    .vulkan
-                    .as_mut()
-                    .unwrap()
-                    .luma_percent_from_internal_fd()
-                    .expect("Unable to compute luma percent");
-
-                state.controller.as_mut().unwrap().adjust(luma);
+            Event::Damage { width, height, .. } => {
+                state.add_damage(width.max(0) as u32, height.max(0) as u32);
+            }
 
+            Event::Ready => {
+                let damaged = state.take_damage();
                 frame.destroy();
-
-                thread::sleep(DELAY_SUCCESS);
-                state.is_processing_frame = false;
+                state.send(Job::Copied { damaged });
             }
 
             Event::Failed { reason } => {
                 log::debug!("Frame copy failed, reason: {reason:?}");
                 frame.destroy();
-
-                thread::sleep(DELAY_FAILURE);
-                state.is_processing_frame = false;
+                state.send(Job::Failed);
             }
 
             _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        assert_eq!("none", describe(&BTreeMap::new()));
+        assert_eq!(
+            "zwlr_screencopy_manager_v1 v3, zwp_linux_dmabuf_v1 v4",
+            describe(&BTreeMap::from([
+                ("zwp_linux_dmabuf_v1".to_string(), 4),
+                ("zwlr_screencopy_manager_v1".to_string(), 3),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_match_output() {
+        let info = OutputInfo {
+            name: Some("DP-1".to_string()),
+            description: Some("Dell Inc. DELL U2720Q ABC123 (DP-1)".to_string()),
+            make: "Dell Inc.".to_string(),
+            model: "DELL U2720Q".to_string(),
+        };
+        let identity = |make: Option<&str>, serial: Option<&str>| OutputIdentity {
+            make: make.map(String::from),
+            model: None,
+            serial: serial.map(String::from),
+        };
+
+        assert_eq!(
+            Some(Match::Name),
+            match_output(&info, "DP-1", &identity(None, None))
+        );
+        assert_eq!(
+            Some(Match::Description),
+            match_output(
+                &info,
+                "U2720Q",
+                &identity(Some("Dell Inc."), Some("ABC123"))
+            )
+        );
+        assert_eq!(None, match_output(&info, "HDMI-A-1", &identity(None, None)));
+        assert_eq!(
+            None,
+            match_output(&info, "DP-1", &identity(Some("Dell"), None))
+        );
+        assert_eq!(
+            None,
+            match_output(&info, "DP-1", &identity(None, Some("ABC")))
+        );
+    }
+}
@@ -1,30 +1,303 @@
+use crate::config::{CaptureRegion, Lightness};
 use itertools::Itertools;
 
 pub mod capturer;
 mod object;
 pub mod vulkan;
 
-pub fn compute_perceived_lightness_percent(rgbas: &[u8], has_alpha: bool, pixels: usize) -> u8 {
+pub fn compute_perceived_lightness_percent(
+    rgbas: &[u8],
+    has_alpha: bool,
+    pixels: usize,
+    lightness: Lightness,
+) -> u8 {
     let channels = if has_alpha { 4 } else { 3 };
 
-    let (rs, gs, bs) = rgbas
-        .iter()
-        .take(channels * pixels)
-        .chunks(channels)
-        .into_iter()
-        .map(|mut chunk| {
-            let r = *chunk.next().unwrap();
-            let g = *chunk.next().unwrap();
-            let b = *chunk.next().unwrap();
-            (r as f64, g as f64, b as f64)
-        })
-        .reduce(|(rs, gs, bs), (r, g, b)| (rs + r, gs + g, bs + b))
-        .unwrap();
+    // Integer sums are exact and let the compiler vectorize the loop
+    let (rs, gs, bs) = rgbas.chunks_exact(channels).take(pixels).fold(
+        (0u64, 0u64, 0u64),
+        |(rs, gs, bs), pixel| {
+            (
+                rs + pixel[0] as u64,
+                gs + pixel[1] as u64,
+                bs + pixel[2] as u64,
+            )
+        },
+    );
 
     let pixels = pixels as f64;
-    let (r, g, b) = (rs / pixels, gs / pixels, bs / pixels);
+    perceived_lightness_percent(
+        lightness,
+        rs as f64 / pixels,
+        gs as f64 / pixels,
+        bs as f64 / pixels,
+    )
+}
+
+/// Instead of averaging all pixels, finds the lightness that the given percent of pixels don't exceed.
+///
+/// The histogram has the number of pixels for every lightness percent.
+fn lightness_percentile(histogram: &[u32], percentile: u8) -> u8 {
+    let pixels: u64 = histogram.iter().map(|&count| count as u64).sum();
+    let rank = (pixels * percentile.min(100) as u64).div_ceil(100).max(1);
+
+    let mut seen = 0;
+    histogram
+        .iter()
+        .position(|&count| {
+            seen += count as u64;
+            seen >= rank
+        })
+        .unwrap_or(histogram.len().saturating_sub(1)) as u8
+}
 
-    let result = (0.241 * r * r + 0.691 * g * g + 0.068 * b * b).sqrt() / 255.0 * 100.0;
+/// Takes sRGB channels between 0 and 255, the shader has the same formulas.
+fn perceived_lightness_percent(lightness: Lightness, r: f64, g: f64, b: f64) -> u8 {
+    let (r, g, b) = (r / 255.0, g / 255.0, b / 255.0);
+    let result = match lightness {
+        Lightness::Hsp => (0.241 * r * r + 0.691 * g * g + 0.068 * b * b).sqrt() * 100.0,
+        Lightness::Rec709 => relative_luminance(r, g, b) * 100.0,
+        Lightness::CieLab => {
+            let y = relative_luminance(r, g, b);
+            // Below this, the curve continues as a straight line instead of a cube root
+            if y <= 216.0 / 24389.0 {
+                y * 24389.0 / 27.0
+            } else {
+                116.0 * y.cbrt() - 16.0
+            }
+        }
+    };
 
     result.round() as u8
 }
+
+fn relative_luminance(r: f64, g: f64, b: f64) -> f64 {
+    let linear = |c: f64| {
+        if c <= 0.04045 {
+            c / 12.92
+        } else {
+            ((c + 0.055) / 1.055).powf(2.4)
+        }
+    };
+    0.2126 * linear(r) + 0.7152 * linear(g) + 0.0722 * linear(b)
+}
+
+/// Generates a frame with a horizontal gradient from black to white, for tests and benchmarks.
+pub fn synthetic_frame(width: usize, height: usize, has_alpha: bool) -> Vec<u8> {
+    let channels = if has_alpha { 4 } else { 3 };
+    let row = (0..width)
+        .flat_map(|x| {
+            let value = (x * 255 / width.saturating_sub(1).max(1)) as u8;
+            [value, value, value, 255].into_iter().take(channels)
+        })
+        .collect_vec();
+    row.repeat(height)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn full(width: u32, height: u32) -> Self {
+        Self {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+}
+
+/// Part of a frame, in fractions of its size.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Area {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Area {
+    pub fn full() -> Self {
+        Self {
+            x: 0.0,
+            y: 0.0,
+            width: 1.0,
+            height: 1.0,
+        }
+    }
+}
+
+/// Resolves the capture region against the actual frame size, keeping at least one pixel inside the frame.
+pub fn crop(region: &CaptureRegion, width: u32, height: u32) -> Rect {
+    let (x, y, right, bottom) = match *region {
+        CaptureRegion::Rect {
+            x,
+            y,
+            width: w,
+            height: h,
+        } => (x, y, x.saturating_add(w), y.saturating_add(h)),
+        CaptureRegion::Margins {
+            top,
+            right,
+            bottom,
+            left,
+        } => {
+            let px = |percent: f64, size: u32| (size as f64 * percent / 100.0).round() as u32;
+            (
+                px(left, width),
+                px(top, height),
+                width.saturating_sub(px(right, width)),
+                height.saturating_sub(px(bottom, height)),
+            )
+        }
+    };
+
+    let x = x.min(width.saturating_sub(1));
+    let y = y.min(height.saturating_sub(1));
+    let right = right.clamp(x + 1, width.max(x + 1));
+    let bottom = bottom.clamp(y + 1, height.max(y + 1));
+
+    Rect {
+        x,
+        y,
+        width: right - x,
+        height: bottom - y,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_perceived_lightness_percent() {
+        let frame = synthetic_frame(1920, 1080, true);
+        assert_eq!(1920 * 1080 * 4, frame.len());
+        assert_eq!(
+            50,
+            compute_perceived_lightness_percent(&frame, true, 1920 * 1080, Lightness::Hsp)
+        );
+
+        let frame = synthetic_frame(640, 480, false);
+        assert_eq!(
+            50,
+            compute_perceived_lightness_percent(&frame, false, 640 * 480, Lightness::Hsp)
+        );
+    }
+
+    #[test]
+    fn test_perceived_lightness_percent() {
+        for lightness in [Lightness::Hsp, Lightness::Rec709, Lightness::CieLab] {
+            assert_eq!(0, perceived_lightness_percent(lightness, 0.0, 0.0, 0.0));
+            assert_eq!(
+                100,
+                perceived_lightness_percent(lightness, 255.0, 255.0, 255.0)
+            );
+        }
+
+        // Middle grey
+        assert_eq!(
+            50,
+            perceived_lightness_percent(Lightness::Hsp, 128.0, 128.0, 128.0)
+        );
+        assert_eq!(
+            22,
+            perceived_lightness_percent(Lightness::Rec709, 128.0, 128.0, 128.0)
+        );
+        assert_eq!(
+            54,
+            perceived_lightness_percent(Lightness::CieLab, 128.0, 128.0, 128.0)
+        );
+
+        // Colorful contents are where the formulas disagree
+        assert_eq!(
+            26,
+            perceived_lightness_percent(Lightness::Hsp, 0.0, 0.0, 255.0)
+        );
+        assert_eq!(
+            7,
+            perceived_lightness_percent(Lightness::Rec709, 0.0, 0.0, 255.0)
+        );
+        assert_eq!(
+            32,
+            perceived_lightness_percent(Lightness::CieLab, 0.0, 0.0, 255.0)
+        );
+    }
+
+    #[test]
+    fn test_lightness_percentile() {
+        // A dark screen with a few bright pixels, e.g. subtitles on a movie
+        let mut histogram = [0; 101];
+        histogram[4] = 8;
+        histogram[98] = 2;
+
+        assert_eq!(4, lightness_percentile(&histogram, 0));
+        assert_eq!(4, lightness_percentile(&histogram, 50));
+        assert_eq!(4, lightness_percentile(&histogram, 80));
+        assert_eq!(98, lightness_percentile(&histogram, 90));
+        assert_eq!(98, lightness_percentile(&histogram, 100));
+    }
+
+    #[test]
+    fn test_crop_rect() {
+        let region = CaptureRegion::Rect {
+            x: 0,
+            y: 30,
+            width: 1920,
+            height: 1050,
+        };
+        assert_eq!(
+            Rect {
+                x: 0,
+                y: 30,
+                width: 1920,
+                height: 1050
+            },
+            crop(&region, 1920, 1080)
+        );
+    }
+
+    #[test]
+    fn test_crop_rect_clamps_to_frame() {
+        let region = CaptureRegion::Rect {
+            x: 100,
+            y: 2000,
+            width: 5000,
+            height: 100,
+        };
+        assert_eq!(
+            Rect {
+                x: 100,
+                y: 1079,
+                width: 1820,
+                height: 1
+            },
+            crop(&region, 1920, 1080)
+        );
+    }
+
+    #[test]
+    fn test_crop_margins() {
+        let region = CaptureRegion::Margins {
+            top: 5.0,
+            right: 0.0,
+            bottom: 10.0,
+            left: 25.0,
+        };
+        assert_eq!(
+            Rect {
+                x: 480,
+                y: 54,
+                width: 1440,
+                height: 918
+            },
+            crop(&region, 1920, 1080)
+        );
+    }
+}
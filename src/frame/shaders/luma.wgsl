@@ -0,0 +1,184 @@
+// Sums up the color channels and builds a lightness histogram of the whole image in a single pass,
+// with every pixel weighted by how close it is to the focused part of the screen.
+//
+// After changing it, compile with naga-cli: naga src/frame/shaders/luma.wgsl src/frame/shaders/luma.spv
+
+struct Result {
+    // Pairs of low and high 32 bits of the red, green and blue sums, and of the sum of weights
+    sums: array<atomic<u32>, 8>,
+    // Weighted number of pixels for every lightness percent
+    histogram: array<atomic<u32>, 101>,
+}
+
+struct Params {
+    // One of the TRANSFER_FUNCTION_* values
+    transfer_function: u32,
+    // One of the LIGHTNESS_* values
+    lightness: u32,
+    // Center of the focused part of the image and the spread of the weights around it, in
+    // fractions of the image size, every pixel weighs the same when the spread is 0
+    focus_x: f32,
+    focus_y: f32,
+    focus_spread_x: f32,
+    focus_spread_y: f32,
+}
+
+const TRANSFER_FUNCTION_NONE: u32 = 0u;
+const TRANSFER_FUNCTION_PQ: u32 = 1u;
+const TRANSFER_FUNCTION_HLG: u32 = 2u;
+
+const LIGHTNESS_HSP: u32 = 0u;
+const LIGHTNESS_REC709: u32 = 1u;
+const LIGHTNESS_CIELAB: u32 = 2u;
+
+// Luminance of SDR white on HDR screens (ITU-R BT.2408)
+const SDR_WHITE_NITS: f32 = 203.0;
+// Scene light of SDR white, encoded by HLG as 75% signal
+const HLG_SDR_WHITE: f32 = 0.26496256;
+
+// Weight of pixels at the center of the focused part, small enough for the weighted sums of 8K
+// images to fit
+const FOCUS_WEIGHT: f32 = 64.0;
+
+var<immediate> params: Params;
+@group(0) @binding(0) var frame: texture_storage_2d<rgba16unorm, read>;
+@group(0) @binding(1) var<storage, read_write> result: Result;
+
+var<workgroup> tile_r: array<u32, 256>;
+var<workgroup> tile_g: array<u32, 256>;
+var<workgroup> tile_b: array<u32, 256>;
+var<workgroup> tile_weight: array<u32, 256>;
+var<workgroup> tile_histogram: array<atomic<u32>, 101>;
+
+fn pq_to_linear(signal: vec3<f32>) -> vec3<f32> {
+    let m1 = 0.1593017578125;
+    let m2 = 78.84375;
+    let c1 = 0.8359375;
+    let c2 = 18.8515625;
+    let c3 = 18.6875;
+
+    let p = pow(signal, vec3<f32>(1.0 / m2));
+    let nits = 10000.0 * pow(max(p - c1, vec3<f32>(0.0)) / (c2 - c3 * p), vec3<f32>(1.0 / m1));
+    return nits / SDR_WHITE_NITS;
+}
+
+fn hlg_to_linear(signal: vec3<f32>) -> vec3<f32> {
+    let a = 0.17883277;
+    let b = 0.28466892;
+    let c = 0.55991073;
+
+    let low = signal * signal / 3.0;
+    let high = (exp((signal - c) / a) + b) / 12.0;
+    return select(high, low, signal <= vec3<f32>(0.5)) / HLG_SDR_WHITE;
+}
+
+// Brings HDR screen contents to the same scale as SDR ones, anything brighter than SDR white is
+// as bright as it
+fn decode(signal: vec3<f32>) -> vec3<f32> {
+    var linear: vec3<f32>;
+    switch params.transfer_function {
+        case TRANSFER_FUNCTION_PQ: {
+            linear = pq_to_linear(signal);
+        }
+        case TRANSFER_FUNCTION_HLG: {
+            linear = hlg_to_linear(signal);
+        }
+        default: {
+            return signal;
+        }
+    }
+    return pow(clamp(linear, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(1.0 / 2.2));
+}
+
+fn relative_luminance(color: vec3<f32>) -> f32 {
+    let low = color / 12.92;
+    let high = pow((color + 0.055) / 1.055, vec3<f32>(2.4));
+    let linear = select(high, low, color <= vec3<f32>(0.04045));
+    return dot(linear, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+// Same formulas as in `perceived_lightness_percent`
+fn lightness_percent(color: vec3<f32>) -> f32 {
+    switch params.lightness {
+        case LIGHTNESS_REC709: {
+            return relative_luminance(color) * 100.0;
+        }
+        case LIGHTNESS_CIELAB: {
+            let y = relative_luminance(color);
+            return select(116.0 * pow(y, 1.0 / 3.0) - 16.0, y * 24389.0 / 27.0, y <= 216.0 / 24389.0);
+        }
+        default: {
+            return sqrt(dot(color * color, vec3<f32>(0.241, 0.691, 0.068))) * 100.0;
+        }
+    }
+}
+
+// Gaussian around the focused part of the image
+fn weight(position: vec2<u32>, size: vec2<u32>) -> u32 {
+    if params.focus_spread_x <= 0.0 || params.focus_spread_y <= 0.0 {
+        return 1u;
+    }
+    let center = (vec2<f32>(position) + 0.5) / vec2<f32>(size);
+    let distance = (center - vec2<f32>(params.focus_x, params.focus_y))
+        / vec2<f32>(params.focus_spread_x, params.focus_spread_y);
+    return u32(round(exp(-0.5 * dot(distance, distance)) * FOCUS_WEIGHT));
+}
+
+fn add_to_sum(channel: u32, value: u32) {
+    let old = atomicAdd(&result.sums[channel * 2u], value);
+    if old + value < old {
+        atomicAdd(&result.sums[channel * 2u + 1u], 1u);
+    }
+}
+
+@compute @workgroup_size(16, 16, 1)
+fn main(
+    @builtin(global_invocation_id) position: vec3<u32>,
+    @builtin(local_invocation_index) index: u32,
+) {
+    let size = textureDimensions(frame);
+
+    if index < 101u {
+        atomicStore(&tile_histogram[index], 0u);
+    }
+    workgroupBarrier();
+
+    var color = vec3<u32>(0u);
+    var pixel_weight = 0u;
+    if position.x < size.x && position.y < size.y {
+        let signal = textureLoad(frame, vec2<i32>(position.xy)).rgb;
+        color = vec3<u32>(round(decode(signal) * 255.0));
+        pixel_weight = weight(position.xy, size);
+
+        let lightness = lightness_percent(vec3<f32>(color) / 255.0);
+        atomicAdd(&tile_histogram[min(u32(round(lightness)), 100u)], pixel_weight);
+    }
+    tile_r[index] = color.r * pixel_weight;
+    tile_g[index] = color.g * pixel_weight;
+    tile_b[index] = color.b * pixel_weight;
+    tile_weight[index] = pixel_weight;
+
+    for (var stride = 128u; stride > 0u; stride = stride >> 1u) {
+        workgroupBarrier();
+        if index < stride {
+            tile_r[index] += tile_r[index + stride];
+            tile_g[index] += tile_g[index + stride];
+            tile_b[index] += tile_b[index + stride];
+            tile_weight[index] += tile_weight[index + stride];
+        }
+    }
+    workgroupBarrier();
+
+    if index == 0u {
+        add_to_sum(0u, tile_r[0]);
+        add_to_sum(1u, tile_g[0]);
+        add_to_sum(2u, tile_b[0]);
+        add_to_sum(3u, tile_weight[0]);
+    }
+    if index < 101u {
+        let count = atomicLoad(&tile_histogram[index]);
+        if count > 0u {
+            atomicAdd(&result.histogram[index], count);
+        }
+    }
+}
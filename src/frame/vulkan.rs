@@ -1,17 +1,123 @@
-use crate::frame::compute_perceived_lightness_percent;
+use crate::config::{CaptureRegion, Lightness, TransferFunction};
+use crate::error::WlumaError;
 use crate::frame::object::Object;
+use crate::frame::{crop, lightness_percentile, perceived_lightness_percent, Area, Rect};
+use ash::ext::image_drm_format_modifier::Device as DrmFormatModifierDevice;
 use ash::khr::external_memory_fd::Device as KHRDevice;
 use ash::{vk, Device, Entry, Instance};
+use itertools::Itertools;
 use std::default::Default;
-use std::error::Error;
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
 use std::ops::Drop;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
 
 const VULKAN_VERSION: u32 = vk::make_api_version(0, 1, 2, 0);
 
-const FINAL_MIP_LEVEL: u32 = 4; // Don't generate mipmaps beyond this level - GPU is doing too poor of a job averaging the colors
-const FENCES_TIMEOUT_NS: u64 = 1_000_000_000;
+/// How long to wait for the GPU before its resources can be released.
+const WAIT_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// Frames that can be processed by the GPU at the same time. Luma of a frame is read as soon as
+/// the GPU is done with it, so that it's never late and the frame is no longer used once the
+/// capturer reports it done.
+const READBACK_RING_LEN: usize = 1;
+
+/// Compiled from `shaders/luma.wgsl`.
+const LUMA_SHADER: &[u8] = include_bytes!("shaders/luma.spv");
+const LUMA_WORKGROUP_SIZE: u32 = 16;
+/// Sums of the color channels and of the weights of pixels (as pairs of low and high 32 bits)
+/// followed by the lightness histogram.
+const LUMA_SUMS_LEN: usize = 8;
+const LUMA_RESULT_LEN: usize = LUMA_SUMS_LEN + 101;
+/// Format of the image frames are blitted into, wide enough to keep the precision of 10-bit frames.
+const IMAGE_FORMAT: vk::Format = vk::Format::R16G16B16A16_UNORM;
+
+pub const DRM_FORMAT_MOD_LINEAR: u64 = 0;
+
+/// Compositors cycle through a few buffers (usually two or three), keep all of them imported.
+const IMPORTED_FRAME_IMAGES: usize = 4;
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    u32::from_le_bytes(*code)
+}
+
+/// Maps a DRM fourcc code to the Vulkan format with the same memory layout.
+fn vk_format(drm_format: u32) -> Option<vk::Format> {
+    match drm_format {
+        f if f == fourcc(b"XR24") || f == fourcc(b"AR24") => Some(vk::Format::B8G8R8A8_UNORM),
+        f if f == fourcc(b"XB24") || f == fourcc(b"AB24") => Some(vk::Format::R8G8B8A8_UNORM),
+        f if f == fourcc(b"XR30") || f == fourcc(b"AR30") => {
+            Some(vk::Format::A2R10G10B10_UNORM_PACK32)
+        }
+        f if f == fourcc(b"XB30") || f == fourcc(b"AB30") => {
+            Some(vk::Format::A2B10G10R10_UNORM_PACK32)
+        }
+        _ => None,
+    }
+}
+
+/// Number of mip levels to scale frames down through, until there are no more pixels than the
+/// given number of samples (or a single one). The luma is computed from the last level.
+fn mip_levels(width: u32, height: u32, samples: Option<u64>) -> u32 {
+    let Some(samples) = samples else {
+        return 1;
+    };
+    let mut levels = 1;
+    let (mut width, mut height) = (width, height);
+    while width as u64 * height as u64 > samples && (width, height) != (1, 1) {
+        (width, height) = mip_extent(width, height, 1);
+        levels += 1;
+    }
+    levels
+}
+
+/// Resolution of the given mip level of an image.
+fn mip_extent(width: u32, height: u32, level: u32) -> (u32, u32) {
+    ((width >> level).max(1), (height >> level).max(1))
+}
+
+/// Values of the `focus_*` push constants of the shader: the center of the focused area (or of
+/// the whole frame) and the spread of weights around it, in fractions of the image that `src` of
+/// the frame is stretched over. All zeros when every pixel weighs the same.
+fn shader_focus(
+    spread: Option<f64>,
+    area: Option<Area>,
+    src: Rect,
+    frame_width: u32,
+    frame_height: u32,
+) -> [f32; 4] {
+    let Some(spread) = spread else {
+        return [0.0; 4];
+    };
+    let area = area.unwrap_or(Area::full());
+    let scale_x = frame_width as f64 / src.width as f64;
+    let scale_y = frame_height as f64 / src.height as f64;
+    [
+        (area.x + area.width / 2.0) * scale_x - src.x as f64 / src.width as f64,
+        (area.y + area.height / 2.0) * scale_y - src.y as f64 / src.height as f64,
+        // Windows can't be so small that the weights of all pixels round to 0
+        (spread * area.width * scale_x).max(0.01),
+        (spread * area.height * scale_y).max(0.01),
+    ]
+    .map(|value| value as f32)
+}
+
+/// Value of the `transfer_function` push constant of the shader.
+fn shader_transfer_function(transfer_function: Option<TransferFunction>) -> u32 {
+    match transfer_function {
+        None => 0,
+        Some(TransferFunction::Pq) => 1,
+        Some(TransferFunction::Hlg) => 2,
+    }
+}
+
+/// Value of the `lightness` push constant of the shader.
+fn shader_lightness(lightness: Lightness) -> u32 {
+    match lightness {
+        Lightness::Hsp => 0,
+        Lightness::Rec709 => 1,
+        Lightness::CieLab => 2,
+    }
+}
 
 pub struct Vulkan {
     _entry: Entry, // must keep reference to prevent early memory release
@@ -19,86 +125,267 @@ pub struct Vulkan {
     device: Device,
     physical_device: vk::PhysicalDevice,
     khr_device: KHRDevice,
-    buffer: Option<vk::Buffer>,
-    buffer_memory: Option<vk::DeviceMemory>,
+    /// Only present when the driver supports images with explicit DRM format modifiers.
+    drm_format_modifier_device: Option<DrmFormatModifierDevice>,
     command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
     queue: vk::Queue,
-    fence: vk::Fence,
+    /// Timeline semaphore, signaled with the value of each submission once it's done.
+    semaphore: vk::Semaphore,
+    /// Value of the last submission.
+    timeline_value: u64,
+    readbacks: Vec<Readback>,
+    next_readback: usize,
     image: Option<vk::Image>,
     image_memory: Option<vk::DeviceMemory>,
-    image_resolution: Option<(u32, u32, u32)>,
+    image_view: Option<vk::ImageView>,
+    image_resolution: Option<(u32, u32)>,
+    /// Mip levels of the image, luma is computed from the last one.
+    image_levels: u32,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
     exportable_frame_image: Option<vk::Image>,
     exportable_frame_image_memory: Option<vk::DeviceMemory>,
     exportable_frame_image_fd: Option<OwnedFd>,
+    imported_frame_images: RecentlyUsed<FrameKey, (vk::Image, vk::DeviceMemory)>,
+    capture_region: Option<CaptureRegion>,
+    luma_percentile: Option<u8>,
+    transfer_function: Option<TransferFunction>,
+    lightness: Lightness,
+    luma_samples: Option<u64>,
+    /// Spread of the weights of pixels around the focused area, every pixel weighs the same if
+    /// not set.
+    luma_focus: Option<f64>,
+    /// Part of the frame with the focused window, the whole frame if not known.
+    focus_area: Option<Area>,
 }
 
-impl Vulkan {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
-        let app_name = CString::new("wluma")?;
-        let app_version: u32 = vk::make_api_version(
-            0,
-            env!("WLUMA_VERSION_MAJOR").parse()?,
-            env!("WLUMA_VERSION_MINOR").parse()?,
-            env!("WLUMA_VERSION_PATCH").parse()?,
-        );
+/// Slot of the readback ring, which the luma of a frame is computed into.
+struct Readback {
+    command_buffer: vk::CommandBuffer,
+    descriptor_set: vk::DescriptorSet,
+    buffer: vk::Buffer,
+    buffer_memory: vk::DeviceMemory,
+    /// `buffer_memory`, mapped for as long as it exists.
+    results: *const u32,
+    /// Timeline value of the submission that computes the results, 0 if there was none yet.
+    timeline_value: u64,
+}
 
-        let app_info = vk::ApplicationInfo::default()
-            .application_name(&app_name)
-            .application_version(app_version)
-            .engine_name(&app_name)
-            .engine_version(app_version)
-            .api_version(VULKAN_VERSION);
+// The mapped memory is only ever accessed through the `Vulkan` instance that owns it
+unsafe impl Send for Readback {}
+
+/// Identifies a buffer exported by the compositor: every DMA-BUF has its own inode, no matter
+/// how many times (and with which fd) it's sent to us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FrameKey {
+    dev: u64,
+    ino: u64,
+    width: u32,
+    height: u32,
+    format: u32,
+}
 
-        let instance_extensions = &[
-            vk::KHR_EXTERNAL_MEMORY_CAPABILITIES_NAME.as_ptr(),
-            vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_NAME.as_ptr(),
-        ];
+impl FrameKey {
+    fn new(frame: &Object) -> Result<Self, WlumaError> {
+        let mut stat = std::mem::MaybeUninit::<libc::stat>::uninit();
+        if unsafe { libc::fstat(frame.fds[0], stat.as_mut_ptr()) } < 0 {
+            return Err(WlumaError::vulkan(format!(
+                "Unable to stat the frame: {}",
+                std::io::Error::last_os_error()
+            )));
+        }
+        let stat = unsafe { stat.assume_init() };
 
-        let entry = Entry::linked();
+        Ok(Self {
+            dev: stat.st_dev,
+            ino: stat.st_ino,
+            width: frame.width,
+            height: frame.height,
+            format: frame.format,
+        })
+    }
+}
 
-        let create_info = vk::InstanceCreateInfo::default()
-            .application_info(&app_info)
-            .enabled_extension_names(instance_extensions);
+/// Keeps up to `capacity` values, evicting the least recently used one when full.
+struct RecentlyUsed<K, V> {
+    capacity: usize,
+    entries: Vec<(K, V)>,
+}
 
-        let instance = unsafe {
-            entry
-                .create_instance(&create_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+impl<K: PartialEq, V: Copy> RecentlyUsed<K, V> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        let entry = self.entries.remove(index);
+        let value = entry.1;
+        self.entries.push(entry);
+        Some(value)
+    }
+
+    /// Returns the evicted value, if any.
+    fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let evicted = (self.entries.len() >= self.capacity).then(|| self.entries.remove(0).1);
+        self.entries.push((key, value));
+        evicted
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = V> + '_ {
+        self.entries.drain(..).map(|(_, v)| v)
+    }
+}
+
+/// GPU as found by Vulkan.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DeviceInfo {
+    name: String,
+    /// Major and minor numbers of its DRM primary and render nodes, if known.
+    nodes: Vec<(u32, u32)>,
+}
+
+/// Decodes major and minor numbers of a device number (`dev_t`), as encoded by glibc.
+fn dev_major_minor(dev: u64) -> (u32, u32) {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0x0000_00ff);
+    (major as u32, minor as u32)
+}
+
+/// Picks the configured GPU, otherwise the one with the DRM device that produces the frames (so
+/// that it can import them), otherwise the first one.
+fn select_device(
+    devices: &[DeviceInfo],
+    configured: Option<&str>,
+    drm_device: Option<u64>,
+) -> Result<usize, WlumaError> {
+    if let Some(configured) = configured {
+        return devices
+            .iter()
+            .position(|device| device.name.contains(configured))
+            .ok_or_else(|| {
+                WlumaError::config(format!(
+                    "Vulkan device '{}' not found, available devices: {}",
+                    configured,
+                    devices.iter().map(|device| &device.name).join(", ")
+                ))
+            });
+    }
+
+    if devices.is_empty() {
+        return Err(WlumaError::vulkan("Unable to find a physical device"));
+    }
+
+    let node = drm_device.map(dev_major_minor);
+    Ok(devices
+        .iter()
+        .position(|device| node.is_some_and(|node| device.nodes.contains(&node)))
+        .unwrap_or(0))
+}
 
-        let physical_devices = unsafe {
-            instance
-                .enumerate_physical_devices()
-                .map_err(anyhow::Error::msg)?
+impl Vulkan {
+    /// Uses the configured GPU, otherwise the one with the given DRM device number, if known.
+    /// Frames are scaled down to at most `luma_samples` pixels, if set, unless a percentile or a
+    /// transfer function is, as those need the encoded values of single pixels rather than their
+    /// averages.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        capture_region: Option<CaptureRegion>,
+        luma_percentile: Option<u8>,
+        transfer_function: Option<TransferFunction>,
+        lightness: Lightness,
+        configured_device: Option<&str>,
+        drm_device: Option<u64>,
+        luma_samples: Option<u64>,
+        luma_focus: Option<f64>,
+    ) -> Result<Self, WlumaError> {
+        let luma_samples = match luma_samples {
+            Some(_) if luma_percentile.is_some() || transfer_function.is_some() => {
+                log::warn!(
+                    "Ignoring luma_samples, frames are not scaled down when luma_percentile or transfer_function is set"
+                );
+                None
+            }
+            samples => samples,
         };
-        let physical_device = *physical_devices
-            .first()
-            .ok_or("Unable to find a physical device")?;
 
-        let queue_family_index = 0;
+        let (entry, instance) = create_instance()?;
+
+        let physical_devices = unsafe { instance.enumerate_physical_devices()? };
+        let devices = physical_devices
+            .iter()
+            .map(|&physical_device| device_info(&instance, physical_device))
+            .collect::<Result<Vec<_>, _>>()?;
+        let selected = select_device(&devices, configured_device, drm_device)?;
+        let physical_device = physical_devices[selected];
+        log::debug!("Using Vulkan device '{}'", devices[selected].name);
+
+        let queue_families =
+            unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+        let queue_family_index = find_queue_family_index(&queue_families).ok_or_else(|| {
+            WlumaError::vulkan("Unable to find a queue family that supports graphics and compute")
+        })?;
         let queue_info = &[vk::DeviceQueueCreateInfo::default()
             .queue_family_index(queue_family_index)
             .queue_priorities(&[1.0])];
 
-        let device_extensions = &[
-            vk::KHR_EXTERNAL_MEMORY_FD_NAME.as_ptr(),
-            vk::EXT_EXTERNAL_MEMORY_DMA_BUF_NAME.as_ptr(),
+        let available_extensions =
+            unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let available_extensions = extension_names(&available_extensions);
+        let required_extensions = [
+            vk::KHR_EXTERNAL_MEMORY_FD_NAME,
+            vk::EXT_EXTERNAL_MEMORY_DMA_BUF_NAME,
         ];
-        let features = vk::PhysicalDeviceFeatures::default();
+        check_extensions("device", &required_extensions, &available_extensions)?;
+        let mut device_extensions = required_extensions.map(CStr::as_ptr).to_vec();
+
+        let supports_drm_format_modifiers =
+            available_extensions.contains(&vk::EXT_IMAGE_DRM_FORMAT_MODIFIER_NAME);
+        if supports_drm_format_modifiers {
+            device_extensions.push(vk::EXT_IMAGE_DRM_FORMAT_MODIFIER_NAME.as_ptr());
+        }
+
+        let mut supported_vulkan_12_features = vk::PhysicalDeviceVulkan12Features::default();
+        let mut supported_features =
+            vk::PhysicalDeviceFeatures2::default().push_next(&mut supported_vulkan_12_features);
+        unsafe { instance.get_physical_device_features2(physical_device, &mut supported_features) };
+        let supports_extended_formats = supported_features
+            .features
+            .shader_storage_image_extended_formats
+            == vk::TRUE;
+        if supported_vulkan_12_features.timeline_semaphore == vk::FALSE {
+            return Err(WlumaError::vulkan(
+                "Your Vulkan driver does not support timeline semaphores",
+            ));
+        }
+        if !supports_extended_formats {
+            return Err(WlumaError::vulkan(
+                "Your Vulkan driver does not support 16-bit storage images",
+            ));
+        }
+
+        let features =
+            vk::PhysicalDeviceFeatures::default().shader_storage_image_extended_formats(true);
+        let mut vulkan_12_features =
+            vk::PhysicalDeviceVulkan12Features::default().timeline_semaphore(true);
 
         let device_create_info = vk::DeviceCreateInfo::default()
+            .push_next(&mut vulkan_12_features)
             .queue_create_infos(queue_info)
-            .enabled_extension_names(device_extensions)
+            .enabled_extension_names(&device_extensions)
             .enabled_features(&features);
 
-        let device = unsafe {
-            instance
-                .create_device(physical_device, &device_create_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let device = unsafe { instance.create_device(physical_device, &device_create_info, None)? };
 
         let khr_device = KHRDevice::new(&instance, &device);
+        let drm_format_modifier_device =
+            supports_drm_format_modifiers.then(|| DrmFormatModifierDevice::new(&instance, &device));
 
         let queue = unsafe { device.get_device_queue(queue_family_index, 0) };
 
@@ -106,29 +393,102 @@ impl Vulkan {
             .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
             .queue_family_index(queue_family_index);
 
-        let command_pool = unsafe {
-            device
-                .create_command_pool(&pool_create_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let command_pool = unsafe { device.create_command_pool(&pool_create_info, None)? };
 
         let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::default()
-            .command_buffer_count(1)
+            .command_buffer_count(READBACK_RING_LEN as u32)
             .command_pool(command_pool)
             .level(vk::CommandBufferLevel::PRIMARY);
 
-        let command_buffers = unsafe {
-            device
-                .allocate_command_buffers(&command_buffer_allocate_info)
-                .map_err(anyhow::Error::msg)?
-        };
-
-        let fence_create_info = vk::FenceCreateInfo::default();
-        let fence = unsafe {
-            device
-                .create_fence(&fence_create_info, None)
-                .map_err(anyhow::Error::msg)?
+        let command_buffers =
+            unsafe { device.allocate_command_buffers(&command_buffer_allocate_info)? };
+
+        let mut semaphore_type_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let semaphore_create_info =
+            vk::SemaphoreCreateInfo::default().push_next(&mut semaphore_type_info);
+        let semaphore = unsafe { device.create_semaphore(&semaphore_create_info, None)? };
+
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE),
+        ];
+        let descriptor_set_layout_info =
+            vk::DescriptorSetLayoutCreateInfo::default().bindings(&bindings);
+        let descriptor_set_layout =
+            unsafe { device.create_descriptor_set_layout(&descriptor_set_layout_info, None)? };
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_IMAGE)
+                .descriptor_count(READBACK_RING_LEN as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(READBACK_RING_LEN as u32),
+        ];
+        let descriptor_pool_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(READBACK_RING_LEN as u32);
+        let descriptor_pool =
+            unsafe { device.create_descriptor_pool(&descriptor_pool_info, None)? };
+
+        let set_layouts = [descriptor_set_layout; READBACK_RING_LEN];
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts);
+        let descriptor_sets =
+            unsafe { device.allocate_descriptor_sets(&descriptor_set_allocate_info)? };
+
+        let memory_properties =
+            unsafe { instance.get_physical_device_memory_properties(physical_device) };
+        let readbacks = command_buffers
+            .iter()
+            .zip(descriptor_sets)
+            .map(|(&command_buffer, descriptor_set)| {
+                create_readback(&device, &memory_properties, command_buffer, descriptor_set)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let push_constant_ranges = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .size(std::mem::size_of::<[u32; 6]>() as u32)];
+        let pipeline_layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts[..1])
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout =
+            unsafe { device.create_pipeline_layout(&pipeline_layout_info, None)? };
+
+        // SPIR-V is a stream of little-endian words
+        let shader_code = LUMA_SHADER
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+            .collect::<Vec<_>>();
+        let shader_module_info = vk::ShaderModuleCreateInfo::default().code(&shader_code);
+        let shader_module = unsafe { device.create_shader_module(&shader_module_info, None)? };
+
+        let pipeline_info = vk::ComputePipelineCreateInfo::default()
+            .stage(
+                vk::PipelineShaderStageCreateInfo::default()
+                    .stage(vk::ShaderStageFlags::COMPUTE)
+                    .module(shader_module)
+                    .name(c"main"),
+            )
+            .layout(pipeline_layout);
+        let pipeline = unsafe {
+            device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
         };
+        // The module is no longer needed once the pipeline is created
+        unsafe { device.destroy_shader_module(shader_module, None) };
+        let pipeline = pipeline.map_err(|(_, err)| err)?[0];
 
         Ok(Self {
             _entry: entry,
@@ -136,49 +496,103 @@ impl Vulkan {
             physical_device,
             device,
             khr_device,
+            drm_format_modifier_device,
             command_pool,
             command_buffers,
             queue,
-            fence,
+            semaphore,
+            timeline_value: 0,
+            readbacks,
+            next_readback: 0,
             image: None,
             image_memory: None,
+            image_view: None,
             image_resolution: None,
-            buffer: None,
-            buffer_memory: None,
+            image_levels: 1,
+            descriptor_set_layout,
+            descriptor_pool,
+            pipeline_layout,
+            pipeline,
             exportable_frame_image: None,
             exportable_frame_image_memory: None,
             exportable_frame_image_fd: None,
+            imported_frame_images: RecentlyUsed::new(IMPORTED_FRAME_IMAGES),
+            capture_region,
+            luma_percentile,
+            transfer_function,
+            lightness,
+            luma_samples,
+            luma_focus,
+            focus_area: None,
         })
     }
 
-    pub fn luma_percent_from_external_fd(&mut self, frame: &Object) -> Result<u8, Box<dyn Error>> {
-        let (frame_image, frame_image_memory) = self.init_frame_image(frame)?;
-
-        let result = self.luma_percent(&frame_image)?;
+    /// Weights pixels around the given part of the frame from the next frame on, or around its
+    /// center if none. Returns whether it changed.
+    pub fn set_focus_area(&mut self, area: Option<Area>) -> bool {
+        let changed = self.luma_focus.is_some() && area != self.focus_area;
+        self.focus_area = area;
+        changed
+    }
 
-        unsafe {
-            self.device.destroy_image(frame_image, None);
-            self.device.free_memory(frame_image_memory, None);
-        }
+    pub fn luma_percent_from_external_fd(&mut self, frame: &Object) -> Result<u8, WlumaError> {
+        let key = FrameKey::new(frame)?;
+        let frame_image = match self.imported_frame_images.get(&key) {
+            Some((frame_image, _)) => {
+                // Only fds that get imported are owned by Vulkan, this one is ours to close
+                drop(unsafe { OwnedFd::from_raw_fd(frame.fds[0]) });
+                self.init_image(frame)?;
+                frame_image
+            }
+            None => {
+                let (frame_image, frame_image_memory) = self.init_frame_image(frame)?;
+                let evicted = self
+                    .imported_frame_images
+                    .insert(key, (frame_image, frame_image_memory));
+                if let Some((old_image, old_image_memory)) = evicted {
+                    self.wait_for_gpu()?;
+                    unsafe {
+                        self.device.destroy_image(old_image, None);
+                        self.device.free_memory(old_image_memory, None);
+                    }
+                }
+                frame_image
+            }
+        };
 
-        Ok(result)
+        self.luma_percent(&frame_image)
     }
 
-    pub fn luma_percent_from_internal_fd(&mut self) -> Result<u8, Box<dyn Error>> {
+    pub fn luma_percent_from_internal_fd(&mut self) -> Result<u8, WlumaError> {
         let frame_image = self.exportable_frame_image.unwrap();
 
-        let result = self.luma_percent(&frame_image)?;
+        self.luma_percent(&frame_image)
+    }
+
+    /// Submits the frame and waits for the GPU to compute its luma, frames are processed off the
+    /// Wayland event loop so waiting holds nothing else back.
+    fn luma_percent(&mut self, frame_image: &vk::Image) -> Result<u8, WlumaError> {
+        let index = self.next_readback;
+        self.submit(frame_image)?;
+        self.next_readback = (index + 1) % self.readbacks.len();
+        self.wait_for_gpu()?;
 
-        Ok(result)
+        Ok(self.read_luma(&self.readbacks[index]))
     }
 
-    fn luma_percent(&self, frame_image: &vk::Image) -> Result<u8, Box<dyn Error>> {
-        let image = self.image.ok_or("Unable to borrow the Vulkan image")?;
-        let buffer_memory = self.buffer_memory.ok_or("Unable to borrow buffer memory")?;
+    fn submit(&mut self, frame_image: &vk::Image) -> Result<(), WlumaError> {
+        let image = self
+            .image
+            .ok_or_else(|| WlumaError::vulkan("Unable to borrow the Vulkan image"))?;
+        let (width, height) = self.image_resolution.unwrap();
+        let levels = self.image_levels;
+        let readback = &self.readbacks[self.next_readback];
+        let command_buffer = readback.command_buffer;
 
-        self.begin_commands()?;
+        self.begin_commands(command_buffer)?;
 
         self.add_barrier(
+            command_buffer,
             frame_image,
             0,
             1,
@@ -189,86 +603,284 @@ impl Vulkan {
             vk::PipelineStageFlags::TOP_OF_PIPE,
         );
 
-        let (target_mip_level, mip_width, mip_height) = self.generate_mipmaps(frame_image, &image);
+        self.add_barrier(
+            command_buffer,
+            &image,
+            0,
+            levels,
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            vk::AccessFlags::default(),
+            vk::AccessFlags::TRANSFER_WRITE,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+        );
 
-        self.copy_mipmap(&image, target_mip_level, mip_width, mip_height)?;
+        // Stretch the captured region over the whole image, so that the rest is unchanged
+        let src = match &self.capture_region {
+            Some(region) => crop(region, width, height),
+            None => Rect::full(width, height),
+        };
+        self.blit(
+            command_buffer,
+            frame_image,
+            src,
+            0,
+            &image,
+            width,
+            height,
+            0,
+        );
 
-        self.submit_commands()?;
+        // Every level is half the previous one, linear filtering averages 2x2 blocks of pixels
+        // exactly as long as the extents are even, the last pixels of odd ones weigh less
+        for level in 1..levels {
+            self.add_barrier(
+                command_buffer,
+                &image,
+                level - 1,
+                1,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                vk::AccessFlags::TRANSFER_WRITE,
+                vk::AccessFlags::TRANSFER_READ,
+                vk::PipelineStageFlags::TRANSFER,
+            );
+            let (src_width, src_height) = mip_extent(width, height, level - 1);
+            let (dst_width, dst_height) = mip_extent(width, height, level);
+            self.blit(
+                command_buffer,
+                &image,
+                Rect::full(src_width, src_height),
+                level - 1,
+                &image,
+                dst_width,
+                dst_height,
+                level,
+            );
+        }
 
-        let pixels = mip_width as usize * mip_height as usize;
-        let rgbas = unsafe {
-            let buffer_pointer = self
-                .device
-                .map_memory(
-                    buffer_memory,
-                    0,
-                    vk::WHOLE_SIZE,
-                    vk::MemoryMapFlags::empty(),
-                )
-                .map_err(anyhow::Error::msg)?;
-            std::slice::from_raw_parts(buffer_pointer as *mut u8, pixels * 4)
+        let focus = shader_focus(self.luma_focus, self.focus_area, src, width, height);
+        let (width, height) = mip_extent(width, height, levels - 1);
+        self.reduce(readback, &image, levels - 1, width, height, focus);
+
+        self.timeline_value += 1;
+        self.submit_commands(command_buffer, self.timeline_value)?;
+
+        self.readbacks[self.next_readback].timeline_value = self.timeline_value;
+
+        Ok(())
+    }
+
+    fn read_luma(&self, readback: &Readback) -> u8 {
+        let result = unsafe { std::slice::from_raw_parts(readback.results, LUMA_RESULT_LEN) };
+
+        let (sums, histogram) = result.split_at(LUMA_SUMS_LEN);
+        let result = match self.luma_percentile {
+            Some(percentile) => lightness_percentile(histogram, percentile),
+            None => {
+                let sum =
+                    |index: usize| sums[index * 2] as u64 | (sums[index * 2 + 1] as u64) << 32;
+                // Weighs 1 per pixel, unless pixels are weighted around the focused area
+                let weights = sum(3).max(1) as f64;
+                let mean = |channel: usize| sum(channel) as f64 / weights;
+                perceived_lightness_percent(self.lightness, mean(0), mean(1), mean(2))
+            }
         };
 
-        let result = compute_perceived_lightness_percent(rgbas, true, pixels);
+        log::trace!("Computed luma {}%", result);
+
+        result
+    }
+
+    /// Sums up the given mip level of the image on GPU, see `shaders/luma.wgsl`.
+    fn reduce(
+        &self,
+        readback: &Readback,
+        image: &vk::Image,
+        level: u32,
+        width: u32,
+        height: u32,
+        focus: [f32; 4],
+    ) {
+        let command_buffer = readback.command_buffer;
 
         unsafe {
-            self.device.unmap_memory(buffer_memory);
+            self.device
+                .cmd_fill_buffer(command_buffer, readback.buffer, 0, vk::WHOLE_SIZE, 0);
         }
 
-        Ok(result)
-    }
+        let image_barrier = vk::ImageMemoryBarrier::default()
+            .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+            .new_layout(vk::ImageLayout::GENERAL)
+            .image(*image)
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .base_mip_level(level)
+                    .level_count(1)
+                    .layer_count(1),
+            )
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ);
+        let buffer_barrier = vk::BufferMemoryBarrier::default()
+            .buffer(readback.buffer)
+            .size(vk::WHOLE_SIZE)
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE);
 
-    fn init_image(&mut self, frame: &Object) -> Result<(), Box<dyn Error>> {
-        let mip_levels = f64::max(frame.width.into(), frame.height.into())
-            .log2()
-            .floor() as u32;
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[buffer_barrier],
+                &[image_barrier],
+            );
 
-        if let Some((w, h, _)) = self.image_resolution {
+            self.device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout,
+                0,
+                &[readback.descriptor_set],
+                &[],
+            );
+            self.device.cmd_push_constants(
+                command_buffer,
+                self.pipeline_layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                &[
+                    shader_transfer_function(self.transfer_function),
+                    shader_lightness(self.lightness),
+                ]
+                .into_iter()
+                .chain(focus.map(f32::to_bits))
+                .flat_map(u32::to_ne_bytes)
+                .collect_vec(),
+            );
+            self.device.cmd_dispatch(
+                command_buffer,
+                width.div_ceil(LUMA_WORKGROUP_SIZE),
+                height.div_ceil(LUMA_WORKGROUP_SIZE),
+                1,
+            );
+        }
+
+        let buffer_barrier = vk::BufferMemoryBarrier::default()
+            .buffer(readback.buffer)
+            .size(vk::WHOLE_SIZE)
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::HOST_READ);
+
+        unsafe {
+            self.device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::HOST,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[buffer_barrier],
+                &[],
+            );
+        }
+    }
+
+    fn init_image(&mut self, frame: &Object) -> Result<(), WlumaError> {
+        if let Some((w, h)) = self.image_resolution {
             if (w, h) == (frame.width, frame.height) {
                 // Image is already initialized, resolution did not change
                 return Ok(());
             }
         }
 
+        // Frames that are still being processed use the current image
+        self.wait_for_gpu()?;
+
+        let levels = mip_levels(frame.width, frame.height, self.luma_samples);
+        let (width, height) = mip_extent(frame.width, frame.height, levels - 1);
+        log::debug!(
+            "Computing luma from {}x{} samples of {}x{} frames",
+            width,
+            height,
+            frame.width,
+            frame.height
+        );
+
         let image_create_info = vk::ImageCreateInfo::default()
             .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::R8G8B8A8_UNORM)
+            .format(IMAGE_FORMAT)
             .extent(vk::Extent3D {
                 width: frame.width,
                 height: frame.height,
                 depth: 1,
             })
-            .mip_levels(mip_levels)
+            .mip_levels(levels)
             .array_layers(1)
             .tiling(vk::ImageTiling::OPTIMAL)
             .initial_layout(vk::ImageLayout::UNDEFINED)
             .samples(vk::SampleCountFlags::TYPE_1)
-            .usage(vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::TRANSFER_SRC)
+            .usage(
+                vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST
+                    | vk::ImageUsageFlags::STORAGE,
+            )
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-        let image = unsafe {
-            self.device
-                .create_image(&image_create_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let image = unsafe { self.device.create_image(&image_create_info, None)? };
         let image_memory_req = unsafe { self.device.get_image_memory_requirements(image) };
 
+        let device_memory_properties = unsafe {
+            self.instance
+                .get_physical_device_memory_properties(self.physical_device)
+        };
+        let memory_type_index = find_memory_type_index(
+            &image_memory_req,
+            &device_memory_properties,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+        .or_else(|| {
+            find_memory_type_index(
+                &image_memory_req,
+                &device_memory_properties,
+                vk::MemoryPropertyFlags::empty(),
+            )
+        })
+        .ok_or_else(|| WlumaError::vulkan("Unable to find suitable memory type for the image"))?;
+
         let image_allocate_info = vk::MemoryAllocateInfo::default()
             .allocation_size(image_memory_req.size)
-            .memory_type_index(0);
+            .memory_type_index(memory_type_index);
 
-        let image_memory = unsafe {
-            self.device
-                .allocate_memory(&image_allocate_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let image_memory = unsafe { self.device.allocate_memory(&image_allocate_info, None)? };
 
-        unsafe {
-            self.device
-                .bind_image_memory(image, image_memory, 0)
-                .map_err(anyhow::Error::msg)?
-        };
+        unsafe { self.device.bind_image_memory(image, image_memory, 0)? };
 
+        let image_view_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(IMAGE_FORMAT)
+            .subresource_range(
+                vk::ImageSubresourceRange::default()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .base_mip_level(levels - 1)
+                    .level_count(1)
+                    .layer_count(1),
+            );
+        let image_view = unsafe { self.device.create_image_view(&image_view_info, None)? };
+
+        if let Some(old_image_view) = self.image_view.replace(image_view) {
+            unsafe {
+                self.device.destroy_image_view(old_image_view, None);
+            }
+        }
         if let Some(old_image) = self.image.replace(image) {
             unsafe {
                 self.device.destroy_image(old_image, None);
@@ -280,82 +892,118 @@ impl Vulkan {
             }
         }
 
-        let buffer_size = 4
-            * (frame.width >> (mip_levels - FINAL_MIP_LEVEL))
-            * (frame.height >> (mip_levels - FINAL_MIP_LEVEL));
-
-        let buffer_info = vk::BufferCreateInfo::default()
-            .size(buffer_size as u64)
-            .usage(vk::BufferUsageFlags::TRANSFER_DST)
-            .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-        let buffer = unsafe {
-            self.device
-                .create_buffer(&buffer_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let image_info = [vk::DescriptorImageInfo::default()
+            .image_view(image_view)
+            .image_layout(vk::ImageLayout::GENERAL)];
+        let descriptor_writes = self
+            .readbacks
+            .iter()
+            .map(|readback| {
+                vk::WriteDescriptorSet::default()
+                    .dst_set(readback.descriptor_set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+                    .image_info(&image_info)
+            })
+            .collect_vec();
+        unsafe {
+            self.device.update_descriptor_sets(&descriptor_writes, &[]);
+        }
 
-        let buffer_memory_req = unsafe { self.device.get_buffer_memory_requirements(buffer) };
+        self.image_resolution.replace((frame.width, frame.height));
+        self.image_levels = levels;
 
-        let device_memory_properties = unsafe {
-            self.instance
-                .get_physical_device_memory_properties(self.physical_device)
-        };
+        Ok(())
+    }
 
-        let memory_type_index = find_memory_type_index(
-            &buffer_memory_req,
-            &device_memory_properties,
-            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-        )
-        .ok_or("Unable to find suitable memory type for the buffer")?;
+    fn frame_format(&self, frame: &Object) -> Result<vk::Format, WlumaError> {
+        vk_format(frame.format).ok_or_else(|| {
+            WlumaError::vulkan(format!(
+                "Frames with DRM format {:#010x} are not supported yet. If you see this issue, please open a GitHub issue (unless there's one already open) and share your format value",
+                frame.format
+            ))
+        })
+    }
 
-        let allocate_info = vk::MemoryAllocateInfo {
-            allocation_size: buffer_memory_req.size,
-            memory_type_index,
-            ..Default::default()
+    /// Lists the DRM format modifiers of single-plane images in the given format that can be read from.
+    pub fn supported_modifiers(&self, drm_format: u32) -> Vec<u64> {
+        let Some(format) = vk_format(drm_format) else {
+            return vec![];
         };
 
-        let buffer_memory = unsafe {
-            self.device
-                .allocate_memory(&allocate_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let mut modifiers = vec![];
 
-        unsafe {
-            self.device
-                .bind_buffer_memory(buffer, buffer_memory, 0)
-                .map_err(anyhow::Error::msg)?
+        let properties = unsafe {
+            self.instance
+                .get_physical_device_format_properties(self.physical_device, format)
         };
+        if properties
+            .linear_tiling_features
+            .contains(vk::FormatFeatureFlags::BLIT_SRC)
+        {
+            modifiers.push(DRM_FORMAT_MOD_LINEAR);
+        }
 
-        if let Some(buffer) = self.buffer.replace(buffer) {
+        if self.drm_format_modifier_device.is_some() {
+            // The first call only tells how many modifiers there are
+            let mut list = vk::DrmFormatModifierPropertiesListEXT::default();
+            let mut properties = vk::FormatProperties2::default().push_next(&mut list);
             unsafe {
-                self.device.destroy_buffer(buffer, None);
-            }
-        }
-        if let Some(buffer_memory) = self.buffer_memory.replace(buffer_memory) {
+                self.instance.get_physical_device_format_properties2(
+                    self.physical_device,
+                    format,
+                    &mut properties,
+                )
+            };
+
+            let mut modifier_properties = vec![
+                vk::DrmFormatModifierPropertiesEXT::default();
+                list.drm_format_modifier_count as usize
+            ];
+            let mut list = vk::DrmFormatModifierPropertiesListEXT::default()
+                .drm_format_modifier_properties(&mut modifier_properties);
+            let mut properties = vk::FormatProperties2::default().push_next(&mut list);
             unsafe {
-                self.device.free_memory(buffer_memory, None);
-            }
+                self.instance.get_physical_device_format_properties2(
+                    self.physical_device,
+                    format,
+                    &mut properties,
+                )
+            };
+
+            modifiers.extend(
+                modifier_properties
+                    .iter()
+                    .filter(|p| {
+                        p.drm_format_modifier_plane_count == 1
+                            && p.drm_format_modifier_tiling_features
+                                .contains(vk::FormatFeatureFlags::BLIT_SRC)
+                    })
+                    .map(|p| p.drm_format_modifier),
+            );
         }
 
-        self.image_resolution
-            .replace((frame.width, frame.height, mip_levels));
-
-        Ok(())
+        modifiers.into_iter().unique().collect()
     }
 
     fn init_frame_image(
         &mut self,
         frame: &Object,
-    ) -> Result<(vk::Image, vk::DeviceMemory), Box<dyn Error>> {
+    ) -> Result<(vk::Image, vk::DeviceMemory), WlumaError> {
         assert_eq!(
             1, frame.num_objects,
             "Frames with multiple objects are not supported yet, use WLR_DRM_NO_MODIFIERS=1 as described in README and follow issue #8"
         );
-        assert_eq!(
-            875713112, frame.format,
-            "Frame with formats other than DRM_FORMAT_XRGB8888 are not supported yet (yours is {}). If you see this issue, please open a GitHub issue (unless there's one already open) and share your format value", frame.format
-        );
+        let format = self.frame_format(frame)?;
+        if !self
+            .supported_modifiers(frame.format)
+            .contains(&DRM_FORMAT_MOD_LINEAR)
+        {
+            return Err(WlumaError::vulkan(format!(
+                "Your GPU cannot read frames with DRM format {:#010x}",
+                frame.format
+            )));
+        }
 
         // External memory info
         let mut frame_image_memory_info = vk::ExternalMemoryImageCreateInfo::default()
@@ -365,7 +1013,7 @@ impl Vulkan {
         let frame_image_create_info = vk::ImageCreateInfo::default()
             .push_next(&mut frame_image_memory_info)
             .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::B8G8R8A8_UNORM)
+            .format(format)
             .extent(vk::Extent3D {
                 width: frame.width,
                 height: frame.height,
@@ -379,11 +1027,7 @@ impl Vulkan {
             .usage(vk::ImageUsageFlags::TRANSFER_SRC)
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-        let frame_image = unsafe {
-            self.device
-                .create_image(&frame_image_create_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        let frame_image = unsafe { self.device.create_image(&frame_image_create_info, None)? };
 
         // Memory requirements info
         let frame_image_memory_req_info =
@@ -405,12 +1049,18 @@ impl Vulkan {
         // Bit i in memory_type_bits is set if the ith memory type in the
         // VkPhysicalDeviceMemoryProperties structure is supported for the image memory.
         // We just use the first type supported (from least significant bit's side)
-
-        // Find suitable memory type index
-        let memory_type_index = frame_image_mem_req
-            .memory_requirements
-            .memory_type_bits
-            .trailing_zeros();
+        let device_memory_properties = unsafe {
+            self.instance
+                .get_physical_device_memory_properties(self.physical_device)
+        };
+        let memory_type_index = find_memory_type_index(
+            &frame_image_mem_req.memory_requirements,
+            &device_memory_properties,
+            vk::MemoryPropertyFlags::empty(),
+        )
+        .ok_or_else(|| {
+            WlumaError::vulkan("Unable to find suitable memory type for the frame image")
+        })?;
 
         // Import memory app_info
         // Construct the memory alloctation info according to the requirements
@@ -437,14 +1087,12 @@ impl Vulkan {
         // Allocate memory and bind it to the image
         let frame_image_memory = unsafe {
             self.device
-                .allocate_memory(&frame_image_allocate_info, None)
-                .map_err(anyhow::Error::msg)?
+                .allocate_memory(&frame_image_allocate_info, None)?
         };
 
         unsafe {
             self.device
-                .bind_image_memory(frame_image, frame_image_memory, 0)
-                .map_err(anyhow::Error::msg)?;
+                .bind_image_memory(frame_image, frame_image_memory, 0)?;
         };
 
         // Also ensure the internal image is initialized with the same dimensions
@@ -453,27 +1101,37 @@ impl Vulkan {
         Ok((frame_image, frame_image_memory))
     }
 
+    /// Creates an image the compositor can copy frames into, using one of the given modifiers.
     pub fn init_exportable_frame_image(
         &mut self,
         frame: &Object,
-    ) -> Result<(i32, u64, u64, u64), Box<dyn Error>> {
+        modifiers: &[u64],
+    ) -> Result<(i32, u64, u64, u64), WlumaError> {
         assert_eq!(
             1, frame.num_objects,
             "Frames with multiple objects are not supported yet, use WLR_DRM_NO_MODIFIERS=1 as described in README and follow issue #8"
         );
 
-        assert_eq!(
-            875713112, frame.format,
-            "Frame with formats other than DRM_FORMAT_XRGB8888 are not supported yet (yours is {}). If you see this issue, please open a GitHub issue (unless there's one already open) and share your format value", frame.format
-        );
+        let format = self.frame_format(frame)?;
+
+        // Linear images don't need the driver to know about modifiers
+        let explicit_modifiers =
+            self.drm_format_modifier_device.is_some() && modifiers != [DRM_FORMAT_MOD_LINEAR];
+        if !explicit_modifiers && !modifiers.contains(&DRM_FORMAT_MOD_LINEAR) {
+            return Err(WlumaError::vulkan(
+                "Your GPU cannot create frames with any of the requested modifiers",
+            ));
+        }
 
         let mut frame_image_memory_info = vk::ExternalMemoryImageCreateInfo::default()
             .handle_types(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
+        let mut frame_image_modifier_info =
+            vk::ImageDrmFormatModifierListCreateInfoEXT::default().drm_format_modifiers(modifiers);
 
-        let frame_image_create_info = vk::ImageCreateInfo::default()
+        let mut frame_image_create_info = vk::ImageCreateInfo::default()
             .push_next(&mut frame_image_memory_info)
             .image_type(vk::ImageType::TYPE_2D)
-            .format(vk::Format::B8G8R8A8_UNORM)
+            .format(format)
             .extent(vk::Extent3D {
                 width: frame.width,
                 height: frame.height,
@@ -487,11 +1145,13 @@ impl Vulkan {
             .usage(vk::ImageUsageFlags::TRANSFER_SRC | vk::ImageUsageFlags::TRANSFER_DST)
             .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-        let frame_image = unsafe {
-            self.device
-                .create_image(&frame_image_create_info, None)
-                .map_err(anyhow::Error::msg)?
-        };
+        if explicit_modifiers {
+            frame_image_create_info = frame_image_create_info
+                .tiling(vk::ImageTiling::DRM_FORMAT_MODIFIER_EXT)
+                .push_next(&mut frame_image_modifier_info);
+        }
+
+        let frame_image = unsafe { self.device.create_image(&frame_image_create_info, None)? };
 
         // Memory requirements info
         let frame_image_memory_req_info =
@@ -513,12 +1173,18 @@ impl Vulkan {
         // Bit i in memory_type_bits is set if the ith memory type in the
         // VkPhysicalDeviceMemoryProperties structure is supported for the image memory.
         // We just use the first type supported (from least significant bit's side)
-
-        // Find suitable memory type index
-        let memory_type_index = frame_image_mem_req
-            .memory_requirements
-            .memory_type_bits
-            .trailing_zeros();
+        let device_memory_properties = unsafe {
+            self.instance
+                .get_physical_device_memory_properties(self.physical_device)
+        };
+        let memory_type_index = find_memory_type_index(
+            &frame_image_mem_req.memory_requirements,
+            &device_memory_properties,
+            vk::MemoryPropertyFlags::empty(),
+        )
+        .ok_or_else(|| {
+            WlumaError::vulkan("Unable to find suitable memory type for the frame image")
+        })?;
 
         // Specify that the memory can be exported
         let mut frame_import_memory_info = vk::ExportMemoryAllocateInfo::default()
@@ -542,15 +1208,13 @@ impl Vulkan {
         // Allocate memory and bind it to the image
         let frame_image_memory = unsafe {
             self.device
-                .allocate_memory(&frame_image_allocate_info, None)
-                .map_err(anyhow::Error::msg)?
+                .allocate_memory(&frame_image_allocate_info, None)?
         };
 
         // Bind memory to the image
         unsafe {
             self.device
-                .bind_image_memory(frame_image, frame_image_memory, 0)
-                .map_err(anyhow::Error::msg)?;
+                .bind_image_memory(frame_image, frame_image_memory, 0)?;
         }
 
         // Get the file descriptor
@@ -558,16 +1222,25 @@ impl Vulkan {
             .memory(frame_image_memory)
             .handle_type(vk::ExternalMemoryHandleTypeFlags::DMA_BUF_EXT);
 
-        let fd = unsafe {
-            OwnedFd::from_raw_fd(
-                self.khr_device
-                    .get_memory_fd(&memory_fd_info)
-                    .map_err(anyhow::Error::msg)?,
-            )
+        let fd = unsafe { OwnedFd::from_raw_fd(self.khr_device.get_memory_fd(&memory_fd_info)?) };
+
+        let (aspect_mask, modifier) = match &self.drm_format_modifier_device {
+            Some(device) if explicit_modifiers => {
+                // The driver picks one of the modifiers from the list
+                let mut properties = vk::ImageDrmFormatModifierPropertiesEXT::default();
+                unsafe {
+                    device.get_image_drm_format_modifier_properties(frame_image, &mut properties)?
+                };
+                (
+                    vk::ImageAspectFlags::MEMORY_PLANE_0_EXT,
+                    properties.drm_format_modifier,
+                )
+            }
+            _ => (vk::ImageAspectFlags::COLOR, DRM_FORMAT_MOD_LINEAR),
         };
 
         let subresource = vk::ImageSubresource::default()
-            .aspect_mask(vk::ImageAspectFlags::COLOR)
+            .aspect_mask(aspect_mask)
             .mip_level(0)
             .array_layer(0);
 
@@ -578,10 +1251,10 @@ impl Vulkan {
 
         let offset = layout.offset;
         let stride = layout.row_pitch;
-        let modifier: u64 = 0; // DRM_FORMAT_MOD_LINEAR
 
         let raw_fd = fd.as_raw_fd();
 
+        self.wait_for_gpu()?;
         if let Some(old_image) = self.exportable_frame_image.replace(frame_image) {
             unsafe {
                 self.device.destroy_image(old_image, None);
@@ -608,6 +1281,7 @@ impl Vulkan {
     #[allow(clippy::too_many_arguments)]
     fn add_barrier(
         &self,
+        command_buffer: vk::CommandBuffer,
         image: &vk::Image,
         base_mip_level: u32,
         mip_levels: u32,
@@ -633,7 +1307,7 @@ impl Vulkan {
 
         unsafe {
             self.device.cmd_pipeline_barrier(
-                self.command_buffers[0],
+                command_buffer,
                 src_stage_mask,
                 vk::PipelineStageFlags::TRANSFER,
                 vk::DependencyFlags::empty(),
@@ -647,9 +1321,9 @@ impl Vulkan {
     #[allow(clippy::too_many_arguments)]
     fn blit(
         &self,
+        command_buffer: vk::CommandBuffer,
         src_image: &vk::Image,
-        src_width: u32,
-        src_height: u32,
+        src: Rect,
         src_mip_level: u32,
         dst_image: &vk::Image,
         dst_width: u32,
@@ -658,10 +1332,14 @@ impl Vulkan {
     ) {
         let blit_info = vk::ImageBlit::default()
             .src_offsets([
-                vk::Offset3D { x: 0, y: 0, z: 0 },
                 vk::Offset3D {
-                    x: src_width as i32,
-                    y: src_height as i32,
+                    x: src.x as i32,
+                    y: src.y as i32,
+                    z: 0,
+                },
+                vk::Offset3D {
+                    x: (src.x + src.width) as i32,
+                    y: (src.y + src.height) as i32,
                     z: 1,
                 },
             ])
@@ -688,7 +1366,7 @@ impl Vulkan {
 
         unsafe {
             self.device.cmd_blit_image(
-                self.command_buffers[0],
+                command_buffer,
                 *src_image,
                 vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
                 *dst_image,
@@ -699,154 +1377,221 @@ impl Vulkan {
         }
     }
 
-    fn generate_mipmaps(&self, frame_image: &vk::Image, image: &vk::Image) -> (u32, u32, u32) {
-        let (mut mip_width, mut mip_height, mip_levels) = self.image_resolution.unwrap();
-
-        self.add_barrier(
-            image,
-            0,
-            mip_levels,
-            vk::ImageLayout::UNDEFINED,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            vk::AccessFlags::default(),
-            vk::AccessFlags::TRANSFER_WRITE,
-            vk::PipelineStageFlags::TOP_OF_PIPE,
-        );
-
-        self.blit(
-            frame_image,
-            mip_width,
-            mip_height,
-            0,
-            image,
-            mip_width,
-            mip_height,
-            0,
-        );
-
-        let target_mip_level = mip_levels - FINAL_MIP_LEVEL;
-        for i in 1..=target_mip_level {
-            self.add_barrier(
-                image,
-                i - 1,
-                1,
-                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                vk::AccessFlags::TRANSFER_WRITE,
-                vk::AccessFlags::TRANSFER_READ,
-                vk::PipelineStageFlags::TRANSFER,
-            );
-
-            let next_mip_width = if mip_width > 1 { mip_width / 2 } else { 1 };
-            let next_mip_height = if mip_height > 1 { mip_height / 2 } else { 1 };
-
-            self.blit(
-                image,
-                mip_width,
-                mip_height,
-                i - 1,
-                image,
-                next_mip_width,
-                next_mip_height,
-                i,
-            );
+    fn begin_commands(&self, command_buffer: vk::CommandBuffer) -> Result<(), WlumaError> {
+        let command_buffer_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-            mip_width = next_mip_width;
-            mip_height = next_mip_height;
+        unsafe {
+            self.device
+                .begin_command_buffer(command_buffer, &command_buffer_info)?;
         }
 
-        (target_mip_level, mip_width, mip_height)
+        Ok(())
     }
 
-    fn copy_mipmap(
+    /// The commands only start once the previous submission is done, as they share the image.
+    fn submit_commands(
         &self,
-        image: &vk::Image,
-        mip_level: u32,
-        width: u32,
-        height: u32,
-    ) -> Result<(), Box<dyn Error>> {
-        self.add_barrier(
-            image,
-            mip_level,
-            1,
-            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-            vk::AccessFlags::TRANSFER_WRITE,
-            vk::AccessFlags::TRANSFER_READ,
-            vk::PipelineStageFlags::TRANSFER,
-        );
-
-        let buffer_image_copy = vk::BufferImageCopy::default()
-            .image_subresource(
-                vk::ImageSubresourceLayers::default()
-                    .aspect_mask(vk::ImageAspectFlags::COLOR)
-                    .mip_level(mip_level)
-                    .layer_count(1),
-            )
-            .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
-            .image_extent(vk::Extent3D {
-                width,
-                height,
-                depth: 1,
-            });
+        command_buffer: vk::CommandBuffer,
+        timeline_value: u64,
+    ) -> Result<(), WlumaError> {
+        unsafe {
+            // End the command buffer
+            self.device.end_command_buffer(command_buffer)?;
+        };
 
-        let buffer = self.buffer.ok_or("Unable to borrow buffer")?;
+        let command_buffers = [command_buffer];
+        let semaphores = [self.semaphore];
+        let wait_values = [timeline_value - 1];
+        let signal_values = [timeline_value];
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::default()
+            .wait_semaphore_values(&wait_values)
+            .signal_semaphore_values(&signal_values);
+        let submit_info = vk::SubmitInfo::default()
+            .push_next(&mut timeline_info)
+            .command_buffers(&command_buffers)
+            .wait_semaphores(&semaphores)
+            .wait_dst_stage_mask(&[vk::PipelineStageFlags::ALL_COMMANDS])
+            .signal_semaphores(&semaphores);
 
         unsafe {
-            self.device.cmd_copy_image_to_buffer(
-                self.command_buffers[0],
-                *image,
-                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                buffer,
-                &[buffer_image_copy],
-            );
+            self.device
+                .queue_submit(self.queue, &[submit_info], vk::Fence::null())?;
         }
 
         Ok(())
     }
 
-    fn begin_commands(&self) -> Result<(), Box<dyn Error>> {
-        let command_buffer_info = vk::CommandBufferBeginInfo::default()
-            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    /// Blocks until the GPU is done with all the frames, before resources they use are released.
+    fn wait_for_gpu(&self) -> Result<(), WlumaError> {
+        let semaphores = [self.semaphore];
+        let values = [self.timeline_value];
+        let wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(&semaphores)
+            .values(&values);
 
-        unsafe {
-            self.device
-                .begin_command_buffer(self.command_buffers[0], &command_buffer_info)
-                .map_err(anyhow::Error::msg)?;
-        }
+        unsafe { self.device.wait_semaphores(&wait_info, WAIT_TIMEOUT_NS)? };
 
         Ok(())
     }
+}
 
-    fn submit_commands(&self) -> Result<(), Box<dyn Error>> {
-        unsafe {
-            // End the command buffer
-            self.device
-                .end_command_buffer(self.command_buffers[0])
-                .map_err(anyhow::Error::msg)?;
-        };
+/// Creates a readback buffer for the shader to write the results to, see `LUMA_RESULT_LEN`.
+fn create_readback(
+    device: &Device,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+    command_buffer: vk::CommandBuffer,
+    descriptor_set: vk::DescriptorSet,
+) -> Result<Readback, WlumaError> {
+    let buffer_size = LUMA_RESULT_LEN * std::mem::size_of::<u32>();
 
-        let submit_info = vk::SubmitInfo::default().command_buffers(&self.command_buffers);
+    let buffer_info = vk::BufferCreateInfo::default()
+        .size(buffer_size as u64)
+        .usage(vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::STORAGE_BUFFER)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-        unsafe {
-            // Submit the command buffers to the queue
-            self.device
-                .queue_submit(self.queue, &[submit_info], self.fence)
-                .map_err(anyhow::Error::msg)?;
+    let buffer = unsafe { device.create_buffer(&buffer_info, None)? };
 
-            // Wait for the fences
-            self.device
-                .wait_for_fences(&[self.fence], true, FENCES_TIMEOUT_NS)
-                .map_err(anyhow::Error::msg)?;
+    let buffer_memory_req = unsafe { device.get_buffer_memory_requirements(buffer) };
 
-            // Reset fences
-            self.device
-                .reset_fences(&[self.fence])
-                .map_err(anyhow::Error::msg)?;
-        }
+    let memory_type_index = find_memory_type_index(
+        &buffer_memory_req,
+        memory_properties,
+        vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+    )
+    .ok_or_else(|| WlumaError::vulkan("Unable to find suitable memory type for the buffer"))?;
 
-        Ok(())
+    let allocate_info = vk::MemoryAllocateInfo {
+        allocation_size: buffer_memory_req.size,
+        memory_type_index,
+        ..Default::default()
+    };
+
+    let buffer_memory = unsafe { device.allocate_memory(&allocate_info, None)? };
+
+    unsafe { device.bind_buffer_memory(buffer, buffer_memory, 0)? };
+
+    let results = unsafe {
+        device.map_memory(
+            buffer_memory,
+            0,
+            vk::WHOLE_SIZE,
+            vk::MemoryMapFlags::empty(),
+        )?
+    } as *const u32;
+
+    let buffer_info = [vk::DescriptorBufferInfo::default()
+        .buffer(buffer)
+        .range(vk::WHOLE_SIZE)];
+    let descriptor_writes = [vk::WriteDescriptorSet::default()
+        .dst_set(descriptor_set)
+        .dst_binding(1)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(&buffer_info)];
+    unsafe {
+        device.update_descriptor_sets(&descriptor_writes, &[]);
     }
+
+    Ok(Readback {
+        command_buffer,
+        descriptor_set,
+        buffer,
+        buffer_memory,
+        results,
+        timeline_value: 0,
+    })
+}
+
+/// Names of the GPUs that `vulkan_device` can select.
+pub fn device_names() -> Result<Vec<String>, WlumaError> {
+    let (_entry, instance) = create_instance()?;
+    let names = unsafe { instance.enumerate_physical_devices() }
+        .map_err(WlumaError::from)
+        .and_then(|physical_devices| {
+            physical_devices
+                .into_iter()
+                .map(|physical_device| Ok(device_info(&instance, physical_device)?.name))
+                .collect()
+        });
+    unsafe { instance.destroy_instance(None) };
+    names
+}
+
+fn create_instance() -> Result<(Entry, Instance), WlumaError> {
+    let app_name = CString::new("wluma").map_err(WlumaError::vulkan)?;
+    let app_version: u32 = vk::make_api_version(
+        0,
+        env!("WLUMA_VERSION_MAJOR")
+            .parse()
+            .map_err(WlumaError::vulkan)?,
+        env!("WLUMA_VERSION_MINOR")
+            .parse()
+            .map_err(WlumaError::vulkan)?,
+        env!("WLUMA_VERSION_PATCH")
+            .parse()
+            .map_err(WlumaError::vulkan)?,
+    );
+
+    let app_info = vk::ApplicationInfo::default()
+        .application_name(&app_name)
+        .application_version(app_version)
+        .engine_name(&app_name)
+        .engine_version(app_version)
+        .api_version(VULKAN_VERSION);
+
+    let entry = Entry::linked();
+
+    let required_extensions = [
+        vk::KHR_EXTERNAL_MEMORY_CAPABILITIES_NAME,
+        vk::KHR_GET_PHYSICAL_DEVICE_PROPERTIES2_NAME,
+    ];
+    let available_extensions = unsafe { entry.enumerate_instance_extension_properties(None)? };
+    check_extensions(
+        "instance",
+        &required_extensions,
+        &extension_names(&available_extensions),
+    )?;
+    let instance_extensions = &required_extensions.map(CStr::as_ptr);
+
+    let create_info = vk::InstanceCreateInfo::default()
+        .application_info(&app_info)
+        .enabled_extension_names(instance_extensions);
+
+    let instance = unsafe { entry.create_instance(&create_info, None)? };
+
+    Ok((entry, instance))
+}
+
+fn device_info(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+) -> Result<DeviceInfo, WlumaError> {
+    let supports_drm = unsafe { instance.enumerate_device_extension_properties(physical_device)? }
+        .iter()
+        .any(|ext| ext.extension_name_as_c_str() == Ok(vk::EXT_PHYSICAL_DEVICE_DRM_NAME));
+
+    let mut drm = vk::PhysicalDeviceDrmPropertiesEXT::default();
+    let mut properties = vk::PhysicalDeviceProperties2::default();
+    if supports_drm {
+        properties = properties.push_next(&mut drm);
+    }
+    unsafe { instance.get_physical_device_properties2(physical_device, &mut properties) };
+    let name = properties
+        .properties
+        .device_name_as_c_str()
+        .map_err(WlumaError::vulkan)?
+        .to_string_lossy()
+        .into_owned();
+
+    let mut nodes = vec![];
+    if drm.has_primary == vk::TRUE {
+        nodes.push((drm.primary_major as u32, drm.primary_minor as u32));
+    }
+    if drm.has_render == vk::TRUE {
+        nodes.push((drm.render_major as u32, drm.render_minor as u32));
+    }
+
+    Ok(DeviceInfo { name, nodes })
 }
 
 impl Drop for Vulkan {
@@ -856,6 +1601,14 @@ impl Drop for Vulkan {
                 .device_wait_idle()
                 .expect("Unable to wait for device to become idle");
 
+            for (frame_image, frame_image_memory) in self.imported_frame_images.drain() {
+                self.device.destroy_image(frame_image, None);
+                self.device.free_memory(frame_image_memory, None);
+            }
+
+            if let Some(image_view) = self.image_view {
+                self.device.destroy_image_view(image_view, None);
+            }
             if let Some(image) = self.image {
                 self.device.destroy_image(image, None);
             }
@@ -863,13 +1616,18 @@ impl Drop for Vulkan {
                 self.device.free_memory(image_memory, None);
             }
 
-            self.device.destroy_fence(self.fence, None);
-            if let Some(buffer) = self.buffer {
-                self.device.destroy_buffer(buffer, None);
-            }
-            if let Some(buffer_memory) = self.buffer_memory {
-                self.device.free_memory(buffer_memory, None);
+            self.device.destroy_semaphore(self.semaphore, None);
+            for readback in &self.readbacks {
+                self.device.destroy_buffer(readback.buffer, None);
+                self.device.free_memory(readback.buffer_memory, None);
             }
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device
+                .destroy_pipeline_layout(self.pipeline_layout, None);
+            self.device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+            self.device
+                .destroy_descriptor_set_layout(self.descriptor_set_layout, None);
             self.device
                 .free_command_buffers(self.command_pool, &self.command_buffers);
             self.device.destroy_command_pool(self.command_pool, None);
@@ -879,6 +1637,43 @@ impl Drop for Vulkan {
     }
 }
 
+/// The luma is computed with blits (which need a graphics queue) and a compute shader.
+fn find_queue_family_index(families: &[vk::QueueFamilyProperties]) -> Option<u32> {
+    families
+        .iter()
+        .position(|family| {
+            family
+                .queue_flags
+                .contains(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        })
+        .map(|index| index as u32)
+}
+
+fn extension_names(extensions: &[vk::ExtensionProperties]) -> Vec<&CStr> {
+    extensions
+        .iter()
+        .filter_map(|ext| ext.extension_name_as_c_str().ok())
+        .collect()
+}
+
+/// Fails with the list of required extensions that are not available, if any.
+fn check_extensions(kind: &str, required: &[&CStr], available: &[&CStr]) -> Result<(), WlumaError> {
+    let missing = required
+        .iter()
+        .filter(|ext| !available.contains(ext))
+        .map(|ext| ext.to_string_lossy())
+        .collect_vec();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(WlumaError::vulkan(format!(
+            "Your Vulkan driver lacks required {} extensions: {}",
+            kind,
+            missing.join(", ")
+        )))
+    }
+}
+
 fn find_memory_type_index(
     memory_req: &vk::MemoryRequirements,
     memory_prop: &vk::PhysicalDeviceMemoryProperties,
@@ -893,3 +1688,148 @@ fn find_memory_type_index(
         })
         .map(|(index, _)| index as _)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vk_format() {
+        assert_eq!(875713112, fourcc(b"XR24"));
+        // vk::Format only implements Debug with ash's debug feature
+        assert!(vk_format(875713112) == Some(vk::Format::B8G8R8A8_UNORM));
+        assert!(vk_format(fourcc(b"XR30")) == Some(vk::Format::A2R10G10B10_UNORM_PACK32));
+        assert!(vk_format(fourcc(b"AB30")) == Some(vk::Format::A2B10G10R10_UNORM_PACK32));
+        assert!(vk_format(fourcc(b"NV12")).is_none());
+    }
+
+    #[test]
+    fn test_mip_levels() {
+        // Every pixel by default
+        assert_eq!(1, mip_levels(3840, 2160, None));
+        assert_eq!(1, mip_levels(1280, 720, Some(1280 * 720)));
+        // 4K needs more levels than 720p for the same number of samples
+        assert_eq!(5, mip_levels(3840, 2160, Some(65536)));
+        assert_eq!((240, 135), mip_extent(3840, 2160, 4));
+        assert_eq!(3, mip_levels(1280, 720, Some(65536)));
+        assert_eq!((320, 180), mip_extent(1280, 720, 2));
+        // Down to a single pixel at most
+        assert_eq!(12, mip_levels(3840, 2160, Some(1)));
+        assert_eq!((1, 1), mip_extent(3840, 2160, 11));
+        assert_eq!((1, 1), mip_extent(1, 1, 3));
+    }
+
+    #[test]
+    fn test_shader_focus() {
+        let full = Rect::full(1920, 1080);
+        // Every pixel weighs the same
+        assert_eq!([0.0; 4], shader_focus(None, None, full, 1920, 1080));
+        // Around the center...
+        assert_eq!(
+            [0.5, 0.5, 0.25, 0.25],
+            shader_focus(Some(0.25), None, full, 1920, 1080)
+        );
+        // ... or the focused window
+        let window = Area {
+            x: 0.5,
+            y: 0.0,
+            width: 0.25,
+            height: 1.0,
+        };
+        assert_eq!(
+            [0.625, 0.5, 0.125, 0.5],
+            shader_focus(Some(0.5), Some(window), full, 1920, 1080)
+        );
+        // The captured region is stretched over the whole image
+        let right_half = Rect {
+            x: 960,
+            y: 0,
+            width: 960,
+            height: 1080,
+        };
+        assert_eq!(
+            [0.25, 0.5, 0.25, 0.5],
+            shader_focus(Some(0.5), Some(window), right_half, 1920, 1080)
+        );
+    }
+
+    #[test]
+    fn test_recently_used_evicts_least_recently_used() {
+        let mut cache = RecentlyUsed::new(2);
+
+        assert_eq!(None, cache.insert(1, 'a'));
+        assert_eq!(None, cache.insert(2, 'b'));
+        assert_eq!(Some('a'), cache.get(&1));
+        assert_eq!(Some('b'), cache.insert(3, 'c'));
+        assert_eq!(None, cache.get(&2));
+        assert_eq!(Some('a'), cache.get(&1));
+        assert_eq!(Some('c'), cache.get(&3));
+        assert_eq!(vec!['a', 'c'], cache.drain().collect_vec());
+    }
+
+    #[test]
+    fn test_find_queue_family_index() {
+        let family = |queue_flags| vk::QueueFamilyProperties {
+            queue_flags,
+            ..Default::default()
+        };
+        let families = [
+            family(vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::COMPUTE | vk::QueueFlags::TRANSFER),
+            family(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE),
+        ];
+
+        assert_eq!(Some(2), find_queue_family_index(&families));
+        assert_eq!(None, find_queue_family_index(&families[..2]));
+    }
+
+    #[test]
+    fn test_check_extensions_lists_missing_ones() {
+        let required = [
+            vk::KHR_EXTERNAL_MEMORY_FD_NAME,
+            vk::EXT_EXTERNAL_MEMORY_DMA_BUF_NAME,
+        ];
+
+        assert!(check_extensions("device", &required, &required).is_ok());
+        let err = check_extensions("device", &required, &[vk::KHR_EXTERNAL_MEMORY_FD_NAME])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("VK_EXT_external_memory_dma_buf"), "{}", err);
+        assert!(!err.contains("VK_KHR_external_memory_fd"), "{}", err);
+    }
+
+    #[test]
+    fn test_dev_major_minor() {
+        // renderD128 and card1
+        assert_eq!((226, 128), dev_major_minor(0xe280));
+        assert_eq!((226, 1), dev_major_minor(0xe201));
+    }
+
+    #[test]
+    fn test_select_device() {
+        let devices = [
+            DeviceInfo {
+                name: "NVIDIA GeForce RTX 3050".to_string(),
+                nodes: vec![(226, 0), (226, 128)],
+            },
+            DeviceInfo {
+                name: "Intel(R) UHD Graphics".to_string(),
+                nodes: vec![(226, 1), (226, 129)],
+            },
+        ];
+
+        // The one that produces frames is preferred...
+        assert_eq!(1, select_device(&devices, None, Some(0xe281)).unwrap());
+        assert_eq!(0, select_device(&devices, None, Some(0xe280)).unwrap());
+        // ... unless configured otherwise...
+        assert_eq!(
+            0,
+            select_device(&devices, Some("NVIDIA"), Some(0xe281)).unwrap()
+        );
+        assert!(select_device(&devices, Some("AMD"), None).is_err());
+        // ... otherwise it's the first one
+        assert_eq!(0, select_device(&devices, None, None).unwrap());
+        assert_eq!(0, select_device(&devices, None, Some(0xe282)).unwrap());
+        assert!(select_device(&[], None, None).is_err());
+    }
+}
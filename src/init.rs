@@ -0,0 +1,282 @@
+use crate::brightness::Backlight;
+use crate::config;
+use crate::diagnose::{self, Globals, BACKLIGHT_PATH, IIO_PATH, LEDS_PATH, V4L_PATH};
+use crate::error::WlumaError;
+use itertools::Itertools;
+use std::fmt::Write;
+use std::fs;
+
+/// Connectors of built-in screens, which are controlled with a backlight rather than DDC.
+const INTERNAL_CONNECTORS: &[&str] = &["eDP-", "LVDS-", "DSI-"];
+const DEFAULT_INTERNAL_OUTPUT: &str = "eDP-1";
+
+/// What was found in the system to write the config for.
+#[derive(Debug, Default)]
+struct Probe {
+    /// Whether the compositor supports any of the screen capture protocols.
+    capture: bool,
+    iio: bool,
+    /// Indexes of video devices.
+    webcams: Vec<usize>,
+    /// Output names and paths of backlight devices.
+    backlights: Vec<(String, String)>,
+    /// Output names of external screens.
+    ddcutil: Vec<String>,
+    /// Names and paths of keyboard backlights.
+    keyboards: Vec<(String, String)>,
+}
+
+/// Probes the system and writes a config for it, unless there is one already.
+pub fn run() -> Result<(), WlumaError> {
+    let path = xdg::BaseDirectories::with_prefix("wluma")
+        .map_err(WlumaError::config)?
+        .place_config_file("config.toml")
+        .map_err(WlumaError::config)?;
+    if path.exists() {
+        return Err(WlumaError::config(format!(
+            "{} already exists, move it away to write a new one",
+            path.display()
+        )));
+    }
+
+    let probe = probe();
+    fs::write(&path, render(&probe)).map_err(WlumaError::config)?;
+
+    println!(
+        "Wrote {}, have a look at it before starting wluma.",
+        path.display()
+    );
+    if probe.backlights.is_empty() && probe.ddcutil.is_empty() && probe.keyboards.is_empty() {
+        println!("No screens were found, add them to the config, see `wluma diagnose` for hints.");
+    }
+    Ok(())
+}
+
+fn probe() -> Probe {
+    let globals = Globals::probe().ok();
+    let outputs = globals
+        .iter()
+        .flat_map(|globals| &globals.outputs)
+        .filter_map(|output| output.name.clone())
+        .collect_vec();
+    let internal = |name: &&String| {
+        INTERNAL_CONNECTORS
+            .iter()
+            .any(|prefix| name.starts_with(prefix))
+    };
+
+    // Laptops might have a firmware device next to the one of the GPU driver, which usually
+    // works better
+    let backlights = diagnose::list_dir(BACKLIGHT_PATH)
+        .into_iter()
+        .sorted_by_key(|path| path.to_string_lossy().contains("acpi_video"))
+        .map(|path| {
+            let name = Backlight::connector(&path)
+                .or_else(|| outputs.iter().find(internal).cloned())
+                .unwrap_or_else(|| DEFAULT_INTERNAL_OUTPUT.to_string());
+            (name, path.display().to_string())
+        })
+        .unique_by(|(name, _)| name.clone())
+        .collect();
+
+    let displays = match diagnose::has_i2c_devices() {
+        true => ddc_hi::Display::enumerate(),
+        false => Vec::new(),
+    };
+    let external = outputs
+        .iter()
+        .filter(|name| !internal(name))
+        .cloned()
+        .collect_vec();
+    let ddcutil = match (displays.is_empty(), external.is_empty()) {
+        (true, _) => Vec::new(),
+        // Screens are found by the EDID of their connector
+        (false, false) => external,
+        (false, true) => displays
+            .iter()
+            .filter_map(|display| display.info.model_name.clone())
+            .collect(),
+    };
+
+    let keyboards = diagnose::list_dir(LEDS_PATH)
+        .into_iter()
+        .filter(|path| diagnose::is_kbd_backlight(path))
+        .map(|path| {
+            let led = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let vendor = led.split("::").next().unwrap_or_default();
+            (format!("keyboard-{}", vendor), path.display().to_string())
+        })
+        .unique_by(|(name, _)| name.clone())
+        .collect();
+
+    Probe {
+        capture: globals.is_some_and(|globals| globals.can_capture()),
+        iio: !diagnose::light_sensors().is_empty(),
+        webcams: diagnose::list_dir(V4L_PATH)
+            .iter()
+            .filter_map(|path| {
+                path.file_name()?
+                    .to_str()?
+                    .strip_prefix("video")?
+                    .parse()
+                    .ok()
+            })
+            .sorted()
+            .collect(),
+        backlights,
+        ddcutil,
+        keyboards,
+    }
+}
+
+/// Config for what was found, with the alternatives that were not chosen commented out.
+fn render(probe: &Probe) -> String {
+    let mut config = String::new();
+    let quote = |value: &str| toml::Value::from(value).to_string();
+    let comment = |enabled: bool| if enabled { "" } else { "# " };
+
+    writeln!(
+        config,
+        "# Written by `wluma init-config`, see the README for all the options.\n"
+    )
+    .unwrap();
+
+    let webcam = !probe.iio && !probe.webcams.is_empty();
+    let time = !probe.iio && !webcam;
+    let iio = comment(probe.iio);
+    writeln!(config, "{}[als.iio]", iio).unwrap();
+    writeln!(config, "{}path = {}", iio, quote(IIO_PATH)).unwrap();
+    writeln!(
+        config,
+        "{}thresholds = {}\n",
+        iio,
+        config::DEFAULT_IIO_THRESHOLDS
+    )
+    .unwrap();
+    let webcam = comment(webcam);
+    writeln!(config, "{}[als.webcam]", webcam).unwrap();
+    writeln!(
+        config,
+        "{}video = {}",
+        webcam,
+        probe.webcams.first().unwrap_or(&0)
+    )
+    .unwrap();
+    writeln!(
+        config,
+        "{}thresholds = {}\n",
+        webcam,
+        config::DEFAULT_WEBCAM_THRESHOLDS
+    )
+    .unwrap();
+    let time = comment(time);
+    writeln!(config, "{}[als.time]", time).unwrap();
+    writeln!(
+        config,
+        "{}thresholds = {}",
+        time,
+        config::DEFAULT_TIME_THRESHOLDS
+    )
+    .unwrap();
+
+    let capturer = match probe.capture {
+        true => "wayland",
+        false => "none",
+    };
+    if !probe.capture {
+        writeln!(
+            config,
+            "\n# None of the screen capture protocols are available, so only the ALS is used to\n# predict brightness"
+        )
+        .unwrap();
+    }
+    for (name, path) in &probe.backlights {
+        writeln!(config, "\n[[output.backlight]]").unwrap();
+        writeln!(config, "name = {}", quote(name)).unwrap();
+        writeln!(config, "path = {}", quote(path)).unwrap();
+        writeln!(config, "capturer = {}", quote(capturer)).unwrap();
+    }
+    for name in &probe.ddcutil {
+        writeln!(config, "\n[[output.ddcutil]]").unwrap();
+        writeln!(config, "name = {}", quote(name)).unwrap();
+        writeln!(config, "capturer = {}", quote(capturer)).unwrap();
+    }
+    if probe.backlights.is_empty() && probe.ddcutil.is_empty() {
+        writeln!(
+            config,
+            "\n# No screens were found, add them here\n# [[output.backlight]]\n# name = \"eDP-1\"\n# path = \"/sys/class/backlight/intel_backlight\"\n# capturer = \"wayland\""
+        )
+        .unwrap();
+    }
+    for (name, path) in &probe.keyboards {
+        writeln!(config, "\n[[keyboard]]").unwrap();
+        writeln!(config, "name = {}", quote(name)).unwrap();
+        writeln!(config, "path = {}", quote(path)).unwrap();
+    }
+
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let config = render(&Probe {
+            capture: true,
+            iio: false,
+            webcams: vec![2],
+            backlights: vec![(
+                "eDP-1".to_string(),
+                "/sys/class/backlight/amdgpu_bl1".to_string(),
+            )],
+            ddcutil: vec!["DP-2".to_string()],
+            keyboards: vec![(
+                "keyboard-dell".to_string(),
+                "/sys/class/leds/dell::kbd_backlight".to_string(),
+            )],
+        });
+        let config: toml::Value = toml::from_str(&config).unwrap();
+
+        let als = config["als"].as_table().unwrap();
+        assert_eq!(vec!["webcam"], als.keys().collect_vec());
+        assert_eq!(2, als["webcam"]["video"].as_integer().unwrap());
+        let backlight = &config["output"]["backlight"][0];
+        assert_eq!("eDP-1", backlight["name"].as_str().unwrap());
+        assert_eq!(
+            "/sys/class/backlight/amdgpu_bl1",
+            backlight["path"].as_str().unwrap()
+        );
+        assert_eq!("wayland", backlight["capturer"].as_str().unwrap());
+        assert_eq!(
+            "DP-2",
+            config["output"]["ddcutil"][0]["name"].as_str().unwrap()
+        );
+        assert_eq!(
+            "keyboard-dell",
+            config["keyboard"][0]["name"].as_str().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_render_falls_back_to_time_and_no_capturer() {
+        let config = render(&Probe {
+            backlights: vec![("eDP-1".to_string(), "/sys/class/backlight/x".to_string())],
+            ..Default::default()
+        });
+        let config: toml::Value = toml::from_str(&config).unwrap();
+
+        let als = config["als"].as_table().unwrap();
+        assert_eq!(vec!["time"], als.keys().collect_vec());
+        assert_eq!(
+            "none",
+            config["output"]["backlight"][0]["capturer"]
+                .as_str()
+                .unwrap()
+        );
+    }
+}
@@ -0,0 +1,105 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to wait for the running instance to exit when taking over, a bit longer than it
+/// takes to finish before exiting.
+const REPLACE_TIMEOUT: Duration = Duration::from_secs(5);
+const REPLACE_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Lock held while wluma is running in this session, released by the kernel on exit.
+pub struct Instance {
+    _file: File,
+}
+
+/// Makes sure this is the only instance in the Wayland session, so that two of them don't fight
+/// over brightness. The running one is asked to exit first if `replace` is set.
+pub fn acquire(replace: bool) -> Result<Instance, String> {
+    let display = std::env::var("WAYLAND_DISPLAY").unwrap_or_else(|_| "wayland-0".to_string());
+    let error =
+        |err: &dyn std::fmt::Display| format!("Unable to create the instance lock: {}", err);
+    let path = xdg::BaseDirectories::with_prefix("wluma")
+        .map_err(|err| error(&err))?
+        .place_runtime_file(format!("{}.lock", display))
+        .map_err(|err| error(&err))?;
+    acquire_at(&path, replace)
+}
+
+fn acquire_at(path: &Path, replace: bool) -> Result<Instance, String> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|err| format!("Unable to open '{}': {}", path.display(), err))?;
+
+    if !try_lock(&file) {
+        let pid = running_pid(&mut file);
+        match (replace, pid) {
+            (true, Some(pid)) => {
+                log::info!("Asking the running instance (pid {}) to exit", pid);
+                unsafe { libc::kill(pid, libc::SIGTERM) };
+                let deadline = Instant::now() + REPLACE_TIMEOUT;
+                while !try_lock(&file) {
+                    if Instant::now() > deadline {
+                        return Err(format!(
+                            "The running instance (pid {}) did not exit in time",
+                            pid
+                        ));
+                    }
+                    thread::sleep(REPLACE_POLL_INTERVAL);
+                }
+            }
+            _ => {
+                let pid = pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default();
+                return Err(format!(
+                    "wluma is already running in this session{}, stop it or start with '--replace' to take over",
+                    pid
+                ));
+            }
+        }
+    }
+
+    file.set_len(0)
+        .and_then(|_| file.rewind())
+        .and_then(|_| write!(file, "{}", std::process::id()))
+        .map_err(|err| format!("Unable to write '{}': {}", path.display(), err))?;
+    Ok(Instance { _file: file })
+}
+
+fn try_lock(file: &File) -> bool {
+    unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) == 0 }
+}
+
+fn running_pid(file: &mut File) -> Option<libc::pid_t> {
+    let mut content = String::new();
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_second_instance_is_refused() {
+        let path = std::env::temp_dir().join(format!("wluma-test-{}.lock", std::process::id()));
+
+        let instance = acquire_at(&path, false).unwrap();
+        assert_eq!(
+            Err(format!(
+                "wluma is already running in this session (pid {}), stop it or start with '--replace' to take over",
+                std::process::id()
+            )),
+            acquire_at(&path, false).map(|_| ())
+        );
+
+        drop(instance);
+        assert!(acquire_at(&path, false).is_ok());
+        std::fs::remove_file(path).unwrap();
+    }
+}
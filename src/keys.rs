@@ -0,0 +1,227 @@
+use crate::brightness::Command;
+use inotify::{Inotify, WatchMask};
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+const EV_KEY: u16 = 0x01;
+const KEY_BRIGHTNESSDOWN: u16 = 224;
+const KEY_BRIGHTNESSUP: u16 = 225;
+const INPUT_DIR: &str = "/dev/input";
+
+/// Tells brightness controllers when a brightness key is pressed, so that they notice the change
+/// it makes right away, instead of on their next periodic check.
+pub struct Watcher {
+    key_txs: Vec<Sender<Command>>,
+}
+
+/// Input device with brightness keys.
+struct Keyboard {
+    name: String,
+    device: File,
+}
+
+impl Watcher {
+    pub fn new(key_txs: Vec<Sender<Command>>) -> Self {
+        Self { key_txs }
+    }
+
+    pub fn run(&mut self) {
+        if let Err(err) = self.watch() {
+            log::warn!(
+                "Unable to follow brightness keys, checking brightness periodically: {}",
+                err
+            );
+        }
+    }
+
+    fn watch(&mut self) -> io::Result<()> {
+        // Keyboards plugged in later show up, then become readable once udev sets their access
+        let mut inotify = Inotify::init()?;
+        inotify
+            .watches()
+            .add(INPUT_DIR, WatchMask::CREATE | WatchMask::ATTRIB)?;
+
+        let mut keyboards = keyboards()?;
+        if keyboards.is_empty() {
+            log::debug!("No readable input device has brightness keys yet");
+        }
+
+        let mut event = [0; std::mem::size_of::<libc::input_event>()];
+        let mut buffer = [0; 4096];
+        loop {
+            let fds = std::iter::once(inotify.as_raw_fd())
+                .chain(keyboards.iter().map(|keyboard| keyboard.device.as_raw_fd()))
+                .collect::<Vec<_>>();
+            let readable = poll_readable(&fds)?;
+
+            // Devices that went away, e.g. were unplugged, are dropped before new ones are added,
+            // as those might reuse their names
+            let mut gone = Vec::new();
+            for &index in readable.iter().filter(|&&index| index > 0) {
+                let keyboard = &mut keyboards[index - 1];
+                match keyboard.device.read_exact(&mut event) {
+                    Ok(()) if brightness_key_pressed(&event) => {
+                        log::trace!("Brightness key pressed");
+                        self.key_txs.retain(|tx| tx.send(Command::Key).is_ok());
+                    }
+                    Ok(()) => {}
+                    Err(err) => {
+                        log::debug!("Stopped following {}/{}: {}", INPUT_DIR, keyboard.name, err);
+                        gone.push(index - 1);
+                    }
+                }
+            }
+            gone.iter().rev().for_each(|&index| {
+                keyboards.remove(index);
+            });
+
+            if readable.first() == Some(&0) {
+                let names = match inotify.read_events(&mut buffer) {
+                    Ok(events) => events
+                        .filter_map(|event| event.name)
+                        .map(|name| name.to_string_lossy().into_owned())
+                        .collect::<Vec<_>>(),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => Vec::new(),
+                    Err(err) => return Err(err),
+                };
+                for name in names {
+                    if keyboards.iter().all(|keyboard| keyboard.name != name) {
+                        keyboards.extend(keyboard(&name));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Input devices that have brightness keys, skipping the ones we are not allowed to read.
+fn keyboards() -> io::Result<Vec<Keyboard>> {
+    let mut keyboards = Vec::new();
+    for entry in fs::read_dir(INPUT_DIR)? {
+        let name = entry?.file_name();
+        keyboards.extend(keyboard(&name.to_string_lossy()));
+    }
+    Ok(keyboards)
+}
+
+/// The input device with the given name, if it has brightness keys and we are allowed to read it.
+fn keyboard(name: &str) -> Option<Keyboard> {
+    if !name.starts_with("event") {
+        return None;
+    }
+    let capabilities = Path::new("/sys/class/input")
+        .join(name)
+        .join("device/capabilities/key");
+    if !fs::read_to_string(capabilities).is_ok_and(|keys| has_brightness_keys(&keys)) {
+        return None;
+    }
+    match File::open(Path::new(INPUT_DIR).join(name)) {
+        Ok(device) => {
+            log::debug!("Following brightness keys of {}/{}", INPUT_DIR, name);
+            Some(Keyboard {
+                name: name.to_string(),
+                device,
+            })
+        }
+        Err(err) => {
+            log::debug!("Unable to read {}/{}: {}", INPUT_DIR, name, err);
+            None
+        }
+    }
+}
+
+/// Waits until any of the file descriptors has something to read, returns which ones do.
+fn poll_readable(fds: &[RawFd]) -> io::Result<Vec<usize>> {
+    let mut pollfds = fds
+        .iter()
+        .map(|&fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+    if unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+    Ok(pollfds
+        .iter()
+        .enumerate()
+        .filter(|(_, pollfd)| pollfd.revents != 0)
+        .map(|(index, _)| index)
+        .collect())
+}
+
+/// Whether the key bitmap from sysfs (hex words, the most significant first) has either key.
+fn has_brightness_keys(keys: &str) -> bool {
+    let words = keys
+        .split_whitespace()
+        .rev()
+        .map(|word| u64::from_str_radix(word, 16).unwrap_or(0))
+        .collect::<Vec<_>>();
+    // Words are as wide as `long` in the kernel
+    let bits = libc::c_long::BITS as usize;
+    let has = |key: u16| {
+        let key = key as usize;
+        words
+            .get(key / bits)
+            .is_some_and(|word| word >> (key % bits) & 1 == 1)
+    };
+    has(KEY_BRIGHTNESSDOWN) || has(KEY_BRIGHTNESSUP)
+}
+
+/// Whether the raw `input_event` is a press or an autorepeat of either key.
+fn brightness_key_pressed(event: &[u8]) -> bool {
+    let event = unsafe { std::ptr::read_unaligned(event.as_ptr() as *const libc::input_event) };
+    event.type_ == EV_KEY
+        && [KEY_BRIGHTNESSDOWN, KEY_BRIGHTNESSUP].contains(&event.code)
+        && event.value != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(type_: u16, code: u16, value: i32) -> Vec<u8> {
+        let event = libc::input_event {
+            time: libc::timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_,
+            code,
+            value,
+        };
+        let bytes = &event as *const libc::input_event as *const u8;
+        unsafe { std::slice::from_raw_parts(bytes, std::mem::size_of::<libc::input_event>()) }
+            .to_vec()
+    }
+
+    #[test]
+    fn test_has_brightness_keys() {
+        // KEY_BRIGHTNESSDOWN is bit 32 of the fourth word from the end
+        assert!(has_brightness_keys("100000000 0 0 0\n"));
+        assert!(has_brightness_keys("300000000 0 0 0\n"));
+        assert!(!has_brightness_keys("ffffffff 0 0 0\n"));
+        assert!(!has_brightness_keys("3 0\n"));
+        assert!(!has_brightness_keys("0\n"));
+    }
+
+    #[test]
+    fn test_brightness_key_pressed() {
+        assert!(brightness_key_pressed(&event(EV_KEY, KEY_BRIGHTNESSUP, 1)));
+        assert!(brightness_key_pressed(&event(
+            EV_KEY,
+            KEY_BRIGHTNESSDOWN,
+            2
+        )));
+        assert!(!brightness_key_pressed(&event(EV_KEY, KEY_BRIGHTNESSUP, 0)));
+        assert!(!brightness_key_pressed(&event(EV_KEY, 30, 1)));
+        assert!(!brightness_key_pressed(&event(0x04, KEY_BRIGHTNESSUP, 1)));
+    }
+}
@@ -0,0 +1,34 @@
+pub mod als;
+pub mod brightness;
+pub mod calibrate;
+pub mod cli;
+pub mod clock;
+pub mod config;
+pub mod control;
+pub mod device_file;
+pub mod diagnose;
+pub mod error;
+pub mod frame;
+pub mod init;
+pub mod instance;
+pub mod keys;
+pub mod lock;
+pub mod logging;
+pub mod notification;
+pub mod plot;
+pub mod power;
+pub mod predictor;
+pub mod record;
+pub mod replay;
+pub mod scene;
+pub mod shutdown;
+pub mod sleep;
+pub mod stats;
+pub mod supervisor;
+pub mod toplevel;
+pub mod tui;
+pub mod window;
+pub mod workspace;
+
+/// Current app version (determined at compile-time).
+pub const VERSION: &str = env!("WLUMA_VERSION");
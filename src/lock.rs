@@ -0,0 +1,80 @@
+use crate::brightness::Command;
+use dbus::arg::prop_cast;
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use dbus::Path;
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+const LOGIN1: &str = "org.freedesktop.login1";
+const LOGIN1_SESSION: &str = "org.freedesktop.login1.Session";
+
+/// Follows whether the graphical session is locked, as reported to logind by the screen locker.
+pub struct Watcher {
+    lock_txs: Vec<Sender<Command>>,
+    locked: bool,
+}
+
+impl Watcher {
+    pub fn new(lock_txs: Vec<Sender<Command>>) -> Self {
+        Self {
+            lock_txs,
+            locked: false,
+        }
+    }
+
+    pub fn run(&mut self) {
+        if let Err(err) = self.watch() {
+            log::warn!(
+                "Unable to follow the session lock state, learning while locked: {}",
+                err
+            );
+        }
+        self.update_locked(false);
+    }
+
+    fn watch(&mut self) -> Result<(), dbus::Error> {
+        let connection = Connection::new_system()?;
+
+        // wluma usually runs as a user service, outside of any session, so follow the graphical
+        // session of the user instead of its own one
+        let user = connection.with_proxy(LOGIN1, "/org/freedesktop/login1/user/self", DBUS_TIMEOUT);
+        let (id, path): (String, Path) = user.get("org.freedesktop.login1.User", "Display")?;
+        log::debug!("Following the lock state of session {}", id);
+
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let rule = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .with_path(path.clone());
+        connection.add_match(rule, move |changed: PropertiesPropertiesChanged, _, _| {
+            if changed.interface_name == LOGIN1_SESSION {
+                if let Some(&locked) = prop_cast::<bool>(&changed.changed_properties, "LockedHint")
+                {
+                    // The receiver only goes away when this watcher stops
+                    let _ = locked_tx.send(locked);
+                }
+            }
+            true
+        })?;
+
+        let session = connection.with_proxy(LOGIN1, path, DBUS_TIMEOUT);
+        self.update_locked(session.get(LOGIN1_SESSION, "LockedHint")?);
+
+        loop {
+            connection.process(Duration::from_secs(60))?;
+            if let Some(locked) = locked_rx.try_iter().last() {
+                self.update_locked(locked);
+            }
+        }
+    }
+
+    fn update_locked(&mut self, locked: bool) {
+        if locked != self.locked {
+            log::debug!("Session lock state changed, locked: {}", locked);
+            self.locked = locked;
+            self.lock_txs
+                .retain(|tx| tx.send(Command::Lock(locked)).is_ok());
+        }
+    }
+}
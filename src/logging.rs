@@ -0,0 +1,113 @@
+use lazy_static::lazy_static;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::sync::RwLock;
+
+/// Filter toggled on by SIGUSR1, on top of the one wluma started with.
+const DEBUG_FILTER: &str = "wluma=debug";
+
+lazy_static! {
+    static ref LOGGER: Logger = Logger {
+        inner: RwLock::new(build(None)),
+        filter: RwLock::new(None),
+    };
+}
+
+/// Logs like `env_logger` does, with filters that can be changed while running, so that logs of
+/// a rare bug can be captured without restarting and losing the state that led to it.
+struct Logger {
+    inner: RwLock<env_logger::Logger>,
+    /// Filter set at runtime, on top of the one from `RUST_LOG`.
+    filter: RwLock<Option<String>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush();
+    }
+}
+
+/// Logs at info level by default, or as `RUST_LOG` says.
+pub fn init() {
+    log::set_logger(&*LOGGER).expect("Unable to set logger");
+    log::set_max_level(LOGGER.inner.read().unwrap().filter());
+}
+
+/// Adds filters in the `RUST_LOG` format (e.g. `wluma::als=trace`) to the ones wluma started
+/// with, or goes back to them when empty.
+pub fn set_filter(filter: &str) {
+    let filter = (!filter.trim().is_empty()).then(|| filter.to_string());
+    let logger = build(filter.as_deref());
+    log::set_max_level(logger.filter());
+    *LOGGER.inner.write().unwrap() = logger;
+    log::info!("Log filter changed to {:?}", filter);
+    *LOGGER.filter.write().unwrap() = filter;
+}
+
+/// Keeps SIGUSR1 for [`watch`], must be called before starting any threads, so that they
+/// inherit it.
+pub fn block_signal() {
+    let signals = signals();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+}
+
+/// Toggles debug logs of wluma on SIGUSR1.
+pub fn watch() {
+    let signals = signals();
+    let mut signal = 0;
+    loop {
+        unsafe { libc::sigwait(&signals, &mut signal) };
+        let filter = LOGGER.filter.read().unwrap().clone();
+        match filter.as_deref() {
+            Some(DEBUG_FILTER) => set_filter(""),
+            _ => set_filter(DEBUG_FILTER),
+        }
+    }
+}
+
+fn build(filter: Option<&str>) -> env_logger::Logger {
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(LevelFilter::Info).parse_default_env();
+    if let Some(filter) = filter {
+        builder.parse_filters(filter);
+    }
+    builder.build()
+}
+
+fn signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGUSR1);
+        signals
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_adds_filter_to_default() {
+        assert_eq!(LevelFilter::Trace, build(Some("wluma::als=trace")).filter());
+        assert!(build(Some("wluma::als=trace")).enabled(
+            &Metadata::builder()
+                .level(log::Level::Trace)
+                .target("wluma::als::iio")
+                .build()
+        ));
+        assert!(!build(Some("wluma::als=trace")).enabled(
+            &Metadata::builder()
+                .level(log::Level::Trace)
+                .target("wluma::brightness")
+                .build()
+        ));
+    }
+}
@@ -1,27 +1,120 @@
 use itertools::Itertools;
-use std::sync::mpsc;
-
-mod als;
-mod brightness;
-mod config;
-mod device_file;
-mod frame;
-mod predictor;
-
-/// Current app version (determined at compile-time).
-pub const VERSION: &str = env!("WLUMA_VERSION");
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc};
+use wluma::error::WlumaError;
+use wluma::{
+    als, brightness, calibrate, cli, clock, config, control, diagnose, frame, init, instance, keys,
+    lock, logging, notification, plot, power, predictor, record, replay, scene, shutdown, sleep,
+    stats, supervisor, toplevel, tui, window, workspace, VERSION,
+};
 
 fn main() {
     let panic_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic_info| {
         panic_hook(panic_info);
+        // Most panics come from what the compositor does or doesn't support
+        if let Some(capabilities) = frame::capturer::wayland::capabilities() {
+            eprintln!(
+                "Capture protocols advertised by the compositor: {}",
+                capabilities
+            );
+        }
         std::process::exit(1);
     }));
 
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Info)
-        .parse_default_env()
-        .init();
+    logging::init();
+
+    let mut replace = false;
+    let command = cli::parse(std::env::args().skip(1)).map(|(command, options)| {
+        config::set_overrides(options.overrides);
+        replace = options.replace;
+        command
+    });
+    let recording = match command {
+        Ok(cli::Command::Run) => false,
+        Ok(cli::Command::Record(path)) => match record::start(&path) {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!("Unable to record to '{}': {}", path.display(), err);
+                std::process::exit(2);
+            }
+        },
+        Ok(cli::Command::Replay(path)) => {
+            if let Err(err) = replay::run(&path) {
+                eprintln!("Unable to replay '{}': {}", path.display(), err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::Diagnose) => return diagnose::run(),
+        Ok(cli::Command::CalibrateAls) => {
+            if let Err(err) = calibrate::run() {
+                eprintln!("Unable to calibrate the ALS: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::InitConfig) => {
+            if let Err(err) = init::run() {
+                eprintln!("Unable to write the config: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::Tui) => {
+            if let Err(err) = tui::run() {
+                eprintln!(
+                    "Unable to get the status of wluma, is it running with the D-Bus service enabled? {}",
+                    err
+                );
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::Inhibit { reason, command }) => {
+            // Dropped by wluma when this process exits, however it exits
+            let _inhibit = control::inhibit(&reason).unwrap_or_else(|err| {
+                eprintln!(
+                    "Unable to inhibit wluma, is it running with the D-Bus service enabled? {}",
+                    err
+                );
+                std::process::exit(1);
+            });
+            match std::process::Command::new(&command[0])
+                .args(&command[1..])
+                .status()
+            {
+                Ok(status) => std::process::exit(status.code().unwrap_or(1)),
+                Err(err) => {
+                    eprintln!("Unable to run '{}': {}", command[0], err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        Ok(cli::Command::Plot { name, csv }) => {
+            if let Err(err) = plot::run(&name, csv) {
+                eprintln!("Unable to plot learned data: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Ok(cli::Command::Help) => return print!("{}", cli::USAGE),
+        Ok(cli::Command::Version) => return println!("wluma {}", VERSION),
+        Err(err) => {
+            eprint!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    // Before starting any threads, to exit gracefully once all of them are done
+    shutdown::block_signals();
+    // Likewise, so that only the scene watcher receives it
+    scene::block_signal();
+    logging::block_signal();
+    std::thread::Builder::new()
+        .name("logging".to_string())
+        .spawn(logging::watch)
+        .expect("Unable to start thread: logging");
 
     log::debug!("== wluma v{} ==", VERSION);
 
@@ -30,129 +123,762 @@ fn main() {
         Err(err) => panic!("Unable to load config: {}", err),
     };
 
+    // Only once the config is fine, not to stop the running instance for nothing
+    let _instance = match instance::acquire(replace) {
+        Ok(instance) => instance,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+
     log::debug!("Using {:#?}", config);
 
-    let als_txs = config
+    if let Some(notifications) = config.notifications {
+        let mut notifier = notification::Notifier::new(std::time::Duration::from_secs(
+            notifications.min_interval_secs,
+        ));
+        std::thread::Builder::new()
+            .name("notification".to_string())
+            .spawn(move || notifier.run())
+            .expect("Unable to start thread: notification");
+    }
+
+    if config.als_export {
+        match als::export::enable() {
+            Ok(path) => log::debug!("Exporting ALS readings to {}", path.display()),
+            Err(err) => log::warn!("Unable to export ALS readings: {}", err),
+        }
+    }
+
+    let exposure_bias = control::ExposureBias::load(config.exposure_bias);
+    let control = config
+        .control
+        .then(|| control::Service::new(exposure_bias, control::DisabledOutputs::load()));
+
+    let app_rules = config.app.clone();
+    let als_profiles = config.als_profiles();
+    let screen_glow = config
+        .als
+        .iter()
+        .any(|als| matches!(als, config::Als::Webcam { screen_glow, .. } if *screen_glow > 0));
+
+    // Outputs with a mirror capturer receive luma values from the capturer of another output
+    let mut mirror_txs: HashMap<String, Vec<mpsc::Sender<u8>>> = HashMap::new();
+    let mut mirror_rxs: HashMap<String, mpsc::Receiver<u8>> = HashMap::new();
+    for output in &config.output {
+        let config::OutputSettings { name, capturer, .. } = output.settings();
+        if let config::Capturer::Mirror(source) = capturer {
+            let (luma_tx, luma_rx) = mpsc::channel();
+            mirror_txs.entry(source.clone()).or_default().push(luma_tx);
+            mirror_rxs.insert(name.clone(), luma_rx);
+        }
+    }
+
+    let mut workspace_txs = Vec::new();
+    let mut window_txs = Vec::new();
+    let mut lock_txs = Vec::new();
+    let mut sleep_txs = Vec::new();
+    let mut key_txs = Vec::new();
+    let mut battery_txs = Vec::new();
+    let mut control_txs = HashMap::new();
+    let mut switchers = HashMap::new();
+    let (als_txs, focus_txs): (Vec<_>, Vec<_>) = config
         .output
         .iter()
         .filter_map(|output| {
-            let output_clone = output.clone();
-
             let (als_tx, als_rx) = mpsc::channel();
+            let (focus_tx, focus_rx) = mpsc::channel();
+            let (workspace_tx, workspace_rx) = mpsc::channel();
+            let (window_tx, window_rx) = mpsc::channel();
             let (user_tx, user_rx) = mpsc::channel();
             let (prediction_tx, prediction_rx) = mpsc::channel();
+            let (command_tx, command_rx) = mpsc::channel();
+            let (capture_tx, paused_rx) = mpsc::channel();
+            if config.lock.is_some() {
+                lock_txs.push(command_tx.clone());
+            }
+            if config.sleep.is_some() {
+                sleep_txs.push(command_tx.clone());
+            }
+            if config.keys {
+                key_txs.push(command_tx.clone());
+            }
+            let locked_brightness = config.lock.and_then(|lock| lock.brightness);
+            let battery = config.battery.map(|battery| {
+                let (battery_tx, battery_rx) = mpsc::channel();
+                battery_txs.push(battery_tx);
+                (battery, battery_rx)
+            });
 
-            let (output_name, output_capturer) = match output_clone.clone() {
-                config::Output::Backlight(cfg) => (cfg.name, cfg.capturer),
-                config::Output::DdcUtil(cfg) => (cfg.name, cfg.capturer),
-            };
+            let config::OutputSettings {
+                name: output_name,
+                capturer: output_capturer,
+                identity,
+                capture_region,
+                luma_percentile,
+                min_damage_percent,
+                transfer_function,
+                oled,
+                luma_jump,
+                luma_focus,
+                screen_off,
+                curve,
+                scenes,
+                predictor,
+                ..
+            } = output.settings().clone();
+            stats::register(&output_name);
+            let window_rx = luma_focus
+                .filter(|luma_focus| luma_focus.follow_window)
+                .map(|_| {
+                    window_txs.push(window_tx);
+                    window_rx
+                });
+            let luma_focus = luma_focus.map(|luma_focus| luma_focus.spread);
+            let luma_jump = luma_jump.map(|luma_jump| (luma_jump, command_tx.clone()));
+            // Outputs that are not captured only ever see black frames
+            let screen_off = screen_off
+                .filter(|_| !matches!(output_capturer, config::Capturer::None))
+                .map(|screen_off| (screen_off, command_tx.clone()));
+            if config.control {
+                control_txs.insert(output_name.clone(), command_tx);
+            }
 
-            let brightness = match output {
-                config::Output::Backlight(cfg) => {
-                    brightness::Backlight::new(&cfg.path, cfg.min_brightness)
-                        .map(|b| Box::new(b) as Box<dyn brightness::Brightness + Send>)
-                }
-                config::Output::DdcUtil(cfg) => {
-                    brightness::DdcUtil::new(&cfg.name, cfg.min_brightness)
-                        .map(|b| Box::new(b) as Box<dyn brightness::Brightness + Send>)
-                }
-            };
+            let switch_rx = (!scenes.is_empty()).then(|| {
+                let names = scenes.iter().map(|(name, _)| name.clone()).collect();
+                let (switcher, switch_rx) = scene::Switcher::new(names);
+                switchers.insert(output_name.clone(), switcher);
+                switch_rx
+            });
 
-            match brightness {
-                Ok(b) => {
-                    let thread_name = format!("backlight-{}", output_name);
-                    std::thread::Builder::new()
-                        .name(thread_name.clone())
-                        .spawn(move || {
-                            brightness::Controller::new(b, user_tx, prediction_rx).run();
-                        })
-                        .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
-
-                    let predictor = match output_clone.clone() {
-                        config::Output::Backlight(backlight_output) => backlight_output.predictor,
-                        config::Output::DdcUtil(ddcutil_output) => ddcutil_output.predictor,
-                    };
-                    let thread_name = format!("predictor-{}", output_name);
-                    std::thread::Builder::new()
-                        .name(thread_name.clone())
-                        .spawn(move || {
-                            let mut frame_capturer: Box<dyn frame::capturer::Capturer> =
-                                match output_capturer {
-                                    config::Capturer::Wayland(protocol) => {
-                                        Box::new(frame::capturer::wayland::Capturer::new(protocol))
-                                    }
-                                    config::Capturer::None => {
-                                        Box::<frame::capturer::none::Capturer>::default()
-                                    }
-                                };
+            if let config::Predictor::Adaptive(config::AdaptivePredictor {
+                per_workspace: true,
+                ..
+            }) = predictor
+            {
+                workspace_txs.push(workspace_tx);
+            }
 
-                            let controller = match predictor {
-                                config::Predictor::Manual { thresholds } => {
-                                    Box::new(predictor::controller::manual::Controller::new(
-                                        prediction_tx,
+            let cooldowns = predictor::Cooldowns::new(config.predictor);
+            let app_rules = app_rules.clone();
+            let als_profiles = als_profiles.clone();
+            let luma_txs = mirror_txs.remove(&output_name).unwrap_or_default();
+            let luma_rx = mirror_rxs.remove(&output_name);
+            // Outputs mirrored by others keep capturing while disabled
+            let paused_rx = luma_txs.is_empty().then_some(paused_rx);
+            let report = config.control;
+            // Screens that are not captured are assumed to show average content, keyboards don't
+            // light up the room
+            let glow = screen_glow && !matches!(output_capturer, config::Capturer::None);
+            let glows =
+                screen_glow && !matches!(output, config::Output::Backlight(cfg) if cfg.keyboard);
+            let restore_on_exit = config.restore_on_exit;
+            let vulkan_device = config.vulkan_device.clone();
+            let luma_samples = config.luma_samples;
+            let lightness = config.lightness;
+            let start = move |b: Box<dyn brightness::Brightness + Send>| {
+                let b = Box::new(brightness::Counted::new(b, &output_name));
+                let b: Box<dyn brightness::Brightness + Send> = match curve {
+                    config::Curve::Linear => b,
+                    curve => Box::new(brightness::Curved::new(b, curve)),
+                };
+                let b: Box<dyn brightness::Brightness + Send> = match oled {
+                    Some(oled) => Box::new(brightness::Oled::new(b, oled)),
+                    None => b,
+                };
+                let b: Box<dyn brightness::Brightness + Send> = match glows {
+                    true => Box::new(brightness::Glowing::new(b, &output_name)),
+                    false => b,
+                };
+                let max_brightness = b.max();
+                let thread_name = format!("backlight-{}", output_name);
+                let backlight_output_name = output_name.clone();
+                std::thread::Builder::new()
+                    .name(thread_name.clone())
+                    .spawn(move || {
+                        brightness::Controller::new(
+                            &backlight_output_name,
+                            b,
+                            user_tx,
+                            prediction_rx,
+                            command_rx,
+                            capture_tx,
+                            locked_brightness,
+                            exposure_bias.current,
+                            restore_on_exit,
+                        )
+                        .run();
+                    })
+                    .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
+
+                let thread_name = format!("predictor-{}", output_name);
+                std::thread::Builder::new()
+                    .name(thread_name.clone())
+                    .spawn(move || {
+                        // While on battery, predictions and brightness set by user are adjusted
+                        // on their way between the brightness controller and the predictor
+                        let (prediction_tx, user_rx, battery) = match battery {
+                            Some((battery, battery_rx)) => {
+                                let (inner_prediction_tx, inner_prediction_rx) = mpsc::channel();
+                                let (inner_user_tx, inner_user_rx) = mpsc::channel();
+                                let wrap = move |inner| {
+                                    predictor::controller::battery::Controller::new(
+                                        inner,
                                         user_rx,
-                                        als_rx,
-                                        thresholds,
+                                        inner_user_tx,
+                                        inner_prediction_rx,
+                                        prediction_tx,
+                                        battery_rx,
+                                        battery,
+                                    )
+                                };
+                                (inner_prediction_tx, inner_user_rx, Some(wrap))
+                            }
+                            None => (prediction_tx, user_rx, None),
+                        };
+
+                        // Application rules and scenes replace predictions, which are adjusted on
+                        // battery all the same
+                        let app_prediction_tx = prediction_tx.clone();
+                        let scene_prediction_tx = prediction_tx.clone();
+
+                        // While a scene is on, brightness set by user and ALS profiles are
+                        // relayed to the predictor only as far as the scene allows
+                        let (user_rx, als_rx, scene) = match switch_rx {
+                            Some(switch_rx) => {
+                                let (inner_user_tx, inner_user_rx) = mpsc::channel();
+                                let (inner_als_tx, inner_als_rx) = mpsc::channel();
+                                let relay = (user_rx, inner_user_tx, als_rx, inner_als_tx);
+                                (inner_user_rx, inner_als_rx, Some((relay, switch_rx)))
+                            }
+                            None => (user_rx, als_rx, None),
+                        };
+
+                        let controller = predictor::controller::build(
+                            predictor,
+                            predictor::controller::Context {
+                                prediction_tx,
+                                user_rx,
+                                als_rx,
+                                workspace_rx,
+                                stateful: true,
+                                output_name: &output_name,
+                                max_brightness,
+                                profiles: predictor::Profiles::new(als_profiles),
+                                cooldowns,
+                                clock: Arc::new(clock::System),
+                                entries: None,
+                            },
+                        );
+
+                        let controller = if recording {
+                            Box::new(predictor::controller::record::Controller::new(
+                                controller,
+                                &output_name,
+                            ))
+                        } else {
+                            controller
+                        };
+
+                        let controller = if app_rules.is_empty() {
+                            controller
+                        } else {
+                            Box::new(predictor::controller::app::Controller::new(
+                                controller,
+                                app_prediction_tx,
+                                focus_rx,
+                                app_rules,
+                            ))
+                        };
+
+                        let controller = match scene {
+                            Some(((user_rx, inner_user_tx, als_rx, inner_als_tx), switch_rx)) => {
+                                Box::new(predictor::controller::scene::Controller::new(
+                                    controller,
+                                    &output_name,
+                                    scene_prediction_tx,
+                                    user_rx,
+                                    inner_user_tx,
+                                    als_rx,
+                                    inner_als_tx,
+                                    switch_rx,
+                                    scenes,
+                                    cooldowns,
+                                    Arc::new(clock::System),
+                                ))
+                            }
+                            None => controller,
+                        };
+
+                        let controller = match battery {
+                            Some(wrap) => Box::new(wrap(controller)),
+                            None => controller,
+                        };
+
+                        let controller = match screen_off {
+                            Some((screen_off, command_tx)) => {
+                                Box::new(predictor::controller::screen_off::Controller::new(
+                                    controller,
+                                    &output_name,
+                                    command_tx,
+                                    screen_off.max_luma,
+                                    std::time::Duration::from_secs_f64(screen_off.after_secs),
+                                ))
+                            }
+                            None => controller,
+                        };
+
+                        let controller = if luma_txs.is_empty() {
+                            controller
+                        } else {
+                            Box::new(predictor::controller::fanout::Controller::new(
+                                controller, luma_txs,
+                            ))
+                        };
+
+                        let controller: Box<dyn predictor::controller::Controller> = Box::new(
+                            predictor::controller::stats::Controller::new(controller, &output_name),
+                        );
+
+                        let controller = match luma_jump {
+                            Some((luma_jump, command_tx)) => {
+                                Box::new(predictor::controller::jump::Controller::new(
+                                    controller,
+                                    command_tx,
+                                    luma_jump.delta,
+                                    luma_jump.head_start,
+                                ))
+                            }
+                            None => controller,
+                        };
+
+                        let controller = if glow {
+                            Box::new(predictor::controller::glow::Controller::new(
+                                controller,
+                                &output_name,
+                            ))
+                        } else {
+                            controller
+                        };
+
+                        let controller = if report {
+                            Box::new(predictor::controller::report::Controller::new(
+                                controller,
+                                &output_name,
+                            ))
+                        } else {
+                            controller
+                        };
+
+                        let controller =
+                            Box::new(predictor::controller::shutdown::Controller::new(controller));
+
+                        let mut frame_capturer: Box<dyn frame::capturer::Capturer> =
+                            match output_capturer {
+                                config::Capturer::Wayland(protocol) => {
+                                    Box::new(frame::capturer::wayland::Capturer::new(
+                                        protocol,
+                                        identity,
+                                        capture_region,
+                                        luma_percentile,
+                                        min_damage_percent,
+                                        transfer_function,
+                                        lightness,
+                                        vulkan_device,
+                                        luma_samples,
+                                        luma_focus,
+                                        window_rx,
+                                        paused_rx,
+                                        controller,
                                     ))
-                                        as Box<dyn predictor::Controller>
                                 }
-                                config::Predictor::Adaptive => {
-                                    Box::new(predictor::controller::adaptive::Controller::new(
-                                        prediction_tx,
-                                        user_rx,
-                                        als_rx,
-                                        true,
-                                        &output_name,
+                                config::Capturer::None => {
+                                    Box::new(frame::capturer::none::Capturer::new(controller))
+                                }
+                                config::Capturer::Directory(path) => {
+                                    Box::new(frame::capturer::directory::Capturer::new(
+                                        &path,
+                                        capture_region,
+                                        luma_percentile,
+                                        lightness,
+                                        controller,
+                                    ))
+                                }
+                                config::Capturer::Mirror(_) => {
+                                    Box::new(frame::capturer::mirror::Capturer::new(
+                                        luma_rx.expect("Mirror capturer must have a channel"),
+                                        controller,
                                     ))
-                                        as Box<dyn predictor::Controller>
                                 }
                             };
 
-                            frame_capturer.run(&output_name, controller)
-                        })
-                        .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
+                        supervisor::supervise(&output_name, || frame_capturer.run(&output_name));
+                    })
+                    .unwrap_or_else(|_| panic!("Unable to start thread: {}", thread_name));
+            };
 
-                    Some(als_tx)
+            match output {
+                config::Output::Backlight(cfg) => {
+                    match brightness::Backlight::find(&cfg.path, &cfg.settings.name) {
+                        Some(path) => match brightness::Backlight::new(
+                            &path,
+                            cfg.settings.min_brightness,
+                            cfg.settings.max_brightness,
+                            cfg.write_mode,
+                        ) {
+                            Ok(b) => start(Box::new(b)),
+                            Err(err) => {
+                                log::warn!(
+                                    "Skipping '{}' as it might be disconnected: {}",
+                                    cfg.settings.name,
+                                    err
+                                );
+                                return None;
+                            }
+                        },
+                        // Backlight drivers might be loaded late (e.g. nvidia_wmi_ec_backlight),
+                        // wait for the device in the background and start once it shows up
+                        None => {
+                            let cfg = cfg.clone();
+                            let thread_name = format!("backlight-{}", cfg.settings.name);
+                            log::info!(
+                                "Waiting for '{}' to appear at {}",
+                                cfg.settings.name,
+                                cfg.path
+                            );
+                            std::thread::Builder::new()
+                                .name(thread_name.clone())
+                                .spawn(move || {
+                                    let path = brightness::Backlight::wait_for(
+                                        &cfg.path,
+                                        &cfg.settings.name,
+                                    );
+                                    log::info!(
+                                        "Found backlight for '{}' at {}",
+                                        cfg.settings.name,
+                                        path
+                                    );
+                                    match brightness::Backlight::new(
+                                        &path,
+                                        cfg.settings.min_brightness,
+                                        cfg.settings.max_brightness,
+                                        cfg.write_mode,
+                                    ) {
+                                        Ok(b) => start(Box::new(b)),
+                                        Err(err) => {
+                                            log::warn!("Skipping '{}': {}", cfg.settings.name, err)
+                                        }
+                                    }
+                                })
+                                .unwrap_or_else(|_| {
+                                    panic!("Unable to start thread: {}", thread_name)
+                                });
+                        }
+                    }
                 }
-                Err(err) => {
-                    log::warn!(
-                        "Skipping '{}' as it might be disconnected: {}",
-                        output_name,
-                        err
-                    );
-
-                    None
+                config::Output::DdcUtil(cfg) => {
+                    // The ddcci driver already owns the DDC bus of the screen when it's loaded
+                    let ddcci = (cfg.feature == brightness::DDCCI_FEATURE && !cfg.contrast_dimming)
+                        .then(|| brightness::Backlight::find_ddcci(&cfg.settings.name))
+                        .flatten()
+                        .map(|path| {
+                            log::info!(
+                                "Using ddcci-backlight at {} for '{}'",
+                                path,
+                                cfg.settings.name
+                            );
+                            brightness::Backlight::new(
+                                &path,
+                                cfg.settings.min_brightness,
+                                cfg.settings.max_brightness,
+                                config::WriteMode::Auto,
+                            )
+                        });
+                    match ddcci {
+                        Some(Ok(b)) => start(Box::new(b)),
+                        ddcci => {
+                            if let Some(Err(err)) = ddcci {
+                                log::warn!(
+                                    "Unable to use ddcci-backlight for '{}', using DDC instead: {}",
+                                    cfg.settings.name,
+                                    err
+                                );
+                            }
+                            // Discovering DDC displays is slow, and they might be turned off at
+                            // startup, keep looking for them in the background and start once
+                            // they show up
+                            let cfg = cfg.clone();
+                            let thread_name = format!("ddcutil-{}", cfg.settings.name);
+                            let mut start = Some(start);
+                            std::thread::Builder::new()
+                                .name(thread_name.clone())
+                                .spawn(move || {
+                                    supervisor::supervise(&cfg.settings.name, || {
+                                        let b = brightness::DdcUtil::new(
+                                            &cfg.settings.name,
+                                            cfg.settings.min_brightness,
+                                            cfg.settings.max_brightness,
+                                            cfg.feature,
+                                            cfg.contrast_dimming,
+                                        )?;
+                                        log::info!("Found display for '{}'", cfg.settings.name);
+                                        if let Some(start) = start.take() {
+                                            start(Box::new(b));
+                                        }
+                                        Ok(())
+                                    });
+                                })
+                                .unwrap_or_else(|_| {
+                                    panic!("Unable to start thread: {}", thread_name)
+                                });
+                        }
+                    }
                 }
-            }
+            };
+
+            Some((als_tx, focus_tx))
         })
-        .collect_vec();
+        .unzip();
+
+    // Let mirroring outputs know if the output they mirror didn't start
+    drop(mirror_txs);
+
+    if !workspace_txs.is_empty() {
+        std::thread::Builder::new()
+            .name("workspace".to_string())
+            .spawn(move || {
+                workspace::Watcher::new(workspace_txs).run();
+            })
+            .expect("Unable to start thread: workspace");
+    }
+
+    if !window_txs.is_empty() {
+        std::thread::Builder::new()
+            .name("window".to_string())
+            .spawn(move || {
+                window::Watcher::new(window_txs).run();
+            })
+            .expect("Unable to start thread: window");
+    }
+
+    if !battery_txs.is_empty() {
+        std::thread::Builder::new()
+            .name("power".to_string())
+            .spawn(move || {
+                power::Watcher::new(battery_txs).run();
+            })
+            .expect("Unable to start thread: power");
+    }
+
+    if !lock_txs.is_empty() {
+        std::thread::Builder::new()
+            .name("lock".to_string())
+            .spawn(move || {
+                lock::Watcher::new(lock_txs).run();
+            })
+            .expect("Unable to start thread: lock");
+    }
+
+    if let Some(sleep) = config.sleep.filter(|_| !sleep_txs.is_empty()) {
+        std::thread::Builder::new()
+            .name("sleep".to_string())
+            .spawn(move || {
+                sleep::Watcher::new(sleep_txs, sleep.grace_secs).run();
+            })
+            .expect("Unable to start thread: sleep");
+    }
+
+    if !switchers.is_empty() {
+        let switchers = switchers.values().cloned().collect();
+        std::thread::Builder::new()
+            .name("scene".to_string())
+            .spawn(move || {
+                scene::Watcher::new(switchers).run();
+            })
+            .expect("Unable to start thread: scene");
+    }
+
+    if !key_txs.is_empty() {
+        std::thread::Builder::new()
+            .name("keys".to_string())
+            .spawn(move || {
+                keys::Watcher::new(key_txs).run();
+            })
+            .expect("Unable to start thread: keys");
+    }
+
+    if let Some(mut control) = control {
+        std::thread::Builder::new()
+            .name("control".to_string())
+            .spawn(move || control.run(control_txs, switchers))
+            .expect("Unable to start thread: control");
+    }
 
+    if !config.app.is_empty() {
+        std::thread::Builder::new()
+            .name("toplevel".to_string())
+            .spawn(move || {
+                toplevel::Watcher::new(focus_txs).run();
+            })
+            .expect("Unable to start thread: toplevel");
+    }
+
+    let lightness = config.lightness;
+    let als_fusion = config.als_fusion;
     std::thread::Builder::new()
         .name("als".to_string())
         .spawn(move || {
-            let als: Box<dyn als::Als> = match config.als {
-                config::Als::Iio { path, thresholds } => Box::new(
-                    als::iio::Als::new(&path, thresholds)
-                        .expect("Unable to initialize ALS IIO sensor"),
-                ),
-                config::Als::Time { thresholds } => Box::new(als::time::Als::new(thresholds)),
-                config::Als::Webcam { video, thresholds } => Box::new({
-                    let (webcam_tx, webcam_rx) = mpsc::channel();
-                    std::thread::Builder::new()
-                        .name("als-webcam".to_string())
-                        .spawn(move || {
-                            als::webcam::Webcam::new(webcam_tx, video).run();
-                        })
-                        .expect("Unable to start thread: als-webcam");
-                    als::webcam::Als::new(webcam_rx, thresholds)
-                }),
-                config::Als::None { .. } => Box::<als::none::Als>::default(),
+            let mut als_configs = config.als;
+            // The webcam is read by the fusion, which takes the place of the IIO sensor
+            let mut fusion = als_fusion.and_then(|fusion| {
+                let webcam = als_configs
+                    .iter()
+                    .position(|als| matches!(als, config::Als::Webcam { .. }))?;
+                Some((fusion, als_configs.remove(webcam)))
+            });
+            let mut sources = als_configs
+                .into_iter()
+                .filter_map(|als_config| {
+                    let polling = als_config.polling();
+                    let (name, als) = match als_config {
+                        als_config @ config::Als::Iio { .. } if fusion.is_some() => {
+                            let (fusion, webcam) = fusion.take().unwrap();
+                            let als = init_fusion(als_config, webcam, fusion, lightness);
+                            ("fusion".to_string(), als)
+                        }
+                        als_config => (als_config.to_string(), init_als(als_config, lightness)),
+                    };
+                    let als = als.map(|als| match polling {
+                        Some(polling) => Box::new(als::paced::Als::new(als, polling)),
+                        None => als,
+                    });
+                    match als {
+                        Ok(als) => Some((name, als)),
+                        Err(err) => {
+                            log::warn!("Skipping ALS source '{}': {}", name, err);
+                            None
+                        }
+                    }
+                })
+                .collect_vec();
+
+            let als: Box<dyn als::Als> = match sources.len() {
+                0 => panic!("Unable to initialize any of the configured ALS sources"),
+                1 => sources.remove(0).1,
+                _ => Box::new(als::fallback::Als::new(sources)),
             };
 
             als::controller::Controller::new(als, als_txs).run();
         })
         .expect("Unable to start thread: als");
 
+    std::thread::Builder::new()
+        .name("stats".to_string())
+        .spawn(stats::run)
+        .expect("Unable to start thread: stats");
+
     log::info!("Continue adjusting brightness and wluma will learn your preference over time.");
-    std::thread::park();
+    shutdown::wait();
+}
+
+fn init_als(
+    als: config::Als,
+    lightness: config::Lightness,
+) -> Result<Box<dyn als::Als>, WlumaError> {
+    Ok(match als {
+        config::Als::Iio {
+            path,
+            hysteresis,
+            smoothing,
+            polling,
+            events,
+            thresholds,
+        } => Box::new(als::iio::Als::new(
+            &path,
+            als::Profiles::new(
+                thresholds,
+                hysteresis,
+                smoothing,
+                std::time::Duration::from_millis(polling.poll_ms),
+            ),
+            events,
+        )?),
+        config::Als::Time {
+            thresholds,
+            location,
+            ..
+        } => Box::new(als::time::Als::new(thresholds, location)),
+        config::Als::Webcam {
+            video,
+            sleep_ms,
+            region,
+            gamma,
+            hysteresis,
+            smoothing,
+            prefer_infrared,
+            screen_glow,
+            polling,
+            thresholds,
+        } => Box::new(als::webcam::Als::new(
+            als::webcam::start(video, sleep_ms, region, gamma, lightness, prefer_infrared)?,
+            als::Profiles::new(
+                thresholds,
+                hysteresis,
+                smoothing,
+                std::time::Duration::from_millis(polling.poll_ms),
+            ),
+            screen_glow,
+        )),
+        config::Als::None => Box::<als::none::Als>::default(),
+    })
+}
+
+fn init_fusion(
+    iio: config::Als,
+    webcam: config::Als,
+    fusion: config::AlsFusion,
+    lightness: config::Lightness,
+) -> Result<Box<dyn als::Als>, WlumaError> {
+    let (
+        config::Als::Iio {
+            path,
+            hysteresis,
+            smoothing,
+            polling,
+            thresholds,
+            ..
+        },
+        config::Als::Webcam {
+            video,
+            sleep_ms,
+            region,
+            gamma,
+            prefer_infrared,
+            screen_glow,
+            ..
+        },
+    ) = (iio, webcam)
+    else {
+        unreachable!("ALS fusion combines an IIO and a webcam source");
+    };
+
+    // Readings are only mapped to profiles once they are fused
+    let unmapped = || als::Profiles::new(HashMap::new(), 0, 1.0, std::time::Duration::ZERO);
+    Ok(Box::new(als::fusion::Als::new(
+        als::iio::Als::new(&path, unmapped(), false)?,
+        als::webcam::Als::new(
+            als::webcam::start(video, sleep_ms, region, gamma, lightness, prefer_infrared)?,
+            unmapped(),
+            screen_glow,
+        ),
+        fusion.mode,
+        fusion.webcam_scale,
+        als::Profiles::new(
+            thresholds,
+            hysteresis,
+            smoothing,
+            std::time::Duration::from_millis(polling.poll_ms),
+        ),
+    )))
 }
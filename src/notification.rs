@@ -0,0 +1,168 @@
+use dbus::arg::PropMap;
+use dbus::blocking::Connection;
+use lazy_static::lazy_static;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const DBUS_TIMEOUT: Duration = Duration::from_secs(1);
+const EXPIRE_TIMEOUT_MS: i32 = 5000;
+
+lazy_static! {
+    static ref EVENT_TX: Mutex<Option<Sender<Event>>> = Mutex::new(None);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    ProfileSwitched {
+        profile: String,
+    },
+    Learned {
+        output_name: String,
+        profile: String,
+        luma: u8,
+        /// In basis points.
+        brightness: u64,
+    },
+}
+
+impl Event {
+    fn body(&self) -> String {
+        match self {
+            Self::ProfileSwitched { profile } => format!("Ambient light is now '{}'", profile),
+            Self::Learned {
+                output_name,
+                profile,
+                luma,
+                brightness,
+            } => format!(
+                "Learned {}% brightness of '{}' for '{}' light and {}% screen contents",
+                brightness / 100,
+                output_name,
+                profile,
+                luma
+            ),
+        }
+    }
+}
+
+/// Shows a desktop notification about the event, if notifications are enabled.
+pub fn notify(event: Event) {
+    if let Some(tx) = EVENT_TX.lock().unwrap().as_ref() {
+        tx.send(event).ok();
+    }
+}
+
+/// Shows events as desktop notifications, at most one per `min_interval`.
+///
+/// Events that come too soon are not lost, the latest one is shown once the interval passes.
+pub struct Notifier {
+    event_rx: Receiver<Event>,
+    min_interval: Duration,
+    replaces_id: u32,
+}
+
+impl Notifier {
+    pub fn new(min_interval: Duration) -> Self {
+        let (event_tx, event_rx) = std::sync::mpsc::channel();
+        *EVENT_TX.lock().unwrap() = Some(event_tx);
+
+        Self {
+            event_rx,
+            min_interval,
+            replaces_id: 0,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let connection = match Connection::new_session() {
+            Ok(connection) => connection,
+            Err(err) => {
+                log::warn!(
+                    "Unable to connect to session DBus, notifications are disabled: {}",
+                    err
+                );
+                *EVENT_TX.lock().unwrap() = None;
+                return;
+            }
+        };
+
+        let mut pending: Option<Event> = None;
+        let mut last_shown: Option<Instant> = None;
+
+        loop {
+            let wait = last_shown.map_or(Duration::ZERO, |last_shown| {
+                self.min_interval.saturating_sub(last_shown.elapsed())
+            });
+
+            let received = match pending {
+                Some(_) => self.event_rx.recv_timeout(wait),
+                None => self
+                    .event_rx
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(event) => pending = Some(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            let ready =
+                last_shown.is_none_or(|last_shown| last_shown.elapsed() >= self.min_interval);
+            if let (true, Some(event)) = (ready, pending.take()) {
+                self.show(&connection, &event);
+                last_shown = Some(Instant::now());
+            }
+        }
+    }
+
+    fn show(&mut self, connection: &Connection, event: &Event) {
+        log::debug!("Showing notification: {:?}", event);
+
+        let proxy = connection.with_proxy(
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            DBUS_TIMEOUT,
+        );
+        let result: Result<(u32,), _> = proxy.method_call(
+            "org.freedesktop.Notifications",
+            "Notify",
+            (
+                "wluma",
+                self.replaces_id,
+                "display-brightness",
+                "wluma",
+                event.body(),
+                Vec::<String>::new(),
+                PropMap::new(),
+                EXPIRE_TIMEOUT_MS,
+            ),
+        );
+
+        match result {
+            // Replace the previous notification instead of stacking them up
+            Ok((id,)) => self.replaces_id = id,
+            Err(err) => log::warn!("Unable to show notification: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let event = Event::Learned {
+            output_name: "eDP-1".to_string(),
+            profile: "dim".to_string(),
+            luma: 40,
+            brightness: 2550,
+        };
+        assert_eq!(
+            "Learned 25% brightness of 'eDP-1' for 'dim' light and 40% screen contents",
+            event.body()
+        );
+    }
+}
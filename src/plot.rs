@@ -0,0 +1,163 @@
+use crate::brightness::BASIS_POINTS;
+use crate::error::WlumaError;
+use crate::predictor::Data;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Columns of the heatmap, by luma rounded to the nearest multiple of this.
+const LUMA_STEP: u8 = 10;
+/// Shades of brightness, from the darkest quarter of the range to the brightest one.
+const SHADES: [char; 4] = ['░', '▒', '▓', '█'];
+/// Cells where nothing was learned.
+const HOLE: char = '·';
+
+/// Prints what was learned for the output or data group, as a heatmap of brightness by ALS
+/// profile and luma, or as CSV with an entry per line.
+pub fn run(name: &str, csv: bool) -> Result<(), WlumaError> {
+    let data = Data::peek(name)?.ok_or_else(|| {
+        WlumaError::predictor(format!(
+            "Nothing is learned for '{}' yet, name an output or a data group",
+            name
+        ))
+    })?;
+    print!("{}", if csv { to_csv(&data) } else { render(&data) });
+    Ok(())
+}
+
+fn render(data: &Data) -> String {
+    let mut cells: BTreeMap<(&str, u8), (u64, u64)> = BTreeMap::new();
+    for entry in &data.entries {
+        let column = (entry.luma.min(100) + LUMA_STEP / 2) / LUMA_STEP;
+        let (sum, count) = cells.entry((&entry.lux, column)).or_default();
+        *sum += entry.brightness;
+        *count += 1;
+    }
+
+    // Profiles that are no longer configured are still shown, after the configured ones
+    let mut profiles: Vec<&str> = data.profiles.iter().collect();
+    for (lux, _) in cells.keys() {
+        if !profiles.contains(lux) {
+            profiles.push(lux);
+        }
+    }
+    let width = profiles
+        .iter()
+        .map(|p| p.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max(6);
+
+    let mut plot = String::new();
+    writeln!(
+        plot,
+        "Learned brightness of '{}' ({} entries)\n",
+        data.output_name,
+        data.entries.len()
+    )
+    .unwrap();
+    write!(plot, "{:>width$}", "luma", width = width).unwrap();
+    for column in 0..=100 / LUMA_STEP {
+        write!(plot, " {:>3}", column * LUMA_STEP).unwrap();
+    }
+    writeln!(plot).unwrap();
+
+    for profile in profiles {
+        write!(plot, "{:>width$}", profile, width = width).unwrap();
+        for column in 0..=100 / LUMA_STEP {
+            let cell = match cells.get(&(profile, column)) {
+                Some(&(sum, count)) => shade(sum / count),
+                None => HOLE,
+            };
+            write!(plot, "  {}{}", cell, cell).unwrap();
+        }
+        writeln!(plot).unwrap();
+    }
+
+    writeln!(
+        plot,
+        "\n{} nothing learned  {} <25%  {} <50%  {} <75%  {} up to 100% brightness",
+        HOLE, SHADES[0], SHADES[1], SHADES[2], SHADES[3]
+    )
+    .unwrap();
+    plot
+}
+
+fn shade(brightness: u64) -> char {
+    let quarter = (brightness * SHADES.len() as u64 / (BASIS_POINTS + 1)) as usize;
+    SHADES[quarter.min(SHADES.len() - 1)]
+}
+
+fn to_csv(data: &Data) -> String {
+    let mut csv = "profile,luma,brightness,learned_at,tag,time_bucket\n".to_string();
+    for entry in &data.entries {
+        writeln!(
+            csv,
+            "{},{},{:.2},{},{},{}",
+            entry.lux,
+            entry.luma,
+            entry.brightness as f64 / 100.0,
+            entry.learned_at.map_or(String::new(), |t| t.to_string()),
+            entry.tag.as_deref().unwrap_or_default(),
+            entry.time_bucket.map_or(String::new(), |h| h.to_string()),
+        )
+        .unwrap();
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::predictor::{Entry, Profiles};
+
+    fn data() -> Data {
+        let mut data = Data::new(
+            "eDP-1",
+            Profiles::new(vec!["night".to_string(), "day".to_string()]),
+        );
+        data.entries = vec![
+            Entry::new("night", 0, 1000),
+            Entry::new("night", 3, 3000),
+            Entry::new("day", 100, 9000),
+            Entry::new("gone", 48, 6000),
+        ];
+        data.entries[0].learned_at = Some(1700000000);
+        data
+    }
+
+    #[test]
+    fn test_render() {
+        let plot = render(&data());
+
+        assert!(plot.starts_with("Learned brightness of 'eDP-1' (4 entries)\n"));
+        // Entries in the same cell are averaged, holes stand out
+        assert!(
+            plot.contains("\n night  ░░  ··  ··  ··  ··  ··  ··  ··  ··  ··  ··\n"),
+            "{}",
+            plot
+        );
+        assert!(
+            plot.contains("\n   day  ··  ··  ··  ··  ··  ··  ··  ··  ··  ··  ██\n"),
+            "{}",
+            plot
+        );
+        assert!(
+            plot.contains("\n  gone  ··  ··  ··  ··  ··  ▓▓  ··  ··  ··  ··  ··\n"),
+            "{}",
+            plot
+        );
+    }
+
+    #[test]
+    fn test_to_csv() {
+        let csv = to_csv(&data());
+
+        assert_eq!(
+            Some("night,0,10.00,1700000000,,"),
+            csv.lines().nth(1),
+            "{}",
+            csv
+        );
+        assert_eq!(5, csv.lines().count());
+    }
+}
@@ -0,0 +1,96 @@
+use dbus::arg::prop_cast;
+use dbus::blocking::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use std::fs;
+use std::path::Path;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::Duration;
+
+const DBUS_TIMEOUT: Duration = Duration::from_secs(5);
+const UPOWER: &str = "org.freedesktop.UPower";
+const UPOWER_PATH: &str = "/org/freedesktop/UPower";
+const POWER_SUPPLY: &str = "/sys/class/power_supply";
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Follows whether the system runs on battery, as reported by UPower, or by the kernel when
+/// UPower is not running.
+pub struct Watcher {
+    battery_txs: Vec<Sender<bool>>,
+    on_battery: Option<bool>,
+}
+
+impl Watcher {
+    pub fn new(battery_txs: Vec<Sender<bool>>) -> Self {
+        Self {
+            battery_txs,
+            on_battery: None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        if let Err(err) = self.watch() {
+            log::debug!(
+                "Unable to follow UPower, checking {} instead: {}",
+                POWER_SUPPLY,
+                err
+            );
+        }
+        loop {
+            self.update_on_battery(on_battery(Path::new(POWER_SUPPLY)));
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn watch(&mut self) -> Result<(), dbus::Error> {
+        let connection = Connection::new_system()?;
+
+        let (on_battery_tx, on_battery_rx) = mpsc::channel();
+        let rule = MatchRule::new_signal("org.freedesktop.DBus.Properties", "PropertiesChanged")
+            .with_path(UPOWER_PATH);
+        connection.add_match(rule, move |changed: PropertiesPropertiesChanged, _, _| {
+            if changed.interface_name == UPOWER {
+                if let Some(&on_battery) =
+                    prop_cast::<bool>(&changed.changed_properties, "OnBattery")
+                {
+                    // The receiver only goes away when this watcher stops
+                    let _ = on_battery_tx.send(on_battery);
+                }
+            }
+            true
+        })?;
+
+        let upower = connection.with_proxy(UPOWER, UPOWER_PATH, DBUS_TIMEOUT);
+        self.update_on_battery(upower.get(UPOWER, "OnBattery")?);
+
+        loop {
+            connection.process(Duration::from_secs(60))?;
+            if let Some(on_battery) = on_battery_rx.try_iter().last() {
+                self.update_on_battery(on_battery);
+            }
+        }
+    }
+
+    fn update_on_battery(&mut self, on_battery: bool) {
+        if Some(on_battery) != self.on_battery {
+            log::debug!("Power source changed, on battery: {}", on_battery);
+            self.on_battery = Some(on_battery);
+            self.battery_txs.retain(|tx| tx.send(on_battery).is_ok());
+        }
+    }
+}
+
+/// Whether any battery is discharging, systems without a battery are always on AC power.
+fn on_battery(power_supply: &Path) -> bool {
+    let read = |path: &Path| fs::read_to_string(path).unwrap_or_default();
+    fs::read_dir(power_supply)
+        .into_iter()
+        .flatten()
+        .flatten()
+        .any(|supply| {
+            let path = supply.path();
+            read(&path.join("type")).trim() == "Battery"
+                && read(&path.join("status")).trim() == "Discharging"
+        })
+}
@@ -1,23 +1,70 @@
-use super::{
-    Controller as _, INITIAL_TIMEOUT_SECS, NEXT_ALS_COOLDOWN_RESET, PENDING_COOLDOWN_RESET,
-};
-use crate::predictor::data::{Data, Entry};
+use super::{Context, Controller as _, Cooldowns, INITIAL_TIMEOUT_SECS};
+use crate::clock::Clock;
+use crate::config::{AdaptivePredictor, Predictor};
+use crate::control::{self, Update};
+use crate::notification::{self, Event};
+use crate::predictor::data::{Data, Entry, Group, Profiles};
+use crate::record;
+use crate::stats::{self, Stat};
+use chrono::{DateTime, Local, Timelike};
+use itertools::Itertools;
 use std::sync::mpsc::{Receiver, Sender};
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Around the start of a time of day bucket, predictions blend between the previous bucket and
+/// this one, for this many minutes on each side.
+const TIME_BLEND_MINUTES: u32 = 60;
+const MINUTES_PER_DAY: u32 = 24 * 60;
+/// Learned data is compacted on load, and again after this many entries are learned.
+const COMPACT_EVERY_LEARNS: u32 = 20;
 
 pub struct Controller {
     prediction_tx: Sender<u64>,
     user_rx: Receiver<u64>,
     als_rx: Receiver<String>,
-    pending_cooldown: u8,
+    workspace_rx: Receiver<Option<String>>,
+    /// Learning waits until then, after the last brightness change made by user.
+    pending_until: Instant,
     pending: Option<Entry>,
+    learned_since_compaction: u32,
     data: Data,
+    /// Other outputs that learn into the same data, if any.
+    group: Option<Group>,
     stateful: bool,
     initial_brightness: Option<u64>,
     last_als: Option<String>,
     next_als: Option<String>,
-    next_als_cooldown: u8,
+    /// The next ALS profile is switched to at this time, unless it changes again.
+    next_als_at: Instant,
+    cooldowns: Cooldowns,
+    clock: Arc<dyn Clock>,
     output_name: String,
+    config: AdaptivePredictor,
+    workspace: Option<String>,
+}
+
+pub fn build(predictor: Predictor, context: Context) -> Box<dyn super::Controller> {
+    let Predictor::Adaptive(config) = predictor else {
+        unreachable!("Registered for the adaptive predictor only");
+    };
+    let controller = Controller::new(
+        context.prediction_tx,
+        context.user_rx,
+        context.als_rx,
+        context.workspace_rx,
+        context.stateful,
+        context.output_name,
+        context.max_brightness,
+        context.profiles,
+        config,
+        context.cooldowns,
+        context.clock,
+    );
+    Box::new(match context.entries {
+        Some(entries) => controller.with_entries(entries),
+        None => controller,
+    })
 }
 
 impl super::Controller for Controller {
@@ -48,54 +95,113 @@ impl super::Controller for Controller {
             };
         }
 
-        match self.als_rx.try_iter().last() {
-            new_als @ Some(_) if self.next_als != new_als => {
-                self.next_als = new_als;
-                self.next_als_cooldown = NEXT_ALS_COOLDOWN_RESET;
-            }
-            _ if self.next_als_cooldown > 1 => {
-                self.next_als_cooldown -= 1;
+        let now = self.clock.now();
+        // The ALS keeps sending the current profile, only a different one is switched to, and
+        // going back to the current one before the cooldown ends cancels the switch
+        if let Some(new_als) = self.als_rx.try_iter().last() {
+            if self.last_als.as_ref() == Some(&new_als) {
+                self.next_als = None;
+            } else if self.next_als.as_ref() != Some(&new_als) {
+                self.next_als = Some(new_als);
+                self.next_als_at = now + self.cooldowns.next_als;
             }
-            _ if self.next_als_cooldown == 1 => {
-                self.next_als_cooldown = 0;
-                self.last_als = self.next_als.take();
+        }
+
+        if now >= self.next_als_at && self.next_als.is_some() {
+            self.last_als = self.next_als.take();
+            if let Some(profile) = self.last_als.clone() {
+                notification::notify(Event::ProfileSwitched { profile });
             }
-            _ => {}
+        }
+
+        if let Some(workspace) = self.workspace_rx.try_iter().last() {
+            self.workspace = workspace;
+        }
+
+        let shared = self
+            .group
+            .as_ref()
+            .map(|group| group.learned().collect_vec())
+            .unwrap_or_default();
+        for entry in shared {
+            log::debug!(
+                "[{}] Learning {:?} from its data group",
+                self.output_name,
+                entry
+            );
+            self.add(entry);
         }
 
         let lux = &self.last_als.clone().expect("ALS value must be known");
+        control::report(Update::Profile(lux.clone()));
         self.process(lux, luma);
     }
+
+    fn flush(&mut self) {
+        if self.pending.is_some() {
+            self.learn();
+        }
+    }
 }
 
 impl Controller {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         prediction_tx: Sender<u64>,
         user_rx: Receiver<u64>,
         als_rx: Receiver<String>,
+        workspace_rx: Receiver<Option<String>>,
         stateful: bool,
         output_name: &str,
+        max_brightness: u64,
+        profiles: Profiles,
+        config: AdaptivePredictor,
+        cooldowns: Cooldowns,
+        clock: Arc<dyn Clock>,
     ) -> Self {
+        let group = config.data_group.as_deref().filter(|_| stateful);
+        let data_name = group.unwrap_or(output_name);
         let data = if stateful {
-            Data::load(output_name)
+            Data::load(data_name, max_brightness, profiles)
         } else {
-            Data::new(output_name)
+            Data::new(data_name, profiles)
         };
+        let group = group.map(Group::join);
 
-        Self {
+        let mut controller = Self {
             prediction_tx,
             user_rx,
             als_rx,
-            pending_cooldown: 0,
+            workspace_rx,
+            pending_until: clock.now(),
             pending: None,
+            learned_since_compaction: 0,
             data,
+            group,
             stateful,
             initial_brightness: None,
             last_als: None,
             next_als: None,
-            next_als_cooldown: 0,
+            next_als_at: clock.now(),
+            cooldowns,
+            clock,
             output_name: output_name.to_string(),
-        }
+            config,
+            workspace: None,
+        };
+        controller.forget_stale(controller.clock.timestamp());
+        controller.compact();
+        record::add(record::Event::Entries {
+            output_name: output_name.to_string(),
+            entries: controller.data.entries.clone(),
+        });
+        controller
+    }
+
+    /// Starts from the given entries instead of the learned ones, e.g. to replay a session.
+    pub fn with_entries(mut self, entries: Vec<Entry>) -> Self {
+        self.data.entries = entries;
+        self
     }
 
     fn process(&mut self, lux: &str, luma: u8) {
@@ -105,15 +211,29 @@ impl Controller {
         if let Some(brightness) = user_changed_brightness {
             self.pending = match &self.pending {
                 // First time we notice user adjusting brightness, freeze lux and luma...
-                None => Some(Entry::new(lux, luma, brightness)),
+                None => Some(Entry {
+                    tag: self.workspace.clone(),
+                    time_bucket: self.time_bucket(minute_of_day(self.clock.wall())),
+                    ..Entry::new(lux, luma, brightness)
+                }),
                 // ... but as user keeps changing brightness,
                 // allow some time for them to reach the desired brightness level for the pending lux and luma
-                Some(Entry { lux, luma, .. }) => Some(Entry::new(lux, *luma, brightness)),
+                Some(Entry {
+                    lux,
+                    luma,
+                    tag,
+                    time_bucket,
+                    ..
+                }) => Some(Entry {
+                    tag: tag.clone(),
+                    time_bucket: *time_bucket,
+                    ..Entry::new(lux, *luma, brightness)
+                }),
             };
             // Every time user changed brightness, reset the cooldown period
-            self.pending_cooldown = PENDING_COOLDOWN_RESET;
-        } else if self.pending_cooldown > 0 {
-            self.pending_cooldown -= 1;
+            self.pending_until = self.clock.now() + self.cooldowns.pending;
+        } else if self.clock.now() < self.pending_until {
+            // The user might still be adjusting it
         } else if self.pending.is_some() {
             self.learn();
         } else {
@@ -122,47 +242,287 @@ impl Controller {
     }
 
     fn learn(&mut self) {
-        let pending = self.pending.take().expect("No pending entry to learn");
+        let mut pending = self.pending.take().expect("No pending entry to learn");
         log::debug!("[{}] Learning {:?}", self.output_name, pending);
 
-        self.data.entries.retain(|entry| {
-            let different_env = entry.lux != pending.lux;
+        pending.learned_at = Some(self.clock.timestamp());
+        stats::add(&self.output_name, Stat::Learned);
 
-            let same_env_darker_screen = entry.lux == pending.lux
-                && entry.luma < pending.luma
-                && entry.brightness >= pending.brightness;
+        notification::notify(Event::Learned {
+            output_name: self.output_name.clone(),
+            profile: pending.lux.clone(),
+            luma: pending.luma,
+            brightness: pending.brightness,
+        });
 
-            let same_env_brighter_screen = entry.lux == pending.lux
-                && entry.luma > pending.luma
-                && entry.brightness <= pending.brightness;
+        if let Some(group) = &self.group {
+            group.share(&pending);
+        }
+        self.add(pending);
 
-            different_env || same_env_darker_screen || same_env_brighter_screen
-        });
+        if self.stateful {
+            self.data.save().expect("Unable to save data");
+        }
+    }
 
-        self.data.entries.push(pending);
+    /// Adds a newly learned entry, dropping the ones it makes obsolete.
+    fn add(&mut self, entry: Entry) {
+        self.forget_stale(self.clock.timestamp());
 
         self.data
             .entries
-            .sort_unstable_by(|x, y| x.lux.cmp(&y.lux).then(x.luma.cmp(&y.luma)));
+            .retain(|older| !entry.supersedes(older, 0));
 
-        if self.stateful {
-            self.data.save().expect("Unable to save data");
+        let (lux, tag, time_bucket) = (entry.lux.clone(), entry.tag.clone(), entry.time_bucket);
+        self.data.entries.push(entry);
+
+        let profiles = &self.data.profiles;
+        self.data
+            .entries
+            .sort_unstable_by(|x, y| profiles.compare(&x.lux, &y.lux).then(x.luma.cmp(&y.luma)));
+
+        if self.config.enforce_monotonic {
+            self.make_monotonic(&lux, &tag, time_bucket);
+        }
+
+        self.learned_since_compaction += 1;
+        if self.learned_since_compaction >= COMPACT_EVERY_LEARNS {
+            self.compact();
+        }
+    }
+
+    /// Entries that were just learned never conflict with the new one, but older entries
+    /// (e.g. learned by previous versions) might, lower their brightness to fix that.
+    fn make_monotonic(&mut self, lux: &str, tag: &Option<String>, time_bucket: Option<u8>) {
+        let mut ceiling = u64::MAX;
+        let entries = self.data.entries.iter_mut();
+        for entry in
+            entries.filter(|e| e.lux == lux && &e.tag == tag && e.time_bucket == time_bucket)
+        {
+            if entry.brightness > ceiling {
+                log::debug!(
+                    "[{}] Lowering brightness of {:?} to {}",
+                    self.output_name,
+                    entry,
+                    ceiling
+                );
+                entry.brightness = ceiling;
+            }
+            ceiling = entry.brightness;
+        }
+    }
+
+    fn compact(&mut self) {
+        let dropped = self.data.compact(self.config.compact_luma_epsilon);
+        if dropped > 0 {
+            log::debug!(
+                "[{}] Compacted learned data, dropped {} entries",
+                self.output_name,
+                dropped
+            );
         }
+        self.learned_since_compaction = 0;
+    }
+
+    fn forget_stale(&mut self, now: u64) {
+        let decay_days = self.config.decay_days;
+        self.data.entries.retain(|entry| {
+            let stale = decay_weight(entry.age_days(now), decay_days) <= 0.0;
+            if stale {
+                log::debug!("[{}] Forgetting stale {:?}", self.output_name, entry);
+            }
+            !stale
+        });
     }
 
     fn predict(&mut self, lux: &str, luma: u8) {
-        if let Some(prediction) = self.interpolate(&self.data.entries, lux, luma) {
+        let prediction = self
+            .interpolate_profile(lux, luma)
+            .or_else(|| self.interpolate_across_profiles(lux, luma));
+
+        if let Some(prediction) = prediction {
             log::trace!("Prediction: {} (lux: {}, luma: {})", prediction, lux, luma);
             self.prediction_tx
                 .send(prediction)
                 .expect("Unable to send predicted brightness value, channel is dead");
         }
     }
+
+    fn interpolate_profile(&self, lux: &str, luma: u8) -> Option<u64> {
+        self.interpolate_profile_at(lux, luma, minute_of_day(self.clock.wall()))
+    }
+
+    /// Blends predictions of the time of day buckets that are relevant at the given minute.
+    fn interpolate_profile_at(&self, lux: &str, luma: u8, minute: u32) -> Option<u64> {
+        let entries = self.workspace_entries(lux);
+        let buckets = &self.config.time_buckets;
+        if buckets.is_empty() {
+            return self.interpolate_entries(&entries, lux, luma);
+        }
+
+        let (sum, weights) = time_bucket_weights(buckets, minute)
+            .into_iter()
+            .filter_map(|(bucket, weight)| {
+                // Entries learned before time buckets were enabled belong to all of them, and
+                // a bucket that has nothing learned yet uses what was learned in the other ones
+                let bucket_entries = entries
+                    .iter()
+                    .filter(|e| {
+                        e.time_bucket
+                            .is_none_or(|hour| time_bucket(buckets, hour as u32 * 60) == bucket)
+                    })
+                    .cloned()
+                    .collect_vec();
+                let bucket_entries = if bucket_entries.is_empty() {
+                    &entries
+                } else {
+                    &bucket_entries
+                };
+                self.interpolate_entries(bucket_entries, lux, luma)
+                    .map(|prediction| (prediction as f64, weight))
+            })
+            .fold((0.0, 0.0), |(sum, weights), (prediction, weight)| {
+                (sum + prediction * weight, weights + weight)
+            });
+
+        (weights > 0.0).then(|| (sum / weights).round() as u64)
+    }
+
+    fn interpolate_entries(&self, entries: &[Entry], lux: &str, luma: u8) -> Option<u64> {
+        if self.config.enforce_monotonic {
+            self.interpolate_linear(entries, lux, luma)
+        } else {
+            let now = self.clock.timestamp();
+            let weight = |entry: &Entry| decay_weight(entry.age_days(now), self.config.decay_days);
+            self.interpolate_weighted(entries, lux, luma, &weight)
+        }
+    }
+
+    /// Time of day bucket to learn in at the given minute, if learning per time of day.
+    fn time_bucket(&self, minute: u32) -> Option<u8> {
+        let buckets = &self.config.time_buckets;
+        (!buckets.is_empty()).then(|| time_bucket(buckets, minute))
+    }
+
+    /// Entries learned on the focused workspace, or all of them if nothing was learned there yet.
+    fn workspace_entries(&self, lux: &str) -> Vec<Entry> {
+        let profile_entries = self.data.entries.iter().filter(|e| e.lux == lux);
+        let workspace_entries = profile_entries
+            .clone()
+            .filter(|e| e.tag == self.workspace)
+            .cloned()
+            .collect_vec();
+
+        if workspace_entries.is_empty() {
+            profile_entries.cloned().collect()
+        } else {
+            workspace_entries
+        }
+    }
+
+    /// Unlike weighted interpolation, a line between the closest known points never makes
+    /// brightness go up when screen contents get brighter.
+    fn interpolate_linear(&self, entries: &[Entry], lux: &str, luma: u8) -> Option<u64> {
+        let entries = entries.iter().filter(|e| e.lux == lux);
+        let below = entries.clone().rfind(|e| e.luma <= luma);
+        let above = entries.clone().find(|e| e.luma >= luma);
+
+        match (below, above) {
+            (Some(below), Some(above)) if below.luma != above.luma => {
+                let ratio = (luma - below.luma) as f64 / (above.luma - below.luma) as f64;
+                let brightness = below.brightness as f64
+                    + (above.brightness as f64 - below.brightness as f64) * ratio;
+                Some(brightness.round() as u64)
+            }
+            (Some(entry), _) | (None, Some(entry)) => Some(entry.brightness),
+            (None, None) => None,
+        }
+    }
+
+    /// Combines predictions of the closest darker and brighter profiles that have learned data,
+    /// trusting each of them less the further away it is from the current profile.
+    fn interpolate_across_profiles(&self, lux: &str, luma: u8) -> Option<u64> {
+        let discount = self.config.profile_fallback_discount;
+        let predict_at = |(index, profile): (usize, &str)| {
+            let steps = index as i32 + 1;
+            self.interpolate_profile(profile, luma)
+                .map(|prediction| (prediction as f64, discount.powi(steps)))
+        };
+        let darker = self
+            .data
+            .profiles
+            .darker(lux)
+            .enumerate()
+            .find_map(predict_at);
+        let brighter = self
+            .data
+            .profiles
+            .brighter(lux)
+            .enumerate()
+            .find_map(predict_at);
+
+        let (sum, weights) = darker
+            .into_iter()
+            .chain(brighter)
+            .fold((0.0, 0.0), |(sum, weights), (prediction, weight)| {
+                (sum + prediction * weight, weights + weight)
+            });
+
+        (weights > 0.0).then(|| (sum / weights).round() as u64)
+    }
+}
+
+/// Start of the bucket that contains the given minute of the day, buckets wrap around midnight.
+fn time_bucket(buckets: &[u8], minute: u32) -> u8 {
+    let bucket = buckets
+        .iter()
+        .rev()
+        .find(|&&hour| hour as u32 * 60 <= minute);
+    *bucket
+        .or(buckets.last())
+        .expect("Time buckets must not be empty")
+}
+
+/// Buckets to predict from at the given minute of the day, and how much each of them weighs.
+/// Close to the start of a bucket, it is blended with the previous one, so that predictions
+/// don't jump at the boundary.
+fn time_bucket_weights(buckets: &[u8], minute: u32) -> Vec<(u8, f64)> {
+    let boundary = buckets.iter().enumerate().find_map(|(index, &hour)| {
+        // Signed distance from the start of the bucket, taking the shorter way around the clock
+        let offset = (minute + MINUTES_PER_DAY * 3 / 2 - hour as u32 * 60) % MINUTES_PER_DAY;
+        let offset = offset as i64 - (MINUTES_PER_DAY / 2) as i64;
+        (offset.unsigned_abs() < TIME_BLEND_MINUTES as u64).then_some((index, offset))
+    });
+
+    match boundary {
+        Some((index, offset)) => {
+            let previous = buckets[(index + buckets.len() - 1) % buckets.len()];
+            let weight =
+                (offset + TIME_BLEND_MINUTES as i64) as f64 / (2 * TIME_BLEND_MINUTES) as f64;
+            vec![(previous, 1.0 - weight), (buckets[index], weight)]
+        }
+        None => vec![(time_bucket(buckets, minute), 1.0)],
+    }
+}
+
+fn minute_of_day(time: DateTime<Local>) -> u32 {
+    time.hour() * 60 + time.minute()
+}
+
+/// Entries keep their full weight until they are `decay_days` old,
+/// then lose it gradually until they are twice as old.
+fn decay_weight(age_days: f64, decay_days: Option<u64>) -> f64 {
+    match decay_days {
+        Some(decay_days) => (2.0 - age_days / decay_days as f64).clamp(0.0, 1.0),
+        None => 1.0,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock;
+    use chrono::TimeZone;
     use itertools::{iproduct, Itertools};
     use std::collections::HashSet;
     use std::error::Error;
@@ -171,81 +531,219 @@ mod tests {
     const ALS_DARK: &str = "dark";
     const ALS_DIM: &str = "dim";
     const ALS_BRIGHT: &str = "bright";
+    const COOLDOWNS: Cooldowns = Cooldowns {
+        pending: Duration::from_millis(1500),
+        next_als: Duration::from_millis(1500),
+    };
+    const FRAME: Duration = Duration::from_millis(100);
+
+    type Setup = (
+        Controller,
+        Sender<String>,
+        Sender<u64>,
+        Receiver<u64>,
+        clock::Manual,
+    );
 
     fn setup() -> Result<(Controller, Sender<u64>, Receiver<u64>), Box<dyn Error>> {
+        let (controller, _, user_tx, prediction_rx, _) = setup_with(COOLDOWNS)?;
+        Ok((controller, user_tx, prediction_rx))
+    }
+
+    fn setup_with(cooldowns: Cooldowns) -> Result<Setup, Box<dyn Error>> {
+        let (_, workspace_rx) = mpsc::channel();
         let (als_tx, als_rx) = mpsc::channel();
         let (user_tx, user_rx) = mpsc::channel();
         let (prediction_tx, prediction_rx) = mpsc::channel();
         als_tx.send(ALS_BRIGHT.to_string())?;
         user_tx.send(0)?;
-        let controller = Controller::new(prediction_tx, user_rx, als_rx, false, "Dell 1");
-        Ok((controller, user_tx, prediction_rx))
+        let profiles = Profiles::new(
+            [ALS_DARK, ALS_DIM, ALS_BRIGHT]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        );
+        let config = AdaptivePredictor {
+            profile_fallback_discount: 0.0,
+            enforce_monotonic: false,
+            decay_days: None,
+            per_workspace: false,
+            time_buckets: Vec::new(),
+            compact_luma_epsilon: 0,
+            data_group: None,
+        };
+        let clock = clock::Manual::new();
+        let controller = Controller::new(
+            prediction_tx,
+            user_rx,
+            als_rx,
+            workspace_rx,
+            false,
+            "Dell 1",
+            100,
+            profiles,
+            config,
+            cooldowns,
+            Arc::new(clock.clone()),
+        );
+        Ok((controller, als_tx, user_tx, prediction_rx, clock))
     }
 
     #[test]
     fn test_process_first_user_change() -> Result<(), Box<dyn Error>> {
-        let (mut controller, user_tx, _) = setup()?;
+        let (mut controller, _, user_tx, _, clock) = setup_with(COOLDOWNS)?;
 
         // User changes brightness to value 33 for a given lux and luma
         user_tx.send(33)?;
         controller.process(ALS_DIM, 66);
 
         assert_eq!(Some(Entry::new(ALS_DIM, 66, 33)), controller.pending);
-        assert_eq!(PENDING_COOLDOWN_RESET, controller.pending_cooldown);
+        assert_eq!(clock.now() + COOLDOWNS.pending, controller.pending_until);
 
         Ok(())
     }
 
     #[test]
     fn test_process_several_continuous_user_changes() -> Result<(), Box<dyn Error>> {
-        let (mut controller, user_tx, _) = setup()?;
+        let (mut controller, _, user_tx, _, clock) = setup_with(COOLDOWNS)?;
 
         // User initiates brightness change for a given lux and luma to value 33...
         user_tx.send(33)?;
         controller.process(ALS_DIM, 66);
         // then quickly continues increasing it to 34 (while lux and luma might already be different)...
+        clock.advance(FRAME);
         user_tx.send(34)?;
         controller.process(ALS_BRIGHT, 36);
         // and even faster to 36 (which is the indended brightness value they wish to learn for the initial lux and luma)
+        clock.advance(FRAME);
         user_tx.send(35)?;
         user_tx.send(36)?;
         controller.process(ALS_DARK, 16);
 
         assert_eq!(Some(Entry::new(ALS_DIM, 66, 36)), controller.pending);
-        assert_eq!(PENDING_COOLDOWN_RESET, controller.pending_cooldown);
+        // The cooldown starts over with every change
+        assert_eq!(clock.now() + COOLDOWNS.pending, controller.pending_until);
 
         Ok(())
     }
 
     #[test]
     fn test_process_learns_user_change_after_cooldown() -> Result<(), Box<dyn Error>> {
-        let (mut controller, user_tx, _) = setup()?;
+        let (mut controller, _, user_tx, _, clock) = setup_with(COOLDOWNS)?;
 
         // User changes brightness to a desired value
         user_tx.send(33)?;
         controller.process(ALS_DIM, 66);
+        clock.advance(FRAME);
         user_tx.send(33)?;
         controller.process(ALS_BRIGHT, 36);
+        clock.advance(FRAME);
         user_tx.send(35)?;
         controller.process(ALS_DARK, 16);
 
-        for i in 1..=PENDING_COOLDOWN_RESET {
+        let frames = COOLDOWNS.pending.as_millis() / FRAME.as_millis();
+        for i in 1..frames {
             // User doesn't change brightness anymore, so even if lux or luma change, we are in cooldown period
-            controller.process(ALS_BRIGHT, i);
-            assert_eq!(PENDING_COOLDOWN_RESET - i, controller.pending_cooldown);
+            clock.advance(FRAME);
+            controller.process(ALS_BRIGHT, i as u8);
             assert_eq!(Some(Entry::new(ALS_DIM, 66, 35)), controller.pending);
         }
 
-        // One final process will trigger the learning
+        // Once the cooldown is over, the next frame triggers the learning
+        clock.advance(FRAME);
         controller.process(ALS_DARK, 17);
 
         assert_eq!(None, controller.pending);
-        assert_eq!(0, controller.pending_cooldown);
         assert_eq!(vec![Entry::new(ALS_DIM, 66, 35)], controller.data.entries);
 
         Ok(())
     }
 
+    #[test]
+    fn test_flush_learns_pending_change() -> Result<(), Box<dyn Error>> {
+        let (mut controller, user_tx, _) = setup()?;
+
+        // Nothing to learn yet
+        controller.flush();
+        assert_eq!(Vec::<Entry>::new(), controller.data.entries);
+
+        // User changes brightness, and no more frames come to finish the cooldown
+        user_tx.send(33)?;
+        controller.process(ALS_DIM, 66);
+        controller.flush();
+
+        assert_eq!(None, controller.pending);
+        assert_eq!(vec![Entry::new(ALS_DIM, 66, 33)], controller.data.entries);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_learns_after_configured_cooldown() -> Result<(), Box<dyn Error>> {
+        let cooldowns = Cooldowns {
+            pending: Duration::from_secs(2),
+            next_als: Duration::ZERO,
+        };
+        let (mut controller, _als_tx, user_tx, _prediction_rx, clock) = setup_with(cooldowns)?;
+
+        user_tx.send(33)?;
+        controller.process(ALS_DIM, 66);
+        // No matter how many frames come meanwhile
+        for _ in 0..100 {
+            controller.process(ALS_DIM, 66);
+        }
+        clock.advance(Duration::from_millis(1900));
+        controller.process(ALS_DIM, 66);
+        assert_eq!(Some(Entry::new(ALS_DIM, 66, 33)), controller.pending);
+
+        clock.advance(FRAME);
+        controller.process(ALS_DIM, 66);
+        assert_eq!(None, controller.pending);
+        assert_eq!(vec![Entry::new(ALS_DIM, 66, 33)], controller.data.entries);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_adjust_switches_als_after_configured_cooldown() -> Result<(), Box<dyn Error>> {
+        let cooldowns = Cooldowns {
+            pending: Duration::ZERO,
+            next_als: Duration::from_secs(2),
+        };
+        let (mut controller, als_tx, _user_tx, _prediction_rx, clock) = setup_with(cooldowns)?;
+        controller.adjust(50);
+        assert_eq!(Some(ALS_BRIGHT.to_string()), controller.last_als);
+
+        // A new ALS profile must last for the configured time...
+        als_tx.send(ALS_DIM.to_string())?;
+        for _ in 0..4 {
+            controller.adjust(50);
+            clock.advance(Duration::from_millis(500));
+            assert_eq!(Some(ALS_BRIGHT.to_string()), controller.last_als);
+        }
+
+        // ... before it is used
+        controller.adjust(50);
+        assert_eq!(Some(ALS_DIM.to_string()), controller.last_als);
+
+        // The same profile sent again is not switched to again
+        als_tx.send(ALS_DIM.to_string())?;
+        controller.adjust(50);
+        assert_eq!(None, controller.next_als);
+
+        // Nor is a profile that goes back before the cooldown ends
+        als_tx.send(ALS_BRIGHT.to_string())?;
+        controller.adjust(50);
+        als_tx.send(ALS_DIM.to_string())?;
+        controller.adjust(50);
+        clock.advance(Duration::from_secs(2));
+        controller.adjust(50);
+        assert_eq!(None, controller.next_als);
+        assert_eq!(Some(ALS_DIM.to_string()), controller.last_als);
+
+        Ok(())
+    }
+
     // If user configured brightness value in certain conditions (amount of light around, screen contents),
     // how changes in environment or screen contents can affect the desired brightness level:
     //
@@ -394,4 +892,273 @@ mod tests {
         assert_eq!(43, prediction_rx.try_recv()?);
         Ok(())
     }
+
+    #[test]
+    fn test_predict_from_neighbouring_profiles() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.config.profile_fallback_discount = 0.5;
+        controller.data.entries =
+            vec![Entry::new(ALS_DARK, 50, 20), Entry::new(ALS_BRIGHT, 50, 60)];
+
+        controller.predict(ALS_DIM, 50);
+
+        assert_eq!(40, prediction_rx.try_recv()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_from_neighbouring_profiles_prefers_closer_ones() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.config.profile_fallback_discount = 0.5;
+        controller.data.profiles = Profiles::new(
+            [ALS_DARK, ALS_DIM, ALS_BRIGHT, "outdoors"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        );
+        controller.data.entries = vec![
+            Entry::new(ALS_DARK, 50, 20),
+            Entry::new("outdoors", 50, 110),
+        ];
+
+        // Dark profile is one step away, outdoors is two steps away
+        controller.predict(ALS_DIM, 50);
+
+        assert_eq!(50, prediction_rx.try_recv()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_learn_enforce_monotonic_fixes_older_entries() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, _) = setup()?;
+        controller.config.enforce_monotonic = true;
+        controller.data.entries = vec![
+            Entry::new(ALS_DIM, 10, 50),
+            Entry::new(ALS_DIM, 30, 70),
+            Entry::new(ALS_DIM, 90, 20),
+        ];
+        controller.pending = Some(Entry::new(ALS_DIM, 60, 30));
+
+        controller.learn();
+
+        assert_eq!(
+            vec![
+                Entry::new(ALS_DIM, 10, 50),
+                Entry::new(ALS_DIM, 30, 50),
+                Entry::new(ALS_DIM, 60, 30),
+                Entry::new(ALS_DIM, 90, 20),
+            ],
+            controller.data.entries
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_enforce_monotonic() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.data.entries = vec![
+            Entry::new(ALS_DIM, 0, 100),
+            Entry::new(ALS_DIM, 10, 0),
+            Entry::new(ALS_DIM, 100, 0),
+        ];
+
+        let predictions = |controller: &mut Controller| {
+            (0..=100)
+                .map(|luma| {
+                    controller.predict(ALS_DIM, luma);
+                    prediction_rx.try_recv()
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        // Weighted interpolation gets pulled up by the far away point
+        let weighted = predictions(&mut controller)?;
+        assert!(weighted.windows(2).any(|w| w[1] > w[0]));
+
+        controller.config.enforce_monotonic = true;
+        let linear = predictions(&mut controller)?;
+        assert!(linear.windows(2).all(|w| w[1] <= w[0]));
+        assert_eq!(50, linear[5]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_decay_weight() {
+        assert_eq!(1.0, decay_weight(1000.0, None));
+        assert_eq!(1.0, decay_weight(10.0, Some(30)));
+        assert_eq!(1.0, decay_weight(30.0, Some(30)));
+        assert_eq!(0.5, decay_weight(45.0, Some(30)));
+        assert_eq!(0.0, decay_weight(60.0, Some(30)));
+        assert_eq!(0.0, decay_weight(90.0, Some(30)));
+    }
+
+    #[test]
+    fn test_learn_forgets_stale_entries() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, _) = setup()?;
+        controller.config.decay_days = Some(30);
+        let now = controller.clock.timestamp();
+        let days_ago = |days: u64| Some(now - days * 86400);
+        controller.data.entries = vec![
+            Entry {
+                learned_at: days_ago(10),
+                ..Entry::new(ALS_DARK, 10, 50)
+            },
+            Entry {
+                learned_at: days_ago(70),
+                ..Entry::new(ALS_BRIGHT, 10, 50)
+            },
+        ];
+        controller.pending = Some(Entry::new(ALS_DIM, 60, 30));
+
+        controller.learn();
+
+        assert_eq!(
+            vec![Entry::new(ALS_DARK, 10, 50), Entry::new(ALS_DIM, 60, 30)],
+            controller.data.entries
+        );
+        assert!(controller.data.entries[1].learned_at.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_prefers_recent_entries() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.config.decay_days = Some(30);
+        let now = controller.clock.timestamp();
+        let days_ago = |days: u64| Some(now - days * 86400);
+        controller.data.entries = vec![
+            Entry {
+                learned_at: days_ago(1),
+                ..Entry::new(ALS_DIM, 40, 30)
+            },
+            Entry {
+                learned_at: days_ago(54),
+                ..Entry::new(ALS_DIM, 60, 90)
+            },
+        ];
+
+        // Both entries are equally far, but the older one only has 20% of the weight
+        controller.predict(ALS_DIM, 50);
+
+        assert_eq!(40, prediction_rx.try_recv()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_learn_keeps_entries_of_other_workspaces() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, _) = setup()?;
+        let coding = Entry {
+            tag: Some("coding".to_string()),
+            ..Entry::new(ALS_DIM, 20, 30)
+        };
+        controller.data.entries = vec![coding.clone()];
+        controller.pending = Some(Entry {
+            tag: Some("browser".to_string()),
+            ..Entry::new(ALS_DIM, 20, 50)
+        });
+
+        controller.learn();
+
+        assert_eq!(2, controller.data.entries.len());
+        assert!(controller.data.entries.contains(&coding));
+        Ok(())
+    }
+
+    #[test]
+    fn test_predict_uses_workspace_entries() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, prediction_rx) = setup()?;
+        controller.data.entries = vec![
+            Entry {
+                tag: Some("coding".to_string()),
+                ..Entry::new(ALS_DIM, 20, 30)
+            },
+            Entry {
+                tag: Some("browser".to_string()),
+                ..Entry::new(ALS_DIM, 20, 50)
+            },
+        ];
+
+        controller.workspace = Some("browser".to_string());
+        controller.predict(ALS_DIM, 20);
+        assert_eq!(50, prediction_rx.try_recv()?);
+
+        // Nothing was learned on this workspace yet, use all entries
+        controller.workspace = Some("music".to_string());
+        controller.predict(ALS_DIM, 20);
+        assert_eq!(40, prediction_rx.try_recv()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_bucket_weights_blend_near_boundaries() {
+        let buckets = [7, 20];
+        let at = |hour: u32, minute: u32| time_bucket_weights(&buckets, hour * 60 + minute);
+
+        assert_eq!(vec![(7, 1.0)], at(12, 0));
+        assert_eq!(vec![(20, 1.0)], at(3, 0));
+        assert_eq!(vec![(20, 1.0)], at(6, 0));
+        assert_eq!(vec![(20, 0.75), (7, 0.25)], at(6, 30));
+        assert_eq!(vec![(7, 0.5), (20, 0.5)], at(20, 0));
+        assert_eq!(vec![(7, 0.25), (20, 0.75)], at(20, 30));
+
+        // Blending works across midnight too
+        assert_eq!(
+            vec![(7, 0.75), (0, 0.25)],
+            time_bucket_weights(&[0, 7], 23 * 60 + 30)
+        );
+    }
+
+    #[test]
+    fn test_predict_blends_time_buckets() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, _) = setup()?;
+        controller.config.time_buckets = vec![7, 20];
+        controller.data.entries = vec![
+            Entry {
+                time_bucket: Some(7),
+                ..Entry::new(ALS_DIM, 20, 80)
+            },
+            Entry {
+                time_bucket: Some(20),
+                ..Entry::new(ALS_DIM, 20, 20)
+            },
+        ];
+
+        assert_eq!(
+            Some(80),
+            controller.interpolate_profile_at(ALS_DIM, 20, 14 * 60)
+        );
+        assert_eq!(
+            Some(20),
+            controller.interpolate_profile_at(ALS_DIM, 20, 2 * 60)
+        );
+        assert_eq!(
+            Some(50),
+            controller.interpolate_profile_at(ALS_DIM, 20, 20 * 60)
+        );
+
+        // Entries learned in buckets that are no longer configured belong to the one containing them
+        controller.config.time_buckets = vec![6, 18];
+        assert_eq!(
+            Some(80),
+            controller.interpolate_profile_at(ALS_DIM, 20, 14 * 60)
+        );
+        assert_eq!(
+            Some(20),
+            controller.interpolate_profile_at(ALS_DIM, 20, 22 * 60)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_buckets_entries_by_clock_time() -> Result<(), Box<dyn Error>> {
+        let (mut controller, _, _) = setup()?;
+        controller.config.time_buckets = vec![7, 20];
+        let evening = Local.with_ymd_and_hms(2026, 1, 1, 21, 30, 0).unwrap();
+        controller.clock = Arc::new(clock::Manual::at(evening));
+
+        controller.process(ALS_DIM, 30);
+
+        assert_eq!(Some(20), controller.pending.unwrap().time_bucket);
+        Ok(())
+    }
 }
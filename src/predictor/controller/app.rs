@@ -0,0 +1,161 @@
+use crate::config::{App, AppMode};
+use crate::toplevel::Focus;
+use std::sync::mpsc::{Receiver, Sender};
+
+/// Applies per-application rules on top of another controller, depending on the focused window.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    prediction_tx: Sender<u64>,
+    focus_rx: Receiver<Option<Focus>>,
+    rules: Vec<App>,
+    mode: AppMode,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        if let Some(focus) = self.focus_rx.try_iter().last() {
+            let mode = find_mode(&self.rules, focus.as_ref());
+            if mode != self.mode {
+                log::debug!("Switching to {:?} mode for {:?}", mode, focus);
+                if let AppMode::Fixed { brightness } = mode {
+                    self.prediction_tx
+                        .send(brightness)
+                        .expect("Unable to send fixed brightness, channel is dead");
+                }
+                self.mode = mode;
+            }
+        }
+
+        if self.mode == AppMode::Normal {
+            self.inner.adjust(luma);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(
+        inner: Box<dyn super::Controller>,
+        prediction_tx: Sender<u64>,
+        focus_rx: Receiver<Option<Focus>>,
+        rules: Vec<App>,
+    ) -> Self {
+        Self {
+            inner,
+            prediction_tx,
+            focus_rx,
+            rules,
+            mode: AppMode::Normal,
+        }
+    }
+}
+
+fn find_mode(rules: &[App], focus: Option<&Focus>) -> AppMode {
+    focus
+        .and_then(|focus| {
+            rules.iter().find(|rule| {
+                focus.app_id.contains(&rule.app_id) && (!rule.fullscreen || focus.fullscreen)
+            })
+        })
+        .map_or(AppMode::Normal, |rule| rule.mode)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Controller as _;
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::sync::mpsc;
+
+    struct Recorder {
+        lumas: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl super::super::Controller for Recorder {
+        fn adjust(&mut self, luma: u8) {
+            self.lumas.borrow_mut().push(luma);
+        }
+    }
+
+    fn rule(app_id: &str, fullscreen: bool, mode: AppMode) -> App {
+        App {
+            app_id: app_id.to_string(),
+            fullscreen,
+            mode,
+        }
+    }
+
+    fn focus(app_id: &str, fullscreen: bool) -> Option<Focus> {
+        Some(Focus {
+            app_id: app_id.to_string(),
+            fullscreen,
+        })
+    }
+
+    #[test]
+    fn test_find_mode() {
+        let rules = vec![
+            rule("mpv", true, AppMode::Freeze),
+            rule("steam_app", false, AppMode::Fixed { brightness: 40 }),
+        ];
+
+        assert_eq!(AppMode::Normal, find_mode(&rules, None));
+        assert_eq!(
+            AppMode::Normal,
+            find_mode(&rules, focus("firefox", true).as_ref())
+        );
+        assert_eq!(
+            AppMode::Normal,
+            find_mode(&rules, focus("mpv", false).as_ref())
+        );
+        assert_eq!(
+            AppMode::Freeze,
+            find_mode(&rules, focus("mpv", true).as_ref())
+        );
+        assert_eq!(
+            AppMode::Fixed { brightness: 40 },
+            find_mode(&rules, focus("steam_app_1234", false).as_ref())
+        );
+    }
+
+    #[test]
+    fn test_adjust_applies_rules_of_focused_app() {
+        let lumas = Rc::new(RefCell::new(vec![]));
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (focus_tx, focus_rx) = mpsc::channel();
+        let mut controller = Controller::new(
+            Box::new(Recorder {
+                lumas: lumas.clone(),
+            }),
+            prediction_tx,
+            focus_rx,
+            vec![
+                rule("mpv", false, AppMode::Freeze),
+                rule("game", false, AppMode::Fixed { brightness: 40 }),
+            ],
+        );
+
+        controller.adjust(10);
+
+        focus_tx.send(focus("mpv", false)).unwrap();
+        controller.adjust(20);
+
+        focus_tx.send(focus("game", false)).unwrap();
+        controller.adjust(30);
+        controller.adjust(40);
+
+        focus_tx.send(None).unwrap();
+        controller.adjust(50);
+
+        assert_eq!(vec![10, 50], *lumas.borrow());
+        assert_eq!(vec![40], prediction_rx.try_iter().collect::<Vec<_>>());
+    }
+}
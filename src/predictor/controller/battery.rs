@@ -0,0 +1,204 @@
+use super::INITIAL_TIMEOUT_SECS;
+use crate::brightness::BASIS_POINTS;
+use crate::config::Battery;
+use std::sync::mpsc::{Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Adjusts predictions of another controller while on battery, which keeps learning what
+/// brightness would be on AC power.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    /// Brightness set by user, passed on to the inner controller.
+    user_rx: Receiver<u64>,
+    inner_user_tx: Sender<u64>,
+    /// Predictions of the inner controller, passed on to the brightness controller.
+    inner_prediction_rx: Receiver<u64>,
+    prediction_tx: Sender<u64>,
+    battery_rx: Receiver<bool>,
+    config: Battery,
+    on_battery: bool,
+    initial_brightness: bool,
+    prediction: Option<u64>,
+    last_frame: Option<Instant>,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        if !self.initial_brightness {
+            // Inner controllers might wait for the initial value before the first frame, and
+            // brightness controller is expected to send it asap
+            let brightness = self
+                .user_rx
+                .recv_timeout(Duration::from_secs(INITIAL_TIMEOUT_SECS))
+                .unwrap_or_else(|e| {
+                    panic!("Did not receive initial brightness value in time: {e:?}")
+                });
+            self.send_user(brightness);
+            self.initial_brightness = true;
+        }
+
+        let mut changed = false;
+        if let Some(on_battery) = self.battery_rx.try_iter().last() {
+            changed = on_battery != self.on_battery;
+            self.on_battery = on_battery;
+        }
+
+        for brightness in self.user_rx.try_iter().collect::<Vec<_>>() {
+            self.send_user(brightness);
+        }
+
+        self.inner.adjust(luma);
+
+        if let Some(prediction) = self.inner_prediction_rx.try_iter().last() {
+            changed = true;
+            self.prediction = Some(prediction);
+        }
+
+        if let (true, Some(prediction)) = (changed, self.prediction) {
+            self.prediction_tx
+                .send(self.adjusted(prediction))
+                .expect("Unable to send predicted brightness value, channel is dead");
+        }
+
+        self.throttle();
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(
+        inner: Box<dyn super::Controller>,
+        user_rx: Receiver<u64>,
+        inner_user_tx: Sender<u64>,
+        inner_prediction_rx: Receiver<u64>,
+        prediction_tx: Sender<u64>,
+        battery_rx: Receiver<bool>,
+        config: Battery,
+    ) -> Self {
+        Self {
+            inner,
+            user_rx,
+            inner_user_tx,
+            inner_prediction_rx,
+            prediction_tx,
+            battery_rx,
+            config,
+            on_battery: false,
+            initial_brightness: false,
+            prediction: None,
+            last_frame: None,
+        }
+    }
+
+    fn adjusted(&self, prediction: u64) -> u64 {
+        if !self.on_battery {
+            return prediction;
+        }
+        prediction
+            .saturating_add_signed(self.config.brightness_offset)
+            .min(self.config.max_brightness.unwrap_or(BASIS_POINTS))
+            .min(BASIS_POINTS)
+    }
+
+    /// Passes on what brightness would be on AC power, so that it can be learned as usual.
+    fn send_user(&self, brightness: u64) {
+        let brightness = if self.on_battery {
+            brightness
+                .saturating_add_signed(-self.config.brightness_offset)
+                .min(BASIS_POINTS)
+        } else {
+            brightness
+        };
+        self.inner_user_tx
+            .send(brightness)
+            .expect("Unable to send new brightness value set by user, channel is dead");
+    }
+
+    /// Captures frames less often while on battery, by holding the capturer back.
+    fn throttle(&mut self) {
+        if let (true, Some(interval), Some(last_frame)) = (
+            self.on_battery,
+            self.config.capture_interval,
+            self.last_frame,
+        ) {
+            thread::sleep(interval.saturating_sub(last_frame.elapsed()));
+        }
+        self.last_frame = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Controller as _;
+    use super::*;
+    use std::sync::mpsc;
+
+    /// Predicts the last brightness set by user, plus one.
+    struct Echo {
+        user_rx: Receiver<u64>,
+        prediction_tx: Sender<u64>,
+    }
+
+    impl super::super::Controller for Echo {
+        fn adjust(&mut self, _: u8) {
+            if let Some(brightness) = self.user_rx.try_iter().last() {
+                self.prediction_tx.send(brightness + 1).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_adjusts_predictions_and_learns_without_adjustments_on_battery() {
+        let (user_tx, user_rx) = mpsc::channel();
+        let (inner_user_tx, inner_user_rx) = mpsc::channel();
+        let (inner_prediction_tx, inner_prediction_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (battery_tx, battery_rx) = mpsc::channel();
+        let inner = Echo {
+            user_rx: inner_user_rx,
+            prediction_tx: inner_prediction_tx,
+        };
+        let config = Battery {
+            brightness_offset: -2000,
+            max_brightness: Some(5000),
+            capture_interval: None,
+        };
+        let mut controller = Controller::new(
+            Box::new(inner),
+            user_rx,
+            inner_user_tx,
+            inner_prediction_rx,
+            prediction_tx,
+            battery_rx,
+            config,
+        );
+
+        // On AC power, everything is passed on as is
+        user_tx.send(3000).unwrap();
+        controller.adjust(0);
+        assert_eq!(vec![3001], prediction_rx.try_iter().collect::<Vec<_>>());
+
+        // On battery, the last prediction is adjusted right away...
+        battery_tx.send(true).unwrap();
+        controller.adjust(0);
+        assert_eq!(vec![1001], prediction_rx.try_iter().collect::<Vec<_>>());
+
+        // ... and brightness set by user is learned as it would be on AC power, predictions
+        // are capped
+        user_tx.send(6000).unwrap();
+        controller.adjust(0);
+        assert_eq!(vec![5000], prediction_rx.try_iter().collect::<Vec<_>>());
+
+        // Nothing changed, nothing to send
+        controller.adjust(0);
+        assert_eq!(0, prediction_rx.try_iter().count());
+    }
+}
@@ -0,0 +1,56 @@
+use std::sync::mpsc::Sender;
+
+/// Shares luma values of a single capture session with outputs that mirror it.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    luma_txs: Vec<Sender<u8>>,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        // Mirroring outputs that failed to start are simply dropped
+        self.luma_txs.retain(|tx| tx.send(luma).is_ok());
+        self.inner.adjust(luma);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(inner: Box<dyn super::Controller>, luma_txs: Vec<Sender<u8>>) -> Self {
+        Self { inner, luma_txs }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Controller as _;
+    use super::*;
+    use std::sync::mpsc;
+
+    struct Noop;
+
+    impl super::super::Controller for Noop {
+        fn adjust(&mut self, _luma: u8) {}
+    }
+
+    #[test]
+    fn test_adjust_forwards_luma_to_alive_mirrors() {
+        let (tx1, rx1) = mpsc::channel();
+        let (tx2, rx2) = mpsc::channel();
+        let mut controller = Controller::new(Box::new(Noop), vec![tx1, tx2]);
+
+        controller.adjust(10);
+        drop(rx2);
+        controller.adjust(20);
+
+        assert_eq!(vec![10, 20], rx1.try_iter().collect::<Vec<_>>());
+        assert_eq!(1, controller.luma_txs.len());
+    }
+}
@@ -0,0 +1,38 @@
+use crate::als::glow;
+
+/// Shares luma values of the output, to estimate how much its screen lights the room.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    output_name: String,
+    /// Once it's not, the screen is assumed to show average content, as with `capturer="none"`.
+    captured: bool,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        if self.captured {
+            glow::set_luma(&self.output_name, luma);
+        }
+        self.inner.adjust(luma);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.captured = false;
+        glow::forget_luma(&self.output_name);
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(inner: Box<dyn super::Controller>, output_name: &str) -> Self {
+        Self {
+            inner,
+            output_name: output_name.to_string(),
+            captured: true,
+        }
+    }
+}
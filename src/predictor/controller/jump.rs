@@ -0,0 +1,81 @@
+use crate::brightness::Command;
+use std::sync::mpsc::Sender;
+
+/// Tells the brightness controller to get a head start towards the next prediction when luma
+/// changes suddenly, e.g. a white page opens in a dark room.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    command_tx: Sender<Command>,
+    /// Smallest change of luma between frames that counts as a jump.
+    delta: u8,
+    head_start: u8,
+    last_luma: Option<u8>,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        // Predict first, so that the prediction is there once the command is received
+        self.inner.adjust(luma);
+        if self
+            .last_luma
+            .is_some_and(|last_luma| last_luma.abs_diff(luma) >= self.delta)
+        {
+            // The brightness controller only stops with wluma
+            let _ = self.command_tx.send(Command::LumaJump(self.head_start));
+        }
+        self.last_luma = Some(luma);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(
+        inner: Box<dyn super::Controller>,
+        command_tx: Sender<Command>,
+        delta: u8,
+        head_start: u8,
+    ) -> Self {
+        Self {
+            inner,
+            command_tx,
+            delta,
+            head_start,
+            last_luma: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Controller as _;
+    use super::*;
+    use std::sync::mpsc;
+
+    struct Noop;
+
+    impl super::super::Controller for Noop {
+        fn adjust(&mut self, _luma: u8) {}
+    }
+
+    #[test]
+    fn test_adjust_sends_command_on_luma_jump() {
+        let (command_tx, command_rx) = mpsc::channel();
+        let mut controller = Controller::new(Box::new(Noop), command_tx, 30, 50);
+
+        for luma in [10, 30, 70, 75, 40, 39] {
+            controller.adjust(luma);
+        }
+
+        assert_eq!(
+            vec![Command::LumaJump(50), Command::LumaJump(50)],
+            command_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,12 +1,14 @@
-use super::{
-    Controller as _, INITIAL_TIMEOUT_SECS, NEXT_ALS_COOLDOWN_RESET, PENDING_COOLDOWN_RESET,
-};
-use crate::predictor::data::Entry;
+use super::{Context, Controller as _, Cooldowns, INITIAL_TIMEOUT_SECS};
+use crate::clock::Clock;
+use crate::config::Predictor;
+use crate::control::{self, Update};
+use crate::predictor::data::{Entry, Profiles};
 use itertools::Itertools;
 use std::{
     collections::HashMap,
     sync::mpsc::{Receiver, Sender},
-    time::Duration,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 pub struct Controller {
@@ -15,11 +17,36 @@ pub struct Controller {
     als_rx: Receiver<String>,
     last_brightness: Option<u64>,
     thresholds: HashMap<String, HashMap<u8, u64>>,
+    /// Profiles to blend thresholds of, for profiles that have none.
+    blend_profiles: Option<Profiles>,
     pre_reduction_brightness: Option<u64>,
-    pending_cooldown: u8,
+    /// Learning waits until then, after the last brightness change made by user.
+    pending_until: Instant,
     last_als: Option<String>,
     next_als: Option<String>,
-    next_als_cooldown: u8,
+    /// The next ALS profile is switched to at this time, unless it changes again.
+    next_als_at: Instant,
+    cooldowns: Cooldowns,
+    clock: Arc<dyn Clock>,
+}
+
+pub fn build(predictor: Predictor, context: Context) -> Box<dyn super::Controller> {
+    let Predictor::Manual {
+        thresholds,
+        blend_profiles,
+    } = predictor
+    else {
+        unreachable!("Registered for the manual predictor only");
+    };
+    Box::new(Controller::new(
+        context.prediction_tx,
+        context.user_rx,
+        context.als_rx,
+        thresholds,
+        blend_profiles.then_some(context.profiles),
+        context.cooldowns,
+        context.clock,
+    ))
 }
 
 impl super::Controller for Controller {
@@ -35,22 +62,19 @@ impl super::Controller for Controller {
                 );
         }
 
-        match self.als_rx.try_iter().last() {
-            new_als @ Some(_) if self.next_als != new_als => {
-                self.next_als = new_als;
-                self.next_als_cooldown = NEXT_ALS_COOLDOWN_RESET;
-            }
-            _ if self.next_als_cooldown > 1 => {
-                self.next_als_cooldown -= 1;
-            }
-            _ if self.next_als_cooldown == 1 => {
-                self.next_als_cooldown = 0;
-                self.last_als = self.next_als.take();
-            }
-            _ => {}
+        let now = self.clock.now();
+        let new_als = self.als_rx.try_iter().last();
+        if new_als.is_some() && self.next_als != new_als {
+            self.next_als = new_als;
+            self.next_als_at = now + self.cooldowns.next_als;
+        }
+
+        if now >= self.next_als_at && self.next_als.is_some() {
+            self.last_als = self.next_als.take();
         }
 
         let lux = &self.last_als.clone().expect("ALS value must be known");
+        control::report(Update::Profile(lux.clone()));
 
         self.process(lux, luma);
     }
@@ -62,6 +86,9 @@ impl Controller {
         user_rx: Receiver<u64>,
         als_rx: Receiver<String>,
         thresholds: HashMap<String, HashMap<u8, u64>>,
+        blend_profiles: Option<Profiles>,
+        cooldowns: Cooldowns,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         Self {
             prediction_tx,
@@ -69,11 +96,14 @@ impl Controller {
             als_rx,
             last_brightness: None,
             thresholds,
+            blend_profiles,
             pre_reduction_brightness: None,
-            pending_cooldown: 0,
+            pending_until: clock.now(),
             last_als: None,
             next_als: None,
-            next_als_cooldown: 0,
+            next_als_at: clock.now(),
+            cooldowns,
+            clock,
         }
     }
 
@@ -98,9 +128,9 @@ impl Controller {
 
         if self.last_brightness != Some(current_brightness) {
             self.process_brightness_change(current_brightness, lux, luma);
-            self.pending_cooldown = PENDING_COOLDOWN_RESET;
-        } else if self.pending_cooldown > 0 {
-            self.pending_cooldown -= 1;
+            self.pending_until = self.clock.now() + self.cooldowns.pending;
+        } else if self.clock.now() < self.pending_until {
+            // The user might still be adjusting it
         } else {
             self.predict(current_brightness, lux, luma);
         }
@@ -121,21 +151,52 @@ impl Controller {
     }
 
     fn get_brightness_reduction(&mut self, current_brightness: u64, lux: &str, luma: u8) -> u64 {
+        let brightness_reduction = match self.thresholds.contains_key(lux) {
+            true => self.percentage_reduction(lux, luma),
+            false => self.blended_percentage_reduction(lux, luma),
+        };
+
+        (current_brightness as f64 * brightness_reduction.unwrap_or(0) as f64 / 100.) as u64
+    }
+
+    fn percentage_reduction(&self, lux: &str, luma: u8) -> Option<u64> {
         let entries = self
             .thresholds
             .get(lux)
             .unwrap_or(&HashMap::new())
             .iter()
-            .map(|(&luma, &percentage_reduction)| Entry {
-                lux: lux.to_string(),
-                luma,
-                brightness: percentage_reduction,
-            })
+            .map(|(&luma, &percentage_reduction)| Entry::new(lux, luma, percentage_reduction))
             .collect_vec();
 
-        let brightness_reduction = self.interpolate(&entries, lux, luma);
+        self.interpolate(&entries, lux, luma)
+    }
 
-        (current_brightness as f64 * brightness_reduction.unwrap_or(0) as f64 / 100.) as u64
+    /// Reduction between the ones of the closest darker and brighter profiles that have
+    /// thresholds, the closer profile has more say.
+    fn blended_percentage_reduction(&self, lux: &str, luma: u8) -> Option<u64> {
+        let profiles = self.blend_profiles.as_ref()?;
+        let closest = |candidates: &mut dyn Iterator<Item = &str>| {
+            let (index, profile) = candidates
+                .enumerate()
+                .find(|(_, profile)| self.thresholds.contains_key(*profile))?;
+            Some((
+                (index + 1) as f64,
+                self.percentage_reduction(profile, luma)?,
+            ))
+        };
+
+        match (
+            closest(&mut profiles.darker(lux)),
+            closest(&mut profiles.brighter(lux)),
+        ) {
+            (Some((darker_steps, darker)), Some((brighter_steps, brighter))) => {
+                let blended = (darker as f64 * brighter_steps + brighter as f64 * darker_steps)
+                    / (darker_steps + brighter_steps);
+                Some(blended.round() as u64)
+            }
+            (Some((_, reduction)), None) | (None, Some((_, reduction))) => Some(reduction),
+            (None, None) => None,
+        }
     }
 
     fn process_brightness_change(&mut self, new_brightness: u64, lux: &str, luma: u8) {
@@ -148,14 +209,34 @@ impl Controller {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock;
     use std::collections::HashMap;
     use std::error::Error;
     use std::sync::mpsc;
 
     const ALS_UNKNOWN: &str = "not-configured-threshold";
     const ALS_DIM: &str = "dim";
+    const ALS_BRIGHT: &str = "bright";
+    const COOLDOWNS: Cooldowns = Cooldowns {
+        pending: Duration::from_millis(1500),
+        next_als: Duration::from_millis(1500),
+    };
+    const FRAME: Duration = Duration::from_millis(100);
+
+    type Setup = (
+        Controller,
+        Sender<String>,
+        Sender<u64>,
+        Receiver<u64>,
+        clock::Manual,
+    );
 
     fn setup() -> Result<(Controller, Sender<u64>, Receiver<u64>), Box<dyn Error>> {
+        let (controller, _, user_tx, prediction_rx, _) = setup_with(COOLDOWNS)?;
+        Ok((controller, user_tx, prediction_rx))
+    }
+
+    fn setup_with(cooldowns: Cooldowns) -> Result<Setup, Box<dyn Error>> {
         let (als_tx, als_rx) = mpsc::channel();
         let (user_tx, user_rx) = mpsc::channel();
         let (prediction_tx, prediction_rx) = mpsc::channel();
@@ -169,8 +250,17 @@ mod tests {
         .into_iter()
         .collect();
 
-        let controller = Controller::new(prediction_tx, user_rx, als_rx, thresholds);
-        Ok((controller, user_tx, prediction_rx))
+        let clock = clock::Manual::new();
+        let controller = Controller::new(
+            prediction_tx,
+            user_rx,
+            als_rx,
+            thresholds,
+            None,
+            cooldowns,
+            Arc::new(clock.clone()),
+        );
+        Ok((controller, als_tx, user_tx, prediction_rx, clock))
     }
 
     #[test]
@@ -215,6 +305,42 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_brightness_reduction_blended_from_closest_profiles() {
+        let (prediction_tx, _) = mpsc::channel();
+        let (_, user_rx) = mpsc::channel();
+        let (_, als_rx) = mpsc::channel();
+        let thresholds: HashMap<String, HashMap<u8, u64>> = [
+            ("night".to_string(), [(0, 0), (100, 60)].into()),
+            ("normal".to_string(), [(0, 0), (100, 30)].into()),
+        ]
+        .into();
+        let profiles = Profiles::new(
+            ["night", "dark", "dim", "normal", "bright"]
+                .map(String::from)
+                .to_vec(),
+        );
+        let mut controller = Controller::new(
+            prediction_tx,
+            user_rx,
+            als_rx,
+            thresholds,
+            Some(profiles),
+            COOLDOWNS,
+            Arc::new(clock::System),
+        );
+
+        // "dark" is closer to "night", "dim" to "normal"
+        assert_eq!(controller.get_brightness_reduction(100, "dark", 100), 50);
+        assert_eq!(controller.get_brightness_reduction(100, "dim", 100), 40);
+        // Only darker profiles have thresholds
+        assert_eq!(controller.get_brightness_reduction(100, "bright", 100), 30);
+        assert_eq!(
+            controller.get_brightness_reduction(100, ALS_UNKNOWN, 100),
+            0
+        );
+    }
+
     #[test]
     fn test_change_in_luma() -> Result<(), Box<dyn Error>> {
         let (mut controller, user_tx, prediction_rx) = setup()?;
@@ -235,7 +361,7 @@ mod tests {
 
     #[test]
     fn test_change_in_brightness_by_user() -> Result<(), Box<dyn Error>> {
-        let (mut controller, user_tx, prediction_rx) = setup()?;
+        let (mut controller, _, user_tx, prediction_rx, clock) = setup_with(COOLDOWNS)?;
 
         // Initial brightness is used to predict right away
         user_tx.send(100)?;
@@ -244,18 +370,62 @@ mod tests {
 
         // Consequent user change causes prediction only after cooldown
         user_tx.send(123)?;
-        for i in 0..=PENDING_COOLDOWN_RESET {
+        controller.process(ALS_DIM, 0);
+        assert_eq!(clock.now() + COOLDOWNS.pending, controller.pending_until);
+        let frames = COOLDOWNS.pending.as_millis() / FRAME.as_millis();
+        for i in 1..frames {
             // User doesn't change brightness anymore, so even if lux or luma change, we are in cooldown period
-            controller.process(ALS_DIM, i);
-            assert_eq!(PENDING_COOLDOWN_RESET - i, controller.pending_cooldown);
+            clock.advance(FRAME);
+            controller.process(ALS_DIM, i as u8);
             assert!(prediction_rx.try_recv().is_err());
         }
 
-        // One final call will generate the actual prediction
+        // Once the cooldown is over, the next call will generate the actual prediction
+        clock.advance(FRAME);
         controller.process(ALS_DIM, 50);
-        assert_eq!(0, controller.pending_cooldown);
         assert_eq!(87, prediction_rx.recv()?);
 
         Ok(())
     }
+
+    #[test]
+    fn test_als_switches_after_configured_cooldown() -> Result<(), Box<dyn Error>> {
+        let cooldowns = Cooldowns {
+            pending: Duration::ZERO,
+            next_als: Duration::from_secs(2),
+        };
+        let (mut controller, als_tx, _user_tx, _prediction_rx, clock) = setup_with(cooldowns)?;
+        controller.adjust(50);
+        assert_eq!(Some(ALS_DIM.to_string()), controller.last_als);
+
+        // A new ALS profile must last for the configured time...
+        als_tx.send(ALS_BRIGHT.to_string())?;
+        for _ in 0..4 {
+            controller.adjust(50);
+            clock.advance(Duration::from_millis(500));
+            assert_eq!(Some(ALS_DIM.to_string()), controller.last_als);
+        }
+
+        // ... before it is used
+        controller.adjust(50);
+        assert_eq!(Some(ALS_BRIGHT.to_string()), controller.last_als);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_als_switches_right_away_without_cooldown() -> Result<(), Box<dyn Error>> {
+        let cooldowns = Cooldowns {
+            pending: Duration::ZERO,
+            next_als: Duration::ZERO,
+        };
+        let (mut controller, als_tx, _user_tx, _prediction_rx, _) = setup_with(cooldowns)?;
+        controller.adjust(50);
+
+        als_tx.send(ALS_BRIGHT.to_string())?;
+        controller.adjust(50);
+        assert_eq!(Some(ALS_BRIGHT.to_string()), controller.last_als);
+
+        Ok(())
+    }
 }
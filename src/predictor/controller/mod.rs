@@ -1,55 +1,233 @@
-use super::data::Entry;
+use super::data::{Entry, Profiles};
+use crate::clock::Clock;
+use crate::config::{Predictor, PredictorTiming};
 use itertools::Itertools;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub mod adaptive;
+pub mod app;
+pub mod battery;
+pub mod fanout;
+pub mod glow;
+pub mod jump;
 pub mod manual;
+pub mod record;
+pub mod report;
+pub mod scene;
+pub mod schedule;
+pub mod screen_off;
+pub mod shutdown;
+pub mod stats;
 
 const INITIAL_TIMEOUT_SECS: u64 = 5;
-const PENDING_COOLDOWN_RESET: u8 = 15;
-const NEXT_ALS_COOLDOWN_RESET: u8 = 15;
+
+/// Builds a predictor from its config, which is always of the kind it's registered for.
+pub type Build = fn(Predictor, Context) -> Box<dyn Controller>;
+
+/// Predictors by the name of their config section, add new ones here.
+const PREDICTORS: &[(&str, Build)] = &[
+    ("adaptive", adaptive::build),
+    ("manual", manual::build),
+    ("schedule", schedule::build),
+];
+
+/// Everything a predictor might need, besides its config.
+pub struct Context<'a> {
+    pub prediction_tx: Sender<u64>,
+    pub user_rx: Receiver<u64>,
+    pub als_rx: Receiver<String>,
+    pub workspace_rx: Receiver<Option<String>>,
+    /// Whether learned data is loaded and saved, rather than only kept in memory.
+    pub stateful: bool,
+    pub output_name: &'a str,
+    pub max_brightness: u64,
+    pub profiles: Profiles,
+    pub cooldowns: Cooldowns,
+    /// Cooldowns are measured with it.
+    pub clock: Arc<dyn Clock>,
+    /// Learned data to start from, instead of the saved one.
+    pub entries: Option<Vec<Entry>>,
+}
+
+/// Builds the configured predictor with the registered [`Build`] function.
+pub fn build(predictor: Predictor, context: Context) -> Box<dyn Controller> {
+    let name = predictor.name();
+    let (_, build) = PREDICTORS
+        .iter()
+        .find(|(registered, _)| *registered == name)
+        .unwrap_or_else(|| panic!("Predictor '{}' is not registered", name));
+    build(predictor, context)
+}
+
+/// Cooldowns measured with a [`Clock`], so that they don't depend on how often frames come.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cooldowns {
+    /// How long to wait after the last brightness change made by user.
+    pub pending: Duration,
+    /// How long a new ALS profile must last, before switching to it.
+    pub next_als: Duration,
+}
+
+impl Cooldowns {
+    pub fn new(timing: PredictorTiming) -> Self {
+        Self {
+            pending: Duration::from_secs_f64(timing.pending_cooldown_secs),
+            next_als: Duration::from_secs_f64(timing.als_cooldown_secs),
+        }
+    }
+}
 
 pub trait Controller {
     fn adjust(&mut self, luma: u8);
 
+    /// Learns whatever is still pending, because no more frames will come.
+    fn flush(&mut self) {}
+
+    /// Screen contents can't be captured any more, so luma only stands in for them from now on.
+    fn uncaptured(&mut self) {}
+
     fn interpolate(&self, entries: &[Entry], lux: &str, luma: u8) -> Option<u64> {
+        self.interpolate_weighted(entries, lux, luma, &|_| 1.0)
+    }
+
+    /// Same as [`Self::interpolate`], but entries with lower weight have less influence.
+    fn interpolate_weighted(
+        &self,
+        entries: &[Entry],
+        lux: &str,
+        luma: u8,
+        weight: &dyn Fn(&Entry) -> f64,
+    ) -> Option<u64> {
         let points = entries
             .iter()
             .filter(|e| e.lux == lux)
             .map(|entry| {
                 let distance = (luma as f64 - entry.luma as f64).abs();
-                (entry.brightness as f64, distance)
+                (entry.brightness as f64, distance, weight(entry))
             })
+            .filter(|p| p.2 > 0.0)
             .collect_vec();
 
         if points.is_empty() {
             return None;
         }
 
-        let points = points
-            .iter()
-            .enumerate()
-            .map(|(i, p)| {
-                let other_distances: f64 = points[0..i]
-                    .iter()
-                    .chain(&points[i + 1..])
-                    .map(|p| p.1)
-                    .product();
-                (p.0, p.1, other_distances)
-            })
-            .collect_vec();
+        // Known conditions win, there might be several of them when entries are combined from
+        // different sources (e.g. workspaces)
+        let exact = points.iter().filter(|p| p.1 == 0.0).collect_vec();
+        if !exact.is_empty() {
+            let weights: f64 = exact.iter().map(|p| p.2).sum();
+            let prediction = exact.iter().map(|p| p.0 * p.2).sum::<f64>() / weights;
+            return Some(prediction as u64);
+        }
 
-        let distance_denominator: f64 = points
-            .iter()
-            .map(|p| p.1)
-            .combinations(points.len() - 1)
-            .map(|c| c.iter().product::<f64>())
-            .sum();
+        // Inverse distance weighting, closer entries have more say. Dividing by the distance
+        // keeps weights in range no matter how many entries there are, unlike multiplying by
+        // the distances to all the other entries, which gives the same proportions
+        let weights = points.iter().map(|p| p.2 / p.1).collect_vec();
+        let denominator: f64 = weights.iter().sum();
 
         let prediction = points
             .iter()
-            .map(|p| p.0 * p.2 / distance_denominator)
+            .zip(&weights)
+            .map(|(p, weight)| p.0 * weight / denominator)
             .sum::<f64>() as u64;
 
         Some(prediction)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_registered_predictors() {
+        use crate::config::AdaptivePredictor;
+        use std::sync::mpsc;
+
+        let predictors = [
+            Predictor::Adaptive(AdaptivePredictor {
+                profile_fallback_discount: 0.5,
+                enforce_monotonic: false,
+                decay_days: None,
+                per_workspace: false,
+                time_buckets: vec![],
+                compact_luma_epsilon: 0,
+                data_group: None,
+            }),
+            Predictor::Manual {
+                thresholds: Default::default(),
+                blend_profiles: false,
+            },
+            Predictor::Schedule {
+                points: vec![(0, 5000)],
+            },
+        ];
+
+        assert!(PREDICTORS.iter().map(|(name, _)| name).all_unique());
+        for predictor in predictors {
+            let context = Context {
+                prediction_tx: mpsc::channel().0,
+                user_rx: mpsc::channel().1,
+                als_rx: mpsc::channel().1,
+                workspace_rx: mpsc::channel().1,
+                stateful: false,
+                output_name: "eDP-1",
+                max_brightness: 100,
+                profiles: Profiles::new(vec!["dim".to_string()]),
+                cooldowns: Cooldowns {
+                    pending: Duration::ZERO,
+                    next_als: Duration::ZERO,
+                },
+                clock: Arc::new(crate::clock::System),
+                entries: None,
+            };
+            build(predictor, context);
+        }
+    }
+
+    struct Noop;
+
+    impl Controller for Noop {
+        fn adjust(&mut self, _: u8) {}
+    }
+
+    /// Entries for every odd luma, several times over, so that luma 50 is right in the middle.
+    fn many_entries(brightness: impl Fn(u8) -> u64) -> Vec<Entry> {
+        (0..500)
+            .map(|i| {
+                let luma = (i % 50) as u8 * 2 + 1;
+                Entry::new("dim", luma, brightness(luma))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_interpolate_many_entries_with_same_brightness() {
+        let entries = many_entries(|_| 5000);
+
+        for luma in [0, 50, 100] {
+            let prediction = Noop.interpolate(&entries, "dim", luma).unwrap();
+            assert!(prediction.abs_diff(5000) <= 1, "{} at {}", prediction, luma);
+        }
+    }
+
+    #[test]
+    fn test_interpolate_many_entries_stays_within_learned_range() {
+        let entries = many_entries(|luma| 10000 - luma as u64 * 100);
+
+        let prediction = Noop.interpolate(&entries, "dim", 50).unwrap();
+        assert!(prediction.abs_diff(5000) <= 1, "{}", prediction);
+
+        // Closer entries have more say
+        let prediction = Noop.interpolate(&entries, "dim", 2).unwrap();
+        assert!((5000..10000).contains(&prediction), "{}", prediction);
+        let weighted = Noop
+            .interpolate_weighted(&entries, "dim", 2, &|e| if e.luma < 50 { 1.0 } else { 0.1 })
+            .unwrap();
+        assert!((prediction..10000).contains(&weighted), "{}", weighted);
+    }
+}
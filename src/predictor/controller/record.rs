@@ -0,0 +1,36 @@
+use crate::record::{self, Event};
+
+/// Records luma values of the output, once the predictor has processed them.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    output_name: String,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        self.inner.adjust(luma);
+        // Recorded afterwards, so that replaying feeds the predictor everything it has
+        // received by then, before the frame
+        record::add(Event::Luma {
+            output_name: self.output_name.clone(),
+            luma,
+        });
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(inner: Box<dyn super::Controller>, output_name: &str) -> Self {
+        Self {
+            inner,
+            output_name: output_name.to_string(),
+        }
+    }
+}
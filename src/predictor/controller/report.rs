@@ -0,0 +1,34 @@
+use crate::control::{self, Update};
+
+/// Reports luma values of the output on D-Bus.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    output_name: String,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        control::report(Update::Luma {
+            output_name: self.output_name.clone(),
+            luma,
+        });
+        self.inner.adjust(luma);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(inner: Box<dyn super::Controller>, output_name: &str) -> Self {
+        Self {
+            inner,
+            output_name: output_name.to_string(),
+        }
+    }
+}
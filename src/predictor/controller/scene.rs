@@ -0,0 +1,318 @@
+use super::{manual, Cooldowns, INITIAL_TIMEOUT_SECS};
+use crate::clock::Clock;
+use crate::config::Scene;
+use crate::control::{self, Update};
+use crate::scene::{self, Switch};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Replaces another controller while a scene is switched on, e.g. to pin the brightness for a
+/// movie. The other controller is suspended meanwhile: it neither predicts, nor learns what
+/// the user changes during the scene, and continues as if nothing happened afterwards.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    output_name: String,
+    prediction_tx: Sender<u64>,
+    user_rx: Receiver<u64>,
+    inner_user_tx: Sender<u64>,
+    als_rx: Receiver<String>,
+    inner_als_tx: Sender<String>,
+    switch_rx: Receiver<Switch>,
+    scenes: Vec<(String, Kind)>,
+    active: Option<usize>,
+    /// Last brightness set by user, in basis points.
+    last_brightness: u64,
+    last_als: String,
+    initial: bool,
+}
+
+enum Kind {
+    Fixed {
+        brightness: u64,
+    },
+    /// Fed with the same values as the other controller, but only while the scene is on.
+    Manual {
+        controller: Box<manual::Controller>,
+        user_tx: Sender<u64>,
+        als_tx: Sender<String>,
+    },
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        if !self.initial {
+            // Inner controllers might wait for the initial values before the first frame, and
+            // brightness and ALS controllers are expected to send them asap
+            self.last_brightness = self
+                .user_rx
+                .recv_timeout(Duration::from_secs(INITIAL_TIMEOUT_SECS))
+                .unwrap_or_else(|e| {
+                    panic!("Did not receive initial brightness value in time: {e:?}")
+                });
+            self.last_als = self
+                .als_rx
+                .recv_timeout(Duration::from_secs(INITIAL_TIMEOUT_SECS))
+                .unwrap_or_else(|e| panic!("Did not receive initial ALS value in time: {e:?}"));
+            self.inner_user_tx.send(self.last_brightness).ok();
+            self.inner_als_tx.send(self.last_als.clone()).ok();
+            self.initial = true;
+        }
+
+        let switches = self.switch_rx.try_iter().collect::<Vec<_>>();
+        switches.into_iter().for_each(|switch| self.switch(switch));
+
+        for brightness in self.user_rx.try_iter() {
+            self.last_brightness = brightness;
+            match self.active.map(|index| &self.scenes[index].1) {
+                None => self.inner_user_tx.send(brightness).ok(),
+                Some(Kind::Manual { user_tx, .. }) => user_tx.send(brightness).ok(),
+                Some(Kind::Fixed { .. }) => None,
+            };
+        }
+        for als in self.als_rx.try_iter() {
+            self.last_als = als.clone();
+            if let Some(Kind::Manual { als_tx, .. }) =
+                self.active.map(|index| &self.scenes[index].1)
+            {
+                als_tx.send(als.clone()).ok();
+            }
+            self.inner_als_tx.send(als).ok();
+        }
+
+        match self.active.map(|index| &mut self.scenes[index].1) {
+            None => self.inner.adjust(luma),
+            Some(Kind::Manual { controller, .. }) => {
+                super::Controller::adjust(controller.as_mut(), luma)
+            }
+            Some(Kind::Fixed { .. }) => {}
+        }
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        inner: Box<dyn super::Controller>,
+        output_name: &str,
+        prediction_tx: Sender<u64>,
+        user_rx: Receiver<u64>,
+        inner_user_tx: Sender<u64>,
+        als_rx: Receiver<String>,
+        inner_als_tx: Sender<String>,
+        switch_rx: Receiver<Switch>,
+        scenes: Vec<(String, Scene)>,
+        cooldowns: Cooldowns,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        let scenes = scenes
+            .into_iter()
+            .map(|(name, scene)| {
+                let kind = match scene {
+                    Scene::Fixed { brightness } => Kind::Fixed { brightness },
+                    Scene::Manual { thresholds } => {
+                        let (user_tx, user_rx) = mpsc::channel();
+                        let (als_tx, als_rx) = mpsc::channel();
+                        Kind::Manual {
+                            controller: Box::new(manual::Controller::new(
+                                prediction_tx.clone(),
+                                user_rx,
+                                als_rx,
+                                thresholds,
+                                None,
+                                cooldowns,
+                                clock.clone(),
+                            )),
+                            user_tx,
+                            als_tx,
+                        }
+                    }
+                };
+                (name, kind)
+            })
+            .collect();
+
+        Self {
+            inner,
+            output_name: output_name.to_string(),
+            prediction_tx,
+            user_rx,
+            inner_user_tx,
+            als_rx,
+            inner_als_tx,
+            switch_rx,
+            scenes,
+            active: None,
+            last_brightness: 0,
+            last_als: String::new(),
+            initial: false,
+        }
+    }
+
+    fn switch(&mut self, switch: Switch) {
+        let active = match switch {
+            Switch::To(None) => None,
+            Switch::To(Some(name)) => match self.scenes.iter().position(|(n, _)| *n == name) {
+                Some(index) => Some(index),
+                None => {
+                    log::warn!("[{}] Unknown scene '{}'", self.output_name, name);
+                    return;
+                }
+            },
+            Switch::Next => scene::next(self.active, self.scenes.len()),
+        };
+        if active == self.active {
+            return;
+        }
+        self.active = active;
+
+        let name = active.map_or("", |index| self.scenes[index].0.as_str());
+        match active {
+            Some(_) => log::info!("[{}] Switched to scene '{}'", self.output_name, name),
+            None => log::info!("[{}] Switched back to predictions", self.output_name),
+        }
+        control::report(Update::Scene {
+            output_name: self.output_name.clone(),
+            scene: name.to_string(),
+        });
+
+        match active.map(|index| &self.scenes[index].1) {
+            Some(Kind::Fixed { brightness }) => self
+                .prediction_tx
+                .send(*brightness)
+                .expect("Unable to send scene brightness, channel is dead"),
+            // Catch up with what happened while the scene was off
+            Some(Kind::Manual {
+                user_tx, als_tx, ..
+            }) => {
+                user_tx.send(self.last_brightness).ok();
+                als_tx.send(self.last_als.clone()).ok();
+            }
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Controller as _;
+    use super::*;
+    use crate::clock;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    struct Recorder {
+        lumas: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl super::super::Controller for Recorder {
+        fn adjust(&mut self, luma: u8) {
+            self.lumas.borrow_mut().push(luma);
+        }
+    }
+
+    #[test]
+    fn test_adjust_suspends_inner_controller_during_scene() {
+        let lumas = Rc::new(RefCell::new(vec![]));
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (inner_user_tx, inner_user_rx) = mpsc::channel();
+        let (als_tx, als_rx) = mpsc::channel();
+        let (inner_als_tx, inner_als_rx) = mpsc::channel();
+        let (switcher, switch_rx) = scene::Switcher::new(vec!["movie".to_string()]);
+        let mut controller = Controller::new(
+            Box::new(Recorder {
+                lumas: lumas.clone(),
+            }),
+            "eDP-1",
+            prediction_tx,
+            user_rx,
+            inner_user_tx,
+            als_rx,
+            inner_als_tx,
+            switch_rx,
+            vec![("movie".to_string(), Scene::Fixed { brightness: 2000 })],
+            Cooldowns {
+                pending: Duration::ZERO,
+                next_als: Duration::ZERO,
+            },
+            Arc::new(clock::System),
+        );
+
+        user_tx.send(5000).unwrap();
+        als_tx.send("dim".to_string()).unwrap();
+        controller.adjust(10);
+
+        // brightness is pinned, and changes made meanwhile are not learned...
+        switcher.send(Switch::Next);
+        user_tx.send(3000).unwrap();
+        als_tx.send("dark".to_string()).unwrap();
+        controller.adjust(20);
+        assert_eq!(vec![2000], prediction_rx.try_iter().collect::<Vec<_>>());
+
+        // ... but the ALS keeps being followed, for when the scene is switched off
+        switcher.send(Switch::Next);
+        controller.adjust(30);
+
+        assert_eq!(vec![10, 30], *lumas.borrow());
+        assert_eq!(vec![5000], inner_user_rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(
+            vec!["dim".to_string(), "dark".to_string()],
+            inner_als_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_adjust_predicts_with_scene_thresholds() {
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (inner_user_tx, _inner_user_rx) = mpsc::channel();
+        let (als_tx, als_rx) = mpsc::channel();
+        let (inner_als_tx, _inner_als_rx) = mpsc::channel();
+        let (switcher, switch_rx) = scene::Switcher::new(vec!["reading".to_string()]);
+        let thresholds: HashMap<String, HashMap<u8, u64>> =
+            [("dim".to_string(), [(0, 0), (100, 50)].into())].into();
+        let mut controller = Controller::new(
+            Box::new(Recorder {
+                lumas: Rc::new(RefCell::new(vec![])),
+            }),
+            "eDP-1",
+            prediction_tx,
+            user_rx,
+            inner_user_tx,
+            als_rx,
+            inner_als_tx,
+            switch_rx,
+            vec![("reading".to_string(), Scene::Manual { thresholds })],
+            Cooldowns {
+                pending: Duration::ZERO,
+                next_als: Duration::ZERO,
+            },
+            Arc::new(clock::System),
+        );
+
+        user_tx.send(8000).unwrap();
+        als_tx.send("dim".to_string()).unwrap();
+        controller.adjust(0);
+
+        // the brightness set by user is kept for the current screen contents, and reduced
+        // for brighter ones
+        switcher.send(Switch::To(Some("reading".to_string())));
+        controller.adjust(0);
+        controller.adjust(100);
+
+        assert_eq!(
+            vec![8000, 4000],
+            prediction_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use super::Context;
+use crate::clock::Clock;
+use crate::config::Predictor;
+use chrono::{DateTime, Local, Timelike};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+
+const MINUTES_PER_DAY: f64 = 24.0 * 60.0;
+
+/// Follows brightness set for times of day, changing it linearly from one point to the next,
+/// no matter the screen contents or ambient light.
+pub struct Controller {
+    prediction_tx: Sender<u64>,
+    user_rx: Receiver<u64>,
+    als_rx: Receiver<String>,
+    /// Minutes since midnight and brightness (in basis points) at that time, sorted.
+    points: Vec<(u32, u64)>,
+    last_prediction: Option<u64>,
+    clock: Arc<dyn Clock>,
+}
+
+pub fn build(predictor: Predictor, context: Context) -> Box<dyn super::Controller> {
+    let Predictor::Schedule { points } = predictor else {
+        unreachable!("Registered for the schedule predictor only");
+    };
+    Box::new(Controller::new(
+        context.prediction_tx,
+        context.user_rx,
+        context.als_rx,
+        points,
+        context.clock,
+    ))
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, _luma: u8) {
+        // Neither is used, but they are still sent and shouldn't pile up
+        self.user_rx.try_iter().for_each(drop);
+        self.als_rx.try_iter().for_each(drop);
+
+        let prediction = brightness_at(&self.points, minute_of_day(self.clock.wall()));
+        // Only sent when it changes, so that brightness set by user is kept until then
+        if self.last_prediction != Some(prediction) {
+            log::trace!("Prediction: {} (scheduled)", prediction);
+            self.prediction_tx
+                .send(prediction)
+                .expect("Unable to send predicted brightness value, channel is dead");
+            self.last_prediction = Some(prediction);
+        }
+    }
+}
+
+impl Controller {
+    pub fn new(
+        prediction_tx: Sender<u64>,
+        user_rx: Receiver<u64>,
+        als_rx: Receiver<String>,
+        points: Vec<(u32, u64)>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            prediction_tx,
+            user_rx,
+            als_rx,
+            points,
+            last_prediction: None,
+            clock,
+        }
+    }
+}
+
+/// Interpolates between the points around the given time, wrapping around midnight.
+fn brightness_at(points: &[(u32, u64)], minute: f64) -> u64 {
+    let (previous, next) = match points.iter().position(|(m, _)| *m as f64 > minute) {
+        Some(i) if i > 0 => (points[i - 1], points[i]),
+        _ => (points[points.len() - 1], points[0]),
+    };
+
+    let span = (next.0 as f64 - previous.0 as f64).rem_euclid(MINUTES_PER_DAY);
+    if span == 0.0 {
+        return previous.1;
+    }
+    let progress = (minute - previous.0 as f64).rem_euclid(MINUTES_PER_DAY) / span;
+    (previous.1 as f64 + (next.1 as f64 - previous.1 as f64) * progress).round() as u64
+}
+
+fn minute_of_day(time: DateTime<Local>) -> f64 {
+    time.num_seconds_from_midnight() as f64 / 60.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_brightness_at_ramps_between_points() {
+        let points = [(6 * 60 + 30, 1000), (8 * 60, 8000), (20 * 60, 8000)];
+
+        assert_eq!(1000, brightness_at(&points, 390.0));
+        assert_eq!(4500, brightness_at(&points, 435.0));
+        assert_eq!(8000, brightness_at(&points, 600.0));
+
+        // Around midnight, from the last point of the day to the first one of the next
+        let before_midnight = brightness_at(&points, 23.0 * 60.0);
+        let after_midnight = brightness_at(&points, 60.0);
+        assert!((1000..8000).contains(&before_midnight));
+        assert!((1000..before_midnight).contains(&after_midnight));
+        assert_eq!(1000, brightness_at(&points, 6.5 * 60.0));
+    }
+
+    #[test]
+    fn test_brightness_at_single_point() {
+        assert_eq!(3000, brightness_at(&[(12 * 60, 3000)], 0.0));
+        assert_eq!(3000, brightness_at(&[(12 * 60, 3000)], 12.0 * 60.0));
+    }
+}
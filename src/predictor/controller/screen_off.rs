@@ -0,0 +1,162 @@
+use crate::brightness::Command;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+/// Keeps black frames from the predictor, and holds brightness once they last long enough to
+/// mean that the compositor blanked the screen, until contents show up again.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    output_name: String,
+    command_tx: Sender<Command>,
+    max_luma: u8,
+    after: Duration,
+    /// When the current streak of black frames started.
+    streak_start: Option<Instant>,
+    off: bool,
+    /// Luma that only stands in for screen contents tells nothing about the screen being off.
+    captured: bool,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        if !self.captured {
+            return self.inner.adjust(luma);
+        }
+        if luma <= self.max_luma {
+            let streak_start = *self.streak_start.get_or_insert_with(Instant::now);
+            if !self.off && streak_start.elapsed() >= self.after {
+                log::debug!("[{}] Screen looks turned off", self.output_name);
+                self.switch(true);
+            }
+            return;
+        }
+
+        self.streak_start = None;
+        if self.off {
+            log::debug!("[{}] Screen looks turned on", self.output_name);
+            self.switch(false);
+        }
+        self.inner.adjust(luma);
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.captured = false;
+        self.streak_start = None;
+        if self.off {
+            self.switch(false);
+        }
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(
+        inner: Box<dyn super::Controller>,
+        output_name: &str,
+        command_tx: Sender<Command>,
+        max_luma: u8,
+        after: Duration,
+    ) -> Self {
+        Self {
+            inner,
+            output_name: output_name.to_string(),
+            command_tx,
+            max_luma,
+            after,
+            streak_start: None,
+            off: false,
+            captured: true,
+        }
+    }
+
+    fn switch(&mut self, off: bool) {
+        self.off = off;
+        // The brightness controller only stops with wluma
+        let _ = self.command_tx.send(Command::ScreenOff(off));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Controller as _;
+    use super::*;
+    use std::sync::mpsc;
+
+    struct Recorder(Sender<u8>);
+
+    impl super::super::Controller for Recorder {
+        fn adjust(&mut self, luma: u8) {
+            self.0.send(luma).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_adjust_holds_black_frames() {
+        let (luma_tx, luma_rx) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let mut controller = Controller::new(
+            Box::new(Recorder(luma_tx)),
+            "DP-1",
+            command_tx,
+            1,
+            Duration::ZERO,
+        );
+
+        for luma in [40, 1, 0, 0, 30] {
+            controller.adjust(luma);
+        }
+
+        assert_eq!(vec![40, 30], luma_rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(
+            vec![Command::ScreenOff(true), Command::ScreenOff(false)],
+            command_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_uncaptured_turns_screen_back_on() {
+        let (luma_tx, luma_rx) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let mut controller = Controller::new(
+            Box::new(Recorder(luma_tx)),
+            "DP-1",
+            command_tx,
+            0,
+            Duration::ZERO,
+        );
+
+        controller.adjust(0);
+        controller.uncaptured();
+        controller.adjust(0);
+
+        assert_eq!(vec![0], luma_rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(
+            vec![Command::ScreenOff(true), Command::ScreenOff(false)],
+            command_rx.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_adjust_ignores_short_streaks() {
+        let (luma_tx, luma_rx) = mpsc::channel();
+        let (command_tx, command_rx) = mpsc::channel();
+        let mut controller = Controller::new(
+            Box::new(Recorder(luma_tx)),
+            "DP-1",
+            command_tx,
+            0,
+            Duration::from_secs(3600),
+        );
+
+        for luma in [40, 0, 0, 30] {
+            controller.adjust(luma);
+        }
+
+        assert_eq!(vec![40, 30], luma_rx.try_iter().collect::<Vec<_>>());
+        assert_eq!(0, command_rx.try_iter().count());
+    }
+}
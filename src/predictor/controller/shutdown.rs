@@ -0,0 +1,35 @@
+use crate::shutdown::{self, Guard};
+
+/// Stops adjusting once wluma is exiting, after letting the predictor learn what's pending.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    done: Option<Guard>,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        if !shutdown::stopping() {
+            self.inner.adjust(luma);
+        } else if let Some(done) = self.done.take() {
+            self.inner.flush();
+            drop(done);
+        }
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(inner: Box<dyn super::Controller>) -> Self {
+        Self {
+            inner,
+            done: Some(shutdown::register()),
+        }
+    }
+}
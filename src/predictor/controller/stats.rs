@@ -0,0 +1,31 @@
+use crate::stats::{self, Stat};
+
+/// Counts frames of the output and their luma, once the predictor has processed them.
+pub struct Controller {
+    inner: Box<dyn super::Controller>,
+    output_name: String,
+}
+
+impl super::Controller for Controller {
+    fn adjust(&mut self, luma: u8) {
+        self.inner.adjust(luma);
+        stats::add(&self.output_name, Stat::Frame(luma));
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+
+    fn uncaptured(&mut self) {
+        self.inner.uncaptured();
+    }
+}
+
+impl Controller {
+    pub fn new(inner: Box<dyn super::Controller>, output_name: &str) -> Self {
+        Self {
+            inner,
+            output_name: output_name.to_string(),
+        }
+    }
+}
@@ -1,62 +1,310 @@
+use crate::brightness::to_basis_points;
+use crate::error::WlumaError;
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version 0 stored raw brightness values, version 1 stores basis points,
+/// version 2 stores the order of ALS profiles, version 3 stores when entries were learned.
+const DATA_VERSION: u8 = 3;
+
+/// Steps that bring data from the version at their index to the next one, every change of the
+/// format needs one, even if there is nothing to convert.
+const MIGRATIONS: [fn(&mut Data, &Migration); DATA_VERSION as usize] =
+    [migrate_to_v1, migrate_to_v2, migrate_to_v3];
+
+/// Members of each data group, by its name.
+static GROUPS: Mutex<BTreeMap<String, Vec<Sender<Entry>>>> = Mutex::new(BTreeMap::new());
+/// Outputs in the same data group save to the same file.
+static SAVING: Mutex<()> = Mutex::new(());
+
+/// ALS profiles, ordered from the darkest to the brightest.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone)]
+#[serde(transparent)]
+pub struct Profiles(Vec<String>);
+
+impl Profiles {
+    pub fn new(profiles: Vec<String>) -> Self {
+        Self(profiles)
+    }
+
+    /// Orders profiles from the darkest to the brightest, unknown profiles go last.
+    pub fn compare(&self, a: &str, b: &str) -> Ordering {
+        let position = |profile| self.0.iter().position(|p| p == profile);
+        match (position(a), position(b)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.cmp(b),
+        }
+    }
+
+    /// Profiles from the darkest to the brightest.
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(String::as_str)
+    }
+
+    /// Profiles darker than the given one, starting from the closest.
+    pub fn darker<'a>(&'a self, profile: &str) -> impl Iterator<Item = &'a str> {
+        let position = self.0.iter().position(|p| p == profile);
+        self.0[..position.unwrap_or(0)]
+            .iter()
+            .rev()
+            .map(String::as_str)
+    }
+
+    /// Profiles brighter than the given one, starting from the closest.
+    pub fn brighter<'a>(&'a self, profile: &str) -> impl Iterator<Item = &'a str> {
+        let position = self.0.iter().position(|p| p == profile);
+        self.0[position.map_or(self.0.len(), |p| p + 1)..]
+            .iter()
+            .map(String::as_str)
+    }
+}
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 pub struct Data {
+    #[serde(default)]
+    pub version: u8,
     pub output_name: String,
+    #[serde(default)]
+    pub profiles: Profiles,
     pub entries: Vec<Entry>,
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Entry {
     pub lux: String,
     pub luma: u8,
     pub brightness: u64,
+    /// Unix timestamp in seconds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub learned_at: Option<u64>,
+    /// Name of the focused workspace, when learning per workspace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// Hour at which the time of day bucket it was learned in starts, when learning per time of day.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time_bucket: Option<u8>,
+}
+
+// Entries are the same if they describe the same preference, no matter when it was learned
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.lux == other.lux
+            && self.luma == other.luma
+            && self.brightness == other.brightness
+            && self.tag == other.tag
+            && self.time_bucket == other.time_bucket
+    }
+}
+
+impl Eq for Entry {}
+
+impl Hash for Entry {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.lux.hash(state);
+        self.luma.hash(state);
+        self.brightness.hash(state);
+        self.tag.hash(state);
+        self.time_bucket.hash(state);
+    }
 }
 
 impl Data {
-    pub fn new(output_name: &str) -> Self {
+    pub fn new(output_name: &str, profiles: Profiles) -> Self {
         Self {
+            version: DATA_VERSION,
             output_name: output_name.to_string(),
+            profiles,
             entries: Vec::default(),
         }
     }
 
-    pub fn load(output_name: &str) -> Self {
-        Self::path(output_name)
+    pub fn load(output_name: &str, max_brightness: u64, profiles: Profiles) -> Self {
+        let mut data = Self::path(output_name)
             .ok()
-            .and_then(|path| Self::read_file(path).ok())
-            .and_then(|file| serde_yaml::from_reader(file).ok())
-            .unwrap_or_else(|| Self::new(output_name))
+            .and_then(|path| Self::read(output_name, &path))
+            .unwrap_or_else(|| Self::new(output_name, profiles.clone()));
+
+        // Profiles in the config are the source of truth, e.g. if thresholds were changed
+        if data.profiles != profiles {
+            log::debug!("Using ALS profiles {:?} for '{}'", profiles, output_name);
+            data.profiles = profiles;
+        }
+
+        if data.version > DATA_VERSION {
+            data.keep_newer();
+        }
+
+        if data.migrate(max_brightness) {
+            log::info!(
+                "Migrating learned data of '{}' to the new format",
+                output_name
+            );
+            if let Err(err) = data.save() {
+                log::error!("Unable to save migrated data: {}", err);
+            }
+        }
+
+        data
     }
 
-    pub fn save(&self) -> Result<(), Box<dyn Error>> {
-        Ok(serde_yaml::to_writer(self.write_file()?, self)?)
+    /// Data as it was saved for the output or data group, without migrating or restoring it,
+    /// for looking into it while wluma is running.
+    pub fn peek(name: &str) -> Result<Option<Self>, WlumaError> {
+        let data = Self::read_file(&Self::path(name)?).map_err(WlumaError::predictor)?;
+        match data {
+            // Brightness was stored in raw units of the device
+            Some(data) if data.version == 0 => Err(WlumaError::predictor(format!(
+                "Learned data of '{}' is in an old format, run wluma once to convert it",
+                name
+            ))),
+            data => Ok(data),
+        }
+    }
+
+    fn migrate(&mut self, max_brightness: u64) -> bool {
+        if self.version >= DATA_VERSION {
+            return false;
+        }
+
+        let migration = Migration {
+            max_brightness,
+            now: now(),
+        };
+        for migrate in &MIGRATIONS[self.version as usize..] {
+            migrate(self, &migration);
+            self.version += 1;
+        }
+        true
     }
 
-    fn read_file(path: PathBuf) -> Result<File, Box<dyn Error>> {
-        Ok(OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(false)
-            .read(true)
-            .open(path)?)
+    /// Data written by a newer version of wluma would lose whatever this one doesn't know about
+    /// on the next save, so a copy of the file is kept for when it's upgraded again.
+    fn keep_newer(&mut self) {
+        let copied = Self::path(&self.output_name).and_then(|path| {
+            let copy_path = path.with_extension(format!("yaml.v{}", self.version));
+            fs::copy(&path, &copy_path)
+                .map(|_| copy_path)
+                .map_err(WlumaError::predictor)
+        });
+        match copied {
+            Ok(copy_path) => log::warn!(
+                "Learned data of '{}' is from a newer version of wluma, kept a copy in '{}'",
+                self.output_name,
+                copy_path.display()
+            ),
+            Err(err) => log::warn!(
+                "Learned data of '{}' is from a newer version of wluma, unable to keep a copy: {}",
+                self.output_name,
+                err
+            ),
+        }
+        self.version = DATA_VERSION;
     }
 
-    fn write_file(&self) -> Result<File, Box<dyn Error>> {
-        let path = Self::path(&self.output_name).unwrap();
-        Ok(OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(path)?)
+    /// Replays learning in the order entries were learned, to drop those superseded by later
+    /// ones, including entries for screen contents within `luma_epsilon` of a later one.
+    /// Returns how many entries were dropped.
+    pub fn compact(&mut self, luma_epsilon: u8) -> usize {
+        let count = self.entries.len();
+        let mut entries = std::mem::take(&mut self.entries);
+        entries.sort_by_key(|entry| entry.learned_at);
+        for entry in entries {
+            self.entries
+                .retain(|older| !entry.supersedes(older, luma_epsilon));
+            self.entries.push(entry);
+        }
+
+        let profiles = &self.profiles;
+        self.entries
+            .sort_by(|x, y| profiles.compare(&x.lux, &y.lux).then(x.luma.cmp(&y.luma)));
+        count - self.entries.len()
     }
 
-    fn path(output_name: &str) -> Result<PathBuf, Box<dyn Error>> {
-        Ok(xdg::BaseDirectories::with_prefix("wluma")?
-            .create_data_directory("")?
+    /// Writes to a temporary file that is renamed into place, so that a crash never leaves a
+    /// partially written file behind. The previous file is kept as a backup.
+    pub fn save(&self) -> Result<(), WlumaError> {
+        let _saving = SAVING.lock().unwrap();
+        let path = Self::path(&self.output_name)?;
+        let tmp_path = path.with_extension("yaml.tmp");
+
+        let file = File::create(&tmp_path).map_err(WlumaError::predictor)?;
+        serde_yaml::to_writer(&file, self).map_err(WlumaError::predictor)?;
+        file.sync_all().map_err(WlumaError::predictor)?;
+
+        match fs::rename(&path, backup_path(&path)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(WlumaError::predictor(err))?,
+            _ => {}
+        }
+        fs::rename(tmp_path, path).map_err(WlumaError::predictor)
+    }
+
+    /// Falls back to the backup when the file is missing or corrupt, e.g. after a crash while
+    /// it was saved.
+    fn read(output_name: &str, path: &Path) -> Option<Self> {
+        match Self::read_file(path) {
+            Ok(Some(data)) => return Some(data),
+            Ok(None) => {}
+            Err(err) => {
+                log::warn!("Learned data in '{}' is corrupt: {}", path.display(), err);
+                // Keep it for inspection, instead of replacing it with the backup on next save
+                if let Err(err) = fs::rename(path, path.with_extension("yaml.corrupt")) {
+                    log::warn!("Unable to move corrupt learned data aside: {}", err);
+                }
+            }
+        }
+
+        let backup_path = backup_path(path);
+        match Self::read_file(&backup_path) {
+            Ok(Some(data)) => {
+                log::warn!(
+                    "Restored learned data of '{}' from '{}'",
+                    output_name,
+                    backup_path.display()
+                );
+                Some(data)
+            }
+            Ok(None) => None,
+            Err(err) => {
+                log::warn!(
+                    "Backup of learned data in '{}' is corrupt: {}",
+                    backup_path.display(),
+                    err
+                );
+                None
+            }
+        }
+    }
+
+    /// Missing and empty files have no data.
+    fn read_file(path: &Path) -> io::Result<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if file.metadata()?.len() == 0 {
+            return Ok(None);
+        }
+        serde_yaml::from_reader(file)
+            .map(Some)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+    }
+
+    fn path(output_name: &str) -> Result<PathBuf, WlumaError> {
+        Ok(xdg::BaseDirectories::with_prefix("wluma")
+            .map_err(WlumaError::predictor)?
+            .create_data_directory("")
+            .map_err(WlumaError::predictor)?
             .join(format!("{:}.yaml", output_name)))
     }
 }
@@ -67,6 +315,240 @@ impl Entry {
             lux: lux.to_string(),
             luma,
             brightness,
+            learned_at: None,
+            tag: None,
+            time_bucket: None,
+        }
+    }
+
+    /// Whether this entry makes an older one obsolete: both were learned in the same conditions,
+    /// and the older one is for a screen within `luma_epsilon`, or it contradicts this one by
+    /// having lower brightness for a darker screen or higher brightness for a brighter one.
+    pub fn supersedes(&self, older: &Entry, luma_epsilon: u8) -> bool {
+        let same_env =
+            self.lux == older.lux && self.tag == older.tag && self.time_bucket == older.time_bucket;
+
+        let close = self.luma.abs_diff(older.luma) <= luma_epsilon;
+
+        let darker_screen = older.luma < self.luma && older.brightness >= self.brightness;
+
+        let brighter_screen = older.luma > self.luma && older.brightness <= self.brightness;
+
+        same_env && (close || !(darker_screen || brighter_screen))
+    }
+
+    pub fn age_days(&self, now: u64) -> f64 {
+        self.learned_at.map_or(0.0, |learned_at| {
+            now.saturating_sub(learned_at) as f64 / 86400.0
+        })
+    }
+}
+
+/// Passes what one output learns on to the other outputs of the same data group, e.g. identical
+/// monitors side by side, so that training one trains all of them.
+pub struct Group {
+    name: String,
+    index: usize,
+    rx: Receiver<Entry>,
+}
+
+impl Group {
+    pub fn join(name: &str) -> Self {
+        let (tx, rx) = mpsc::channel();
+        let mut groups = GROUPS.lock().unwrap();
+        let members = groups.entry(name.to_string()).or_default();
+        members.push(tx);
+        Self {
+            name: name.to_string(),
+            index: members.len() - 1,
+            rx,
+        }
+    }
+
+    pub fn share(&self, entry: &Entry) {
+        let groups = GROUPS.lock().unwrap();
+        for (index, tx) in groups[&self.name].iter().enumerate() {
+            if index != self.index {
+                // Members only go away if their output fails to start
+                tx.send(entry.clone()).ok();
+            }
         }
     }
+
+    /// Entries learned by the other members since the last call.
+    pub fn learned(&self) -> impl Iterator<Item = Entry> + '_ {
+        self.rx.try_iter()
+    }
+}
+
+/// What migrations need to know besides the data.
+struct Migration {
+    max_brightness: u64,
+    /// Unix timestamp in seconds.
+    now: u64,
+}
+
+fn migrate_to_v1(data: &mut Data, migration: &Migration) {
+    data.entries.iter_mut().for_each(|entry| {
+        entry.brightness = to_basis_points(entry.brightness, migration.max_brightness)
+    });
+}
+
+/// Profiles are taken from the config on load, entries only need to follow their order.
+fn migrate_to_v2(data: &mut Data, _: &Migration) {
+    let profiles = &data.profiles;
+    data.entries
+        .sort_by(|x, y| profiles.compare(&x.lux, &y.lux).then(x.luma.cmp(&y.luma)));
+}
+
+/// Unknown age of older entries, let them age starting from now.
+fn migrate_to_v3(data: &mut Data, migration: &Migration) {
+    data.entries
+        .iter_mut()
+        .for_each(|entry| entry.learned_at = entry.learned_at.or(Some(migration.now)));
+}
+
+fn backup_path(path: &Path) -> PathBuf {
+    path.with_extension("yaml.bak")
+}
+
+/// Current unix timestamp in seconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_migrate_raw_brightness() -> Result<(), Box<dyn Error>> {
+        let mut data: Data = serde_yaml::from_str(
+            "output_name: eDP-1\nentries:\n- lux: dim\n  luma: 10\n  brightness: 48000\n",
+        )?;
+
+        assert!(data.migrate(96000));
+        assert_eq!(DATA_VERSION, data.version);
+        assert_eq!(vec![Entry::new("dim", 10, 5000)], data.entries);
+        assert!(data.entries[0].learned_at.is_some());
+
+        // Already migrated data is left as is
+        assert!(!data.migrate(96000));
+        assert_eq!(vec![Entry::new("dim", 10, 5000)], data.entries);
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_orders_entries_by_profile() -> Result<(), Box<dyn Error>> {
+        let mut data: Data = serde_yaml::from_str(
+            "version: 1\noutput_name: eDP-1\nentries:\n- lux: dim\n  luma: 10\n  brightness: 5000\n- lux: dark\n  luma: 20\n  brightness: 2000\n",
+        )?;
+        data.profiles = Profiles::new(vec!["dark".to_string(), "dim".to_string()]);
+
+        assert!(data.migrate(96000));
+        assert_eq!(
+            vec![Entry::new("dark", 20, 2000), Entry::new("dim", 10, 5000)],
+            data.entries
+        );
+        Ok(())
+    }
+
+    /// The same learned data, in every format it was ever saved in.
+    const FIXTURES: [&str; DATA_VERSION as usize + 1] = [
+        include_str!("fixtures/data-v0.yaml"),
+        include_str!("fixtures/data-v1.yaml"),
+        include_str!("fixtures/data-v2.yaml"),
+        include_str!("fixtures/data-v3.yaml"),
+    ];
+
+    #[test]
+    fn test_migrate_fixtures_of_every_version() -> Result<(), Box<dyn Error>> {
+        let current: Data = serde_yaml::from_str(FIXTURES[DATA_VERSION as usize])?;
+
+        for (version, fixture) in FIXTURES.iter().enumerate() {
+            let mut data: Data = serde_yaml::from_str(fixture)?;
+            assert_eq!(version as u8, data.version);
+            // As on load
+            data.profiles = current.profiles.clone();
+
+            assert_eq!(version < DATA_VERSION as usize, data.migrate(96000));
+            assert_eq!(current, data, "migrating version {}", version);
+            assert!(data.entries.iter().all(|entry| entry.learned_at.is_some()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_replays_learning_in_order() {
+        let learned = |lux: &str, luma: u8, brightness: u64, learned_at: u64| Entry {
+            learned_at: Some(learned_at),
+            ..Entry::new(lux, luma, brightness)
+        };
+        let mut data = Data::new(
+            "eDP-1",
+            Profiles::new(vec!["dim".to_string(), "bright".to_string()]),
+        );
+        data.entries = vec![
+            learned("dim", 10, 6000, 1),
+            learned("dim", 50, 3000, 5),
+            learned("dim", 51, 3100, 2),
+            learned("dim", 60, 3050, 3),
+            learned("dim", 90, 1000, 4),
+            learned("bright", 50, 9000, 1),
+        ];
+
+        // the newer entry at luma 50 replaces the one at 51 within epsilon, and the one at 60
+        // that contradicts it, other conditions are not affected
+        assert_eq!(2, data.compact(1));
+        assert_eq!(
+            vec![
+                Entry::new("dim", 10, 6000),
+                Entry::new("dim", 50, 3000),
+                Entry::new("dim", 90, 1000),
+                Entry::new("bright", 50, 9000),
+            ],
+            data.entries
+        );
+
+        // without epsilon, nearly identical entries are kept
+        data.entries.push(learned("dim", 11, 5900, 6));
+        assert_eq!(0, data.compact(0));
+        assert_eq!(5, data.entries.len());
+    }
+
+    #[test]
+    fn test_profiles_neighbours() {
+        let profiles = Profiles::new(
+            ["night", "dark", "dim", "bright"]
+                .into_iter()
+                .map(String::from)
+                .collect(),
+        );
+
+        assert_eq!(
+            vec!["dark", "night"],
+            profiles.darker("dim").collect::<Vec<_>>()
+        );
+        assert_eq!(vec!["bright"], profiles.brighter("dim").collect::<Vec<_>>());
+        assert_eq!(0, profiles.darker("unknown").count());
+        assert_eq!(0, profiles.brighter("unknown").count());
+        assert_eq!(Ordering::Less, profiles.compare("bright", "unknown"));
+    }
+
+    #[test]
+    fn test_group_shares_entries_with_other_members() {
+        let desk = Group::join("desk");
+        let other_desk = Group::join("desk");
+        let tv = Group::join("tv");
+        let entry = Entry::new("dim", 10, 5000);
+
+        desk.share(&entry);
+
+        assert_eq!(0, desk.learned().count());
+        assert_eq!(vec![entry], other_desk.learned().collect::<Vec<_>>());
+        assert_eq!(0, tv.learned().count());
+    }
 }
@@ -1,3 +1,4 @@
 pub mod controller;
 mod data;
-pub use controller::Controller;
+pub use controller::{Controller, Cooldowns};
+pub use data::{Data, Entry, Profiles};
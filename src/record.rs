@@ -0,0 +1,143 @@
+use crate::predictor::Entry;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+lazy_static! {
+    static ref RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+}
+
+/// What happened during a session, in enough detail to replay what predictors did.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// Raw value read from an ALS source, and the profile it falls into.
+    Lux {
+        source: String,
+        raw: u64,
+        profile: String,
+    },
+    /// ALS profile sent to predictors, when it changes.
+    Profile { profile: String },
+    /// Learned entries an adaptive predictor starts with.
+    Entries {
+        output_name: String,
+        entries: Vec<Entry>,
+    },
+    /// Brightness set by user, sent to the predictor.
+    User {
+        output_name: String,
+        /// In basis points.
+        brightness: u64,
+    },
+    /// Luma of a frame, once the predictor has processed it.
+    Luma { output_name: String, luma: u8 },
+    /// Brightness predicted for the output, when it changes.
+    Prediction {
+        output_name: String,
+        /// In basis points.
+        brightness: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Record {
+    /// Seconds since the recording started.
+    pub time: f64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+struct Recorder {
+    writer: LineWriter<File>,
+    started: Instant,
+}
+
+/// Starts recording events of this session to the file, one JSON object per line.
+pub fn start(path: &Path) -> io::Result<()> {
+    let writer = LineWriter::new(File::create(path)?);
+    *RECORDER.lock().unwrap() = Some(Recorder {
+        writer,
+        started: Instant::now(),
+    });
+    Ok(())
+}
+
+/// Adds the event to the recording, if this session is being recorded.
+pub fn add(event: Event) {
+    let mut recorder = RECORDER.lock().unwrap();
+    if let Some(recorder) = recorder.as_mut() {
+        let record = Record {
+            time: recorder.started.elapsed().as_secs_f64(),
+            event,
+        };
+        let result = serde_json::to_writer(&mut recorder.writer, &record)
+            .map_err(io::Error::from)
+            .and_then(|_| recorder.writer.write_all(b"\n"));
+        if let Err(err) = result {
+            log::warn!("Unable to record event: {}", err);
+        }
+    }
+}
+
+/// Reads back a recording made by [`start`].
+pub fn read(path: &Path) -> io::Result<Vec<Record>> {
+    parse(BufReader::new(File::open(path)?))
+}
+
+fn parse(reader: impl BufRead) -> io::Result<Vec<Record>> {
+    reader
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_records() -> Result<(), Box<dyn std::error::Error>> {
+        let records = parse(
+            concat!(
+                r#"{"time":0.5,"event":"profile","profile":"dim"}"#,
+                "\n\n",
+                r#"{"time":0.75,"event":"luma","output_name":"eDP-1","luma":40}"#,
+                "\n",
+            )
+            .as_bytes(),
+        )?;
+
+        assert_eq!(
+            vec![
+                Record {
+                    time: 0.5,
+                    event: Event::Profile {
+                        profile: "dim".to_string()
+                    }
+                },
+                Record {
+                    time: 0.75,
+                    event: Event::Luma {
+                        output_name: "eDP-1".to_string(),
+                        luma: 40
+                    }
+                },
+            ],
+            records
+        );
+
+        // Records are written the same way they are read
+        let line = serde_json::to_string(&records[1])?;
+        assert_eq!(
+            r#"{"time":0.75,"event":"luma","output_name":"eDP-1","luma":40}"#,
+            line
+        );
+        Ok(())
+    }
+}
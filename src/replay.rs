@@ -0,0 +1,172 @@
+use crate::clock;
+use crate::config::{self, Output};
+use crate::error::WlumaError;
+use crate::predictor::{self, Entry, Profiles};
+use crate::record::{self, Event, Record};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Predictor of an output, fed with recorded events instead of live ones.
+struct Replayed {
+    controller: Box<dyn predictor::Controller>,
+    als_tx: Sender<String>,
+    user_tx: Sender<u64>,
+    prediction_rx: Receiver<u64>,
+    /// Predictors wait for the initial ALS profile and brightness before the first frame.
+    has_profile: bool,
+    has_brightness: bool,
+    prediction: Option<u64>,
+}
+
+impl Replayed {
+    /// Takes the initial entries of the output out of the recorded ones.
+    fn new(
+        output: &Output,
+        config: &config::Config,
+        entries: &mut HashMap<String, Vec<Entry>>,
+        clock: &clock::Manual,
+    ) -> (String, Self) {
+        let config::OutputSettings {
+            name, predictor, ..
+        } = output.settings();
+
+        let (als_tx, als_rx) = mpsc::channel();
+        let (user_tx, user_rx) = mpsc::channel();
+        let (prediction_tx, prediction_rx) = mpsc::channel();
+        let (_, workspace_rx) = mpsc::channel();
+
+        // Nothing is loaded from or saved to the learned data, the predictor starts from the
+        // entries it had when the session was recorded
+        let controller = predictor::controller::build(
+            predictor.clone(),
+            predictor::controller::Context {
+                prediction_tx,
+                user_rx,
+                als_rx,
+                workspace_rx,
+                stateful: false,
+                output_name: name,
+                max_brightness: 0,
+                profiles: Profiles::new(config.als_profiles()),
+                cooldowns: predictor::Cooldowns::new(config.predictor),
+                clock: Arc::new(clock.clone()),
+                entries: Some(entries.remove(name).unwrap_or_default()),
+            },
+        );
+
+        let replayed = Self {
+            controller,
+            als_tx,
+            user_tx,
+            prediction_rx,
+            has_profile: false,
+            has_brightness: false,
+            prediction: None,
+        };
+        (name.clone(), replayed)
+    }
+}
+
+/// Replays a recorded session with the current config, without touching displays or learned
+/// data, and prints how predictions change next to the recorded ones.
+pub fn run(path: &Path) -> Result<(), WlumaError> {
+    let config = config::load()?;
+    let records = record::read(path).map_err(WlumaError::predictor)?;
+
+    let mut entries: HashMap<String, Vec<Entry>> = HashMap::new();
+    for record in &records {
+        if let Event::Entries {
+            output_name,
+            entries: initial,
+        } = &record.event
+        {
+            entries
+                .entry(output_name.clone())
+                .or_insert_with(|| initial.clone());
+        }
+    }
+
+    // Cooldowns follow the time of the recording, not how fast it is replayed
+    let clock = clock::Manual::new();
+    let mut outputs: HashMap<String, Replayed> = config
+        .output
+        .iter()
+        .map(|output| Replayed::new(output, &config, &mut entries, &clock))
+        .collect();
+
+    for output_name in entries.keys() {
+        log::warn!("Skipping '{}' as it is not in the config", output_name);
+    }
+
+    let mut elapsed = 0.0;
+    for Record { time, event } in records {
+        clock.advance(Duration::from_secs_f64((time - elapsed).max(0.0)));
+        elapsed = elapsed.max(time);
+        match event {
+            Event::Profile { profile } => {
+                println!("{:>9.3}s  ALS profile is now '{}'", time, profile);
+                for output in outputs.values_mut() {
+                    output.als_tx.send(profile.clone()).ok();
+                    output.has_profile = true;
+                }
+            }
+            Event::User {
+                output_name,
+                brightness,
+            } => {
+                if let Some(output) = outputs.get_mut(&output_name) {
+                    println!(
+                        "{:>9.3}s  [{}] User set {:.2}%",
+                        time,
+                        output_name,
+                        percent(brightness)
+                    );
+                    output.user_tx.send(brightness).ok();
+                    output.has_brightness = true;
+                }
+            }
+            Event::Luma { output_name, luma } => {
+                let Some(output) = outputs.get_mut(&output_name) else {
+                    continue;
+                };
+                if !output.has_profile || !output.has_brightness {
+                    continue;
+                }
+
+                output.controller.adjust(luma);
+                let prediction = output.prediction_rx.try_iter().last();
+                if prediction.is_some() && prediction != output.prediction {
+                    println!(
+                        "{:>9.3}s  [{}] Replayed prediction {:.2}% for {}% luma",
+                        time,
+                        output_name,
+                        percent(prediction.unwrap_or_default()),
+                        luma
+                    );
+                    output.prediction = prediction;
+                }
+            }
+            Event::Prediction {
+                output_name,
+                brightness,
+            } => {
+                println!(
+                    "{:>9.3}s  [{}] Recorded prediction {:.2}%",
+                    time,
+                    output_name,
+                    percent(brightness)
+                );
+            }
+            Event::Lux { .. } | Event::Entries { .. } => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn percent(basis_points: u64) -> f64 {
+    basis_points as f64 / 100.0
+}
@@ -0,0 +1,91 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Request to switch the scene of an output.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Switch {
+    /// Switches to the scene with the given name, or back to predictions when there is none.
+    To(Option<String>),
+    /// Switches to the next scene in alphabetical order, back to predictions after the last one.
+    Next,
+}
+
+/// Lets other threads switch the scenes of an output.
+#[derive(Debug, Clone)]
+pub struct Switcher {
+    /// Names of the scenes, sorted.
+    pub names: Vec<String>,
+    tx: Sender<Switch>,
+}
+
+impl Switcher {
+    pub fn new(names: Vec<String>) -> (Self, Receiver<Switch>) {
+        let (tx, rx) = mpsc::channel();
+        (Self { names, tx }, rx)
+    }
+
+    pub fn send(&self, switch: Switch) {
+        // The predictor only goes away if the output fails to start
+        self.tx.send(switch).ok();
+    }
+}
+
+/// Keeps SIGUSR2 for [`Watcher`], must be called before starting any threads, so that they
+/// inherit it.
+pub fn block_signal() {
+    let signals = signals();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+}
+
+/// Cycles through the scenes of all outputs on SIGUSR2.
+pub struct Watcher {
+    switchers: Vec<Switcher>,
+}
+
+impl Watcher {
+    pub fn new(switchers: Vec<Switcher>) -> Self {
+        Self { switchers }
+    }
+
+    pub fn run(&mut self) {
+        let signals = signals();
+        let mut signal = 0;
+        loop {
+            unsafe { libc::sigwait(&signals, &mut signal) };
+            log::debug!("Switching to the next scene on signal {}", signal);
+            for switcher in &self.switchers {
+                switcher.send(Switch::Next);
+            }
+        }
+    }
+}
+
+fn signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGUSR2);
+        signals
+    }
+}
+
+/// Index of the scene that follows the active one, none after the last one.
+pub fn next(active: Option<usize>, count: usize) -> Option<usize> {
+    match active {
+        None if count > 0 => Some(0),
+        Some(index) if index + 1 < count => Some(index + 1),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_next_cycles_through_scenes_and_back() {
+        assert_eq!(Some(0), next(None, 2));
+        assert_eq!(Some(1), next(Some(0), 2));
+        assert_eq!(None, next(Some(1), 2));
+        assert_eq!(None, next(None, 0));
+    }
+}
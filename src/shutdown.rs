@@ -0,0 +1,69 @@
+use lazy_static::lazy_static;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long to wait for everything to finish before exiting anyway, e.g. predictors only find
+/// out about it with the next frame, which might never come if the screen doesn't change.
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref DONE_RXS: Mutex<Vec<Receiver<()>>> = Mutex::new(Vec::new());
+}
+
+/// Held by a part of wluma that has something to finish before exiting, dropped once done.
+pub struct Guard {
+    _done_tx: Sender<()>,
+}
+
+/// Registers a part of wluma that checks [`stopping`] regularly, to finish before exiting.
+pub fn register() -> Guard {
+    let (done_tx, done_rx) = mpsc::channel();
+    DONE_RXS.lock().unwrap().push(done_rx);
+    Guard { _done_tx: done_tx }
+}
+
+/// Whether wluma is exiting, and registered parts should finish what they are doing.
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+/// Keeps SIGINT and SIGTERM for [`wait`], must be called before starting any threads,
+/// so that they inherit it.
+pub fn block_signals() {
+    let signals = signals();
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+}
+
+/// Blocks until SIGINT or SIGTERM, then lets registered parts finish.
+pub fn wait() {
+    let signals = signals();
+    let mut signal = 0;
+    unsafe { libc::sigwait(&signals, &mut signal) };
+
+    log::info!("Exiting on signal {}", signal);
+    STOPPING.store(true, Ordering::Relaxed);
+
+    let deadline = Instant::now() + TIMEOUT;
+    let done_rxs = std::mem::take(&mut *DONE_RXS.lock().unwrap());
+    for done_rx in done_rxs {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        if let Err(RecvTimeoutError::Timeout) = done_rx.recv_timeout(timeout) {
+            log::warn!("Not everything finished in time, exiting anyway");
+            return;
+        }
+    }
+}
+
+fn signals() -> libc::sigset_t {
+    unsafe {
+        let mut signals = std::mem::zeroed();
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        signals
+    }
+}
@@ -0,0 +1,81 @@
+use crate::brightness::Command;
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use std::sync::mpsc::{self, Sender};
+use std::time::{Duration, Instant};
+
+/// How long to wait for signals when not waiting for the grace period to end.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Follows the system going to sleep and resuming, as reported by logind, so that stale ALS
+/// readings and the screen waking up right after resuming are not learned from.
+pub struct Watcher {
+    sleep_txs: Vec<Sender<Command>>,
+    grace: Duration,
+    asleep: bool,
+}
+
+impl Watcher {
+    pub fn new(sleep_txs: Vec<Sender<Command>>, grace_secs: f64) -> Self {
+        Self {
+            sleep_txs,
+            grace: Duration::from_secs_f64(grace_secs),
+            asleep: false,
+        }
+    }
+
+    pub fn run(&mut self) {
+        if let Err(err) = self.watch() {
+            log::warn!(
+                "Unable to follow suspend and resume, learning right after resume: {}",
+                err
+            );
+        }
+        self.update_asleep(false);
+    }
+
+    fn watch(&mut self) -> Result<(), dbus::Error> {
+        let connection = Connection::new_system()?;
+
+        let (sleep_tx, sleep_rx) = mpsc::channel();
+        let rule = MatchRule::new_signal("org.freedesktop.login1.Manager", "PrepareForSleep")
+            .with_path("/org/freedesktop/login1");
+        connection.add_match(rule, move |(start,): (bool,), _, _| {
+            // The receiver only goes away when this watcher stops
+            let _ = sleep_tx.send(start);
+            true
+        })?;
+
+        let mut resumed_at = None;
+        loop {
+            let timeout = resumed_at.map_or(IDLE_TIMEOUT, |resumed_at: Instant| {
+                self.grace.saturating_sub(resumed_at.elapsed())
+            });
+            connection.process(timeout)?;
+
+            for start in sleep_rx.try_iter() {
+                if start {
+                    log::debug!("System is going to sleep");
+                    resumed_at = None;
+                    self.update_asleep(true);
+                } else {
+                    log::debug!("System resumed, waiting {:?} before adjusting", self.grace);
+                    resumed_at = Some(Instant::now());
+                }
+            }
+
+            if resumed_at.is_some_and(|resumed_at| resumed_at.elapsed() >= self.grace) {
+                resumed_at = None;
+                self.update_asleep(false);
+            }
+        }
+    }
+
+    fn update_asleep(&mut self, asleep: bool) {
+        if asleep != self.asleep {
+            self.asleep = asleep;
+            self.sleep_txs
+                .retain(|tx| tx.send(Command::Sleep(asleep)).is_ok());
+        }
+    }
+}
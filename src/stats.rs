@@ -0,0 +1,109 @@
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+lazy_static! {
+    static ref STATS: Mutex<BTreeMap<String, Counters>> = Mutex::new(BTreeMap::new());
+}
+
+/// Something worth counting that happened to an output.
+pub enum Stat {
+    /// Luma of a frame, once the predictor has processed it.
+    Frame(u8),
+    /// Brightness predicted for the output, when it changes.
+    Prediction,
+    /// Brightness level that reached the device.
+    Write,
+    /// Brightness set by user, learned by the predictor.
+    Learned,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Counters {
+    frames: u64,
+    luma_sum: u64,
+    predictions: u64,
+    writes: u64,
+    learned: u64,
+}
+
+/// Includes the output in every summary from now on, even when nothing happens to it.
+pub fn register(output_name: &str) {
+    STATS
+        .lock()
+        .unwrap()
+        .entry(output_name.to_string())
+        .or_default();
+}
+
+/// Counts the event towards the next summary of the output.
+pub fn add(output_name: &str, stat: Stat) {
+    let mut stats = STATS.lock().unwrap();
+    let counters = stats.entry(output_name.to_string()).or_default();
+    match stat {
+        Stat::Frame(luma) => {
+            counters.frames += 1;
+            counters.luma_sum += luma as u64;
+        }
+        Stat::Prediction => counters.predictions += 1,
+        Stat::Write => counters.writes += 1,
+        Stat::Learned => counters.learned += 1,
+    }
+}
+
+/// Logs what each output has been up to every few minutes, so that it's easy to tell whether
+/// frames are not captured, or the predictor doesn't change anything.
+pub fn run() {
+    loop {
+        thread::sleep(INTERVAL);
+        let mut stats = STATS.lock().unwrap();
+        for (output_name, counters) in stats.iter_mut() {
+            log::info!("[{}] {}", output_name, summary(counters));
+            *counters = Counters::default();
+        }
+    }
+}
+
+fn summary(counters: &Counters) -> String {
+    let average_luma = match counters.frames {
+        0 => "-".to_string(),
+        frames => format!("{}%", counters.luma_sum / frames),
+    };
+    format!(
+        "Last {} minutes: {} frames processed (average luma {}), {} predictions, {} brightness writes, {} learned",
+        INTERVAL.as_secs() / 60,
+        counters.frames,
+        average_luma,
+        counters.predictions,
+        counters.writes,
+        counters.learned,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        let counters = Counters {
+            frames: 4,
+            luma_sum: 90,
+            predictions: 2,
+            writes: 30,
+            learned: 1,
+        };
+        assert_eq!(
+            "Last 5 minutes: 4 frames processed (average luma 22%), 2 predictions, 30 brightness writes, 1 learned",
+            summary(&counters)
+        );
+        assert_eq!(
+            "Last 5 minutes: 0 frames processed (average luma -), 0 predictions, 0 brightness writes, 0 learned",
+            summary(&Counters::default())
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::error::WlumaError;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const HEALTHY_RUN: Duration = Duration::from_secs(60);
+
+struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Self {
+            delay: INITIAL_BACKOFF,
+        }
+    }
+
+    /// Doubles the delay after each failure, unless the task was running fine for a while.
+    fn next(&mut self, ran_for: Duration) -> Duration {
+        if ran_for >= HEALTHY_RUN {
+            self.delay = INITIAL_BACKOFF;
+        }
+
+        let delay = self.delay;
+        self.delay = (delay * 2).min(MAX_BACKOFF);
+        delay
+    }
+}
+
+/// Runs the task until it finishes successfully, restarting it with an exponential backoff when it
+/// fails with a recoverable error.
+pub fn supervise(name: &str, mut task: impl FnMut() -> Result<(), WlumaError>) {
+    let mut backoff = Backoff::new();
+
+    loop {
+        let started = Instant::now();
+        match task() {
+            Ok(()) => return,
+            Err(err) if !err.is_recoverable() => {
+                log::error!("Task for '{}' failed: {}, not restarting", name, err);
+                return;
+            }
+            Err(err) => {
+                let delay = backoff.next(started.elapsed());
+                log::error!(
+                    "Task for '{}' failed: {}, restarting in {}s",
+                    name,
+                    err,
+                    delay.as_secs()
+                );
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_up_to_max() {
+        let mut backoff = Backoff::new();
+
+        let delays = (0..12)
+            .map(|_| backoff.next(Duration::ZERO).as_secs())
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            vec![1, 2, 4, 8, 16, 32, 64, 128, 256, 300, 300, 300],
+            delays
+        );
+    }
+
+    #[test]
+    fn test_backoff_resets_after_healthy_run() {
+        let mut backoff = Backoff::new();
+        backoff.next(Duration::ZERO);
+        backoff.next(Duration::ZERO);
+
+        assert_eq!(INITIAL_BACKOFF, backoff.next(HEALTHY_RUN));
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use wayland_client::backend::ObjectId;
+use wayland_client::protocol::wl_registry::WlRegistry;
+use wayland_client::{event_created_child, Connection, Dispatch, Proxy, QueueHandle};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::{
+    State, ZwlrForeignToplevelHandleV1,
+};
+use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::{
+    ZwlrForeignToplevelManagerV1, EVT_TOPLEVEL_OPCODE,
+};
+
+/// Window that currently has keyboard focus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Focus {
+    pub app_id: String,
+    pub fullscreen: bool,
+}
+
+#[derive(Default)]
+struct Toplevel {
+    app_id: String,
+    activated: bool,
+    fullscreen: bool,
+}
+
+/// Follows the focused window using wlr-foreign-toplevel-management-unstable-v1 protocol.
+pub struct Watcher {
+    focus_txs: Vec<Sender<Option<Focus>>>,
+    manager: Option<ZwlrForeignToplevelManagerV1>,
+    toplevels: HashMap<ObjectId, Toplevel>,
+    focus: Option<Focus>,
+}
+
+impl Watcher {
+    pub fn new(focus_txs: Vec<Sender<Option<Focus>>>) -> Self {
+        Self {
+            focus_txs,
+            manager: None,
+            toplevels: HashMap::new(),
+            focus: None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let connection =
+            Connection::connect_to_env().expect("Unable to connect to Wayland display");
+        let mut event_queue = connection.new_event_queue();
+        let qh = event_queue.handle();
+
+        connection.display().get_registry(&qh, ());
+
+        event_queue
+            .roundtrip(self)
+            .expect("Unable to perform initial roundtrip");
+
+        if self.manager.is_none() {
+            log::warn!("Compositor does not support wlr-foreign-toplevel-management-unstable-v1 protocol, app rules are ignored");
+            return;
+        }
+
+        loop {
+            event_queue
+                .blocking_dispatch(self)
+                .expect("Error running wayland toplevel event loop");
+        }
+    }
+
+    fn update_focus(&mut self) {
+        let focus = self
+            .toplevels
+            .values()
+            .find(|t| t.activated)
+            .map(|t| Focus {
+                app_id: t.app_id.clone(),
+                fullscreen: t.fullscreen,
+            });
+
+        if focus != self.focus {
+            log::debug!("Focused window changed: {:?}", focus);
+            self.focus = focus;
+            self.focus_txs.iter().for_each(|tx| {
+                tx.send(self.focus.clone())
+                    .expect("Unable to send focused window, channel is dead")
+            });
+        }
+    }
+}
+
+impl Dispatch<WlRegistry, ()> for Watcher {
+    fn event(
+        state: &mut Self,
+        registry: &WlRegistry,
+        event: <WlRegistry as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        qh: &QueueHandle<Self>,
+    ) {
+        use wayland_client::protocol::wl_registry::Event;
+
+        if let Event::Global {
+            name,
+            interface,
+            version,
+        } = event
+        {
+            if interface == ZwlrForeignToplevelManagerV1::interface().name {
+                log::debug!(
+                    "Detected support for wlr-foreign-toplevel-management-unstable-v1 protocol"
+                );
+                state.manager = Some(registry.bind::<ZwlrForeignToplevelManagerV1, _, _>(
+                    name,
+                    version.min(3),
+                    qh,
+                    (),
+                ));
+            }
+        }
+    }
+}
+
+impl Dispatch<ZwlrForeignToplevelManagerV1, ()> for Watcher {
+    fn event(
+        state: &mut Self,
+        _: &ZwlrForeignToplevelManagerV1,
+        event: <ZwlrForeignToplevelManagerV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_manager_v1::Event;
+
+        match event {
+            Event::Toplevel { toplevel } => {
+                state.toplevels.insert(toplevel.id(), Toplevel::default());
+            }
+            Event::Finished => {
+                log::warn!("Compositor stopped sending toplevel events");
+                state.toplevels.clear();
+                state.update_focus();
+            }
+            _ => {}
+        }
+    }
+
+    event_created_child!(Watcher, ZwlrForeignToplevelManagerV1, [
+        EVT_TOPLEVEL_OPCODE => (ZwlrForeignToplevelHandleV1, ()),
+    ]);
+}
+
+impl Dispatch<ZwlrForeignToplevelHandleV1, ()> for Watcher {
+    fn event(
+        state: &mut Self,
+        handle: &ZwlrForeignToplevelHandleV1,
+        event: <ZwlrForeignToplevelHandleV1 as Proxy>::Event,
+        _: &(),
+        _: &Connection,
+        _: &QueueHandle<Self>,
+    ) {
+        use wayland_protocols_wlr::foreign_toplevel::v1::client::zwlr_foreign_toplevel_handle_v1::Event;
+
+        match event {
+            Event::AppId { app_id } => {
+                if let Some(toplevel) = state.toplevels.get_mut(&handle.id()) {
+                    toplevel.app_id = app_id;
+                }
+            }
+            Event::State { state: states } => {
+                let states = states
+                    .chunks_exact(4)
+                    .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect::<Vec<_>>();
+                if let Some(toplevel) = state.toplevels.get_mut(&handle.id()) {
+                    toplevel.activated = states.contains(&(State::Activated as u32));
+                    toplevel.fullscreen = states.contains(&(State::Fullscreen as u32));
+                }
+            }
+            Event::Done => state.update_focus(),
+            Event::Closed => {
+                state.toplevels.remove(&handle.id());
+                handle.destroy();
+                state.update_focus();
+            }
+            _ => {}
+        }
+    }
+}
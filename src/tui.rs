@@ -0,0 +1,168 @@
+use crate::control::{INTERFACE, NAME, PATH};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::{Connection, Proxy};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write;
+use std::thread;
+use std::time::Duration;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Brightness values kept for the sparkline, one per refresh.
+const HISTORY_LEN: usize = 60;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Properties of the D-Bus service, see `control::Status`.
+#[derive(Debug, Default)]
+struct Status {
+    profile: String,
+    als_value: u64,
+    paused: bool,
+    inhibitors: Vec<String>,
+    exposure_bias: i32,
+    brightness: HashMap<String, u32>,
+    luma: HashMap<String, u8>,
+    disabled_outputs: Vec<String>,
+    scenes: HashMap<String, String>,
+}
+
+impl Status {
+    fn get(proxy: &Proxy<&Connection>) -> Result<Self, dbus::Error> {
+        Ok(Self {
+            profile: proxy.get(INTERFACE, "CurrentProfile")?,
+            als_value: proxy.get(INTERFACE, "AlsValue")?,
+            paused: proxy.get(INTERFACE, "Paused")?,
+            inhibitors: proxy.get(INTERFACE, "Inhibitors")?,
+            exposure_bias: proxy.get(INTERFACE, "ExposureBias")?,
+            brightness: proxy.get(INTERFACE, "Brightness")?,
+            luma: proxy.get(INTERFACE, "Luma")?,
+            disabled_outputs: proxy.get(INTERFACE, "DisabledOutputs")?,
+            scenes: proxy.get(INTERFACE, "Scenes")?,
+        })
+    }
+}
+
+/// Shows what the running instance is doing, as reported on D-Bus, until interrupted.
+pub fn run() -> Result<(), dbus::Error> {
+    let connection = Connection::new_session()?;
+    let proxy = connection.with_proxy(NAME, PATH, TIMEOUT);
+
+    let mut history: BTreeMap<String, VecDeque<u32>> = BTreeMap::new();
+    loop {
+        let status = Status::get(&proxy)?;
+        for (output_name, brightness) in &status.brightness {
+            let values = history.entry(output_name.clone()).or_default();
+            if values.len() == HISTORY_LEN {
+                values.pop_front();
+            }
+            values.push_back(*brightness);
+        }
+
+        // Clear the screen and move the cursor to its top left corner
+        print!("\x1b[2J\x1b[H{}", render(&status, &history));
+        thread::sleep(REFRESH_INTERVAL);
+    }
+}
+
+fn render(status: &Status, history: &BTreeMap<String, VecDeque<u32>>) -> String {
+    let mut screen = String::new();
+    writeln!(screen, "wluma v{}\n", crate::VERSION).unwrap();
+    writeln!(
+        screen,
+        "ALS profile    {} ({})",
+        status.profile, status.als_value
+    )
+    .unwrap();
+    let inhibited = if status.inhibitors.is_empty() {
+        String::new()
+    } else {
+        format!("  [inhibited: {}]", status.inhibitors.join(", "))
+    };
+    writeln!(
+        screen,
+        "Exposure bias  {:+}%{}{}\n",
+        status.exposure_bias,
+        if status.paused { "  [paused]" } else { "" },
+        inhibited
+    )
+    .unwrap();
+
+    for (output_name, values) in history {
+        let luma = status
+            .luma
+            .get(output_name)
+            .map_or("-".to_string(), |luma| format!("{}%", luma));
+        let disabled = status.disabled_outputs.contains(output_name);
+        let scene = status
+            .scenes
+            .get(output_name)
+            .map_or(String::new(), |scene| format!("  [{}]", scene));
+        writeln!(
+            screen,
+            "{}{}{}\n  luma {:>4}  brightness {:>4}%  {}",
+            output_name,
+            if disabled { "  [disabled]" } else { "" },
+            scene,
+            luma,
+            values.back().unwrap_or(&0),
+            sparkline(values.iter().copied())
+        )
+        .unwrap();
+    }
+
+    screen
+}
+
+/// Draws percentages as a line of bars of 8 heights.
+fn sparkline(percents: impl Iterator<Item = u32>) -> String {
+    percents
+        .map(|percent| SPARKS[(percent.min(100) as usize * (SPARKS.len() - 1) + 50) / 100])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        assert_eq!(
+            "▁▁▂▅▅██",
+            sparkline([0, 5, 20, 50, 60, 100, 150].into_iter())
+        );
+        assert_eq!("", sparkline(std::iter::empty()));
+    }
+
+    #[test]
+    fn test_render() {
+        let status = Status {
+            profile: "dim".to_string(),
+            als_value: 42,
+            exposure_bias: -5,
+            luma: [("eDP-1".to_string(), 30)].into(),
+            scenes: [("eDP-1".to_string(), "movie".to_string())].into(),
+            disabled_outputs: vec!["HDMI-A-1".to_string()],
+            ..Default::default()
+        };
+        let history = [
+            ("eDP-1".to_string(), [10, 20].into()),
+            ("HDMI-A-1".to_string(), [70].into()),
+        ]
+        .into();
+
+        let screen = render(&status, &history);
+
+        assert!(screen.contains("ALS profile    dim (42)\n"), "{}", screen);
+        assert!(screen.contains("Exposure bias  -5%\n"), "{}", screen);
+        assert!(
+            screen.contains("HDMI-A-1  [disabled]\n  luma    -  brightness   70%  ▆\n"),
+            "{}",
+            screen
+        );
+        assert!(
+            screen.contains("eDP-1  [movie]\n  luma  30%  brightness   20%  ▂▂\n"),
+            "{}",
+            screen
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use crate::error::WlumaError;
+use crate::frame::Area;
+use crate::workspace::{
+    hyprland_dir, hyprland_request, sway_receive, sway_send, SWAY_EVENT, SWAY_GET_TREE,
+    SWAY_SUBSCRIBE,
+};
+use serde::Deserialize;
+use std::env;
+use std::io::{BufRead, BufReader};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+/// Window events of Sway that don't move or focus windows.
+const SWAY_IGNORED_CHANGES: [&str; 3] = ["title", "mark", "urgent"];
+/// Hyprland events after which the focused window may be elsewhere. Hyprland has none for
+/// resized windows, they are followed once something else happens.
+const HYPRLAND_EVENTS: [&str; 8] = [
+    "activewindowv2",
+    "movewindowv2",
+    "changefloatingmode",
+    "fullscreen",
+    "workspacev2",
+    "focusedmon",
+    "openwindow",
+    "closewindow",
+];
+
+/// Window that currently has keyboard focus, and where it is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FocusedWindow {
+    /// Connector name of the output it's on, e.g. `DP-1`.
+    pub output: String,
+    pub area: Area,
+}
+
+#[derive(Deserialize)]
+struct SwayEvent {
+    change: String,
+}
+
+/// Rectangle in layout coordinates, which all outputs share.
+#[derive(Deserialize)]
+struct LayoutRect {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+#[derive(Deserialize)]
+struct SwayNode {
+    #[serde(rename = "type")]
+    kind: String,
+    name: Option<String>,
+    rect: LayoutRect,
+    #[serde(default)]
+    focused: bool,
+    #[serde(default)]
+    nodes: Vec<SwayNode>,
+    #[serde(default)]
+    floating_nodes: Vec<SwayNode>,
+}
+
+#[derive(Deserialize)]
+struct HyprlandWindow {
+    at: [f64; 2],
+    size: [f64; 2],
+    monitor: i64,
+}
+
+#[derive(Deserialize)]
+struct HyprlandMonitor {
+    id: i64,
+    name: String,
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+    scale: f64,
+    transform: u8,
+}
+
+/// Follows where the focused window is using Sway or Hyprland IPC, as Wayland protocols don't
+/// tell where windows are.
+pub struct Watcher {
+    window_txs: Vec<Sender<Option<FocusedWindow>>>,
+    window: Option<FocusedWindow>,
+}
+
+impl Watcher {
+    pub fn new(window_txs: Vec<Sender<Option<FocusedWindow>>>) -> Self {
+        Self {
+            window_txs,
+            window: None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let result = if let Ok(path) = env::var("SWAYSOCK") {
+            self.run_sway(&path)
+        } else if let Ok(signature) = env::var("HYPRLAND_INSTANCE_SIGNATURE") {
+            self.run_hyprland(&signature)
+        } else {
+            Err(WlumaError::config(
+                "Neither Sway nor Hyprland IPC is available",
+            ))
+        };
+
+        if let Err(err) = result {
+            log::warn!(
+                "Unable to follow the focused window, weighting luma around the center of screens: {}", err
+            );
+        }
+        self.update_window(None);
+    }
+
+    fn run_sway(&mut self, path: &str) -> Result<(), WlumaError> {
+        let mut commands = UnixStream::connect(path).map_err(WlumaError::wayland)?;
+        let mut events = UnixStream::connect(path).map_err(WlumaError::wayland)?;
+
+        sway_send(&mut events, SWAY_SUBSCRIBE, br#"["window", "workspace"]"#)?;
+        let mut refresh = true;
+        loop {
+            if refresh {
+                sway_send(&mut commands, SWAY_GET_TREE, b"")?;
+                let (_, tree) = sway_receive(&mut commands)?;
+                let tree: SwayNode = serde_json::from_slice(&tree).map_err(WlumaError::wayland)?;
+                self.update_window(find_sway_window(&tree, None));
+            }
+
+            let (kind, payload) = sway_receive(&mut events)?;
+            refresh = kind & SWAY_EVENT != 0
+                && serde_json::from_slice::<SwayEvent>(&payload)
+                    .is_ok_and(|event| !SWAY_IGNORED_CHANGES.contains(&event.change.as_str()));
+        }
+    }
+
+    fn run_hyprland(&mut self, signature: &str) -> Result<(), WlumaError> {
+        let dir = hyprland_dir(signature);
+        self.update_window(hyprland_window(&dir)?);
+
+        let events = UnixStream::connect(dir.join(".socket2.sock")).map_err(WlumaError::wayland)?;
+        for line in BufReader::new(events).lines() {
+            let line = line.map_err(WlumaError::wayland)?;
+            let event = line
+                .split_once(">>")
+                .map_or(line.as_str(), |(event, _)| event);
+            if HYPRLAND_EVENTS.contains(&event) {
+                self.update_window(hyprland_window(&dir)?);
+            }
+        }
+
+        Err(WlumaError::wayland("Hyprland closed the event socket"))
+    }
+
+    fn update_window(&mut self, window: Option<FocusedWindow>) {
+        if window != self.window {
+            log::debug!("Focused window moved: {:?}", window);
+            self.window = window;
+            self.window_txs
+                .retain(|tx| tx.send(self.window.clone()).is_ok());
+        }
+    }
+}
+
+/// Part of the output covered by the window.
+fn area(window: &LayoutRect, output: &LayoutRect) -> Area {
+    Area {
+        x: (window.x - output.x) / output.width,
+        y: (window.y - output.y) / output.height,
+        width: window.width / output.width,
+        height: window.height / output.height,
+    }
+}
+
+/// Finds the focused window in the tree, nothing if an empty workspace is focused.
+fn find_sway_window(node: &SwayNode, output: Option<&SwayNode>) -> Option<FocusedWindow> {
+    let output = if node.kind == "output" {
+        Some(node)
+    } else {
+        output
+    };
+    if node.focused {
+        return match (node.kind.as_str(), output) {
+            ("con" | "floating_con", Some(output)) => Some(FocusedWindow {
+                output: output.name.clone()?,
+                area: area(&node.rect, &output.rect),
+            }),
+            _ => None,
+        };
+    }
+    node.nodes
+        .iter()
+        .chain(&node.floating_nodes)
+        .find_map(|child| find_sway_window(child, output))
+}
+
+fn hyprland_window(dir: &Path) -> Result<Option<FocusedWindow>, WlumaError> {
+    let window = hyprland_request(dir, "j/activewindow")?;
+    let monitors = hyprland_request(dir, "j/monitors")?;
+    parse_hyprland_window(&window, &monitors)
+}
+
+fn parse_hyprland_window(
+    window: &[u8],
+    monitors: &[u8],
+) -> Result<Option<FocusedWindow>, WlumaError> {
+    // Hyprland replies with an empty object when no window is focused
+    let Ok(window) = serde_json::from_slice::<HyprlandWindow>(window) else {
+        return Ok(None);
+    };
+    let monitors: Vec<HyprlandMonitor> =
+        serde_json::from_slice(monitors).map_err(WlumaError::wayland)?;
+    let Some(monitor) = monitors.into_iter().find(|m| m.id == window.monitor) else {
+        return Ok(None);
+    };
+
+    // Sizes of monitors are in pixels, before they are rotated
+    let (width, height) = match monitor.transform % 2 {
+        0 => (monitor.width, monitor.height),
+        _ => (monitor.height, monitor.width),
+    };
+    let output = LayoutRect {
+        x: monitor.x,
+        y: monitor.y,
+        width: width / monitor.scale,
+        height: height / monitor.scale,
+    };
+    let window = LayoutRect {
+        x: window.at[0],
+        y: window.at[1],
+        width: window.size[0],
+        height: window.size[1],
+    };
+    Ok(Some(FocusedWindow {
+        output: monitor.name,
+        area: area(&window, &output),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_sway_window() {
+        let tree = br#"{"type":"root","name":"root","rect":{"x":0,"y":0,"width":4480,"height":1440},"nodes":[
+            {"type":"output","name":"eDP-1","rect":{"x":0,"y":0,"width":1920,"height":1080},"nodes":[
+                {"type":"workspace","name":"1","rect":{"x":0,"y":0,"width":1920,"height":1080},"nodes":[]}]},
+            {"type":"output","name":"DP-1","rect":{"x":1920,"y":0,"width":2560,"height":1440},"nodes":[
+                {"type":"workspace","name":"2","rect":{"x":1920,"y":0,"width":2560,"height":1440},"nodes":[
+                    {"type":"con","name":"editor","rect":{"x":1920,"y":0,"width":1280,"height":1440},"focused":false}],
+                 "floating_nodes":[
+                    {"type":"floating_con","name":"calculator","rect":{"x":2560,"y":360,"width":640,"height":720},"focused":true}]}]}]}"#;
+        let tree: SwayNode = serde_json::from_slice(tree).unwrap();
+
+        assert_eq!(
+            Some(FocusedWindow {
+                output: "DP-1".to_string(),
+                area: Area {
+                    x: 0.25,
+                    y: 0.25,
+                    width: 0.25,
+                    height: 0.5,
+                },
+            }),
+            find_sway_window(&tree, None)
+        );
+
+        // An empty workspace is focused
+        let tree = br#"{"type":"root","rect":{"x":0,"y":0,"width":1920,"height":1080},"nodes":[
+            {"type":"output","name":"eDP-1","rect":{"x":0,"y":0,"width":1920,"height":1080},"nodes":[
+                {"type":"workspace","name":"1","rect":{"x":0,"y":0,"width":1920,"height":1080},"focused":true}]}]}"#;
+        let tree: SwayNode = serde_json::from_slice(tree).unwrap();
+        assert_eq!(None, find_sway_window(&tree, None));
+    }
+
+    #[test]
+    fn test_parse_hyprland_window() {
+        let monitors = br#"[
+            {"id":0,"name":"eDP-1","x":0,"y":0,"width":2880,"height":1800,"scale":2.0,"transform":0},
+            {"id":1,"name":"DP-1","x":1440,"y":0,"width":1440,"height":2560,"scale":1.0,"transform":1}]"#;
+
+        // Rotated monitor
+        let window = br#"{"address":"0x1","at":[1440,360],"size":[1280,720],"monitor":1}"#;
+        assert_eq!(
+            Some(FocusedWindow {
+                output: "DP-1".to_string(),
+                area: Area {
+                    x: 0.0,
+                    y: 0.25,
+                    width: 0.5,
+                    height: 0.5,
+                },
+            }),
+            parse_hyprland_window(window, monitors).unwrap()
+        );
+
+        // Scaled monitor
+        let window = br#"{"address":"0x2","at":[360,0],"size":[720,900],"monitor":0}"#;
+        assert_eq!(
+            Some(FocusedWindow {
+                output: "eDP-1".to_string(),
+                area: Area {
+                    x: 0.25,
+                    y: 0.0,
+                    width: 0.5,
+                    height: 1.0,
+                },
+            }),
+            parse_hyprland_window(window, monitors).unwrap()
+        );
+
+        assert_eq!(None, parse_hyprland_window(b"{}", monitors).unwrap());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::error::WlumaError;
+use serde::Deserialize;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+
+const SWAY_MAGIC: &[u8] = b"i3-ipc";
+const SWAY_GET_WORKSPACES: u32 = 1;
+pub const SWAY_SUBSCRIBE: u32 = 2;
+pub const SWAY_GET_TREE: u32 = 4;
+/// Bit set in the type of every Sway IPC event, unlike replies to commands.
+pub const SWAY_EVENT: u32 = 0x80000000;
+/// Workspace events are the first kind of events, their type has nothing else set.
+const SWAY_EVENT_WORKSPACE: u32 = SWAY_EVENT;
+
+#[derive(Deserialize)]
+struct SwayWorkspace {
+    name: String,
+    #[serde(default)]
+    focused: bool,
+}
+
+#[derive(Deserialize)]
+struct SwayWorkspaceEvent {
+    change: String,
+    current: Option<SwayWorkspace>,
+}
+
+#[derive(Deserialize)]
+struct HyprlandWorkspace {
+    name: String,
+}
+
+/// Follows the name of the focused workspace using Sway or Hyprland IPC.
+pub struct Watcher {
+    workspace_txs: Vec<Sender<Option<String>>>,
+    workspace: Option<String>,
+}
+
+impl Watcher {
+    pub fn new(workspace_txs: Vec<Sender<Option<String>>>) -> Self {
+        Self {
+            workspace_txs,
+            workspace: None,
+        }
+    }
+
+    pub fn run(&mut self) {
+        let result = if let Ok(path) = env::var("SWAYSOCK") {
+            self.run_sway(&path)
+        } else if let Ok(signature) = env::var("HYPRLAND_INSTANCE_SIGNATURE") {
+            self.run_hyprland(&signature)
+        } else {
+            Err(WlumaError::config(
+                "Neither Sway nor Hyprland IPC is available",
+            ))
+        };
+
+        if let Err(err) = result {
+            log::warn!(
+                "Unable to follow the focused workspace, learning without workspaces: {}",
+                err
+            );
+        }
+        self.update_workspace(None);
+    }
+
+    fn run_sway(&mut self, path: &str) -> Result<(), WlumaError> {
+        let mut stream = UnixStream::connect(path).map_err(WlumaError::wayland)?;
+
+        sway_send(&mut stream, SWAY_GET_WORKSPACES, b"")?;
+        let (_, payload) = sway_receive(&mut stream)?;
+        let workspaces: Vec<SwayWorkspace> =
+            serde_json::from_slice(&payload).map_err(WlumaError::wayland)?;
+        self.update_workspace(workspaces.into_iter().find(|w| w.focused).map(|w| w.name));
+
+        sway_send(&mut stream, SWAY_SUBSCRIBE, br#"["workspace"]"#)?;
+        loop {
+            let (kind, payload) = sway_receive(&mut stream)?;
+            if kind == SWAY_EVENT_WORKSPACE {
+                if let Some(workspace) = parse_sway_event(&payload) {
+                    self.update_workspace(Some(workspace));
+                }
+            }
+        }
+    }
+
+    fn run_hyprland(&mut self, signature: &str) -> Result<(), WlumaError> {
+        let dir = hyprland_dir(signature);
+
+        let reply = hyprland_request(&dir, "j/activeworkspace")?;
+        let workspace: HyprlandWorkspace =
+            serde_json::from_slice(&reply).map_err(WlumaError::wayland)?;
+        self.update_workspace(Some(workspace.name));
+
+        let events = UnixStream::connect(dir.join(".socket2.sock")).map_err(WlumaError::wayland)?;
+        for line in BufReader::new(events).lines() {
+            let line = line.map_err(WlumaError::wayland)?;
+            if let Some(workspace) = parse_hyprland_event(&line) {
+                self.update_workspace(Some(workspace));
+            }
+        }
+
+        Err(WlumaError::wayland("Hyprland closed the event socket"))
+    }
+
+    fn update_workspace(&mut self, workspace: Option<String>) {
+        if workspace != self.workspace {
+            log::debug!("Focused workspace changed: {:?}", workspace);
+            self.workspace = workspace;
+            self.workspace_txs
+                .retain(|tx| tx.send(self.workspace.clone()).is_ok());
+        }
+    }
+}
+
+pub fn hyprland_dir(signature: &str) -> PathBuf {
+    let runtime_dir = env::var("XDG_RUNTIME_DIR")
+        .map(|dir| PathBuf::from(dir).join("hypr").join(signature))
+        .ok();
+
+    // Older versions of Hyprland keep sockets in /tmp
+    runtime_dir
+        .filter(|dir| dir.exists())
+        .unwrap_or_else(|| PathBuf::from("/tmp/hypr").join(signature))
+}
+
+/// Sends a command to Hyprland and returns its reply, every command needs its own connection.
+pub fn hyprland_request(dir: &Path, command: &str) -> Result<Vec<u8>, WlumaError> {
+    let mut stream = UnixStream::connect(dir.join(".socket.sock")).map_err(WlumaError::wayland)?;
+    stream
+        .write_all(command.as_bytes())
+        .map_err(WlumaError::wayland)?;
+    let mut reply = Vec::new();
+    stream
+        .read_to_end(&mut reply)
+        .map_err(WlumaError::wayland)?;
+    Ok(reply)
+}
+
+pub fn sway_send(stream: &mut UnixStream, kind: u32, payload: &[u8]) -> Result<(), WlumaError> {
+    let mut message = SWAY_MAGIC.to_vec();
+    message.extend((payload.len() as u32).to_ne_bytes());
+    message.extend(kind.to_ne_bytes());
+    message.extend(payload);
+    stream.write_all(&message).map_err(WlumaError::wayland)
+}
+
+pub fn sway_receive(stream: &mut UnixStream) -> Result<(u32, Vec<u8>), WlumaError> {
+    let mut header = [0u8; 14];
+    stream
+        .read_exact(&mut header)
+        .map_err(WlumaError::wayland)?;
+    if &header[..6] != SWAY_MAGIC {
+        return Err(WlumaError::wayland("Unexpected reply from Sway IPC"));
+    }
+
+    let length = u32::from_ne_bytes(header[6..10].try_into().unwrap());
+    let kind = u32::from_ne_bytes(header[10..14].try_into().unwrap());
+    let mut payload = vec![0u8; length as usize];
+    stream
+        .read_exact(&mut payload)
+        .map_err(WlumaError::wayland)?;
+    Ok((kind, payload))
+}
+
+fn parse_sway_event(payload: &[u8]) -> Option<String> {
+    let event: SwayWorkspaceEvent = serde_json::from_slice(payload).ok()?;
+    match event.change.as_str() {
+        "focus" => event.current.map(|w| w.name),
+        _ => None,
+    }
+}
+
+fn parse_hyprland_event(line: &str) -> Option<String> {
+    let (event, data) = line.split_once(">>")?;
+    match event {
+        "workspace" => Some(data.to_string()),
+        // Focusing another monitor also focuses its workspace
+        "focusedmon" => data.split_once(',').map(|(_, name)| name.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sway_event() {
+        let focus = br#"{"change":"focus","current":{"name":"2: coding","focused":true},"old":{"name":"1"}}"#;
+        assert_eq!(Some("2: coding".to_string()), parse_sway_event(focus));
+
+        let init = br#"{"change":"init","current":{"name":"3"}}"#;
+        assert_eq!(None, parse_sway_event(init));
+    }
+
+    #[test]
+    fn test_parse_hyprland_event() {
+        assert_eq!(
+            Some("browser".to_string()),
+            parse_hyprland_event("workspace>>browser")
+        );
+        assert_eq!(
+            Some("2".to_string()),
+            parse_hyprland_event("focusedmon>>DP-1,2")
+        );
+        assert_eq!(None, parse_hyprland_event("activewindow>>mpv,video"));
+    }
+}